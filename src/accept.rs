@@ -0,0 +1,181 @@
+// a single entry of an `Accept` header, e.g. "text/*;q=0.5"
+#[derive(Debug, PartialEq)]
+pub struct MediaRange {
+    pub media_type: String,
+    pub subtype: String,
+    pub q: f32,
+}
+
+impl MediaRange {
+    fn matches(&self, content_type: &str) -> bool {
+        let Some((media_type, subtype)) = content_type.split_once('/') else {
+            return false;
+        };
+        (self.media_type == "*" || self.media_type.eq_ignore_ascii_case(media_type))
+            && (self.subtype == "*" || self.subtype.eq_ignore_ascii_case(subtype))
+    }
+
+    // more specific ranges take precedence: type/subtype > type/* > */*
+    fn specificity(&self) -> u8 {
+        match (self.media_type.as_str(), self.subtype.as_str()) {
+            ("*", _) => 0,
+            (_, "*") => 1,
+            _ => 2,
+        }
+    }
+}
+
+// parses an `Accept` header, skipping malformed entries
+pub fn parse_accept(header: &str) -> Vec<MediaRange> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let (media_type, subtype) = params.next()?.trim().split_once('/')?;
+            if media_type.is_empty() || subtype.is_empty() {
+                return None;
+            }
+
+            let mut q = 1.0;
+            for param in params {
+                if let Some((key, value)) = param.trim().split_once('=') {
+                    if key.trim().eq_ignore_ascii_case("q") {
+                        q = value.trim().parse::<f32>().ok()?.clamp(0.0, 1.0);
+                    }
+                }
+            }
+
+            Some(MediaRange {
+                media_type: media_type.to_owned(),
+                subtype: subtype.to_owned(),
+                q,
+            })
+        })
+        .collect()
+}
+
+// picks the available content type the client prefers most; ties keep the server's order
+pub fn negotiate<'a>(accept: Option<&str>, available: &[&'a str]) -> Option<&'a str> {
+    let Some(accept) = accept else {
+        return available.first().copied();
+    };
+    let ranges = parse_accept(accept);
+    if ranges.is_empty() {
+        return available.first().copied();
+    }
+
+    let mut best: Option<(&str, f32)> = None;
+    for &content_type in available {
+        let q = ranges
+            .iter()
+            .filter(|range| range.matches(content_type))
+            .max_by_key(|range| range.specificity())
+            .map_or(0.0, |range| range.q);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((content_type, q));
+        }
+    }
+    best.map(|(content_type, _)| content_type)
+}
+
+// the codings of an `Accept-Encoding` header with their weights, skipping malformed entries
+fn parse_accept_encoding(header: &str) -> Vec<(&str, f32)> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let coding = params.next()?.trim();
+            if coding.is_empty() {
+                return None;
+            }
+            let mut q = 1.0;
+            for param in params {
+                if let Some((key, value)) = param.trim().split_once('=') {
+                    if key.trim().eq_ignore_ascii_case("q") {
+                        q = value.trim().parse::<f32>().ok()?.clamp(0.0, 1.0);
+                    }
+                }
+            }
+            Some((coding, q))
+        })
+        .collect()
+}
+
+// picks the available content coding the client prefers most, ties keeping the server's order;
+// None means sending the body as it is
+pub fn negotiate_encoding<'a>(
+    accept_encoding: Option<&str>,
+    available: &[&'a str],
+) -> Option<&'a str> {
+    let codings = parse_accept_encoding(accept_encoding?);
+    let mut best: Option<(&str, f32)> = None;
+    for &coding in available {
+        let weight = |wanted: &str| {
+            codings
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+                .map(|(_, q)| *q)
+        };
+        let q = weight(coding).or_else(|| weight("*")).unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((coding, q));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_accept() {
+        let ranges = parse_accept("text/html, application/json;q=0.5, */*;q=0.1, bad");
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].media_type, "text");
+        assert_eq!(ranges[0].subtype, "html");
+        assert_eq!(ranges[0].q, 1.0);
+        assert_eq!(ranges[1].q, 0.5);
+        assert_eq!(ranges[2].subtype, "*");
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let available = ["text/plain", "text/html", "application/json"];
+        assert_eq!(negotiate(None, &available), Some("text/plain"));
+        assert_eq!(negotiate(Some("*/*"), &available), Some("text/plain"));
+        assert_eq!(
+            negotiate(Some("text/html;q=0.9, application/json"), &available),
+            Some("application/json")
+        );
+        assert_eq!(
+            negotiate(Some("text/*;q=0.5, text/html"), &available),
+            Some("text/html")
+        );
+        assert_eq!(
+            negotiate(Some("*/*, text/plain;q=0"), &available),
+            Some("text/html")
+        );
+        assert_eq!(negotiate(Some("image/png"), &available), None);
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let available = ["br", "gzip"];
+        assert_eq!(negotiate_encoding(None, &available), None);
+        assert_eq!(
+            negotiate_encoding(Some("gzip, deflate, br"), &available),
+            Some("br")
+        );
+        assert_eq!(negotiate_encoding(Some("GZIP"), &available), Some("gzip"));
+        assert_eq!(
+            negotiate_encoding(Some("br;q=0.5, gzip"), &available),
+            Some("gzip")
+        );
+        assert_eq!(
+            negotiate_encoding(Some("*, br;q=0"), &available),
+            Some("gzip")
+        );
+        assert_eq!(negotiate_encoding(Some("identity"), &available), None);
+    }
+}
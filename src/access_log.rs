@@ -0,0 +1,292 @@
+use crate::date::format_log_date;
+use crate::http::{percent_encode_path, Request, StatusCode};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+// one line per request in the Common Log Format, with the seconds taken to answer and until the
+// response was ready appended:
+// 127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] "GET /files/a.txt HTTP/1.1" 200 5 0.001 0.000
+pub(crate) enum AccessLog {
+    Stdout,
+    File(Mutex<LogFile>),
+}
+
+// when a log file is moved aside for a new one: access.log becomes access.log.1, access.log.1
+// becomes access.log.2 and so on, dropping the oldest beyond `keep`
+#[derive(Clone, Copy)]
+pub(crate) struct Rotation {
+    pub(crate) max_size: Option<u64>,
+    // at the end of every period since the epoch, so daily is at midnight UTC
+    pub(crate) period: Option<Duration>,
+    pub(crate) keep: usize,
+}
+
+pub(crate) struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // the period the file was opened in
+    opened: u64,
+    rotation: Rotation,
+    // set on SIGUSR1, after something else such as logrotate has moved the file
+    reopen: Arc<AtomicBool>,
+}
+
+impl AccessLog {
+    // "-" is stdout, anything else a file that's appended to
+    pub(crate) fn open(target: &str, rotation: Rotation) -> io::Result<Self> {
+        if target == "-" {
+            return Ok(AccessLog::Stdout);
+        }
+        let path = PathBuf::from(target);
+        let (file, size) = open_file(&path)?;
+        let reopen = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, Arc::clone(&reopen))?;
+        Ok(AccessLog::File(Mutex::new(LogFile {
+            opened: rotation.period_of(now()),
+            path,
+            file,
+            size,
+            rotation,
+            reopen,
+        })))
+    }
+
+    // `request_line` is None for requests that couldn't be parsed; `bytes` counts the body only
+    pub(crate) fn log(
+        &self,
+        client: Option<IpAddr>,
+        request_line: Option<&str>,
+        status: StatusCode,
+        bytes: u64,
+        elapsed: Duration,
+        handled: Duration,
+    ) {
+        let secs = now();
+        let line = format_line(client, request_line, status, bytes, secs, elapsed, handled);
+        match self {
+            AccessLog::Stdout => println!("{}", line),
+            AccessLog::File(file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                file.write_line(&line, secs);
+            }
+        }
+    }
+}
+
+impl LogFile {
+    // a full disk, or a rotation that failed, shouldn't take the server down with it
+    fn write_line(&mut self, line: &str, secs: u64) {
+        let len = line.len() as u64 + 1;
+        let rotation = self.rotation;
+        let too_big = rotation
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len > max);
+        let period = rotation.period_of(secs);
+        let result = if too_big || period != self.opened {
+            self.opened = period;
+            rotate(&self.path, rotation.keep).and_then(|_| self.reopen())
+        } else if self.reopen.swap(false, Ordering::Relaxed) {
+            self.reopen()
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            warn!(path = %self.path.display(), error = %e, "reopening the access log failed");
+        }
+        if writeln!(self.file, "{}", line).is_ok() {
+            self.size += len;
+        }
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        (self.file, self.size) = open_file(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Rotation {
+    fn period_of(&self, secs: u64) -> u64 {
+        match self.period {
+            Some(period) => secs / period.as_secs().max(1),
+            None => 0,
+        }
+    }
+}
+
+fn open_file(path: &Path) -> io::Result<(File, u64)> {
+    let file = File::options().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+// shifts the numbered older files up by one and moves the current one to .1
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let numbered = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    for n in (1..keep).rev() {
+        // there may not be that many yet
+        let _ = fs::rename(numbered(n), numbered(n + 1));
+    }
+    fs::rename(path, numbered(1))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// the request as it came in, with the path encoded again
+pub(crate) fn request_line(request: &Request) -> String {
+    let query = match request.query() {
+        Some(query) => format!("?{}", query),
+        None => String::new(),
+    };
+    format!(
+        "{} {}{} {}",
+        request.method.as_str(),
+        percent_encode_path(&request.path),
+        query,
+        request.version
+    )
+}
+
+fn format_line(
+    client: Option<IpAddr>,
+    request_line: Option<&str>,
+    status: StatusCode,
+    bytes: u64,
+    secs: u64,
+    elapsed: Duration,
+    handled: Duration,
+) -> String {
+    let client = client.map_or("-".to_owned(), |client| client.to_string());
+    let request_line = request_line.map_or("-".to_owned(), escape);
+    let bytes = match bytes {
+        0 => "-".to_owned(),
+        bytes => bytes.to_string(),
+    };
+    format!(
+        "{} - - [{}] \"{}\" {} {} {:.3} {:.3}",
+        client,
+        format_log_date(secs),
+        request_line,
+        status.as_u16(),
+        bytes,
+        elapsed.as_secs_f64(),
+        handled.as_secs_f64()
+    )
+}
+
+// quotes, backslashes and control characters as \xNN like Apache, so a request can't forge lines
+// or fields
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c.is_control() {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("\\x{:02x}", byte));
+            }
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_access_log() {
+        let request = Request::new(Method::Get, "/files/a b.txt?download");
+        let line = format_line(
+            Some("127.0.0.1".parse().unwrap()),
+            Some(&request_line(&request)),
+            StatusCode::OK,
+            5,
+            784111777,
+            Duration::from_micros(1500),
+            Duration::from_micros(400),
+        );
+        assert_eq!(
+            line,
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /files/a%20b.txt?download HTTP/1.1\" 200 5 0.002 0.000"
+        );
+
+        let line = format_line(
+            None,
+            None,
+            StatusCode::BAD_REQUEST,
+            0,
+            0,
+            Duration::ZERO,
+            Duration::ZERO,
+        );
+        assert_eq!(
+            line,
+            "- - - [01/Jan/1970:00:00:00 +0000] \"-\" 400 - 0.000 0.000"
+        );
+        assert_eq!(escape("GET /\"a\"\n\\"), "GET /\\x22a\\x22\\x0a\\x5c");
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = TempDir::new("rotate");
+        let path = dir.join("access.log");
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap_or_default();
+        let rotation = Rotation {
+            max_size: Some(10),
+            period: Some(Duration::from_secs(3600)),
+            keep: 2,
+        };
+        let AccessLog::File(log) = AccessLog::open(path.to_str().unwrap(), rotation).unwrap()
+        else {
+            panic!("not a file");
+        };
+        let mut log = log.into_inner().unwrap();
+        let hour = log.opened * 3600;
+
+        // by size: a line that doesn't fit goes to a new file, but a long one is still written
+        for line in ["aaaa", "bbbb", "cccc", "dddddddddddd", "eeee"] {
+            log.write_line(line, hour);
+        }
+        assert_eq!(read("access.log"), "eeee\n");
+        assert_eq!(read("access.log.1"), "dddddddddddd\n");
+        assert_eq!(read("access.log.2"), "cccc\n");
+        assert!(!dir.join("access.log.3").exists());
+
+        // by time
+        log.write_line("ffff", hour + 3600);
+        assert_eq!(read("access.log"), "ffff\n");
+        assert_eq!(read("access.log.1"), "eeee\n");
+
+        // moved by someone else, who then sends SIGUSR1
+        #[cfg(unix)]
+        {
+            fs::rename(&path, dir.join("moved.log")).unwrap();
+            signal_hook::low_level::raise(signal_hook::consts::SIGUSR1).unwrap();
+            log.write_line("gggg", hour + 3600);
+            assert_eq!(read("moved.log"), "ffff\n");
+            assert_eq!(read("access.log"), "gggg\n");
+        }
+    }
+}
@@ -0,0 +1,2234 @@
+use crate::accept;
+use crate::archive;
+use crate::date::{format_http_date, parse_http_date};
+use crate::dav;
+use crate::extract::PathParams;
+use crate::form::Form;
+use crate::health::{healthz_handler, readyz_handler};
+use crate::http::{
+    percent_encode_path, Body, Headers, Method, Request, Response, ServerError, StatusCode, ACCEPT,
+    ACCEPT_ENCODING, APPLICATION_JSON, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_ENCODING,
+    CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED, LOCATION,
+    RANGE, TEXT_HTML, USER_AGENT, VARY, X_REQUEST_ID,
+};
+use crate::listing;
+use crate::metrics::metrics_handler;
+use crate::middleware::{request_id, BasicAuth, BearerAuth, Cors, Csrf, SecurityHeaders};
+use crate::mime;
+use crate::multipart;
+use crate::proxy::Tunnel;
+use crate::router::{Router, TrailingSlash};
+use crate::server::{Config, Mount, State};
+use crate::sha256::{self, Sha256};
+use crate::stats::stats_handler;
+use crate::websocket;
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+// what ".<name>" of a resumable upload is called until all of it is there
+const PARTIAL_SUFFIX: &str = ".partial";
+// how often the mounts are searched for abandoned partial uploads
+const PARTIAL_UPLOAD_SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+fn root_handler() -> &'static str {
+    "Hello World"
+}
+
+fn echo_handler(request: Request) -> String {
+    match request.method {
+        // HTML form submissions are echoed as decoded "key=value" lines
+        Method::Post => match Form::from_request(&request) {
+            Some(form) => form
+                .pairs()
+                .iter()
+                .map(|(key, value)| format!("{}={}\n", key, value))
+                .collect(),
+            None => request.text().into_owned(),
+        },
+        _ => request.param("text").unwrap_or("").to_owned(),
+    }
+}
+
+fn user_agent_handler(headers: Headers) -> Result<String, StatusCode> {
+    headers
+        .get(USER_AGENT)
+        .map(str::to_owned)
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+// the client's address, as trusted proxies have it
+fn ip_handler(request: Request) -> Result<String, StatusCode> {
+    request
+        .client_ip()
+        .map(|ip| ip.to_string())
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+// sends every message back, for trying out WebSocket clients
+fn websocket_echo_handler(request: Request) -> Response {
+    websocket::upgrade(&request, |mut socket| {
+        while let Ok(Some(message)) = socket.recv() {
+            if socket.send(message).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+fn file_handler(
+    state: Arc<State>,
+    params: PathParams,
+    mut request: Request,
+) -> Result<Response, ServerError> {
+    let path = params.get("name").unwrap_or("");
+    let mount = request_mount(&state.config, &request)?;
+
+    if request.method != Method::Get {
+        check_writable(&state, mount, &request)?;
+    }
+
+    // preloaded files are plain files inside the mount, so their path needn't be checked on disk
+    let preloaded = Path::new(&mount.directory).join(path);
+    if request.method == Method::Get && state.preload.get(&preloaded).is_some() {
+        check_allowed(mount, Path::new(path), false)?;
+        return get_file(&state, &preloaded, &request);
+    }
+
+    let file_path = resolve_path(mount, &state.config, Path::new(path))?;
+    check_allowed(mount, Path::new(path), file_path.is_dir())?;
+    let replaces = matches!(request.method, Method::Put | Method::Delete | Method::Patch);
+    if replaces && !preconditions_hold(&request, &file_path) {
+        return Ok(Response::new(StatusCode::PRECONDITION_FAILED));
+    }
+    let action = request.method == Method::Post
+        && request.query_pairs().iter().any(|(key, _)| key == "action");
+    // with ?parents, uploads create the directories leading to them like `mkdir -p`
+    let writes = matches!(request.method, Method::Post | Method::Put) && !action;
+    if writes && query_flag(&request, "parents") {
+        let directory = match path.is_empty() || path.ends_with('/') {
+            true => Some(file_path.as_path()),
+            false => file_path.parent(),
+        };
+        if let Some(directory) = directory {
+            fs::create_dir_all(directory).map_err(|_| {
+                ServerError::new(
+                    StatusCode::CONFLICT,
+                    "the parent directories couldn't be created",
+                )
+            })?;
+        }
+    }
+    let response = match request.method {
+        Method::Get if file_path.is_dir() => {
+            serve_directory(&state, mount, Path::new(path), &request)
+        }
+        // client-side routes of a single-page app all get its index page
+        Method::Get if state.config.spa && !file_path.exists() => {
+            match find_index(mount, &state.config, Path::new(""))? {
+                Some(index) => get_file(&state, &index, &request),
+                None => Ok(Response::new(StatusCode::NOT_FOUND)),
+            }
+        }
+        Method::Post if action => file_action(&state, mount, &file_path, &request),
+        // a trailing slash names a directory to upload into
+        Method::Post if path.is_empty() || path.ends_with('/') => {
+            if !file_path.is_dir() {
+                return Ok(Response::new(StatusCode::NOT_FOUND));
+            }
+            upload_files(mount, &file_path, &mut request)
+        }
+        Method::Post => post_file(&file_path, &mut request.body_reader()),
+        Method::Put if request.headers.get(CONTENT_RANGE).is_some() => {
+            put_range(&file_path, &mut request)
+        }
+        Method::Put => put_file(&file_path, &mut request.body_reader()),
+        Method::Delete => delete_file(&file_path),
+        Method::Patch => append_file(&file_path, &mut request),
+        _ => get_file(&state, &file_path, &request),
+    };
+    if request.method != Method::Get {
+        state.preload.refresh(&file_path);
+    }
+    response
+}
+
+// the mount the request's path is under
+pub(crate) fn request_mount<'a>(
+    config: &'a Config,
+    request: &Request,
+) -> Result<&'a Mount, ServerError> {
+    config
+        .mount_for(&request.path)
+        .ok_or_else(|| ServerError::new(StatusCode::NOT_FOUND, "no directory is mounted there"))
+}
+
+// 403 for dotfiles and extensions the mount doesn't serve, anywhere in the path; `is_dir` tells
+// whether the last component is a directory
+pub(crate) fn check_allowed(
+    mount: &Mount,
+    relative: &Path,
+    is_dir: bool,
+) -> Result<(), ServerError> {
+    let mut names = relative.iter().peekable();
+    while let Some(name) = names.next() {
+        let is_last = names.peek().is_none();
+        if !mount.allows(&name.to_string_lossy(), is_dir || !is_last) {
+            return Err(ServerError::new(
+                StatusCode::FORBIDDEN,
+                "files of that kind aren't served here",
+            ));
+        }
+    }
+    Ok(())
+}
+
+// refused on read-only mounts, and to clients without a certificate when --tls-client-ca makes
+// writes need one
+pub(crate) fn check_writable(
+    state: &State,
+    mount: &Mount,
+    request: &Request,
+) -> Result<(), ServerError> {
+    if mount.read_only {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "the files there are read-only",
+        ));
+    }
+    if request.client.is_none() && state.writes_need_client_cert() {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "a client certificate is needed to change files",
+        ));
+    }
+    Ok(())
+}
+
+fn get_file(state: &State, path: &Path, request: &Request) -> Result<Response, ServerError> {
+    let config = &state.config;
+    let preloaded = state.preload.get(path).is_some();
+    if !preloaded && !path.is_file() {
+        return Ok(Response::new(StatusCode::NOT_FOUND));
+    }
+    if let Some((_, algorithm)) = request
+        .query_pairs()
+        .into_iter()
+        .find(|(key, _)| key == "checksum")
+    {
+        return checksum(state, path, &algorithm);
+    }
+    // precompressed copies are smaller than the file, so they're preloaded too if it is
+    let variants: Vec<_> = PRECOMPRESSED
+        .iter()
+        .filter_map(|(coding, extension)| {
+            let variant = match preloaded {
+                true => Some(variant_path(path, extension))
+                    .filter(|variant| state.preload.get(variant).is_some()),
+                false => precompressed(path, extension),
+            };
+            Some((*coding, variant?))
+        })
+        .collect();
+    let codings: Vec<_> = variants.iter().map(|(coding, _)| *coding).collect();
+    let coding = accept::negotiate_encoding(request.headers.get(ACCEPT_ENCODING), &codings);
+    let served = variants
+        .iter()
+        .find(|(known, _)| Some(*known) == coding)
+        .map_or(path, |(_, variant)| variant.as_path());
+
+    let (body, etag, modified) = match state.preload.get(served) {
+        Some(file) => (
+            Body::Bytes(file.bytes.clone()),
+            file.etag.clone(),
+            file.modified,
+        ),
+        None => {
+            let file = File::open(served)?;
+            let metadata = file.metadata()?;
+            let etag = file_etag(&metadata);
+            (
+                Body::File(file, metadata.len()),
+                etag,
+                modified_secs(&metadata),
+            )
+        }
+    };
+    let mut response = Response::new(StatusCode::OK).with_header(ETAG, &etag);
+    response.body = body;
+    let mut response =
+        response.with_content_type_and_current_length(mime::content_type(path, &config.mime_types));
+    if let Some(coding) = coding {
+        response.headers.insert(CONTENT_ENCODING, coding);
+    }
+    // caches must keep the variants apart
+    if !variants.is_empty() {
+        response.headers.insert(VARY, ACCEPT_ENCODING);
+    }
+    if let Some(modified) = modified {
+        response
+            .headers
+            .insert(LAST_MODIFIED, &format_http_date(modified));
+    }
+    if let Some(value) = cache_control(&config.cache_control, path, &request.path) {
+        response.headers.insert(CACHE_CONTROL, value);
+    }
+    if is_download(config, path, request) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        response
+            .headers
+            .insert(CONTENT_DISPOSITION, &content_disposition(&name));
+    }
+    Ok(response)
+}
+
+// the file's hash in the format of `sha256sum`, so a download can be checked with
+// `sha256sum -c`; files are hashed as they're read rather than loaded whole
+fn checksum(state: &State, path: &Path, algorithm: &str) -> Result<Response, ServerError> {
+    if !algorithm.eq_ignore_ascii_case("sha256") {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "the only checksum supported is sha256",
+        ));
+    }
+    let mut hasher = Sha256::new();
+    match state.preload.get(path) {
+        Some(file) => hasher.update(&file.bytes),
+        None => {
+            io::copy(&mut File::open(path)?, &mut hasher)?;
+        }
+    }
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let body = format!("{}  {}\n", sha256::hex(&hasher.finish()), name);
+    Ok(Response::new(StatusCode::OK)
+        .with_body(&body)
+        .with_content_type_and_current_length("text/plain; charset=utf-8"))
+}
+
+// content codings with the extension of their precompressed files, in order of preference
+const PRECOMPRESSED: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+fn variant_path(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+// the compressed copy of the file with the extension, if there is one; symlinks aren't taken as
+// they weren't checked by resolve_path
+fn precompressed(path: &Path, extension: &str) -> Option<PathBuf> {
+    let variant = variant_path(path, extension);
+    let metadata = fs::symlink_metadata(&variant).ok()?;
+    metadata.is_file().then_some(variant)
+}
+
+// the value of the first rule matching the file
+fn cache_control<'a>(
+    rules: &'a [(String, String)],
+    path: &Path,
+    url_path: &str,
+) -> Option<&'a str> {
+    rules
+        .iter()
+        .find(|(pattern, _)| matches_file(pattern, path, url_path))
+        .map(|(_, value)| value.as_str())
+}
+
+// "*.ext" matches by extension, anything else as a prefix of the URL path
+fn matches_file(pattern: &str, path: &Path, url_path: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(wanted) => path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| extension.eq_ignore_ascii_case(wanted)),
+        None => url_path.starts_with(pattern),
+    }
+}
+
+// asked for with "?download", or configured for the file with --download
+fn is_download(config: &Config, path: &Path, request: &Request) -> bool {
+    query_flag(request, "download")
+        || config
+            .downloads
+            .iter()
+            .any(|pattern| matches_file(pattern, path, &request.path))
+}
+
+// "?name" or "?name=1" but not "?name=0" or "?name=false"
+fn query_flag(request: &Request, name: &str) -> bool {
+    request
+        .query_pairs()
+        .iter()
+        .any(|(key, value)| key == name && value != "0" && value != "false")
+}
+
+// "attachment" with the name as a quoted ASCII fallback and as RFC 5987 UTF-8 for clients that
+// understand it
+fn content_disposition(name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for b in name.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+// a strong validator from the modification time and size, so it changes whenever the file is
+// rewritten
+pub(crate) fn file_etag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", modified.as_nanos(), metadata.len())
+}
+
+pub(crate) fn modified_secs(metadata: &Metadata) -> Option<u64> {
+    let modified = metadata.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+// whether the If-Match or else If-Unmodified-Since precondition of a write holds for the file as
+// it is now, so a client doesn't overwrite changes it hasn't seen (RFC 9110 section 13.1)
+fn preconditions_hold(request: &Request, path: &Path) -> bool {
+    let metadata = fs::metadata(path).ok().filter(Metadata::is_file);
+    let mut if_match = request
+        .headers
+        .get_all(IF_MATCH)
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .peekable();
+    if if_match.peek().is_some() {
+        let Some(metadata) = &metadata else {
+            return false;
+        };
+        let etag = file_etag(metadata);
+        return if_match.any(|tag| tag == "*" || tag == etag);
+    }
+
+    // a missing file or an unparseable date leaves nothing to compare
+    let since = request
+        .headers
+        .get(IF_UNMODIFIED_SINCE)
+        .and_then(parse_http_date);
+    match (since, metadata.as_ref().and_then(modified_secs)) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => true,
+    }
+}
+
+// the file in the mount's directory that the relative path names, or 400 for ".." components and
+// 403 when it leads outside the directory through a symlink, or through any symlink unless
+// they're followed; parts that don't exist yet are kept as given so writes can create them
+pub(crate) fn resolve_path(
+    mount: &Mount,
+    config: &Config,
+    relative: &Path,
+) -> Result<PathBuf, ServerError> {
+    let plain =
+        |component: &Component| matches!(component, Component::Normal(_) | Component::CurDir);
+    if !relative.components().all(|component| plain(&component)) {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "file paths can't contain \"..\"",
+        ));
+    }
+
+    let root = Path::new(&mount.directory).canonicalize()?;
+    let mut resolved = root.clone();
+    let mut missing = PathBuf::new();
+    for component in relative.components() {
+        if !missing.as_os_str().is_empty() {
+            missing.push(component);
+            continue;
+        }
+        let next = resolved.join(component);
+        let Ok(metadata) = next.symlink_metadata() else {
+            missing.push(component);
+            continue;
+        };
+        if metadata.file_type().is_symlink() && !config.follow_symlinks {
+            return Err(ServerError::new(
+                StatusCode::FORBIDDEN,
+                "symlinks aren't followed",
+            ));
+        }
+        // a broken symlink would let a write create its target wherever it points
+        resolved = next.canonicalize().map_err(|_| {
+            ServerError::new(StatusCode::FORBIDDEN, "the symlink's target doesn't exist")
+        })?;
+    }
+
+    if !resolved.starts_with(&root) {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "the path leads outside the served directory",
+        ));
+    }
+    // joining an empty path would add a trailing slash
+    if !missing.as_os_str().is_empty() {
+        resolved.push(missing);
+    }
+    Ok(resolved)
+}
+
+// the first index file found in the directory, or else a listing if enabled: HTML, or JSON for
+// clients that prefer it, which also skip the index file
+fn serve_directory(
+    state: &State,
+    mount: &Mount,
+    relative: &Path,
+    request: &Request,
+) -> Result<Response, ServerError> {
+    // relative links in the listing and index page need the trailing slash
+    if !request.path.ends_with('/') {
+        let mut location = percent_encode_path(&format!("{}/", request.path));
+        if let Some(query) = request.query() {
+            location = format!("{}?{}", location, query);
+        }
+        return Ok(Response::new(StatusCode::MOVED_PERMANENTLY).with_header(LOCATION, &location));
+    }
+
+    let config = &state.config;
+    if let Some((_, format)) = request
+        .query_pairs()
+        .into_iter()
+        .find(|(key, _)| key == "format")
+    {
+        return serve_archive(state, mount, relative, &format);
+    }
+    let format = accept::negotiate(request.headers.get(ACCEPT), &[TEXT_HTML, APPLICATION_JSON]);
+    if format != Some(APPLICATION_JSON) {
+        if let Some(index) = find_index(mount, config, relative)? {
+            return get_file(state, &index, request);
+        }
+    }
+    if !config.directory_listing {
+        return Ok(Response::new(StatusCode::NOT_FOUND));
+    }
+    let Some(format) = format else {
+        return Ok(Response::new(StatusCode::NOT_ACCEPTABLE).with_header(VARY, ACCEPT));
+    };
+
+    let path = resolve_path(mount, config, relative)?;
+    let visible = |entry: &listing::Entry| mount.allows(&entry.name, entry.is_dir);
+    let (body, content_type) = match format {
+        APPLICATION_JSON => (listing::json(&path, visible)?, APPLICATION_JSON),
+        _ => {
+            let is_root = relative.as_os_str().is_empty();
+            let html = listing::html(&path, &request.path, is_root, visible)?;
+            (html, "text/html; charset=utf-8")
+        }
+    };
+    Ok(Response::new(StatusCode::OK)
+        .with_body(&body)
+        .with_content_type_and_current_length(content_type)
+        .with_header(VARY, ACCEPT))
+}
+
+// the directory's contents as a zip or tar.gz, streamed as it's written; archives are a kind of
+// listing, so they're off without one
+fn serve_archive(
+    state: &State,
+    mount: &Mount,
+    relative: &Path,
+    format: &str,
+) -> Result<Response, ServerError> {
+    let Some(format) = archive::Format::parse(format) else {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "archives are either zip or tar.gz",
+        ));
+    };
+    if !state.config.directory_listing {
+        return Ok(Response::new(StatusCode::NOT_FOUND));
+    }
+    let path = resolve_path(mount, &state.config, relative)?;
+    let name = match relative.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => mount.name.clone(),
+    };
+    let name = format!("{}.{}", name, format.extension());
+    Ok(Response::new(StatusCode::OK)
+        .with_stream(archive::stream(path, mount.clone(), format))
+        .with_content_type_and_current_length(format.content_type())
+        .with_header(CONTENT_DISPOSITION, &content_disposition(&name)))
+}
+
+// the first of the index files that exists in the directory
+fn find_index(
+    mount: &Mount,
+    config: &Config,
+    relative: &Path,
+) -> Result<Option<PathBuf>, ServerError> {
+    for name in &config.index_files {
+        let index = resolve_path(mount, config, &relative.join(name))?;
+        if index.is_file() {
+            return Ok(Some(index));
+        }
+    }
+    Ok(None)
+}
+
+fn post_file(path: &Path, body: &mut dyn Read) -> Result<Response, ServerError> {
+    if path.exists() {
+        return Ok(Response::new(StatusCode::CONFLICT));
+    }
+    if !path.parent().is_some_and(Path::is_dir) {
+        return Err(ServerError::new(
+            StatusCode::CONFLICT,
+            "the parent directory doesn't exist",
+        ));
+    }
+    Upload::write(path, body)?.create(path)
+}
+
+// creates or replaces the file, answering 201 or 204 respectively
+fn put_file(path: &Path, body: &mut dyn Read) -> Result<Response, ServerError> {
+    check_replaceable(path)?;
+    let upload = Upload::write(path, body)?;
+    let existed = path.exists();
+    upload.persist(path)?;
+    match existed {
+        true => Ok(Response::new(StatusCode::NO_CONTENT)),
+        false => Ok(Response::new(StatusCode::CREATED)),
+    }
+}
+
+fn check_replaceable(path: &Path) -> Result<(), ServerError> {
+    if path.is_dir() {
+        return Err(ServerError::new(
+            StatusCode::CONFLICT,
+            "a directory can't be replaced with a file",
+        ));
+    }
+    if !path.parent().is_some_and(Path::is_dir) {
+        return Err(ServerError::new(
+            StatusCode::CONFLICT,
+            "the parent directory doesn't exist",
+        ));
+    }
+    Ok(())
+}
+
+// "?action=move&to=/files/new/name" renames the file or directory and "?action=copy" copies it,
+// within the same mount and without replacing anything at the destination
+fn file_action(
+    state: &State,
+    mount: &Mount,
+    source: &Path,
+    request: &Request,
+) -> Result<Response, ServerError> {
+    let query = request.query_pairs();
+    let value = |name: &str| {
+        query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let moves = match value("action") {
+        Some("move") => true,
+        Some("copy") => false,
+        _ => {
+            return Err(ServerError::new(
+                StatusCode::BAD_REQUEST,
+                "the action is either move or copy",
+            ))
+        }
+    };
+    let Some(to) = value("to") else {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "the destination is given with ?to=",
+        ));
+    };
+    let Some(relative) = to.strip_prefix(&format!("/{}/", mount.name)) else {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "files are only moved or copied within their mount",
+        ));
+    };
+    dav::transfer(
+        state,
+        mount,
+        source,
+        relative,
+        moves,
+        false,
+        Some(StatusCode::CONFLICT),
+    )
+}
+
+// PATCH only appends, asked for with ?append or "Content-Range: bytes */*"; the body is staged
+// like an upload first so a slow client doesn't hold the file's lock, which keeps concurrent
+// appends from interleaving
+fn append_file(path: &Path, request: &mut Request) -> Result<Response, ServerError> {
+    let appends = query_flag(request, "append")
+        || request
+            .headers
+            .get(CONTENT_RANGE)
+            .is_some_and(|range| range.trim() == "bytes */*");
+    if !appends {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "PATCH appends with ?append or \"Content-Range: bytes */*\"",
+        ));
+    }
+    if !path.is_file() {
+        return Ok(Response::new(StatusCode::NOT_FOUND));
+    }
+    let upload = Upload::write(path, &mut request.body_reader())?;
+    let mut file = File::options().append(true).open(path)?;
+    file.lock()?;
+    // a failed append is cut off again rather than left half written
+    let len = file.metadata()?.len();
+    if let Err(err) = io::copy(&mut File::open(&upload.path)?, &mut file) {
+        file.set_len(len)?;
+        return Err(err.into());
+    }
+    file.sync_all()?;
+    Ok(Response::new(StatusCode::NO_CONTENT))
+}
+
+// a piece of a resumable upload, "Content-Range: bytes <first>-<last>/<total>", appended to a
+// hidden partial file that replaces the file once all of it is there; until then the answer is
+// 202 with "Range: bytes=0-<last stored>", which is also what "bytes */<total>" asks for
+fn put_range(path: &Path, request: &mut Request) -> Result<Response, ServerError> {
+    let Some((range, total)) = request
+        .headers
+        .get(CONTENT_RANGE)
+        .and_then(parse_content_range)
+    else {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "Content-Range must be \"bytes <first>-<last>/<total>\" or \"bytes */<total>\"",
+        ));
+    };
+    check_replaceable(path)?;
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(PARTIAL_SUFFIX);
+    let partial = path.with_file_name(name);
+    let stored = fs::metadata(&partial).map_or(0, |metadata| metadata.len());
+    let Some((first, last)) = range else {
+        return Ok(upload_progress(stored));
+    };
+    if last < first || last >= total {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "the range doesn't fit the total size",
+        ));
+    }
+    // pieces go in order, but starting over from 0 is always possible
+    if first != stored && first != 0 {
+        let mut response = upload_progress(stored);
+        response.status = StatusCode::RANGE_NOT_SATISFIABLE;
+        return Ok(response);
+    }
+
+    // what's stored is kept, up to where this piece starts
+    let mut file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&partial)?;
+    file.set_len(first)?;
+    file.seek(SeekFrom::Start(first))?;
+    let written = io::copy(&mut request.body_reader(), &mut file)?;
+    if written != last - first + 1 {
+        file.set_len(first)?;
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "the body's length doesn't match Content-Range",
+        ));
+    }
+    if last + 1 < total {
+        return Ok(upload_progress(last + 1));
+    }
+    file.sync_all()?;
+    let existed = path.exists();
+    fs::rename(&partial, path)?;
+    match existed {
+        true => Ok(Response::new(StatusCode::NO_CONTENT)),
+        false => Ok(Response::new(StatusCode::CREATED)),
+    }
+}
+
+// sweeps the writable mounts every PARTIAL_UPLOAD_SWEEP_INTERVAL for as long as the state is in
+// use; it visits every directory, so it's kept off the request path
+pub(crate) fn watch_partial_uploads(state: &Arc<State>) {
+    if state.config.read_only {
+        return;
+    }
+    let state = Arc::downgrade(state);
+    thread::spawn(move || loop {
+        thread::sleep(PARTIAL_UPLOAD_SWEEP_INTERVAL);
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let max_age = state.config.partial_upload_max_age;
+        for mount in state.config.mounts.iter().filter(|mount| !mount.read_only) {
+            expire_partial_uploads(Path::new(&mount.directory), max_age);
+        }
+    });
+}
+
+// deletes the partial files of resumable uploads in the directory or below it that nothing was
+// added to for longer than max_age, so abandoned uploads don't fill the disk; symlinks aren't
+// followed
+fn expire_partial_uploads(directory: &Path, max_age: Duration) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            expire_partial_uploads(&entry.path(), max_age);
+            continue;
+        }
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with('.') || !name.ends_with(PARTIAL_SUFFIX) {
+            continue;
+        }
+        let age = metadata
+            .modified()
+            .map(|modified| modified.elapsed().unwrap_or_default());
+        if age.is_ok_and(|age| age > max_age) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+// "bytes <first>-<last>/<total>", or no range for "bytes */<total>"
+fn parse_content_range(value: &str) -> Option<(Option<(u64, u64)>, u64)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let total = total.parse().ok()?;
+    if range == "*" {
+        return Some((None, total));
+    }
+    let (first, last) = range.split_once('-')?;
+    Some((Some((first.parse().ok()?, last.parse().ok()?)), total))
+}
+
+fn upload_progress(stored: u64) -> Response {
+    let response = Response::new(StatusCode::ACCEPTED);
+    match stored {
+        0 => response,
+        _ => response.with_header(RANGE, &format!("bytes=0-{}", stored - 1)),
+    }
+}
+
+// a body written to a hidden file next to its destination, which only gets the name once it's
+// complete so a failed upload never leaves a truncated file behind; removed when dropped
+struct Upload {
+    path: PathBuf,
+}
+
+impl Upload {
+    fn write(destination: &Path, body: &mut dyn Read) -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let name = destination
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let temp_name = format!(".{}.{}-{}.upload", name, std::process::id(), count);
+        let upload = Self {
+            path: destination.with_file_name(temp_name),
+        };
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&upload.path)?;
+        io::copy(body, &mut file)?;
+        file.sync_all()?;
+        Ok(upload)
+    }
+
+    // replaces whatever is at the destination
+    fn persist(self, destination: &Path) -> io::Result<()> {
+        fs::rename(&self.path, destination)
+    }
+
+    // answers 201, or 409 if the destination exists; linking fails if the file was created
+    // meanwhile, where a rename would replace it
+    fn create(self, destination: &Path) -> Result<Response, ServerError> {
+        match fs::hard_link(&self.path, destination) {
+            Ok(()) => Ok(Response::new(StatusCode::CREATED)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Ok(Response::new(StatusCode::CONFLICT))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// saves every file of a multipart/form-data upload, e.g. from an HTML form
+fn upload_files(
+    mount: &Mount,
+    directory: &Path,
+    request: &mut Request,
+) -> Result<Response, ServerError> {
+    let Some(boundary) = request
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(multipart::boundary)
+    else {
+        return Ok(Response::new(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    };
+    // each file is streamed to an upload of its own, and they're only given their names once
+    // the whole body turned out fine
+    let mut parts = multipart::Reader::new(request.body_reader(), &boundary);
+    let mut uploads = Vec::new();
+    loop {
+        let part = match parts.next_part() {
+            Ok(Some(part)) => part,
+            Ok(None) => break,
+            Err(_) => return Ok(Response::new(StatusCode::BAD_REQUEST)),
+        };
+        let Some(filename) = part.filename else {
+            continue;
+        };
+        if filename.is_empty() || filename.starts_with("..") || filename.contains(['/', '\\']) {
+            return Ok(Response::new(StatusCode::BAD_REQUEST));
+        }
+        if !mount.allows(&filename, false) {
+            return Ok(Response::new(StatusCode::FORBIDDEN));
+        }
+        let path = directory.join(&filename);
+        if path.exists() {
+            return Ok(Response::new(StatusCode::CONFLICT));
+        }
+        match Upload::write(&path, &mut parts) {
+            Ok(upload) => uploads.push((upload, path)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(Response::new(StatusCode::BAD_REQUEST))
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if uploads.is_empty() {
+        return Ok(Response::new(StatusCode::BAD_REQUEST));
+    }
+
+    for (upload, path) in uploads {
+        let response = upload.create(&path)?;
+        if response.status != StatusCode::CREATED {
+            return Ok(response);
+        }
+    }
+    Ok(Response::new(StatusCode::CREATED))
+}
+
+fn delete_file(path: &PathBuf) -> Result<Response, ServerError> {
+    if !path.exists() {
+        return Ok(Response::new(StatusCode::NOT_FOUND));
+    }
+    if path.is_dir() {
+        if fs::read_dir(path)?.next().is_some() {
+            return Err(ServerError::new(
+                StatusCode::CONFLICT,
+                "only empty directories can be deleted",
+            ));
+        }
+        fs::remove_dir(path)?;
+        return Ok(Response::new(StatusCode::OK));
+    }
+    fs::remove_file(path)?;
+    Ok(Response::new(StatusCode::OK))
+}
+
+// the routes the binary serves: a few demo endpoints and the files of each mount
+pub fn app_router(config: &Config) -> Router {
+    let router = config.mounts.iter().fold(demo_routes(), |router, mount| {
+        let files = match config.write_tokens.is_empty() {
+            true => file_routes(config.read_only),
+            false => file_routes(config.read_only).layer(write_auth(config)),
+        };
+        router.nest(&format!("/{}", mount.name), files)
+    });
+    let router = match config.metrics {
+        true => router.get("/metrics", metrics_handler),
+        false => router,
+    };
+    let router = match config.admin_token {
+        Some(_) => router.get("/_stats", stats_handler),
+        None => router,
+    };
+    let router = router
+        .get("/healthz", healthz_handler)
+        .get("/readyz", readyz_handler)
+        .fallback(not_found_handler)
+        .trailing_slash(TrailingSlash::Redirect);
+    // inside the authentication, so the upstream only sees requests that passed it
+    let router = config
+        .proxies
+        .iter()
+        .fold(router, |router, proxy| router.layer(proxy.clone()));
+    let router = match config.connect_allowed.is_empty() {
+        true => router,
+        false => router.layer(Tunnel::new(config.connect_allowed.clone())),
+    };
+    let router = match config.basic_auth_users.is_empty() && config.basic_auth_file.is_none() {
+        true => router,
+        false => router.layer(basic_auth(config)),
+    };
+    let router = match config.csrf {
+        true => router.layer(csrf(config)),
+        false => router,
+    };
+    // outside the authentication, as browsers send preflights without credentials
+    let router = match config.cors {
+        true => router.layer(
+            config
+                .cors_origins
+                .iter()
+                .fold(Cors::new(), |cors, origin| cors.allow_origin(origin))
+                .expose_headers(&[X_REQUEST_ID]),
+        ),
+        false => router,
+    };
+    let router = match config.security_headers {
+        true => router.layer(security_headers(config)),
+        false => router,
+    };
+    router.layer(request_id)
+}
+
+// over HTTPS the cookie is never sent in the clear
+fn csrf(config: &Config) -> Csrf {
+    #[cfg(feature = "tls")]
+    if config.tls_cert.is_some() {
+        return Csrf::new().secure();
+    }
+    #[cfg(not(feature = "tls"))]
+    let _ = config;
+    Csrf::new()
+}
+
+fn security_headers(config: &Config) -> SecurityHeaders {
+    match &config.content_security_policy {
+        Some(policy) => SecurityHeaders::new().content_security_policy(policy),
+        None => SecurityHeaders::new(),
+    }
+}
+
+fn basic_auth(config: &Config) -> BasicAuth {
+    let auth = config.basic_auth_users.iter().fold(
+        BasicAuth::new("http-server-rust"),
+        |auth, (name, password)| auth.user(name, password),
+    );
+    let auth = match &config.basic_auth_file {
+        Some(path) => auth.htpasswd(path),
+        None => auth,
+    };
+    config
+        .basic_auth_paths
+        .iter()
+        .fold(auth, |auth, prefix| auth.protect(prefix))
+}
+
+fn write_auth(config: &Config) -> BearerAuth {
+    config
+        .write_tokens
+        .iter()
+        .fold(BearerAuth::new(), |auth, token| auth.token(token))
+        .writes_only()
+}
+
+fn demo_routes() -> Router {
+    Router::new()
+        .get("/", root_handler)
+        .get("/user-agent", user_agent_handler)
+        .get("/ip", ip_handler)
+        .get("/ws", websocket_echo_handler)
+        .group("/echo", |echo| {
+            echo.get("", echo_handler)
+                .post("", echo_handler)
+                .get("/*text", echo_handler)
+        })
+}
+
+// without the methods that change files when read-only, so the router answers them 405 with only
+// the remaining ones in Allow (or 501 for WebDAV's, which then aren't routed anywhere)
+fn file_routes(read_only: bool) -> Router {
+    if read_only {
+        return dav::routes(Router::new().get("/*name", file_handler), true);
+    }
+    let router = Router::new()
+        .post("/", file_handler)
+        .get("/*name", file_handler)
+        .post("/*name", file_handler)
+        .put("/*name", file_handler)
+        .delete("/*name", file_handler)
+        .patch("/*name", file_handler);
+    dav::routes(router, false)
+}
+
+fn not_found_handler() -> Response {
+    Response::new(StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::form;
+    use crate::http::{
+        decode_path, parse_to_request, write_response, Body, ParserConfig, ALLOW, AUTHORIZATION,
+        CONTENT_LENGTH, X_REQUEST_ID,
+    };
+    use crate::server::{check_config, handle_request, parse_args};
+    use crate::test_util::{args_state, files_state, get, send, TempDir};
+    use std::time::SystemTime;
+
+    fn dispatch(request: Request) -> Response {
+        handle_request(Arc::new(State::new(Config::default())), request)
+    }
+
+    fn body_to_string(body: Body) -> String {
+        let mut buf = Vec::new();
+        body.write_to(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_root() {
+        let res = dispatch(Request::new(Method::Get, "/"));
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "Hello World");
+        assert!(res.headers.get(X_REQUEST_ID).is_some());
+
+        let req = Request::new(Method::Post, "/");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers.get(ALLOW), Some("GET, HEAD"));
+    }
+
+    #[test]
+    fn test_echo() {
+        let req = Request::new(Method::Get, "/echo");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "");
+
+        let req = Request::new(Method::Get, "/echo/abc");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "abc");
+
+        let req = Request::new(Method::Post, "/echo");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "");
+
+        let req = Request::new(Method::Post, "/echo").with_body("abc");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "abc");
+
+        let req = Request::new(Method::Post, "/echo")
+            .with_header(CONTENT_TYPE, form::APPLICATION_FORM_URLENCODED)
+            .with_body("text=hello+world&n=1");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "text=hello world\nn=1\n");
+
+        let req = Request::new(Method::Post, "/echo/abc");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers.get(ALLOW), Some("GET, HEAD"));
+
+        let req = Request::new(Method::Put, "/echo");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers.get(ALLOW), Some("GET, HEAD, POST"));
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let req = Request::new(Method::Get, "/user-agent");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+        let header_val = "curl/7.64.1";
+        let req = Request::new(Method::Get, "/user-agent").with_header(USER_AGENT, header_val);
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, header_val);
+
+        let req = Request::new(Method::Post, "/user-agent");
+        let res = dispatch(req);
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_query() {
+        let req = Request::new(Method::Get, "/echo/hi?upper=true&name=a%20b+c&flag");
+        assert_eq!(req.path, "/echo/hi");
+        assert_eq!(req.query(), Some("upper=true&name=a%20b+c&flag"));
+        assert_eq!(
+            req.query_pairs(),
+            vec![
+                ("upper".to_owned(), "true".to_owned()),
+                ("name".to_owned(), "a b c".to_owned()),
+                ("flag".to_owned(), "".to_owned()),
+            ]
+        );
+
+        let res = dispatch(req);
+        assert_eq!(res.body, "hi");
+
+        let req = Request::new(Method::Get, "/echo/hi");
+        assert_eq!(req.query(), None);
+        assert!(req.query_pairs().is_empty());
+    }
+
+    #[test]
+    fn test_percent_decoding() {
+        let mut raw = "GET /echo/hello%20world HTTP/1.1\r\nHost: localhost\r\n\r\n".as_bytes();
+        let req = parse_to_request(&mut raw, &ParserConfig::default()).unwrap();
+        assert_eq!(req.path, "/echo/hello world");
+        let res = dispatch(req);
+        assert_eq!(res.body, "hello world");
+
+        assert_eq!(
+            decode_path("/files/my%20file.txt"),
+            Some("/files/my file.txt".to_owned())
+        );
+        assert_eq!(decode_path("/files/%2e%2e/Cargo.toml"), None);
+        assert_eq!(decode_path("/files/..%2fCargo.toml"), None);
+        assert_eq!(decode_path("/files/a%00"), None);
+        assert_eq!(decode_path("/files/%zz"), None);
+    }
+
+    #[test]
+    fn test_router() {
+        let state = Arc::new(State::new(Config::default()));
+        let status = |method: Method, path: &str| {
+            handle_request(state.clone(), Request::new(method, path)).status
+        };
+
+        assert_eq!(status(Method::Get, "/"), StatusCode::OK);
+        assert_eq!(status(Method::Get, "/echo"), StatusCode::OK);
+        assert_eq!(status(Method::Get, "/echo/"), StatusCode::OK);
+        assert_eq!(status(Method::Get, "/echo/a/b"), StatusCode::OK);
+        assert_eq!(status(Method::Post, "/echo"), StatusCode::OK);
+        assert_eq!(
+            status(Method::Post, "/echo/a"),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(status(Method::Put, "/"), StatusCode::METHOD_NOT_ALLOWED);
+        let res = handle_request(state.clone(), Request::new(Method::Put, "/echo"));
+        assert_eq!(res.headers.get(ALLOW), Some("GET, HEAD, POST"));
+        assert_eq!(status(Method::Get, "/files"), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(status(Method::Get, "/nope"), StatusCode::NOT_FOUND);
+        assert_eq!(status(Method::Get, "/user-agent/x"), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = TempDir::new("files");
+        let state = files_state(&dir, |config| config.directory_listing = true);
+
+        let req = Request::new(Method::Post, "/files/test.txt").with_body("test!");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::CREATED);
+
+        let req = Request::new(Method::Get, "/files/test.txt");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers.get(CONTENT_LENGTH), Some("5"));
+        assert_eq!(
+            res.headers.get(CONTENT_TYPE),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(body_to_string(res.body), "test!");
+
+        let req = Request::new(Method::Post, "/files/test.txt").with_body("test!");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::CONFLICT);
+
+        let req = Request::new(Method::Put, "/files/test.txt").with_body("new");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::NO_CONTENT);
+        let res = handle_request(state.clone(), Request::new(Method::Get, "/files/test.txt"));
+        assert_eq!(body_to_string(res.body), "new");
+
+        let req = Request::new(Method::Delete, "/files/test.txt");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::OK);
+
+        let req = Request::new(Method::Get, "/files/test.txt");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+        let req = Request::new(Method::Get, "/files/..");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+        let req = Request::new(Method::Get, "/files/../Cargo.toml");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+
+        let req = Request::new(Method::Get, "/files/test/hello.txt");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+        let res = handle_request(state.clone(), Request::new(Method::Get, "/files/"));
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(body_to_string(res.body).contains("Index of /files/"));
+        let state = Arc::new(State::new(Config {
+            mounts: state.config.mounts.clone(),
+            directory_listing: false,
+            ..Config::default()
+        }));
+        let res = handle_request(state, Request::new(Method::Get, "/files/"));
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_large_binary_file() {
+        let dir = TempDir::new("large");
+        // every byte value, so it isn't UTF-8, over several of the chunks it's copied in
+        let contents: Vec<u8> = (0..200_000).map(|i| (i % 256) as u8).collect();
+        std::fs::write(dir.join("big.bin"), &contents).unwrap();
+        let state = files_state(&dir, |_| {});
+
+        let res = get(&state, "/files/big.bin");
+        assert_eq!(res.status, StatusCode::OK);
+        assert!(matches!(res.body, Body::File(_, 200_000)));
+        let mut written = Vec::new();
+        assert_eq!(write_response(res, &mut written).unwrap(), 200_000);
+        let head_end = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8_lossy(&written[..head_end]);
+        assert!(head.contains("Content-Length: 200000\r\n"), "{}", head);
+        assert!(!head.contains("Transfer-Encoding"), "{}", head);
+        assert!(written[head_end..] == contents[..]);
+    }
+
+    #[test]
+    fn test_subdirectories() {
+        let dir = TempDir::new("subdirs");
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        let state = files_state(&dir, |config| config.directory_listing = true);
+
+        let req = Request::new(Method::Post, "/files/docs/readme.txt").with_body("read me");
+        assert_eq!(send(&state, req).status, StatusCode::CREATED);
+        let res = send(&state, Request::new(Method::Get, "/files/docs/readme.txt"));
+        assert_eq!(body_to_string(res.body), "read me");
+
+        let res = send(&state, Request::new(Method::Get, "/files/docs"));
+        assert_eq!(res.status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers.get(LOCATION), Some("/files/docs/"));
+        let listing = body_to_string(send(&state, Request::new(Method::Get, "/files/docs/")).body);
+        assert!(listing.contains("href=\"../\""));
+        assert!(listing.contains("href=\"readme.txt\""));
+
+        let body = "--b\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"up.txt\"\r\n\r\n\
+            up\r\n--b--\r\n";
+        let req = Request::new(Method::Post, "/files/docs/")
+            .with_header(CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .with_body(body);
+        assert_eq!(send(&state, req).status, StatusCode::CREATED);
+        assert_eq!(std::fs::read(dir.join("docs/up.txt")).unwrap(), b"up");
+
+        let req = Request::new(Method::Post, "/files/missing/a.txt").with_body("a");
+        assert_eq!(send(&state, req).status, StatusCode::CONFLICT);
+        let req = Request::new(Method::Put, "/files/docs/put.txt").with_body("put");
+        assert_eq!(send(&state, req).status, StatusCode::CREATED);
+        let req = Request::new(Method::Put, "/files/docs").with_body("x");
+        assert_eq!(send(&state, req).status, StatusCode::CONFLICT);
+        let req = Request::new(Method::Post, "/files/missing/");
+        assert_eq!(send(&state, req).status, StatusCode::NOT_FOUND);
+        let req = Request::new(Method::Delete, "/files/docs");
+        assert_eq!(send(&state, req).status, StatusCode::CONFLICT);
+        let req = Request::new(Method::Delete, "/files/docs/readme.txt");
+        assert_eq!(send(&state, req).status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let dir = TempDir::new("cache");
+        std::fs::create_dir_all(dir.join("assets")).unwrap();
+        std::fs::write(dir.join("index.html"), "<p>hi</p>").unwrap();
+        std::fs::write(dir.join("assets/app.1234.js"), "").unwrap();
+        std::fs::write(dir.join("assets/page.html"), "").unwrap();
+        std::fs::write(dir.join("data.json"), "{}").unwrap();
+        let args: Vec<String> = [
+            "--directory",
+            dir.to_str().unwrap(),
+            "--cache-control",
+            "*.html=no-cache",
+            "--cache-control",
+            "/files/assets/=public, max-age=31536000, immutable",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let state = Arc::new(State::new(parse_args(&args).unwrap()));
+        let cache_control = |path: &str| {
+            let res = handle_request(state.clone(), Request::new(Method::Get, path));
+            res.headers.get(CACHE_CONTROL).map(str::to_owned)
+        };
+
+        assert_eq!(cache_control("/files/").as_deref(), Some("no-cache"));
+        assert_eq!(
+            cache_control("/files/assets/app.1234.js").as_deref(),
+            Some("public, max-age=31536000, immutable")
+        );
+        assert_eq!(
+            cache_control("/files/assets/page.html").as_deref(),
+            Some("no-cache")
+        );
+        assert_eq!(cache_control("/files/data.json"), None);
+    }
+
+    #[test]
+    fn test_precompressed() {
+        let dir = TempDir::new("gz");
+        std::fs::write(dir.join("app.js"), "plain").unwrap();
+        std::fs::write(dir.join("app.js.gz"), "gzipped").unwrap();
+        std::fs::write(dir.join("app.js.br"), "brotli").unwrap();
+        std::fs::write(dir.join("page.html"), "page").unwrap();
+        let state = files_state(&dir, |_| {});
+        let get = |path: &str, accept_encoding: &str| {
+            let req = Request::new(Method::Get, path).with_header(ACCEPT_ENCODING, accept_encoding);
+            handle_request(state.clone(), req)
+        };
+
+        let res = get("/files/app.js", "gzip, br");
+        assert_eq!(res.headers.get(CONTENT_ENCODING), Some("br"));
+        assert_eq!(
+            res.headers.get(CONTENT_TYPE),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(res.headers.get(VARY), Some(ACCEPT_ENCODING));
+        assert_eq!(body_to_string(res.body), "brotli");
+        let res = get("/files/app.js", "gzip");
+        assert_eq!(res.headers.get(CONTENT_ENCODING), Some("gzip"));
+        assert_eq!(body_to_string(res.body), "gzipped");
+        let res = get("/files/app.js", "identity");
+        assert_eq!(res.headers.get(CONTENT_ENCODING), None);
+        assert_eq!(res.headers.get(VARY), Some(ACCEPT_ENCODING));
+        assert_eq!(body_to_string(res.body), "plain");
+
+        let res = get("/files/page.html", "gzip");
+        assert_eq!(res.headers.get(CONTENT_ENCODING), None);
+        assert_eq!(res.headers.get(VARY), None);
+    }
+
+    #[test]
+    fn test_downloads() {
+        assert_eq!(
+            content_disposition("report.pdf"),
+            "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            content_disposition("naïve \"x\".txt"),
+            "attachment; filename=\"na_ve _x_.txt\"; filename*=UTF-8''na%C3%AFve%20%22x%22.txt"
+        );
+
+        let dir = TempDir::new("dl");
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        std::fs::write(dir.join("b.zip"), "b").unwrap();
+        let state = files_state(&dir, |config| config.downloads = vec!["*.zip".to_owned()]);
+        let disposition = |target: &str| {
+            let res = handle_request(state.clone(), Request::new(Method::Get, target));
+            res.headers.get(CONTENT_DISPOSITION).map(str::to_owned)
+        };
+
+        assert_eq!(disposition("/files/a.txt"), None);
+        assert_eq!(disposition("/files/a.txt?download=0"), None);
+        assert!(disposition("/files/a.txt?download")
+            .unwrap()
+            .starts_with("attachment; filename=\"a.txt\""));
+        assert!(disposition("/files/b.zip").is_some());
+    }
+
+    #[test]
+    fn test_write_preconditions() {
+        let dir = TempDir::new("412");
+        let state = files_state(&dir, |_| {});
+        let put = |body: &str| Request::new(Method::Put, "/files/a.txt").with_body(body);
+
+        let req = put("one").with_header(IF_MATCH, "*");
+        assert_eq!(send(&state, req).status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(send(&state, put("one")).status, StatusCode::CREATED);
+        let res = send(&state, Request::new(Method::Get, "/files/a.txt"));
+        let etag = res.headers.get(ETAG).unwrap().to_owned();
+        let modified = res.headers.get(LAST_MODIFIED).unwrap().to_owned();
+
+        let req = put("two!").with_header(IF_MATCH, "\"stale\", \"other\"");
+        assert_eq!(send(&state, req).status, StatusCode::PRECONDITION_FAILED);
+        let req = put("two!").with_header(IF_MATCH, &format!("\"x\", {}", etag));
+        assert_eq!(send(&state, req).status, StatusCode::NO_CONTENT);
+        // the file changed since the first GET
+        let req = put("three").with_header(IF_MATCH, &etag);
+        assert_eq!(send(&state, req).status, StatusCode::PRECONDITION_FAILED);
+
+        let req = put("three").with_header(IF_UNMODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(send(&state, req).status, StatusCode::PRECONDITION_FAILED);
+        let req = Request::new(Method::Delete, "/files/a.txt")
+            .with_header(IF_UNMODIFIED_SINCE, "Fri, 01 Jan 2100 00:00:00 GMT");
+        assert_eq!(send(&state, req).status, StatusCode::OK);
+        assert!(parse_http_date(&modified).is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("links");
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("inside.txt"), "inside").unwrap();
+        std::fs::write(root.join("..dots"), "dots").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        symlink(root.join("inside.txt"), root.join("link")).unwrap();
+        symlink(dir.join("secret.txt"), root.join("escape")).unwrap();
+        symlink(dir.join("nowhere"), root.join("broken")).unwrap();
+        let state = |follow_symlinks| {
+            Arc::new(State::new(Config {
+                mounts: vec![Mount {
+                    dotfiles: true,
+                    ..Mount::new("files", root.to_str().unwrap())
+                }],
+                follow_symlinks,
+                ..Config::default()
+            }))
+        };
+        let get = |follow_symlinks, path: &str| {
+            handle_request(state(follow_symlinks), Request::new(Method::Get, path))
+        };
+
+        assert_eq!(body_to_string(get(true, "/files/link").body), "inside");
+        assert_eq!(body_to_string(get(true, "/files/..dots").body), "dots");
+        assert_eq!(get(true, "/files/escape").status, StatusCode::FORBIDDEN);
+        assert_eq!(get(false, "/files/link").status, StatusCode::FORBIDDEN);
+        assert_eq!(get(false, "/files/inside.txt").status, StatusCode::OK);
+
+        let req = Request::new(Method::Post, "/files/broken").with_body("x");
+        let res = handle_request(state(true), req);
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        assert!(!dir.join("nowhere").exists());
+    }
+
+    #[test]
+    fn test_index_files() {
+        let dir = TempDir::new("index");
+        std::fs::write(dir.join("index.html"), "<h1>home</h1>").unwrap();
+        std::fs::write(dir.join("index.htm"), "old").unwrap();
+        let state = |index_files: &[&str]| {
+            files_state(&dir, |config| {
+                config.index_files = index_files.iter().map(|name| name.to_string()).collect();
+                config.directory_listing = true;
+            })
+        };
+
+        let res = handle_request(state(&["index.html"]), Request::new(Method::Get, "/files/"));
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            res.headers.get(CONTENT_TYPE),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(body_to_string(res.body), "<h1>home</h1>");
+
+        // with --spa, unknown paths get the root index page
+        let res = handle_request(
+            state(&["index.html"]),
+            Request::new(Method::Get, "/files/a/b"),
+        );
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+        let spa = files_state(&dir, |config| config.spa = true);
+        let res = handle_request(spa.clone(), Request::new(Method::Get, "/files/a/b"));
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(body_to_string(res.body), "<h1>home</h1>");
+        let res = handle_request(spa.clone(), Request::new(Method::Get, "/files/index.htm"));
+        assert_eq!(body_to_string(res.body), "old");
+        let res = handle_request(spa, Request::new(Method::Delete, "/files/a/b"));
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+        let index_files = ["missing.html", "index.htm", "index.html"];
+        let res = handle_request(state(&index_files), Request::new(Method::Get, "/files/"));
+        assert_eq!(body_to_string(res.body), "old");
+
+        let res = handle_request(state(&[]), Request::new(Method::Get, "/files/"));
+        assert!(body_to_string(res.body).contains("Index of /files/"));
+    }
+
+    #[test]
+    fn test_upload_files() {
+        let dir = TempDir::new("upload-files");
+        let state = files_state(&dir, |_| {});
+
+        let body = "--b\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"upload.txt\"\r\n\r\n\
+            uploaded!\r\n--b--\r\n";
+        let req = Request::new(Method::Post, "/files/")
+            .with_header(CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .with_body(body);
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::CREATED);
+
+        let req = Request::new(Method::Post, "/files/")
+            .with_header(CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .with_body(body);
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::CONFLICT);
+
+        let req = Request::new(Method::Get, "/files/upload.txt");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(body_to_string(res.body), "uploaded!");
+
+        let req = Request::new(Method::Delete, "/files/upload.txt");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::OK);
+
+        let req = Request::new(Method::Post, "/files/").with_body(body);
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // a body cut off in a later part saves nothing, not even the parts before it
+        let cut = "--b\r\n\
+            Content-Disposition: form-data; name=\"a\"; filename=\"first.txt\"\r\n\r\n\
+            first\r\n--b\r\n\
+            Content-Disposition: form-data; name=\"b\"; filename=\"second.txt\"\r\n\r\n\
+            sec";
+        let req = Request::new(Method::Post, "/files/")
+            .with_header(CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .with_body(cut);
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_mounts() {
+        let dir = TempDir::new("mounts");
+        std::fs::create_dir_all(dir.join("assets")).unwrap();
+        std::fs::create_dir_all(dir.join("uploads")).unwrap();
+        std::fs::write(dir.join("assets/app.css"), "body {}").unwrap();
+        let mount =
+            |name: &str, suffix: &str| format!("{}={}{}", name, dir.join(name).display(), suffix);
+        let args: Vec<String> = vec![
+            "--mount".to_owned(),
+            mount("assets", ":ro"),
+            "--mount".to_owned(),
+            mount("uploads", ""),
+        ];
+        let config = parse_args(&args).unwrap();
+        assert_eq!(config.mounts.len(), 3);
+        assert!(config.parser.streams("/uploads/a.bin"));
+        let state = Arc::new(State::new(config));
+        let send = |method: Method, path: &str| {
+            handle_request(state.clone(), Request::new(method, path).with_body("data")).status
+        };
+
+        assert_eq!(send(Method::Get, "/assets/app.css"), StatusCode::OK);
+        assert_eq!(send(Method::Put, "/assets/app.css"), StatusCode::FORBIDDEN);
+        assert_eq!(
+            send(Method::Delete, "/assets/app.css"),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(send(Method::Post, "/uploads/a.txt"), StatusCode::CREATED);
+        assert_eq!(std::fs::read(dir.join("uploads/a.txt")).unwrap(), b"data");
+        assert_eq!(send(Method::Get, "/uploads/app.css"), StatusCode::NOT_FOUND);
+        assert_eq!(send(Method::Get, "/other/app.css"), StatusCode::NOT_FOUND);
+
+        let args = ["--mount".to_owned(), "a/b=.".to_owned()];
+        assert!(parse_args(&args).is_err());
+        assert!(parse_args(&["--mount".to_owned(), "assets".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_preload() {
+        let dir = TempDir::new("preload");
+        std::fs::create_dir_all(dir.join("css")).unwrap();
+        std::fs::write(dir.join("css/app.css"), "body {}").unwrap();
+        std::fs::write(dir.join("css/app.css.gz"), "gz").unwrap();
+        std::fs::write(dir.join("big.txt"), "more than sixteen bytes").unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let state = files_state(&dir, |config| config.preload_max_size = 16);
+        assert!(state.preload.get(&dir.join("css/app.css")).is_some());
+        assert!(state.preload.get(&dir.join("big.txt")).is_none());
+        let get = |path: &str| {
+            let res = handle_request(state.clone(), Request::new(Method::Get, path));
+            (
+                res.headers.get(ETAG).map(str::to_owned),
+                body_to_string(res.body),
+            )
+        };
+
+        // changes behind the server's back show up with the next rescan
+        let (etag, body) = get("/files/css/app.css");
+        assert_eq!(body, "body {}");
+        std::fs::write(dir.join("css/app.css"), "p {}").unwrap();
+        assert_eq!(get("/files/css/app.css"), (etag, "body {}".to_owned()));
+        state.preload.reload(&state.config.mounts);
+        assert_eq!(get("/files/css/app.css").1, "p {}");
+
+        let req =
+            Request::new(Method::Get, "/files/css/app.css").with_header(ACCEPT_ENCODING, "gzip");
+        let res = handle_request(state.clone(), req);
+        assert_eq!(res.headers.get(CONTENT_ENCODING), Some("gzip"));
+        assert_eq!(body_to_string(res.body), "gz");
+
+        // writes through the server are picked up at once
+        let req = Request::new(Method::Put, "/files/css/app.css").with_body("a {}");
+        assert_eq!(
+            handle_request(state.clone(), req).status,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(get("/files/css/app.css").1, "a {}");
+        let req = Request::new(Method::Delete, "/files/css/app.css");
+        assert_eq!(handle_request(state.clone(), req).status, StatusCode::OK);
+        assert!(state.preload.get(&dir.join("css/app.css")).is_none());
+        assert_eq!(get("/files/big.txt").1, "more than sixteen bytes");
+
+        // a mount given by a path that isn't canonical still finds its files in the preload by
+        // the mount's directory, without looking at the disk
+        let mut config = Config {
+            mounts: vec![Mount::new("files", dir.join("css/..").to_str().unwrap())],
+            preload_max_size: 16,
+            ..Config::default()
+        };
+        check_config(&mut config).unwrap();
+        let state = Arc::new(State::new(config));
+        let directory = Path::new(&state.config.mounts[0].directory);
+        assert!(state
+            .preload
+            .get(&directory.join("css/app.css.gz"))
+            .is_some());
+    }
+
+    #[test]
+    fn test_extension_rules() {
+        let dir = TempDir::new("exts");
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::create_dir_all(dir.join("img")).unwrap();
+        std::fs::write(dir.join(".env"), "SECRET=1").unwrap();
+        std::fs::write(dir.join(".git/config"), "").unwrap();
+        std::fs::write(dir.join("img/cat.png"), "png").unwrap();
+        std::fs::write(dir.join("img/run.sh"), "").unwrap();
+        let args: Vec<String> = [
+            "--directory",
+            dir.to_str().unwrap(),
+            "--deny-extensions",
+            "files=sh,.exe",
+            "--directory-listing",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let state = Arc::new(State::new(parse_args(&args).unwrap()));
+        let send = |method: Method, path: &str| {
+            handle_request(state.clone(), Request::new(method, path).with_body("x"))
+        };
+
+        assert_eq!(
+            send(Method::Get, "/files/.env").status,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(Method::Get, "/files/.git/config").status,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(Method::Get, "/files/img/run.sh").status,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(Method::Delete, "/files/img/run.sh").status,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(Method::Put, "/files/img/a.EXE").status,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            send(Method::Get, "/files/img/cat.png").status,
+            StatusCode::OK
+        );
+        let listing = body_to_string(send(Method::Get, "/files/img/").body);
+        assert!(listing.contains("cat.png") && !listing.contains("run.sh"));
+
+        let mut config = parse_args(&args).unwrap();
+        config.mounts[0].allowed_extensions = vec!["png".to_owned()];
+        config.mounts[0].dotfiles = true;
+        let state = Arc::new(State::new(config));
+        let get =
+            |path: &str| handle_request(state.clone(), Request::new(Method::Get, path)).status;
+        assert_eq!(get("/files/img/cat.png"), StatusCode::OK);
+        assert_eq!(get("/files/img/"), StatusCode::OK);
+        assert_eq!(get("/files/.env"), StatusCode::FORBIDDEN);
+        assert_eq!(get("/files/.git/"), StatusCode::OK);
+
+        assert!(parse_args(&["--dotfiles".to_owned(), "nope".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_atomic_uploads() {
+        // a body that breaks off like a client disconnecting halfway
+        struct Broken<'a>(&'a [u8]);
+
+        impl Read for Broken<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::ErrorKind::ConnectionReset.into());
+                }
+                let n = self.0.read(buf)?;
+                Ok(n)
+            }
+        }
+
+        let dir = TempDir::new("atomic");
+        let path = dir.join("a.txt");
+        assert!(post_file(&path, &mut Broken(b"partial")).is_err());
+        assert!(put_file(&path, &mut Broken(b"partial")).is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        let res = post_file(&path, &mut "whole".as_bytes()).unwrap();
+        assert_eq!(res.status, StatusCode::CREATED);
+        let res = post_file(&path, &mut "again".as_bytes()).unwrap();
+        assert_eq!(res.status, StatusCode::CONFLICT);
+        assert!(put_file(&path, &mut Broken(b"partial")).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"whole");
+        let res = put_file(&path, &mut "new".as_bytes()).unwrap();
+        assert_eq!(res.status, StatusCode::NO_CONTENT);
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_json_listing() {
+        let dir = TempDir::new("json");
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::write(dir.join("index.html"), "<p>home</p>").unwrap();
+        std::fs::write(dir.join("docs/a.txt"), "abc").unwrap();
+        let state = files_state(&dir, |config| config.directory_listing = true);
+        let get = |path: &str, accept: &str| {
+            let req = Request::new(Method::Get, path).with_header(ACCEPT, accept);
+            handle_request(state.clone(), req)
+        };
+
+        let res = get("/files/docs/", "application/json");
+        assert_eq!(res.headers.get(CONTENT_TYPE), Some(APPLICATION_JSON));
+        assert_eq!(res.headers.get(VARY), Some(ACCEPT));
+        let body = body_to_string(res.body);
+        assert!(body.starts_with("[{\"name\":\"a.txt\",\"type\":\"file\",\"size\":3,\"mtime\":"));
+        let res = get("/files/docs/", "text/html,application/json;q=0.9");
+        assert!(body_to_string(res.body).contains("Index of /files/docs/"));
+        assert_eq!(
+            get("/files/docs/", "image/png").status,
+            StatusCode::NOT_ACCEPTABLE
+        );
+
+        // the index page is only for browsers
+        assert_eq!(body_to_string(get("/files/", "*/*").body), "<p>home</p>");
+        let body = body_to_string(get("/files/", "application/json").body);
+        assert!(body.contains("\"name\":\"docs\",\"type\":\"directory\",\"size\":null"));
+    }
+
+    #[test]
+    fn test_create_parents() {
+        let dir = TempDir::new("parents");
+        std::fs::write(dir.join("file"), "").unwrap();
+        let state = files_state(&dir, |_| {});
+        let send = |method: Method, target: &str| {
+            let req = Request::new(method, target).with_body("hi");
+            handle_request(state.clone(), req).status
+        };
+
+        assert_eq!(send(Method::Post, "/files/a/b/c.txt"), StatusCode::CONFLICT);
+        assert!(!dir.join("a").exists());
+        assert_eq!(
+            send(Method::Post, "/files/a/b/c.txt?parents"),
+            StatusCode::CREATED
+        );
+        assert_eq!(std::fs::read(dir.join("a/b/c.txt")).unwrap(), b"hi");
+        assert_eq!(
+            send(Method::Put, "/files/a/d/e.txt?parents=1"),
+            StatusCode::CREATED
+        );
+        assert!(dir.join("a/d/e.txt").is_file());
+        assert_eq!(
+            send(Method::Put, "/files/x/y.txt?parents=false"),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            send(Method::Put, "/files/file/y.txt?parents"),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            send(Method::Post, "/files/.hidden/y.txt?parents"),
+            StatusCode::FORBIDDEN
+        );
+        assert!(!dir.join(".hidden").exists());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let dir = TempDir::new("checksum");
+        std::fs::write(dir.join("abc.txt"), "abc").unwrap();
+        let state = files_state(&dir, |_| {});
+
+        let res = get(&state, "/files/abc.txt?checksum=sha256");
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(
+            body_to_string(res.body),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  abc.txt\n"
+        );
+        assert_eq!(
+            get(&state, "/files/abc.txt?checksum=md5").status,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            get(&state, "/files/nope.txt?checksum=sha256").status,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_resumable_uploads() {
+        let dir = TempDir::new("resume");
+        let state = files_state(&dir, |_| {});
+        let put = |range: &str, body: &str| {
+            let req = Request::new(Method::Put, "/files/big.bin")
+                .with_header(CONTENT_RANGE, range)
+                .with_body(body);
+            let res = handle_request(state.clone(), req);
+            (res.status, res.headers.get(RANGE).map(str::to_owned))
+        };
+
+        assert_eq!(put("bytes */10", ""), (StatusCode::ACCEPTED, None));
+        let stored = Some("bytes=0-3".to_owned());
+        assert_eq!(
+            put("bytes 0-3/10", "0123"),
+            (StatusCode::ACCEPTED, stored.clone())
+        );
+        assert!(!dir.join("big.bin").exists());
+        // after a dropped connection the client asks where to carry on
+        assert_eq!(
+            put("bytes */10", ""),
+            (StatusCode::ACCEPTED, stored.clone())
+        );
+        assert_eq!(
+            put("bytes 6-9/10", "6789"),
+            (StatusCode::RANGE_NOT_SATISFIABLE, stored.clone())
+        );
+        assert_eq!(put("bytes 4-6/10", "45").0, StatusCode::BAD_REQUEST);
+        assert_eq!(put("bytes */10", ""), (StatusCode::ACCEPTED, stored));
+        assert_eq!(put("bytes 4-9/10", "456789"), (StatusCode::CREATED, None));
+        assert_eq!(std::fs::read(dir.join("big.bin")).unwrap(), b"0123456789");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert_eq!(put("bytes 0-1/2", "ab"), (StatusCode::NO_CONTENT, None));
+        assert_eq!(put("bytes 2-1/2", "").0, StatusCode::BAD_REQUEST);
+        assert_eq!(put("bytes 0-1", "ab").0, StatusCode::BAD_REQUEST);
+
+        // the sweep deletes an upload abandoned long ago anywhere under the mount, and keeps a
+        // recent one
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        let abandoned = dir.join("sub/.old.bin.partial");
+        let recent = dir.join(".new.bin.partial");
+        let two_days = Duration::from_secs(2 * 24 * 60 * 60);
+        std::fs::write(&abandoned, "0123").unwrap();
+        let file = File::options().write(true).open(&abandoned).unwrap();
+        file.set_modified(SystemTime::now() - two_days).unwrap();
+        std::fs::write(&recent, "0123").unwrap();
+        expire_partial_uploads(&dir, state.config.partial_upload_max_age);
+        assert!(!abandoned.exists());
+        assert!(recent.exists());
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = TempDir::new("append");
+        std::fs::write(dir.join("app.log"), "one\n").unwrap();
+        let state = files_state(&dir, |_| {});
+        let patch = |target: &str, range: Option<&str>, body: &str| {
+            let mut req = Request::new(Method::Patch, target).with_body(body);
+            if let Some(range) = range {
+                req = req.with_header(CONTENT_RANGE, range);
+            }
+            handle_request(state.clone(), req).status
+        };
+
+        assert_eq!(
+            patch("/files/app.log?append", None, "two\n"),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            patch("/files/app.log", Some("bytes */*"), "three\n"),
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("app.log")).unwrap(),
+            "one\ntwo\nthree\n"
+        );
+        assert_eq!(
+            patch("/files/app.log", None, "four\n"),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            patch("/files/new.log?append", None, "one\n"),
+            StatusCode::NOT_FOUND
+        );
+        assert!(!dir.join("new.log").exists());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_file_actions() {
+        let dir = TempDir::new("actions");
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+        let mut config = Config::default();
+        config.add_mount(Mount::new("files", dir.to_str().unwrap()));
+        config.add_mount(Mount::new("other", dir.join("docs").to_str().unwrap()));
+        let state = Arc::new(State::new(config));
+        let post = |target: &str| handle_request(state.clone(), Request::new(Method::Post, target));
+
+        let res = post("/files/a.txt?action=copy&to=/files/docs/a%20copy.txt");
+        assert_eq!(res.status, StatusCode::CREATED);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("docs/a copy.txt")).unwrap(),
+            "a"
+        );
+        let res = post("/files/a.txt?action=move&to=/files/docs/a.txt");
+        assert_eq!(res.status, StatusCode::CREATED);
+        assert!(!dir.join("a.txt").exists());
+
+        // nothing is replaced
+        let res = post("/files/b.txt?action=move&to=/files/docs/a.txt");
+        assert_eq!(res.status, StatusCode::CONFLICT);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("docs/a.txt")).unwrap(),
+            "a"
+        );
+        let res = post("/files/b.txt?action=move&to=/other/b.txt");
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        let res = post("/files/b.txt?action=move&to=/files/../b.txt");
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            post("/files/b.txt?action=link&to=/files/c.txt").status,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post("/files/b.txt?action=copy").status,
+            StatusCode::BAD_REQUEST
+        );
+        let res = post("/files/nope.txt?action=copy&to=/files/c.txt");
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+        let res = post("/files/docs?action=move&to=/files/moved");
+        assert_eq!(res.status, StatusCode::CREATED);
+        assert!(dir.join("moved/a copy.txt").is_file());
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let args = [
+            "--basic-auth",
+            "admin:s3:cret",
+            "--basic-auth-path",
+            "/echo/",
+        ];
+        let state = args_state(&args);
+        let get = |path: &str, authorization: Option<&str>| {
+            let mut request = Request::new(Method::Get, path);
+            if let Some(authorization) = authorization {
+                request = request.with_header(AUTHORIZATION, authorization);
+            }
+            handle_request(state.clone(), request).status
+        };
+
+        assert_eq!(get("/", None), StatusCode::OK);
+        assert_eq!(get("/echo/abc", None), StatusCode::UNAUTHORIZED);
+        // admin:s3:cret
+        let authorization = Some("Basic YWRtaW46czM6Y3JldA==");
+        assert_eq!(get("/echo/abc", authorization), StatusCode::OK);
+        assert!(parse_args(&["--basic-auth".to_owned(), "admin".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_write_tokens() {
+        let dir = TempDir::new("tokens");
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        let state = files_state(&dir, |config| {
+            config.write_tokens = vec!["t0ken".to_owned()]
+        });
+        let send = |method: Method, authorization: Option<&str>| {
+            let mut request = Request::new(method, "/files/b.txt").with_body("b");
+            if let Some(authorization) = authorization {
+                request = request.with_header(AUTHORIZATION, authorization);
+            }
+            handle_request(state.clone(), request).status
+        };
+
+        let get = Request::new(Method::Get, "/files/a.txt");
+        assert_eq!(handle_request(state.clone(), get).status, StatusCode::OK);
+        assert_eq!(send(Method::Put, None), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            send(Method::Delete, Some("Bearer nope")),
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(!dir.join("b.txt").exists());
+        assert_eq!(send(Method::Put, Some("Bearer t0ken")), StatusCode::CREATED);
+        assert_eq!(send(Method::Delete, Some("Bearer t0ken")), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_cors() {
+        let args = [
+            "--cors-origin",
+            "https://a.example",
+            "--basic-auth",
+            "admin:secret",
+        ];
+        let state = args_state(&args);
+
+        // preflights don't carry credentials
+        let preflight = Request::new(Method::Other("OPTIONS".to_owned()), "/echo/abc")
+            .with_header("Origin", "https://a.example")
+            .with_header("Access-Control-Request-Method", "GET")
+            .with_header("Access-Control-Request-Headers", "authorization");
+        let res = handle_request(state.clone(), preflight);
+        assert_eq!(res.status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers.get("Access-Control-Allow-Headers"),
+            Some("authorization")
+        );
+
+        let res = handle_request(
+            state.clone(),
+            Request::new(Method::Get, "/echo/abc")
+                .with_header("Origin", "https://a.example")
+                .with_header(AUTHORIZATION, "Basic YWRtaW46c2VjcmV0"),
+        );
+        assert_eq!(res.body, "abc");
+        assert_eq!(
+            res.headers.get("Access-Control-Allow-Origin"),
+            Some("https://a.example")
+        );
+        assert_eq!(
+            res.headers.get("Access-Control-Expose-Headers"),
+            Some(X_REQUEST_ID)
+        );
+        // the 401 can be read by the page too
+        let res = handle_request(
+            state,
+            Request::new(Method::Get, "/echo/abc").with_header("Origin", "https://a.example"),
+        );
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert!(res.headers.get("Access-Control-Allow-Origin").is_some());
+    }
+
+    #[test]
+    fn test_security_headers() {
+        let args = ["--csp", "default-src 'self'"];
+        let state = args_state(&args);
+        let res = handle_request(state, Request::new(Method::Get, "/"));
+        assert_eq!(res.headers.get("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(res.headers.get("X-Frame-Options"), Some("DENY"));
+        assert_eq!(
+            res.headers.get("Content-Security-Policy"),
+            Some("default-src 'self'")
+        );
+
+        let state = Arc::new(State::new(Config::default()));
+        let res = handle_request(state, Request::new(Method::Get, "/"));
+        assert_eq!(res.headers.get("X-Content-Type-Options"), None);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = TempDir::new("ro");
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        let args = ["--directory", dir.to_str().unwrap(), "--read-only"];
+        let state = args_state(&args);
+        let send = |method: Method, path: &str| {
+            let request = Request::new(method, path).with_body("b");
+            handle_request(state.clone(), request)
+        };
+        let dav = |method: &str| Method::Other(method.to_owned());
+
+        assert_eq!(send(Method::Get, "/files/a.txt").status, StatusCode::OK);
+        for method in [Method::Post, Method::Put, Method::Patch, Method::Delete] {
+            let res = send(method, "/files/a.txt");
+            assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(res.headers.get(ALLOW), Some("GET, HEAD, OPTIONS, PROPFIND"));
+        }
+        // nor do the WebDAV methods that change files
+        for method in ["MKCOL", "COPY", "MOVE"] {
+            let res = send(dav(method), "/files/a.txt");
+            assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(res.headers.get(ALLOW), Some("GET, HEAD, OPTIONS, PROPFIND"));
+        }
+        let res = send(Method::Post, "/files/");
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(std::fs::read_to_string(dir.join("a.txt")).unwrap(), "a");
+        let res = send(dav("OPTIONS"), "/files/");
+        assert_eq!(res.headers.get(ALLOW), Some("GET, HEAD, OPTIONS, PROPFIND"));
+        assert_eq!(
+            send(dav("BREW"), "/files/").status,
+            StatusCode::NOT_IMPLEMENTED
+        );
+    }
+
+    #[test]
+    fn test_csrf() {
+        let dir = TempDir::new("csrf");
+        let args = ["--directory", dir.to_str().unwrap(), "--csrf"];
+        let state = args_state(&args);
+        let upload = |headers: &[(&str, &str)]| {
+            let mut request = Request::new(Method::Put, "/files/a.txt").with_body("a");
+            for (key, value) in headers {
+                request = request.with_header(key, value);
+            }
+            handle_request(state.clone(), request).status
+        };
+
+        let token = "00112233445566778899aabbccddeeff";
+        let cookie = format!("csrf_token={}", token);
+        let page = [("Origin", "http://localhost:4221"), ("Cookie", &cookie)];
+        assert_eq!(upload(&page), StatusCode::FORBIDDEN);
+        assert!(!dir.join("a.txt").exists());
+        let with_token = [page[0], page[1], ("X-CSRF-Token", token)];
+        assert_eq!(upload(&with_token), StatusCode::CREATED);
+        // replacing it, from curl
+        assert_eq!(upload(&[]), StatusCode::NO_CONTENT);
+    }
+}
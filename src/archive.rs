@@ -0,0 +1,512 @@
+use crate::app::modified_secs;
+use crate::date::civil_from_days;
+use crate::server::Mount;
+use std::fs::{self, File, Metadata};
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+
+// archives are handed over in chunks of about this size, with at most QUEUED_CHUNKS waiting for
+// a slow client
+const CHUNK_SIZE: usize = 64 * 1024;
+const QUEUED_CHUNKS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Zip,
+    // gzip with stored deflate blocks, as there's no compressor here; it still saves a request
+    // per file
+    TarGz,
+}
+
+impl Format {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "zip" => Some(Format::Zip),
+            "tar.gz" | "tgz" => Some(Format::TarGz),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::TarGz => "tar.gz",
+        }
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Format::Zip => "application/zip",
+            Format::TarGz => "application/gzip",
+        }
+    }
+}
+
+// the directory's files and subdirectories that the mount serves, archived by a thread while
+// they're sent; symlinks are left out as they could lead outside the mount
+pub(crate) fn stream(directory: PathBuf, mount: Mount, format: Format) -> impl Read + Send {
+    let (sender, receiver) = mpsc::sync_channel(QUEUED_CHUNKS);
+    thread::spawn(move || {
+        let mut writer = ChunkWriter {
+            sender,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+        };
+        let written = match format {
+            Format::Zip => write_zip(&directory, &mount, &mut writer),
+            Format::TarGz => write_tar_gz(&directory, &mount, &mut writer),
+        };
+        let end = written.and_then(|()| writer.flush()).map(|()| Vec::new());
+        let _ = writer.sender.send(end);
+    });
+    ChunkReader {
+        receiver,
+        chunk: Vec::new(),
+        read: 0,
+        done: false,
+    }
+}
+
+// an empty chunk marks the end; an error cuts the archive short
+struct ChunkWriter {
+    sender: SyncSender<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    // fails once the client is gone, which stops the archiving
+    fn flush(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.chunk, Vec::with_capacity(CHUNK_SIZE));
+        self.sender
+            .send(Ok(chunk))
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))
+    }
+}
+
+struct ChunkReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    read: usize,
+    done: bool,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.chunk.len() {
+            if self.done {
+                return Ok(0);
+            }
+            // a thread that died without the end marker left the archive incomplete
+            let chunk = self
+                .receiver
+                .recv()
+                .map_err(|_| io::Error::from(ErrorKind::UnexpectedEof))??;
+            self.done = chunk.is_empty();
+            self.chunk = chunk;
+            self.read = 0;
+        }
+        let n = buf.len().min(self.chunk.len() - self.read);
+        buf[..n].copy_from_slice(&self.chunk[self.read..self.read + n]);
+        self.read += n;
+        Ok(n)
+    }
+}
+
+// every entry below the directory, parents before their contents and by name within each
+// directory, with names relative to it and directories ending in "/"
+fn walk(
+    directory: &Path,
+    prefix: &str,
+    mount: &Mount,
+    visit: &mut dyn FnMut(&str, &Path, &Metadata) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(directory)?.collect::<io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let metadata = entry.metadata()?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if metadata.file_type().is_symlink() || !mount.allows(&name, metadata.is_dir()) {
+            continue;
+        }
+        let path = entry.path();
+        if metadata.is_dir() {
+            let name = format!("{}{}/", prefix, name);
+            visit(&name, &path, &metadata)?;
+            walk(&path, &name, mount, visit)?;
+        } else if metadata.is_file() {
+            visit(&format!("{}{}", prefix, name), &path, &metadata)?;
+        }
+    }
+    Ok(())
+}
+
+// copies exactly `len` bytes of the file, as the archive already announced that size
+fn copy_file(path: &Path, len: u64, out: &mut dyn Write, crc: &mut u32) -> io::Result<()> {
+    let mut file = File::open(path)?.take(len);
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        *crc = crc32(*crc, &buf[..n]);
+        out.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+    if copied < len {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "file truncated"));
+    }
+    Ok(())
+}
+
+fn write_tar_gz(directory: &Path, mount: &Mount, out: &mut dyn Write) -> io::Result<()> {
+    let mut gzip = Gzip::new(out)?;
+    walk(directory, "", mount, &mut |name, path, metadata| {
+        let (kind, size) = match metadata.is_dir() {
+            true => (b'5', 0),
+            false => (b'0', metadata.len()),
+        };
+        // GNU tar's extension for names that don't fit the header
+        if name.len() > 100 {
+            let long_name = [name.as_bytes(), b"\0"].concat();
+            gzip.write_all(&tar_header(
+                "././@LongLink",
+                b'L',
+                long_name.len() as u64,
+                0,
+            )?)?;
+            gzip.write_all(&long_name)?;
+            gzip.write_all(&[0; 512][..padding(long_name.len() as u64)])?;
+        }
+        let mtime = modified_secs(metadata).unwrap_or(0);
+        gzip.write_all(&tar_header(name, kind, size, mtime)?)?;
+        if size > 0 {
+            copy_file(path, size, &mut gzip, &mut 0)?;
+            gzip.write_all(&[0; 512][..padding(size)])?;
+        }
+        Ok(())
+    })?;
+    // two empty blocks end the archive
+    gzip.write_all(&[0; 1024])?;
+    gzip.finish()
+}
+
+fn padding(len: u64) -> usize {
+    ((512 - len % 512) % 512) as usize
+}
+
+// a ustar header, with the name cut to 100 bytes
+fn tar_header(name: &str, kind: u8, size: u64, mtime: u64) -> io::Result<[u8; 512]> {
+    let mut header = [0; 512];
+    let name = &name.as_bytes()[..name.len().min(100)];
+    header[..name.len()].copy_from_slice(name);
+    let mode = if kind == b'5' { 0o755 } else { 0o644 };
+    octal(&mut header[100..108], mode)?;
+    octal(&mut header[108..116], 0)?;
+    octal(&mut header[116..124], 0)?;
+    octal(&mut header[124..136], size)?;
+    octal(&mut header[136..148], mtime)?;
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // summed with the checksum field as spaces
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    Ok(header)
+}
+
+// zero-padded and NUL-terminated, so a 12-byte size field holds up to 8 GiB
+fn octal(field: &mut [u8], value: u64) -> io::Result<()> {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    if digits.len() > field.len() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "file too large for a tar header",
+        ));
+    }
+    field.copy_from_slice(digits.as_bytes());
+    Ok(())
+}
+
+// gzip framing around deflate's stored blocks, which hold up to 65535 bytes each
+struct Gzip<'a> {
+    out: &'a mut dyn Write,
+    block: Vec<u8>,
+    crc: u32,
+    len: u32,
+}
+
+impl<'a> Gzip<'a> {
+    const BLOCK_SIZE: usize = 65535;
+
+    fn new(out: &'a mut dyn Write) -> io::Result<Self> {
+        // no mtime, no flags, unknown OS
+        out.write_all(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff])?;
+        Ok(Self {
+            out,
+            block: Vec::with_capacity(Self::BLOCK_SIZE),
+            crc: 0,
+            len: 0,
+        })
+    }
+
+    fn write_block(&mut self, last: bool) -> io::Result<()> {
+        let len = self.block.len() as u16;
+        self.out.write_all(&[u8::from(last)])?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(&(!len).to_le_bytes())?;
+        self.out.write_all(&self.block)?;
+        self.block.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.write_block(true)?;
+        self.out.write_all(&self.crc.to_le_bytes())?;
+        self.out.write_all(&self.len.to_le_bytes())
+    }
+}
+
+impl Write for Gzip<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(Self::BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..n]);
+        self.crc = crc32(self.crc, &buf[..n]);
+        // the length modulo 2^32
+        self.len = self.len.wrapping_add(n as u32);
+        if self.block.len() == Self::BLOCK_SIZE {
+            self.write_block(false)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// what goes into the central directory at the end of a zip
+struct ZipEntry {
+    name: String,
+    is_dir: bool,
+    crc: u32,
+    size: u32,
+    offset: u32,
+    time: u16,
+    date: u16,
+}
+
+// uncompressed entries, each followed by a data descriptor with its CRC as that's only known
+// once the file has been read; without zip64, files and the archive are limited to 4 GiB
+fn write_zip(directory: &Path, mount: &Mount, out: &mut dyn Write) -> io::Result<()> {
+    // data descriptor follows, UTF-8 names
+    const FLAGS: u16 = 0x0808;
+    let too_large = || io::Error::new(ErrorKind::InvalidData, "too large for a zip");
+    let mut entries = Vec::new();
+    let mut offset: u64 = 0;
+
+    walk(directory, "", mount, &mut |name, path, metadata| {
+        let (time, date) = dos_time(modified_secs(metadata).unwrap_or(0));
+        let size = match metadata.is_dir() {
+            true => 0,
+            false => u32::try_from(metadata.len()).map_err(|_| too_large())?,
+        };
+        let mut entry = ZipEntry {
+            name: name.to_owned(),
+            is_dir: metadata.is_dir(),
+            crc: 0,
+            size,
+            offset: u32::try_from(offset).map_err(|_| too_large())?,
+            time,
+            date,
+        };
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&20u16.to_le_bytes());
+        header.extend_from_slice(&FLAGS.to_le_bytes());
+        // stored
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        // CRC and sizes are in the data descriptor
+        header.extend_from_slice(&[0; 12]);
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        out.write_all(&header)?;
+        if size > 0 {
+            copy_file(path, u64::from(size), out, &mut entry.crc)?;
+        }
+        let mut descriptor = Vec::with_capacity(16);
+        descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&entry.crc.to_le_bytes());
+        descriptor.extend_from_slice(&size.to_le_bytes());
+        descriptor.extend_from_slice(&size.to_le_bytes());
+        out.write_all(&descriptor)?;
+        offset += (header.len() + descriptor.len()) as u64 + u64::from(size);
+        entries.push(entry);
+        Ok(())
+    })?;
+
+    let start = u32::try_from(offset).map_err(|_| too_large())?;
+    let count = u16::try_from(entries.len()).map_err(|_| too_large())?;
+    let mut central = Vec::new();
+    for entry in &entries {
+        central.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        // made by and needed: version 2.0
+        central.extend_from_slice(&20u16.to_le_bytes());
+        central.extend_from_slice(&20u16.to_le_bytes());
+        central.extend_from_slice(&FLAGS.to_le_bytes());
+        central.extend_from_slice(&0u16.to_le_bytes());
+        central.extend_from_slice(&entry.time.to_le_bytes());
+        central.extend_from_slice(&entry.date.to_le_bytes());
+        central.extend_from_slice(&entry.crc.to_le_bytes());
+        central.extend_from_slice(&entry.size.to_le_bytes());
+        central.extend_from_slice(&entry.size.to_le_bytes());
+        central.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        // extra field, comment, disk number and internal attributes
+        central.extend_from_slice(&[0; 8]);
+        // the MS-DOS directory attribute
+        let attributes: u32 = if entry.is_dir { 0x10 } else { 0 };
+        central.extend_from_slice(&attributes.to_le_bytes());
+        central.extend_from_slice(&entry.offset.to_le_bytes());
+        central.extend_from_slice(entry.name.as_bytes());
+    }
+    let central_len = u32::try_from(central.len()).map_err(|_| too_large())?;
+    out.write_all(&central)?;
+
+    let mut end = Vec::with_capacity(22);
+    end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    // this disk and the one the central directory starts on
+    end.extend_from_slice(&[0; 4]);
+    end.extend_from_slice(&count.to_le_bytes());
+    end.extend_from_slice(&count.to_le_bytes());
+    end.extend_from_slice(&central_len.to_le_bytes());
+    end.extend_from_slice(&start.to_le_bytes());
+    // no comment
+    end.extend_from_slice(&[0; 2]);
+    out.write_all(&end)
+}
+
+// MS-DOS time and date fields, which start in 1980 and count seconds in twos
+fn dos_time(secs: u64) -> (u16, u16) {
+    let (year, month, day) = civil_from_days(secs / 86400);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = secs % 86400;
+    let dos_time = (time / 3600) << 11 | (time % 3600 / 60) << 5 | (time % 60 / 2);
+    let dos_date = ((year - 1980).min(127) << 9) | (month << 5) | day;
+    (dos_time as u16, dos_date as u16)
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+// CRC-32 as zip and gzip use it, with the reflected polynomial 0xedb88320
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// continues the CRC of what came before, starting from 0
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_archives() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf43926);
+
+        let dir = TempDir::new("archive");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        fs::write(dir.join("sub/b.txt"), vec![b'b'; 70_000]).unwrap();
+        fs::write(dir.join(".secret"), "no").unwrap();
+        let read = |format| {
+            let mut archive = Vec::new();
+            let mount = Mount::new("files", dir.to_str().unwrap());
+            stream(dir.to_path_buf(), mount, format)
+                .read_to_end(&mut archive)
+                .unwrap();
+            archive
+        };
+
+        let zip = read(Format::Zip);
+        assert!(zip.starts_with(&0x04034b50u32.to_le_bytes()));
+        let end = &zip[zip.len() - 22..];
+        assert!(end.starts_with(&0x06054b50u32.to_le_bytes()));
+        // a.txt, sub/ and sub/b.txt, without the dotfile
+        assert_eq!(&end[8..10], &3u16.to_le_bytes());
+        let names: Vec<_> = ["a.txt", "sub/", "sub/b.txt", ".secret"]
+            .iter()
+            .map(|name| {
+                zip.windows(name.len())
+                    .any(|window| window == name.as_bytes())
+            })
+            .collect();
+        assert_eq!(names, [true, true, true, false]);
+        let hello_crc = crc32(0, b"hello").to_le_bytes();
+        assert!(zip.windows(4).any(|window| window == hello_crc));
+
+        let gz = read(Format::TarGz);
+        assert!(gz.starts_with(&[0x1f, 0x8b, 8]));
+        // the stored blocks' contents, skipping each 5-byte block header
+        let mut tar = Vec::new();
+        let mut rest = &gz[10..gz.len() - 8];
+        while !rest.is_empty() {
+            let len = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+            tar.extend_from_slice(&rest[5..5 + len]);
+            rest = &rest[5 + len..];
+        }
+        let trailer = &gz[gz.len() - 8..];
+        assert_eq!(&trailer[..4], &crc32(0, &tar).to_le_bytes());
+        assert_eq!(&trailer[4..], &(tar.len() as u32).to_le_bytes());
+        assert_eq!(tar.len() % 512, 0);
+        assert!(tar.starts_with(b"a.txt\0"));
+        assert_eq!(&tar[257..263], b"ustar\0");
+        assert_eq!(&tar[512..517], b"hello");
+        assert_eq!(&tar[1024..1028], b"sub/");
+    }
+}
@@ -0,0 +1,406 @@
+use crate::http::{
+    parse_head, temp_file, write_response, write_upgrading_response, OnUpgrade, Request, Response,
+    StatusCode, StatusError, Upgraded,
+};
+use crate::proxy_protocol;
+use crate::server::{
+    connection_allowed, finish_response, handle_request, parse_error_response, rejection_response,
+    Config, Current, Listener, RequestSummary, State,
+};
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::future::Future;
+use std::io::{Seek, Write};
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task;
+use tokio::time;
+use tracing::{debug, field, info_span, warn, Instrument, Span};
+
+// serves every connection as a tokio task so idle keep-alive connections don't pin a thread;
+// handlers stay blocking and run on tokio's blocking pool
+pub(crate) fn run(current: Arc<Current>, listeners: Vec<Listener>) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async move {
+        let mut accept_loops = Vec::new();
+        for Listener {
+            socket,
+            proxy_protocol,
+        } in listeners
+        {
+            socket.set_nonblocking(true)?;
+            let socket = TcpListener::from_std(socket)?;
+            let accept_loop = accept_loop(Arc::clone(&current), socket, proxy_protocol);
+            accept_loops.push(tokio::spawn(accept_loop));
+        }
+        for accept_loop in accept_loops {
+            accept_loop.await?;
+        }
+        Ok(())
+    })
+}
+
+async fn accept_loop(current: Arc<Current>, listener: TcpListener, proxy_protocol: bool) {
+    loop {
+        let accepted = listener.accept().await;
+        let state = current.get();
+        match accepted {
+            Ok((stream, peer))
+                if !proxy_protocol && !connection_allowed(&state, Some(peer.ip())) =>
+            {
+                reject(&state, stream, StatusCode::FORBIDDEN)
+            }
+            // behind a load balancer the client is only known once the header is read
+            Ok((stream, peer)) => match state
+                .try_connect(Some(peer.ip()).filter(|_| !proxy_protocol))
+            {
+                Some(guard) => {
+                    let span = info_span!("connection", peer = %peer.ip());
+                    tokio::spawn(
+                        async move {
+                            handle_connection(Arc::clone(&guard.0), stream, proxy_protocol).await;
+                            drop(guard);
+                        }
+                        .instrument(span),
+                    );
+                }
+                None => reject(&state, stream, StatusCode::SERVICE_UNAVAILABLE),
+            },
+            Err(e) => warn!(error = %e, "accepting a connection failed"),
+        }
+    }
+}
+
+// answers 503 for too many open connections or 403 for a disallowed client, without holding up
+// the accept loop
+fn reject(state: &State, mut stream: TcpStream, status: StatusCode) {
+    let mut buf = Vec::new();
+    if write_response(rejection_response(state, status), &mut buf).is_ok() {
+        let write_timeout = state.config.write_timeout;
+        tokio::spawn(async move {
+            let _ = time::timeout(write_timeout, stream.write_all(&buf)).await;
+        });
+    }
+}
+
+// the async counterpart of crate::handle_connection
+async fn handle_connection(mut state: Arc<State>, mut stream: TcpStream, proxy_protocol: bool) {
+    let mut peer = stream.peer_addr().ok().map(|addr| addr.ip());
+    // the client's slot under max_connections_per_ip, taken here rather than when accepted
+    let mut _client = None;
+    if proxy_protocol {
+        let header = proxy_protocol::read_header_async(&mut stream);
+        match time::timeout(state.config.read_timeout, header).await {
+            Ok(Ok(client)) => peer = client.or(peer),
+            Ok(Err(e)) => {
+                debug!(peer = ?peer, error = %e, "connection without a valid PROXY protocol header");
+                return;
+            }
+            Err(_) => return,
+        }
+        if !connection_allowed(&state, peer) {
+            return;
+        }
+        match state.client_slot(peer) {
+            Some(slot) => _client = Some(slot),
+            None => return reject(&state, stream, StatusCode::SERVICE_UNAVAILABLE),
+        }
+        if let Some(peer) = peer {
+            Span::current().record("peer", field::display(peer));
+        }
+    }
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        // the client closed the connection between requests, or stayed idle too long
+        match time::timeout(state.config.read_timeout, reader.fill_buf()).await {
+            Ok(Ok(buf)) if !buf.is_empty() => {}
+            _ => break,
+        }
+
+        // settings reloaded since the previous request apply to this one
+        state = state.latest();
+        let mut timing = state.request_started();
+        let parsed = read_request(&mut reader, &state.config).await;
+        timing.parsed();
+        let mut client_ip = peer;
+        let (response, close, summary) = match parsed {
+            Ok(mut request) => {
+                state.set_peer(&mut request, peer);
+                client_ip = request.client_ip();
+                let summary = RequestSummary::of(&request);
+                let close = request.wants_close();
+                if let Some(response) = state.rate_limited(client_ip) {
+                    (response, close, Some(summary))
+                } else {
+                    let state = Arc::clone(&state);
+                    let span = Span::current();
+                    let handled = task::spawn_blocking(move || {
+                        span.in_scope(|| handle_request(state, request))
+                    });
+                    match handled.await {
+                        Ok(response) => (response, close, Some(summary)),
+                        Err(_) => (
+                            Response::new(StatusCode::INTERNAL_SERVER_ERROR),
+                            true,
+                            Some(summary),
+                        ),
+                    }
+                }
+            }
+            // the rest of the stream can't be trusted after a malformed request
+            Err(e) => (parse_error_response(&state, &e), true, None),
+        };
+
+        let handled = timing.handled();
+        let (response, close) = finish_response(&state, response, close, handled);
+        let status = response.status;
+        // file bodies are read on the blocking pool too
+        let rendered = task::spawn_blocking(move || {
+            let mut buf = Vec::new();
+            write_upgrading_response(response, &mut buf)
+                .map(|(bytes, upgrade)| (buf, bytes, upgrade))
+        })
+        .await;
+        let Ok(Ok((buf, bytes, upgrade))) = rendered else {
+            state.request_answered(client_ip, summary.as_ref(), status, 0, &timing);
+            break;
+        };
+        let written = time::timeout(state.config.write_timeout, writer.write_all(&buf)).await;
+        let written = matches!(written, Ok(Ok(())));
+        let bytes = if written { bytes } else { 0 };
+        state.request_answered(client_ip, summary.as_ref(), status, bytes, &timing);
+        if let (true, Some(upgrade)) = (written, upgrade) {
+            upgrade_connection(&state, reader, writer, upgrade).await;
+            break;
+        }
+        if !written || close {
+            break;
+        }
+    }
+}
+
+// runs what took over the connection on the blocking pool, with a blocking socket like the
+// worker pool's
+async fn upgrade_connection(
+    state: &State,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    upgrade: OnUpgrade,
+) {
+    let buffered = reader.buffer().to_vec();
+    let Ok(stream) = reader.into_inner().reunite(writer) else {
+        return;
+    };
+    let Ok(stream) = stream.into_std() else {
+        return;
+    };
+    let config = &state.config;
+    let blocking = stream.set_nonblocking(false).is_ok()
+        && stream.set_read_timeout(Some(config.read_timeout)).is_ok()
+        && stream.set_write_timeout(Some(config.write_timeout)).is_ok();
+    if !blocking {
+        return;
+    }
+    let span = Span::current();
+    let _ = task::spawn_blocking(move || {
+        span.in_scope(|| upgrade(Upgraded::new(buffered, &mut &stream, Some(&stream))))
+    })
+    .await;
+}
+
+// buffers the head with the parser's limits, then parses it like the blocking server does
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R, config: &Config) -> Result<Request> {
+    let parser = &config.parser;
+    // however steadily it trickles in, the head has to arrive within head_timeout
+    let head = match time::timeout(config.head_timeout, read_head(reader, config)).await {
+        Ok(head) => head?,
+        Err(_) => bail!(StatusError(StatusCode::REQUEST_TIMEOUT)),
+    };
+
+    let (mut request, content_length) = parse_head(&mut head.as_slice(), parser)?;
+    if content_length > 0 && parser.streams(&request.path) {
+        request.body_file = Some(stream_body(reader, content_length, config).await?);
+        return Ok(request);
+    }
+    let mut body = vec![0; content_length];
+    with_timeout(config, reader.read_exact(&mut body)).await?;
+    request.body = body;
+    Ok(request)
+}
+
+// the request line and headers, up to the empty line ending them
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R, config: &Config) -> Result<Vec<u8>> {
+    let parser = &config.parser;
+    let mut head = Vec::new();
+    read_line_limited(
+        reader,
+        &mut head,
+        parser.max_request_line,
+        StatusCode::URI_TOO_LONG,
+        config,
+    )
+    .await?;
+
+    let mut headers_size = 0;
+    loop {
+        let n = read_line_limited(
+            reader,
+            &mut head,
+            parser.max_header_line,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            config,
+        )
+        .await?;
+        headers_size += n;
+        if headers_size > parser.max_headers_size {
+            bail!(StatusError(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE));
+        }
+        if n == 0 || head.ends_with(b"\n\r\n") || head.ends_with(b"\n\n") {
+            break;
+        }
+    }
+    Ok(head)
+}
+
+// copies the body to a temporary file a chunk at a time; the writes block, but only briefly as
+// they go to the page cache
+async fn stream_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    content_length: usize,
+    config: &Config,
+) -> Result<File> {
+    let mut file = temp_file()?;
+    let mut remaining = content_length;
+    let mut chunk = vec![0; 64 * 1024];
+    while remaining > 0 {
+        let len = remaining.min(chunk.len());
+        let n = with_timeout(config, reader.read(&mut chunk[..len])).await?;
+        if n == 0 {
+            bail!("body shorter than content-length");
+        }
+        file.write_all(&chunk[..n])?;
+        remaining -= n;
+    }
+    file.rewind()?;
+    Ok(file)
+}
+
+// appends a line of at most `limit` bytes to `buf`, failing with `status` beyond that
+async fn read_line_limited<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    limit: usize,
+    status: StatusCode,
+    config: &Config,
+) -> Result<usize> {
+    let mut limited = reader.take(limit as u64 + 1);
+    let n = with_timeout(config, limited.read_until(b'\n', buf)).await?;
+    if n > limit {
+        bail!(StatusError(status));
+    }
+    Ok(n)
+}
+
+// fails with 408 if the client doesn't send within the read timeout
+async fn with_timeout<T>(
+    config: &Config,
+    read: impl Future<Output = std::io::Result<T>>,
+) -> Result<T> {
+    match time::timeout(config.read_timeout, read).await {
+        Ok(result) => Ok(result?),
+        Err(_) => bail!(StatusError(StatusCode::REQUEST_TIMEOUT)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_async_keep_alive() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let config = Config {
+                read_timeout: Duration::from_millis(200),
+                ..Config::default()
+            };
+            let state = Arc::new(State::new(config));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(handle_connection(Arc::clone(&state), stream, false));
+                }
+            });
+
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(
+                    b"GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                      POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\n\r\nb\
+                      GET /echo/c HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+            assert!(response.contains("\r\n\r\na"));
+            assert!(response.contains("\r\n\r\nb"));
+            assert!(response.ends_with("\r\n\r\nc"));
+
+            // a head that stops halfway runs into the read timeout
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        });
+    }
+
+    #[test]
+    fn test_async_head_timeout() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let config = Config {
+                head_timeout: Duration::from_millis(300),
+                ..Config::default()
+            };
+            let state = Arc::new(State::new(config));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                handle_connection(state, stream, false).await;
+            });
+
+            // every byte arrives well within the read timeout, but the head never ends
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let started = std::time::Instant::now();
+            let mut response = Vec::new();
+            for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Drip: 1234567890" {
+                if client.write_all(&[*byte]).await.is_err() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(20)).await;
+            }
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+            assert!(started.elapsed() < Duration::from_secs(5));
+        });
+    }
+}
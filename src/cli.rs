@@ -0,0 +1,605 @@
+use crate::cidr::Cidr;
+use crate::logging::{self, LogFormat};
+use crate::proxy::{Destination, ForwardedHeader, Proxy};
+use crate::server::{check_mount_name, find_mount, mime_type, Config, Mount, DEFAULT_DIRECTORY};
+use anyhow::{anyhow, bail, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser};
+use std::iter;
+use std::time::Duration;
+use tracing::Level;
+
+// the command line options, each applied on top of what the environment or a --config file set;
+// options that aren't repeatable may still be given again, the last one wins
+#[derive(Parser)]
+#[command(version, about = "Simple HTTP server", args_override_self = true)]
+pub(crate) struct Cli {
+    /// Read options from a TOML file, overridden by the command line
+    #[arg(long, value_name = "FILE")]
+    pub(crate) config: Option<String>,
+
+    /// Directory served under /files/
+    #[arg(long, value_name = "DIR", default_value = DEFAULT_DIRECTORY)]
+    directory: Option<String>,
+    /// Serve another directory under /NAME/, read-only with ":ro" appended
+    #[arg(long, value_name = "NAME=DIR[:ro]", value_parser = mount)]
+    mount: Vec<Mount>,
+    /// Only serve and accept files with these extensions from a mount
+    #[arg(long, value_name = "MOUNT=EXT,EXT", value_parser = mount_extensions)]
+    allow_extensions: Vec<(String, Vec<String>)>,
+    /// Refuse files with these extensions in a mount
+    #[arg(long, value_name = "MOUNT=EXT,EXT", value_parser = mount_extensions)]
+    deny_extensions: Vec<(String, Vec<String>)>,
+    /// Serve names starting with a dot from a mount
+    #[arg(long, value_name = "MOUNT")]
+    dotfiles: Vec<String>,
+    /// Make every mount read-only
+    #[arg(long)]
+    read_only: bool,
+    /// Don't follow symlinks under the mounts
+    #[arg(long)]
+    no_follow_symlinks: bool,
+    /// File names served for a directory, in order, comma-separated
+    #[arg(long, value_name = "NAMES")]
+    index_files: Option<String>,
+    /// List the entries of directories without an index file
+    #[arg(long)]
+    directory_listing: bool,
+    /// Answer 404 for directories without an index file, the default
+    #[arg(long)]
+    no_directory_listing: bool,
+    /// Serve the root index.html for missing files
+    #[arg(long)]
+    spa: bool,
+    /// Hold files of up to this many bytes in memory
+    #[arg(long, value_name = "BYTES", value_parser = positive)]
+    preload: Option<usize>,
+    /// Seconds an unfinished resumable upload is kept without another piece
+    #[arg(long, value_name = "SECONDS", value_parser = seconds)]
+    partial_upload_max_age: Option<Duration>,
+    /// Content-Type of files with an extension
+    #[arg(long, value_name = "EXT=TYPE", value_parser = mime)]
+    mime_type: Vec<(String, String)>,
+    /// Cache-Control of files by extension or URL prefix
+    #[arg(long, value_name = "*.EXT=VALUE|/PREFIX/=VALUE", value_parser = cache_control)]
+    cache_control: Vec<(String, String)>,
+    /// Send files by extension or URL prefix as attachments
+    #[arg(long, value_name = "*.EXT|/PREFIX/")]
+    download: Vec<String>,
+
+    /// Address to listen on, optionally with a port; repeatable
+    #[arg(long, value_name = "ADDR")]
+    bind: Vec<String>,
+    /// Also listen on ADDR, for a load balancer sending the PROXY protocol; repeatable
+    #[arg(long, value_name = "ADDR")]
+    proxy_protocol_bind: Vec<String>,
+    /// Port for addresses without their own
+    #[arg(long)]
+    port: Option<u16>,
+    /// Worker threads
+    #[arg(long, value_name = "N", value_parser = positive)]
+    threads: Option<usize>,
+    /// Accepted connections waiting for a worker before new ones get 503
+    #[arg(long, value_name = "N", value_parser = positive)]
+    queue_size: Option<usize>,
+    /// Open connections before new ones get 503
+    #[arg(long, value_name = "N", value_parser = positive)]
+    max_connections: Option<usize>,
+    /// Open connections from a single IP address before new ones get 503
+    #[arg(long, value_name = "N", value_parser = positive)]
+    max_connections_per_ip: Option<usize>,
+    /// Sockets bound to each address with SO_REUSEPORT
+    #[arg(long, value_name = "N", value_parser = positive)]
+    acceptors: Option<usize>,
+    /// Serve connections as tokio tasks instead of on the worker pool
+    #[cfg(feature = "async")]
+    #[arg(long = "async")]
+    async_io: bool,
+
+    /// Seconds a request's head may take to arrive
+    #[arg(long, value_name = "SECONDS", value_parser = seconds)]
+    head_timeout: Option<Duration>,
+    /// Seconds a connection may be idle while reading
+    #[arg(long, value_name = "SECONDS", value_parser = seconds)]
+    read_timeout: Option<Duration>,
+    /// Seconds a client may take to read a response
+    #[arg(long, value_name = "SECONDS", value_parser = seconds)]
+    write_timeout: Option<Duration>,
+    /// Longest request line, in bytes
+    #[arg(long, value_name = "BYTES", value_parser = positive)]
+    max_request_line: Option<usize>,
+    /// Longest header line, in bytes
+    #[arg(long, value_name = "BYTES", value_parser = positive)]
+    max_header_size: Option<usize>,
+    /// Largest head of all header lines, in bytes
+    #[arg(long, value_name = "BYTES", value_parser = positive)]
+    max_headers_size: Option<usize>,
+    /// Largest request body, in bytes, or for paths under a prefix
+    #[arg(long, value_name = "BYTES|/PREFIX=BYTES", value_parser = body_size)]
+    max_body_size: Vec<(Option<String>, usize)>,
+    /// Accept bare LF line endings
+    #[arg(long)]
+    lenient: bool,
+
+    /// Add a default header to every response, or remove one with "Name:"
+    #[arg(long, value_name = "NAME: VALUE", value_parser = header)]
+    header: Vec<(String, String)>,
+    /// Add an X-Response-Time header
+    #[arg(long)]
+    response_time: bool,
+    /// Answer GET /metrics with request counters
+    #[arg(long)]
+    metrics: bool,
+    /// Don't answer GET /metrics, the default
+    #[arg(long)]
+    no_metrics: bool,
+    /// Answer GET /_stats to requests with this bearer token
+    #[arg(long, value_name = "TOKEN")]
+    admin_token: Option<String>,
+
+    /// Access log file, "-" for stdout
+    #[arg(long, value_name = "FILE", overrides_with = "no_access_log")]
+    access_log: Option<String>,
+    /// Don't write an access log
+    #[arg(long, overrides_with = "access_log")]
+    no_access_log: bool,
+    /// Rotate the access log file beyond this many bytes
+    #[arg(long, value_name = "BYTES", value_parser = positive)]
+    access_log_max_size: Option<usize>,
+    /// Rotate the access log file hourly or daily
+    #[arg(long, value_name = "PERIOD", value_parser = rotation_period)]
+    access_log_rotate: Option<Duration>,
+    /// Rotated access log files kept
+    #[arg(long, value_name = "N", value_parser = positive)]
+    access_log_keep: Option<usize>,
+    /// Log format, text or json
+    #[arg(long, value_name = "FORMAT", value_parser = log_format)]
+    log_format: Option<LogFormat>,
+    /// Log level: error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL", value_parser = log_level)]
+    log_level: Option<Level>,
+    /// Log more, repeat for even more
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Log less, repeat for even less
+    #[arg(short, long, action = ArgAction::Count)]
+    quiet: u8,
+    /// Log requests taking at least this long as warnings
+    #[arg(long, value_name = "MILLIS", value_parser = positive)]
+    slow_request_ms: Option<usize>,
+
+    /// Require Basic auth with this user
+    #[arg(long, value_name = "NAME:PASSWORD", value_parser = user)]
+    basic_auth: Vec<(String, String)>,
+    /// Only require Basic auth under this path
+    #[arg(long, value_name = "PATH")]
+    basic_auth_path: Vec<String>,
+    /// Require Basic auth with the users of an htpasswd file
+    #[arg(long, value_name = "FILE")]
+    basic_auth_file: Option<String>,
+    /// Require this bearer token to change files
+    #[arg(long, value_name = "TOKEN")]
+    write_token: Vec<String>,
+    /// Require a CSRF token for unsafe requests from browsers
+    #[arg(long)]
+    csrf: bool,
+    /// Only serve clients in this network
+    #[arg(long, value_name = "CIDR")]
+    allow_ip: Vec<Cidr>,
+    /// Refuse clients in this network
+    #[arg(long, value_name = "CIDR")]
+    deny_ip: Vec<Cidr>,
+    /// Believe X-Forwarded-For from proxies in this network
+    #[arg(long, value_name = "CIDR")]
+    trusted_proxy: Vec<Cidr>,
+    /// Header the trusted proxies set, x-forwarded-for or forwarded
+    #[arg(long, value_name = "HEADER", value_parser = forwarded_header)]
+    forwarded_header: Option<ForwardedHeader>,
+    /// Requests per client: N or N/s per second, N/m per minute
+    #[arg(long, value_name = "RATE", value_parser = rate)]
+    rate_limit: Option<f64>,
+    /// Requests a quiet client may make at once
+    #[arg(long, value_name = "N", value_parser = positive)]
+    rate_limit_burst: Option<usize>,
+    /// Answer CORS requests from any origin
+    #[arg(long)]
+    cors: bool,
+    /// Answer CORS requests from this origin
+    #[arg(long, value_name = "ORIGIN")]
+    cors_origin: Vec<String>,
+    /// Add nosniff, frame and referrer headers
+    #[arg(long)]
+    security_headers: bool,
+    /// Add security headers with this Content-Security-Policy
+    #[arg(long, value_name = "POLICY")]
+    csp: Option<String>,
+    /// Forward requests under PREFIX to an upstream HTTP server
+    #[arg(long, value_name = "PREFIX=URL", value_parser = proxy)]
+    proxy: Vec<Proxy>,
+    /// Let CONNECT open tunnels to HOST:PORT, with * for any host, subdomain or port
+    #[arg(long, value_name = "HOST:PORT")]
+    connect_allow: Vec<Destination>,
+
+    /// PEM file with the certificate chain to serve HTTPS with
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE")]
+    tls_cert: Option<String>,
+    /// PEM file with the private key
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE")]
+    tls_key: Option<String>,
+    /// PEM file with the CAs client certificates must be signed by
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "FILE")]
+    tls_client_ca: Option<String>,
+    /// Accept clients without a certificate too
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_client_optional: bool,
+}
+
+impl Cli {
+    pub(crate) fn parse(args: &[String]) -> Result<Self, clap::Error> {
+        let args = iter::once("rust-http-server").chain(args.iter().map(String::as_str));
+        let matches = Self::command().try_get_matches_from(args)?;
+        let mut cli = Self::from_arg_matches(&matches)?;
+        // the default directory is there for --help, Config::default has it already and the
+        // environment or a config file may have replaced it
+        if matches.value_source("directory") == Some(ValueSource::DefaultValue) {
+            cli.directory = None;
+        }
+        Ok(cli)
+    }
+
+    pub(crate) fn apply(self, mut config: Config) -> Result<Config> {
+        if let Some(directory) = self.directory {
+            config.add_mount(Mount::new("files", &directory));
+        }
+        for mount in self.mount {
+            config.add_mount(mount);
+        }
+        // options for a mount refer to it by name, wherever it was given
+        for (name, extensions) in self.allow_extensions {
+            find_mount(&mut config, &name)?
+                .allowed_extensions
+                .extend(extensions);
+        }
+        for (name, extensions) in self.deny_extensions {
+            find_mount(&mut config, &name)?
+                .denied_extensions
+                .extend(extensions);
+        }
+        for name in self.dotfiles {
+            find_mount(&mut config, &name)?.dotfiles = true;
+        }
+        config.read_only |= self.read_only;
+        config.follow_symlinks &= !self.no_follow_symlinks;
+        if let Some(names) = self.index_files {
+            // empty to serve no index files
+            config.index_files = names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+        config.directory_listing |= self.directory_listing;
+        config.directory_listing &= !self.no_directory_listing;
+        config.spa |= self.spa;
+        set(&mut config.preload_max_size, self.preload.map(|n| n as u64));
+        set(
+            &mut config.partial_upload_max_age,
+            self.partial_upload_max_age,
+        );
+        config.mime_types.extend(self.mime_type);
+        config.cache_control.extend(self.cache_control);
+        config.downloads.extend(self.download);
+
+        // replacing the default (or environment) addresses
+        if !self.bind.is_empty() {
+            config.bind = self.bind;
+        }
+        config.proxy_protocol_bind.extend(self.proxy_protocol_bind);
+        set(&mut config.port, self.port);
+        set(&mut config.threads, self.threads);
+        set(&mut config.queue_size, self.queue_size);
+        set(&mut config.max_connections, self.max_connections);
+        if self.max_connections_per_ip.is_some() {
+            config.max_connections_per_ip = self.max_connections_per_ip;
+        }
+        set(&mut config.acceptors, self.acceptors);
+        #[cfg(feature = "async")]
+        {
+            config.async_io |= self.async_io;
+        }
+
+        set(&mut config.head_timeout, self.head_timeout);
+        set(&mut config.read_timeout, self.read_timeout);
+        set(&mut config.write_timeout, self.write_timeout);
+        set(&mut config.parser.max_request_line, self.max_request_line);
+        set(&mut config.parser.max_header_line, self.max_header_size);
+        set(&mut config.parser.max_headers_size, self.max_headers_size);
+        for (prefix, size) in self.max_body_size {
+            match prefix {
+                Some(prefix) => config.parser.route_max_body_sizes.push((prefix, size)),
+                None => config.parser.max_body_size = size,
+            }
+        }
+        config.parser.lenient_line_endings |= self.lenient;
+
+        for (key, value) in self.header {
+            if value.is_empty() {
+                config.default_headers.remove(&key);
+            } else {
+                config.default_headers.insert(&key, &value);
+            }
+        }
+        config.response_time_header |= self.response_time;
+        config.metrics |= self.metrics;
+        config.metrics &= !self.no_metrics;
+        if self.admin_token.is_some() {
+            config.admin_token = self.admin_token;
+        }
+
+        if self.access_log.is_some() {
+            config.access_log = self.access_log;
+        }
+        if self.no_access_log {
+            config.access_log = None;
+        }
+        if let Some(size) = self.access_log_max_size {
+            config.access_log_max_size = Some(size as u64);
+        }
+        if self.access_log_rotate.is_some() {
+            config.access_log_period = self.access_log_rotate;
+        }
+        set(&mut config.access_log_keep, self.access_log_keep);
+        set(&mut config.log_format, self.log_format);
+        set(&mut config.log_level, self.log_level);
+        // -v, -vv and so on are each a level more verbose, -q and -qq less
+        let steps = self.verbose as isize - self.quiet as isize;
+        config.log_level = logging::verbosity(config.log_level, steps);
+        if let Some(millis) = self.slow_request_ms {
+            config.slow_request = Some(Duration::from_millis(millis as u64));
+        }
+
+        config.basic_auth_users.extend(self.basic_auth);
+        config.basic_auth_paths.extend(self.basic_auth_path);
+        if self.basic_auth_file.is_some() {
+            config.basic_auth_file = self.basic_auth_file;
+        }
+        config.write_tokens.extend(self.write_token);
+        config.csrf |= self.csrf;
+        config.allowed_ips.extend(self.allow_ip);
+        config.trusted_proxies.extend(self.trusted_proxy);
+        set(&mut config.forwarded_header, self.forwarded_header);
+        config.denied_ips.extend(self.deny_ip);
+        if self.rate_limit.is_some() {
+            config.rate_limit = self.rate_limit;
+        }
+        if self.rate_limit_burst.is_some() {
+            config.rate_limit_burst = self.rate_limit_burst;
+        }
+        // any origin given turns CORS on
+        config.cors |= self.cors || !self.cors_origin.is_empty();
+        config.cors_origins.extend(self.cors_origin);
+        // and so does a policy the security headers
+        config.security_headers |= self.security_headers || self.csp.is_some();
+        if self.csp.is_some() {
+            config.content_security_policy = self.csp;
+        }
+        for proxy in self.proxy {
+            config.add_proxy(proxy);
+        }
+        config.connect_allowed.extend(self.connect_allow);
+
+        #[cfg(feature = "tls")]
+        {
+            if self.tls_cert.is_some() {
+                config.tls_cert = self.tls_cert;
+            }
+            if self.tls_key.is_some() {
+                config.tls_key = self.tls_key;
+            }
+            if self.tls_client_ca.is_some() {
+                config.tls_client_ca = self.tls_client_ca;
+            }
+            config.tls_client_optional |= self.tls_client_optional;
+        }
+        Ok(config)
+    }
+}
+
+fn set<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
+
+// the message of a clap error, without its usage and tips, for errors that aren't about the
+// command line itself
+pub(crate) fn message(e: clap::Error) -> anyhow::Error {
+    let rendered = e.render().to_string();
+    let line = rendered.lines().next().unwrap_or_default();
+    anyhow!("{}", line.trim_start_matches("error: "))
+}
+
+pub(crate) fn positive(value: &str) -> Result<usize> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("expected a positive number"),
+    }
+}
+
+fn seconds(value: &str) -> Result<Duration> {
+    Ok(Duration::from_secs(positive(value)? as u64))
+}
+
+// "name=directory", with ":ro" appended for a read-only one
+fn mount(value: &str) -> Result<Mount> {
+    let Some((name, directory)) = value.split_once('=') else {
+        bail!("expected name=directory or name=directory:ro");
+    };
+    let (directory, read_only) = match directory.strip_suffix(":ro") {
+        Some(directory) => (directory, true),
+        None => (directory.strip_suffix(":rw").unwrap_or(directory), false),
+    };
+    let mount = Mount {
+        read_only,
+        ..Mount::new(name.trim(), directory.trim())
+    };
+    check_mount_name(&mount.name)?;
+    Ok(mount)
+}
+
+// "/prefix=http://host:port/path"
+fn proxy(value: &str) -> Result<Proxy> {
+    let Some((prefix, upstream)) = value.split_once('=') else {
+        bail!("expected prefix=url");
+    };
+    Proxy::new(prefix.trim(), upstream.trim())
+}
+
+// "mount=ext,ext", with a leading dot on the extensions ignored
+fn mount_extensions(value: &str) -> Result<(String, Vec<String>)> {
+    let Some((name, extensions)) = value.split_once('=') else {
+        bail!("expected mount=ext,ext");
+    };
+    let extensions = extensions
+        .split(',')
+        .map(|extension| extension.trim().trim_start_matches('.'))
+        .filter(|extension| !extension.is_empty())
+        .map(str::to_owned)
+        .collect();
+    Ok((name.trim().to_owned(), extensions))
+}
+
+fn mime(value: &str) -> Result<(String, String)> {
+    let Some((extension, mime)) = value.split_once('=') else {
+        bail!("expected extension=type");
+    };
+    Ok(mime_type(extension, mime))
+}
+
+fn cache_control(value: &str) -> Result<(String, String)> {
+    let Some((pattern, value)) = value.split_once('=') else {
+        bail!("expected *.ext=value or /prefix=value");
+    };
+    Ok((pattern.trim().to_owned(), value.trim().to_owned()))
+}
+
+// "Name: value", where an empty value removes a default header
+fn header(value: &str) -> Result<(String, String)> {
+    let Some((key, value)) = value.split_once(':') else {
+        bail!("expected \"Name: value\"");
+    };
+    Ok((key.trim().to_owned(), value.trim().to_owned()))
+}
+
+// either a global limit or "/prefix=limit" for paths under a prefix
+fn body_size(value: &str) -> Result<(Option<String>, usize)> {
+    match value.split_once('=') {
+        Some((prefix, size)) => Ok((Some(prefix.to_owned()), positive(size)?)),
+        None => Ok((None, positive(value)?)),
+    }
+}
+
+fn user(value: &str) -> Result<(String, String)> {
+    match value.split_once(':') {
+        Some((name, password)) if !name.is_empty() => Ok((name.to_owned(), password.to_owned())),
+        _ => bail!("expected name:password"),
+    }
+}
+
+fn rotation_period(value: &str) -> Result<Duration> {
+    match value {
+        "hourly" => Ok(Duration::from_secs(3600)),
+        "daily" => Ok(Duration::from_secs(86400)),
+        _ => bail!("expected hourly or daily"),
+    }
+}
+
+// "N" or "N/s" requests per second, or "N/m" per minute
+fn rate(value: &str) -> Result<f64> {
+    let (count, per) = match value.split_once('/') {
+        Some((count, "s")) => (count, 1.0),
+        Some((count, "m")) => (count, 60.0),
+        Some(_) => bail!("expected N, N/s or N/m"),
+        None => (value, 1.0),
+    };
+    Ok(positive(count)? as f64 / per)
+}
+
+pub(crate) fn log_format(value: &str) -> Result<LogFormat> {
+    match value {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        _ => bail!("expected text or json"),
+    }
+}
+
+fn forwarded_header(value: &str) -> Result<ForwardedHeader> {
+    match value.to_ascii_lowercase().as_str() {
+        "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+        "forwarded" => Ok(ForwardedHeader::Forwarded),
+        _ => bail!("expected x-forwarded-for or forwarded"),
+    }
+}
+
+pub(crate) fn log_level(value: &str) -> Result<Level> {
+    match value.parse() {
+        Ok(level) => Ok(level),
+        _ => bail!("expected error, warn, info, debug or trace"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|s| s.to_string()).collect() };
+
+        // options for a mount may come before it
+        let cli = Cli::parse(&args(&[
+            "--dotfiles",
+            "assets",
+            "--mount",
+            "assets=/srv:ro",
+        ]));
+        let config = cli.unwrap().apply(Config::default()).unwrap();
+        assert!(config.mounts[1].dotfiles && config.mounts[1].read_only);
+
+        // the last of an option given twice wins
+        let cli = Cli::parse(&args(&["--port", "1", "--port", "2", "-vv", "-q"])).unwrap();
+        let config = cli.apply(Config::default()).unwrap();
+        assert_eq!(config.port, 2);
+        assert_eq!(config.log_level, Level::DEBUG);
+
+        let Err(e) = Cli::parse(&args(&["--threads", "0"])) else {
+            panic!("--threads 0 was accepted");
+        };
+        assert_eq!(
+            message(e).to_string(),
+            "invalid value '0' for '--threads <N>': expected a positive number"
+        );
+        let Err(e) = Cli::parse(&args(&["--help"])) else {
+            panic!("--help was not answered");
+        };
+        assert_eq!(e.kind(), clap::error::ErrorKind::DisplayHelp);
+        assert!(e
+            .to_string()
+            .contains("--max-body-size <BYTES|/PREFIX=BYTES>"));
+        assert!(e.to_string().contains("[default: lol]"));
+
+        // the default directory doesn't replace one set before the flags
+        let mut config = Config::default();
+        config.add_mount(Mount::new("files", "/srv/www"));
+        let config = Cli::parse(&[]).unwrap().apply(config).unwrap();
+        assert_eq!(config.mounts[0].directory, "/srv/www");
+        let cli = Cli::parse(&args(&["--directory", "lol"])).unwrap();
+        assert_eq!(cli.directory.as_deref(), Some("lol"));
+    }
+}
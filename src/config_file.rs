@@ -0,0 +1,108 @@
+use crate::cli::Cli;
+use anyhow::{bail, Context, Result};
+use clap::CommandFactory;
+use std::fs;
+use toml::{Table, Value};
+
+// the settings of a TOML config file as the command line options they stand for, in their order,
+// so they're checked and applied exactly like those: `port = 8080` is `--port 8080`, `spa = true`
+// is `--spa`, `metrics = false` is `--no-metrics` (and left out for flags without a `--no-` form,
+// which are off unless given), an array repeats the option, and keys in a table are prefixed with
+// its name, so `cert` in `[tls]` is `--tls-cert`; underscores work like dashes
+pub(crate) fn read(path: &str) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Could not read {}", path))?;
+    let table: Table = text
+        .parse()
+        .with_context(|| format!("Invalid config file {}", path))?;
+    let mut args = Vec::new();
+    add_table(&mut args, "", &table)?;
+    Ok(args)
+}
+
+fn add_table(args: &mut Vec<String>, prefix: &str, table: &Table) -> Result<()> {
+    for (key, value) in table {
+        let name = format!("{}{}", prefix, key.replace('_', "-"));
+        match value {
+            Value::Table(table) => add_table(args, &format!("{}-", name), table)?,
+            Value::Array(values) => {
+                for value in values {
+                    add_value(args, &name, value)?;
+                }
+            }
+            value => add_value(args, &name, value)?,
+        }
+    }
+    Ok(())
+}
+
+fn add_value(args: &mut Vec<String>, name: &str, value: &Value) -> Result<()> {
+    match value {
+        Value::Boolean(true) => args.push(format!("--{}", name)),
+        Value::Boolean(false) => {
+            let negated = format!("no-{}", name);
+            if Cli::command()
+                .get_arguments()
+                .any(|arg| arg.get_long() == Some(negated.as_str()))
+            {
+                args.push(format!("--{}", negated));
+            }
+        }
+        Value::String(value) => args.extend([format!("--{}", name), value.clone()]),
+        Value::Integer(value) => args.extend([format!("--{}", name), value.to_string()]),
+        Value::Float(value) => args.extend([format!("--{}", name), value.to_string()]),
+        _ => bail!("Unsupported value for {} in the config file!", name),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_config_file() {
+        let dir = TempDir::new("config-file");
+        let path = dir.join("config.toml");
+        let text = r#"
+            bind = ["0.0.0.0", "[::]:8080"]
+            port = 8000
+            mount = ["assets=/var/www:ro"]
+            max_body_size = 4096
+            spa = true
+            metrics = false
+            cors = false
+
+            [tls]
+            cert = "cert.pem"
+        "#;
+        fs::write(&path, text).unwrap();
+        let args = read(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            args,
+            [
+                "--bind",
+                "0.0.0.0",
+                "--bind",
+                "[::]:8080",
+                "--port",
+                "8000",
+                "--mount",
+                "assets=/var/www:ro",
+                "--max-body-size",
+                "4096",
+                "--spa",
+                "--no-metrics",
+                "--tls-cert",
+                "cert.pem"
+            ]
+        );
+
+        fs::write(&path, "port = [[1]]").unwrap();
+        assert!(read(path.to_str().unwrap()).is_err());
+        fs::write(&path, "port = ").unwrap();
+        assert!(read(path.to_str().unwrap()).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(read(path.to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+// parses a `Cookie` request header value, e.g. "a=1; b=2"
+pub fn parse_cookies(header: &str) -> HashMap<String, String> {
+    header
+        .split(';')
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            let name = name.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let value = value.trim().trim_matches('"');
+            Some((name.to_owned(), value.to_owned()))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    fn as_str(&self) -> &str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+// a cookie to be sent with `Set-Cookie`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    max_age: Option<u64>,
+    same_site: Option<SameSite>,
+    http_only: bool,
+    secure: bool,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: None,
+            max_age: None,
+            same_site: None,
+            http_only: false,
+            secure: false,
+        }
+    }
+
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn with_max_age(mut self, seconds: u64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn with_http_only(mut self) -> Self {
+        self.http_only = true;
+        self
+    }
+
+    pub fn with_secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+}
+
+impl Display for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age)?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site.as_str())?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        // browsers reject SameSite=None without Secure
+        if self.secure || self.same_site == Some(SameSite::None) {
+            write!(f, "; Secure")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cookies() {
+        let cookies = parse_cookies("session=abc123; theme=\"dark\";  empty=; =bad; flag");
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["session"], "abc123");
+        assert_eq!(cookies["theme"], "dark");
+        assert_eq!(cookies["empty"], "");
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        let cookie = Cookie::new("session", "abc123");
+        assert_eq!(cookie.to_string(), "session=abc123");
+
+        let cookie = Cookie::new("session", "abc123")
+            .with_path("/")
+            .with_max_age(3600)
+            .with_same_site(SameSite::Lax)
+            .with_http_only()
+            .with_secure();
+        assert_eq!(
+            cookie.to_string(),
+            "session=abc123; Path=/; Max-Age=3600; SameSite=Lax; HttpOnly; Secure"
+        );
+
+        let cookie = Cookie::new("id", "1").with_same_site(SameSite::None);
+        assert_eq!(cookie.to_string(), "id=1; SameSite=None; Secure");
+    }
+}
@@ -0,0 +1,405 @@
+use crate::app::{
+    check_allowed, check_writable, file_etag, modified_secs, request_mount, resolve_path,
+};
+use crate::date::format_http_date;
+use crate::extract::PathParams;
+use crate::http::{
+    decode_path, percent_encode_path, Method, Request, Response, ServerError, StatusCode, ALLOW,
+};
+use crate::listing::{self, escape_html};
+use crate::mime;
+use crate::router::Router;
+use crate::server::{Mount, State};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+const DAV: &str = "DAV";
+const DEPTH: &str = "Depth";
+const DESTINATION: &str = "Destination";
+const OVERWRITE: &str = "Overwrite";
+const APPLICATION_XML: &str = "application/xml; charset=utf-8";
+
+// the WebDAV methods on top of the file routes' GET, PUT and DELETE; class 1 only, there is no
+// locking
+const METHODS: [&str; 5] = ["OPTIONS", "PROPFIND", "MKCOL", "COPY", "MOVE"];
+// those that don't change anything
+const READ_METHODS: [&str; 2] = ["OPTIONS", "PROPFIND"];
+
+// adds the WebDAV methods to the router serving files, so clients like cadaver can mount it; when
+// read-only the writing ones aren't routed and get 405 like the other writes
+pub(crate) fn routes(router: Router, read_only: bool) -> Router {
+    METHODS.iter().fold(router, |router, method| {
+        let method = Method::Other(method.to_string());
+        match read_only && !READ_METHODS.contains(&method.as_str()) {
+            true => router.recognize(method),
+            false => router.route(method, "/*name", dav_handler),
+        }
+    })
+}
+
+fn dav_handler(
+    state: Arc<State>,
+    params: PathParams,
+    request: Request,
+) -> Result<Response, ServerError> {
+    let name = params.get("name").unwrap_or("");
+    let mount = request_mount(&state.config, &request)?;
+    let path = resolve_path(mount, &state.config, Path::new(name))?;
+    check_allowed(mount, Path::new(name), path.is_dir())?;
+    match request.method.as_str() {
+        "OPTIONS" => {
+            let allowed = state.router.allowed(&request.path);
+            let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+            Ok(Response::new(StatusCode::OK)
+                .with_header(DAV, "1")
+                .with_header(ALLOW, &allow.join(", ")))
+        }
+        "PROPFIND" => propfind(mount, &path, &request),
+        method => {
+            check_writable(&state, mount, &request)?;
+            match method {
+                "MKCOL" => mkcol(&path),
+                _ => copy_or_move(&state, mount, &path, &request),
+            }
+        }
+    }
+}
+
+// the properties of the resource and, with "Depth: 1", of a directory's entries; "infinity" is
+// refused as it could walk the whole tree
+fn propfind(mount: &Mount, path: &Path, request: &Request) -> Result<Response, ServerError> {
+    let Ok(metadata) = fs::metadata(path) else {
+        return Ok(Response::new(StatusCode::NOT_FOUND));
+    };
+    let depth = request.headers.get(DEPTH).unwrap_or("infinity");
+    if !matches!(depth, "0" | "1") {
+        let body = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+            <D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>\n";
+        return Ok(Response::new(StatusCode::FORBIDDEN)
+            .with_body(body)
+            .with_content_type_and_current_length(APPLICATION_XML));
+    }
+
+    // directories are addressed with a trailing slash
+    let mut href = request.path.clone();
+    if metadata.is_dir() && !href.ends_with('/') {
+        href.push('/');
+    }
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    write_response(&mut xml, &href, path, &metadata);
+    if metadata.is_dir() && depth == "1" {
+        for entry in listing::entries(path)? {
+            if !mount.allows(&entry.name, entry.is_dir) {
+                continue;
+            }
+            let child = path.join(&entry.name);
+            // entries that vanished since being listed are left out
+            let Ok(metadata) = fs::metadata(&child) else {
+                continue;
+            };
+            let suffix = if entry.is_dir { "/" } else { "" };
+            let href = format!("{}{}{}", href, entry.name, suffix);
+            write_response(&mut xml, &href, &child, &metadata);
+        }
+    }
+    xml.push_str("</D:multistatus>\n");
+    Ok(Response::new(StatusCode::MULTI_STATUS)
+        .with_body(&xml)
+        .with_content_type_and_current_length(APPLICATION_XML))
+}
+
+// a <D:response> with the live properties of the file or directory
+fn write_response(xml: &mut String, href: &str, path: &Path, metadata: &fs::Metadata) {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let _ = write!(
+        xml,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}</D:displayname>",
+        escape_html(&percent_encode_path(href)),
+        escape_html(&name)
+    );
+    if metadata.is_dir() {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let _ = write!(
+            xml,
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+             <D:getcontenttype>{}</D:getcontenttype><D:getetag>{}</D:getetag>",
+            metadata.len(),
+            escape_html(mime::content_type(path, &[])),
+            escape_html(&file_etag(metadata))
+        );
+    }
+    if let Some(modified) = modified_secs(metadata) {
+        let _ = write!(
+            xml,
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            format_http_date(modified)
+        );
+    }
+    xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n");
+}
+
+fn mkcol(path: &Path) -> Result<Response, ServerError> {
+    if path.exists() {
+        return Ok(Response::new(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    if !path.parent().is_some_and(Path::is_dir) {
+        return Err(ServerError::new(
+            StatusCode::CONFLICT,
+            "the parent directory doesn't exist",
+        ));
+    }
+    fs::create_dir(path)?;
+    Ok(Response::new(StatusCode::CREATED))
+}
+
+// COPY or MOVE to the Destination header's path, which must be under the same mount; an existing
+// destination is replaced unless "Overwrite: F" is sent
+fn copy_or_move(
+    state: &State,
+    mount: &Mount,
+    source: &Path,
+    request: &Request,
+) -> Result<Response, ServerError> {
+    let Some(destination) = request.headers.get(DESTINATION) else {
+        return Err(ServerError::new(
+            StatusCode::BAD_REQUEST,
+            "a Destination header is needed",
+        ));
+    };
+    let prefix = format!("/{}/", mount.name);
+    let Some(relative) = destination_path(destination).and_then(|path| {
+        let relative = path.strip_prefix(&prefix)?;
+        Some(relative.to_owned())
+    }) else {
+        return Err(ServerError::new(
+            StatusCode::BAD_GATEWAY,
+            "the destination isn't served from here",
+        ));
+    };
+    let moves = request.method.as_str() == "MOVE";
+    // "Depth: 0" copies a directory without its contents
+    let shallow = request.headers.get(DEPTH) == Some("0");
+    let conflict = match request.headers.get(OVERWRITE) {
+        Some("F") => Some(StatusCode::PRECONDITION_FAILED),
+        _ => None,
+    };
+    transfer(state, mount, source, &relative, moves, shallow, conflict)
+}
+
+// copies or moves the source to a path relative to the mount; an existing destination is
+// answered with `conflict`, or replaced if there's none, unless it's a directory with anything in
+// it, which is never deleted to make room
+pub(crate) fn transfer(
+    state: &State,
+    mount: &Mount,
+    source: &Path,
+    relative: &str,
+    moves: bool,
+    shallow: bool,
+    conflict: Option<StatusCode>,
+) -> Result<Response, ServerError> {
+    if !source.exists() {
+        return Ok(Response::new(StatusCode::NOT_FOUND));
+    }
+    if relative.trim_matches('/').is_empty() {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "the mount itself can't be replaced",
+        ));
+    }
+    let target = resolve_path(mount, &state.config, Path::new(relative))?;
+    check_allowed(mount, Path::new(relative), source.is_dir())?;
+
+    if target.starts_with(source) {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "a directory can't be copied or moved into itself",
+        ));
+    }
+    if source.starts_with(&target) {
+        return Err(ServerError::new(
+            StatusCode::FORBIDDEN,
+            "a directory can't be replaced with something inside it",
+        ));
+    }
+    if !target.parent().is_some_and(Path::is_dir) {
+        return Err(ServerError::new(
+            StatusCode::CONFLICT,
+            "the destination's parent directory doesn't exist",
+        ));
+    }
+    let existed = target.exists();
+    if existed {
+        if let Some(status) = conflict {
+            return Ok(Response::new(status));
+        }
+        if target.is_dir() && fs::read_dir(&target)?.next().is_some() {
+            return Err(ServerError::new(
+                StatusCode::CONFLICT,
+                "only empty directories can be replaced",
+            ));
+        }
+        remove(&target)?;
+    }
+
+    if moves {
+        fs::rename(source, &target)?;
+    } else if source.is_dir() {
+        copy_dir(source, &target, shallow)?;
+    } else {
+        fs::copy(source, &target)?;
+    }
+    state.preload.refresh(source);
+    state.preload.refresh(&target);
+    match existed {
+        true => Ok(Response::new(StatusCode::NO_CONTENT)),
+        false => Ok(Response::new(StatusCode::CREATED)),
+    }
+}
+
+// the decoded path of a Destination, which is usually an absolute URL
+fn destination_path(destination: &str) -> Option<String> {
+    let path = match destination.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/')?..],
+        None => destination,
+    };
+    decode_path(path)
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match path.is_dir() {
+        true => fs::remove_dir(path),
+        false => fs::remove_file(path),
+    }
+}
+
+// symlinks inside aren't copied, as they could lead outside the served directory
+fn copy_dir(source: &Path, target: &Path, shallow: bool) -> io::Result<()> {
+    fs::create_dir(target)?;
+    if shallow {
+        return Ok(());
+    }
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = target.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &target, false)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::handle_request;
+    use crate::test_util::{files_state, TempDir};
+
+    #[test]
+    fn test_webdav() {
+        let dir = TempDir::new("dav");
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("docs/a b.txt"), "hello").unwrap();
+        let state = files_state(&dir, |_| {});
+        let send = |method: &str, target: &str, headers: &[(&str, &str)]| {
+            let mut request = Request::new(Method::Other(method.to_owned()), target);
+            for (key, value) in headers {
+                request = request.with_header(key, value);
+            }
+            handle_request(state.clone(), request)
+        };
+
+        let res = send("OPTIONS", "/files/", &[]);
+        assert_eq!(res.headers.get(DAV), Some("1"));
+        assert_eq!(
+            res.headers.get(ALLOW),
+            Some("POST, GET, HEAD, PUT, DELETE, PATCH, OPTIONS, PROPFIND, MKCOL, COPY, MOVE")
+        );
+
+        let res = send("PROPFIND", "/files/docs/", &[(DEPTH, "1")]);
+        assert_eq!(res.status, StatusCode::MULTI_STATUS);
+        let mut xml = Vec::new();
+        res.body.write_to(&mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains("<D:href>/files/docs/</D:href>"));
+        assert!(xml.contains("<D:collection/>"));
+        assert!(xml.contains("<D:href>/files/docs/a%20b.txt</D:href>"));
+        assert!(xml.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        let res = send("PROPFIND", "/files/docs/", &[]);
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        let res = send("PROPFIND", "/files/nope", &[(DEPTH, "0")]);
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+
+        assert_eq!(send("MKCOL", "/files/new", &[]).status, StatusCode::CREATED);
+        assert_eq!(
+            send("MKCOL", "/files/new", &[]).status,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            send("MKCOL", "/files/x/y", &[]).status,
+            StatusCode::CONFLICT
+        );
+
+        let to = |path: &str| format!("http://localhost:4221{}", path);
+        let copy = [(DESTINATION, to("/files/new/copy.txt"))];
+        let copy: Vec<_> = copy.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let res = send("COPY", "/files/docs/a b.txt", &copy);
+        assert_eq!(res.status, StatusCode::CREATED);
+        assert_eq!(fs::read(dir.join("new/copy.txt")).unwrap(), b"hello");
+        let mut no_overwrite = copy.clone();
+        no_overwrite.push((OVERWRITE, "F"));
+        let res = send("COPY", "/files/docs/a b.txt", &no_overwrite);
+        assert_eq!(res.status, StatusCode::PRECONDITION_FAILED);
+
+        let destination = to("/files/moved/");
+        let res = send("MOVE", "/files/docs/", &[(DESTINATION, &destination)]);
+        assert_eq!(res.status, StatusCode::CREATED);
+        assert!(dir.join("moved/a b.txt").is_file());
+        assert!(!dir.join("docs").exists());
+
+        let destination = to("/elsewhere/x");
+        let res = send("MOVE", "/files/moved", &[(DESTINATION, &destination)]);
+        assert_eq!(res.status, StatusCode::BAD_GATEWAY);
+        let destination = to("/files/moved/inner");
+        let res = send("COPY", "/files/moved", &[(DESTINATION, &destination)]);
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+        // nothing gets deleted to make room: not the mount, a directory the source is in, or one
+        // with anything in it
+        let res = send(
+            "COPY",
+            "/files/new/copy.txt",
+            &[(DESTINATION, &to("/files/"))],
+        );
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        let destination = to("/files/moved");
+        let res = send(
+            "MOVE",
+            "/files/moved/a b.txt",
+            &[(DESTINATION, &destination)],
+        );
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        let res = send(
+            "COPY",
+            "/files/new/copy.txt",
+            &[(DESTINATION, &destination)],
+        );
+        assert_eq!(res.status, StatusCode::CONFLICT);
+        assert!(dir.join("moved/a b.txt").is_file());
+        assert!(dir.join("new/copy.txt").is_file());
+        assert_eq!(
+            send("LOCK", "/files/moved", &[]).status,
+            StatusCode::NOT_IMPLEMENTED
+        );
+    }
+}
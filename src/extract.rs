@@ -0,0 +1,124 @@
+use crate::form::Form;
+use crate::http::{Headers, Request, StatusCode, StatusError};
+use crate::server::State;
+use std::sync::Arc;
+
+// typed handler arguments built from the request, the error status is sent when it fails
+pub trait FromRequest: Sized {
+    fn from_request(state: &Arc<State>, request: &Request) -> Result<Self, StatusError>;
+}
+
+// the params captured by the matched route
+#[derive(Debug, PartialEq)]
+pub struct PathParams(pub Vec<(String, String)>);
+
+impl PathParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl FromRequest for PathParams {
+    fn from_request(_state: &Arc<State>, request: &Request) -> Result<Self, StatusError> {
+        Ok(Self(request.params.clone()))
+    }
+}
+
+impl FromRequest for Headers {
+    fn from_request(_state: &Arc<State>, request: &Request) -> Result<Self, StatusError> {
+        Ok(request.headers.clone())
+    }
+}
+
+impl FromRequest for Arc<State> {
+    fn from_request(state: &Arc<State>, _request: &Request) -> Result<Self, StatusError> {
+        Ok(Arc::clone(state))
+    }
+}
+
+// 415 unless the body is application/x-www-form-urlencoded
+impl FromRequest for Form {
+    fn from_request(_state: &Arc<State>, request: &Request) -> Result<Self, StatusError> {
+        Form::from_request(request).ok_or(StatusError(StatusCode::UNSUPPORTED_MEDIA_TYPE))
+    }
+}
+
+// the query string deserialized into T, 400 if it doesn't fit
+#[cfg(feature = "query")]
+#[derive(Debug, PartialEq)]
+pub struct Query<T>(pub T);
+
+#[cfg(feature = "query")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(_state: &Arc<State>, request: &Request) -> Result<Self, StatusError> {
+        serde_urlencoded::from_str(request.query().unwrap_or(""))
+            .map(Query)
+            .map_err(|_| StatusError(StatusCode::BAD_REQUEST))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::form::APPLICATION_FORM_URLENCODED;
+    use crate::http::{Method, Response, CONTENT_TYPE, USER_AGENT};
+    use crate::router::Router;
+    use crate::server::Config;
+
+    #[test]
+    fn test_extractors() {
+        let router = Router::new()
+            .get("/users/:id", |params: PathParams, headers: Headers| {
+                let body = format!(
+                    "{} {}",
+                    params.get("id").unwrap(),
+                    headers.get(USER_AGENT).unwrap_or("-")
+                );
+                Response::new(StatusCode::OK).with_body(&body)
+            })
+            .post("/form", |form: Form, request: Request| {
+                let body = format!("{}:{}", form.get("a").unwrap_or(""), request.path);
+                Response::new(StatusCode::OK).with_body(&body)
+            })
+            .get("/dir", |state: Arc<State>| {
+                Response::new(StatusCode::OK).with_body(&state.config.mounts[0].directory)
+            });
+        let state = Arc::new(State::new(Config::default()));
+        let handle = |request: Request| router.handle(state.clone(), request);
+
+        let res = handle(Request::new(Method::Get, "/users/7").with_header(USER_AGENT, "curl"));
+        assert_eq!(res.body, "7 curl");
+        assert_eq!(handle(Request::new(Method::Get, "/dir")).body, "lol");
+
+        let req = Request::new(Method::Post, "/form")
+            .with_header(CONTENT_TYPE, APPLICATION_FORM_URLENCODED)
+            .with_body("a=1");
+        assert_eq!(handle(req).body, "1:/form");
+        let res = handle(Request::new(Method::Post, "/form").with_body("a=1"));
+        assert_eq!(res.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[cfg(feature = "query")]
+    #[test]
+    fn test_query() {
+        #[derive(serde::Deserialize)]
+        struct Page {
+            page: u32,
+            q: Option<String>,
+        }
+
+        let router = Router::new().get("/search", |Query(page): Query<Page>| {
+            let body = format!("{} {:?}", page.page, page.q);
+            Response::new(StatusCode::OK).with_body(&body)
+        });
+        let state = Arc::new(State::new(Config::default()));
+        let get = |path: &str| router.handle(state.clone(), Request::new(Method::Get, path));
+
+        assert_eq!(get("/search?page=2&q=a+b").body, r#"2 Some("a b")"#);
+        assert_eq!(get("/search?page=x").status, StatusCode::BAD_REQUEST);
+        assert_eq!(get("/search").status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::http::{percent_decode, Request, CONTENT_TYPE};
+
+pub const APPLICATION_FORM_URLENCODED: &str = "application/x-www-form-urlencoded";
+
+// decoded key/value pairs of an application/x-www-form-urlencoded body
+#[derive(Debug, PartialEq)]
+pub struct Form {
+    pairs: Vec<(String, String)>,
+}
+
+impl Form {
+    pub fn parse(body: &[u8]) -> Self {
+        Self {
+            pairs: parse_urlencoded(&String::from_utf8_lossy(body)),
+        }
+    }
+
+    // the request's form, if it was sent as application/x-www-form-urlencoded
+    pub fn from_request(request: &Request) -> Option<Self> {
+        let content_type = request.headers.get(CONTENT_TYPE)?;
+        let media_type = content_type.split(';').next()?.trim();
+        media_type
+            .eq_ignore_ascii_case(APPLICATION_FORM_URLENCODED)
+            .then(|| Self::parse(&request.body))
+    }
+
+    // first value of a key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn pairs(&self) -> &[(String, String)] {
+        &self.pairs
+    }
+}
+
+// parses "a=1&b=two+words", as used by both query strings and form bodies
+pub fn parse_urlencoded(s: &str) -> Vec<(String, String)> {
+    s.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_component(key), decode_component(value))
+        })
+        .collect()
+}
+
+// components additionally use '+' for spaces; malformed input is kept as is
+fn decode_component(s: &str) -> String {
+    let s = s.replace('+', " ");
+    percent_decode(&s).unwrap_or(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    #[test]
+    fn test_form() {
+        let form = Form::parse(b"name=Jane+Doe&note=50%25+off&empty=&name=again");
+        assert_eq!(form.get("name"), Some("Jane Doe"));
+        assert_eq!(form.get("note"), Some("50% off"));
+        assert_eq!(form.get("empty"), Some(""));
+        assert_eq!(form.get("missing"), None);
+        assert_eq!(form.pairs().len(), 4);
+
+        let req = Request::new(Method::Post, "/echo")
+            .with_header(
+                CONTENT_TYPE,
+                "application/x-www-form-urlencoded; charset=utf-8",
+            )
+            .with_body("a=1");
+        assert_eq!(Form::from_request(&req).unwrap().get("a"), Some("1"));
+
+        let req = Request::new(Method::Post, "/echo").with_body("a=1");
+        assert_eq!(Form::from_request(&req), None);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::http::{Response, StatusCode};
+use crate::server::State;
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+// liveness: answering at all is the proof
+pub(crate) fn healthz_handler() -> Response {
+    Response::new(StatusCode::OK).with_body("ok\n")
+}
+
+// readiness: 503 with the reasons while new connections would be turned away or a mount
+// can't be read, so a load balancer sends traffic elsewhere
+pub(crate) fn readyz_handler(state: Arc<State>) -> Response {
+    let problems = problems(&state);
+    if problems.is_empty() {
+        return Response::new(StatusCode::OK).with_body("ok\n");
+    }
+    let body: String = problems
+        .iter()
+        .map(|problem| format!("{}\n", problem))
+        .collect();
+    Response::new(StatusCode::SERVICE_UNAVAILABLE).with_body(&body)
+}
+
+fn problems(state: &State) -> Vec<String> {
+    let config = &state.config;
+    let mut problems = Vec::new();
+    for mount in &config.mounts {
+        if let Err(e) = fs::read_dir(&mount.directory) {
+            problems.push(format!("/{}/ can't be read: {}", mount.name, e));
+        }
+    }
+    if state.queued_connections.load(Ordering::SeqCst) >= config.queue_size {
+        problems.push("the worker pool's queue is full".to_owned());
+    }
+    if state.active_connections.load(Ordering::SeqCst) >= config.max_connections {
+        problems.push("the connection limit is reached".to_owned());
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{files_state, get, TempDir};
+
+    #[test]
+    fn test_health() {
+        let dir = TempDir::new("health");
+        let state = files_state(&dir, |config| config.queue_size = 2);
+
+        assert_eq!(get(&state, "/healthz").status, StatusCode::OK);
+        let res = get(&state, "/readyz");
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "ok\n");
+
+        state.queued_connections.store(2, Ordering::SeqCst);
+        let res = get(&state, "/readyz");
+        assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.body, "the worker pool's queue is full\n");
+        state.queued_connections.store(0, Ordering::SeqCst);
+
+        fs::remove_dir(&dir).unwrap();
+        assert_eq!(
+            get(&state, "/readyz").status,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(problems(&state)[0].starts_with("/files/ can't be read"));
+        assert_eq!(get(&state, "/healthz").status, StatusCode::OK);
+    }
+}
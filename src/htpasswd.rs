@@ -0,0 +1,160 @@
+use crate::base64;
+use crate::middleware::constant_time_eq;
+use crate::sha1::sha1;
+use crate::sha256::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+// the users of an Apache htpasswd file, read again whenever the file changes so users can be added
+// and removed while the server runs; bcrypt ("$2y$...", from htpasswd -B) and SHA-1 ("{SHA}...",
+// from htpasswd -s) passwords are understood
+pub(crate) struct Htpasswd {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+#[derive(Default)]
+struct Loaded {
+    read: bool,
+    // modification time and size of the file when it was read, None if it was missing
+    version: Option<(SystemTime, u64)>,
+    users: HashMap<String, String>,
+    // SHA-256 of passwords bcrypt has accepted for each user, so a client sending its credentials
+    // with every request doesn't cost a deliberately slow bcrypt run each time
+    verified: HashMap<String, [u8; 32]>,
+}
+
+impl Htpasswd {
+    pub(crate) fn new(path: &str) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            loaded: Mutex::new(Loaded::default()),
+        }
+    }
+
+    pub(crate) fn verify(&self, name: &str, password: &str) -> bool {
+        let digest = {
+            let mut hasher = Sha256::new();
+            hasher.update(password.as_bytes());
+            hasher.finish()
+        };
+        let hash = {
+            let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+            self.reload_if_changed(&mut loaded);
+            if let Some(verified) = loaded.verified.get(name) {
+                return constant_time_eq(verified, &digest);
+            }
+            match loaded.users.get(name) {
+                Some(hash) => hash.clone(),
+                None => return false,
+            }
+        };
+
+        if let Some(encoded) = hash.strip_prefix("{SHA}") {
+            let expected = base64::decode(encoded).unwrap_or_default();
+            return constant_time_eq(&sha1(password.as_bytes()), &expected);
+        }
+        if !hash.starts_with("$2") {
+            return false;
+        }
+        // outside the lock, it takes a while on purpose
+        if !bcrypt::verify(password, &hash).unwrap_or(false) {
+            return false;
+        }
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        // unless the file changed meanwhile
+        if loaded.users.get(name) == Some(&hash) {
+            loaded.verified.insert(name.to_owned(), digest);
+        }
+        true
+    }
+
+    fn reload_if_changed(&self, loaded: &mut Loaded) {
+        let version = fs::metadata(&self.path)
+            .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+            .ok();
+        if loaded.read && version == loaded.version {
+            return;
+        }
+        let users = match fs::read_to_string(&self.path) {
+            Ok(text) => parse(&text),
+            Err(e) => {
+                // nobody gets in rather than whoever was in the old file
+                warn!(path = %self.path.display(), error = %e, "reading the htpasswd file failed");
+                HashMap::new()
+            }
+        };
+        if loaded.read {
+            info!(path = %self.path.display(), users = users.len(), "htpasswd file reloaded");
+        }
+        *loaded = Loaded {
+            read: true,
+            version,
+            users,
+            verified: HashMap::new(),
+        };
+    }
+}
+
+// "name:hash" lines, skipping blank ones and comments
+fn parse(text: &str) -> HashMap<String, String> {
+    let mut users = HashMap::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, hash)) = line.split_once(':') else {
+            continue;
+        };
+        if !hash.starts_with("$2") && !hash.starts_with("{SHA}") {
+            warn!(
+                user = name,
+                "only bcrypt and {{SHA}} htpasswd passwords are supported"
+            );
+            continue;
+        }
+        users.insert(name.to_owned(), hash.to_owned());
+    }
+    users
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_htpasswd() {
+        let path =
+            env::temp_dir().join(format!("http-server-rust-{}.htpasswd", std::process::id()));
+        let bcrypted = bcrypt::hash("opensesame", 4).unwrap();
+        // htpasswd -nbs jasmine tiger
+        let sha = "{SHA}RuPXcqGIjq3/JsetpH/XUC15bgc=";
+        let text = format!(
+            "# users\naladdin:{}\njasmine:{}\n\nabu:$apr1$salt$hash\n",
+            bcrypted, sha
+        );
+        fs::write(&path, text).unwrap();
+        let htpasswd = Htpasswd::new(path.to_str().unwrap());
+
+        assert!(htpasswd.verify("aladdin", "opensesame"));
+        // the second time from what was remembered
+        assert!(htpasswd.verify("aladdin", "opensesame"));
+        assert!(!htpasswd.verify("aladdin", "opensesam"));
+        assert!(htpasswd.verify("jasmine", "tiger"));
+        assert!(!htpasswd.verify("jasmine", "Tiger"));
+        assert!(!htpasswd.verify("abu", "banana"));
+        assert!(!htpasswd.verify("nobody", ""));
+
+        // a changed file is read again, forgetting what was remembered
+        fs::write(&path, format!("jasmine:{}\n# aladdin is gone\n", sha)).unwrap();
+        assert!(!htpasswd.verify("aladdin", "opensesame"));
+        assert!(htpasswd.verify("jasmine", "tiger"));
+        fs::remove_file(&path).unwrap();
+        assert!(!htpasswd.verify("jasmine", "tiger"));
+    }
+}
@@ -0,0 +1,1414 @@
+use crate::accept;
+use crate::cookies::{parse_cookies, Cookie};
+use crate::date;
+use crate::form;
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, ErrorKind, Read, Seek, Write};
+use std::net::{IpAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// header keys
+pub const ACCEPT: &str = "Accept";
+pub const ACCEPT_ENCODING: &str = "Accept-Encoding";
+pub const ACCESS_CONTROL_ALLOW_CREDENTIALS: &str = "Access-Control-Allow-Credentials";
+pub const ACCESS_CONTROL_ALLOW_HEADERS: &str = "Access-Control-Allow-Headers";
+pub const ACCESS_CONTROL_ALLOW_METHODS: &str = "Access-Control-Allow-Methods";
+pub const ACCESS_CONTROL_ALLOW_ORIGIN: &str = "Access-Control-Allow-Origin";
+pub const ACCESS_CONTROL_EXPOSE_HEADERS: &str = "Access-Control-Expose-Headers";
+pub const ACCESS_CONTROL_MAX_AGE: &str = "Access-Control-Max-Age";
+pub const ACCESS_CONTROL_REQUEST_HEADERS: &str = "Access-Control-Request-Headers";
+pub const ACCESS_CONTROL_REQUEST_METHOD: &str = "Access-Control-Request-Method";
+pub const ALLOW: &str = "Allow";
+pub const AUTHORIZATION: &str = "Authorization";
+pub const CACHE_CONTROL: &str = "Cache-Control";
+pub const CONNECTION: &str = "Connection";
+pub const CONTENT_DISPOSITION: &str = "Content-Disposition";
+pub const CONTENT_ENCODING: &str = "Content-Encoding";
+pub const CONTENT_LENGTH: &str = "Content-Length";
+pub const CONTENT_RANGE: &str = "Content-Range";
+pub const CONTENT_SECURITY_POLICY: &str = "Content-Security-Policy";
+pub const CONTENT_TYPE: &str = "Content-Type";
+pub const COOKIE: &str = "Cookie";
+pub const DATE: &str = "Date";
+pub const ETAG: &str = "ETag";
+pub const FORWARDED: &str = "Forwarded";
+pub const HOST: &str = "Host";
+pub const IF_MATCH: &str = "If-Match";
+pub const IF_UNMODIFIED_SINCE: &str = "If-Unmodified-Since";
+pub const LAST_MODIFIED: &str = "Last-Modified";
+pub const LOCATION: &str = "Location";
+pub const ORIGIN: &str = "Origin";
+pub const RANGE: &str = "Range";
+pub const REFERER: &str = "Referer";
+pub const REFERRER_POLICY: &str = "Referrer-Policy";
+pub const RETRY_AFTER: &str = "Retry-After";
+pub const SERVER: &str = "Server";
+pub const TRANSFER_ENCODING: &str = "Transfer-Encoding";
+pub const SET_COOKIE: &str = "Set-Cookie";
+pub const UPGRADE: &str = "Upgrade";
+pub const USER_AGENT: &str = "User-Agent";
+pub const VARY: &str = "Vary";
+pub const X_CSRF_TOKEN: &str = "X-CSRF-Token";
+pub const WWW_AUTHENTICATE: &str = "WWW-Authenticate";
+pub const X_CONTENT_TYPE_OPTIONS: &str = "X-Content-Type-Options";
+pub const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+pub const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+pub const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+pub const X_FRAME_OPTIONS: &str = "X-Frame-Options";
+pub const X_REQUEST_ID: &str = "X-Request-Id";
+pub const X_RESPONSE_TIME: &str = "X-Response-Time";
+
+// header content types
+pub const TEXT_PLAIN: &str = "text/plain";
+pub const TEXT_HTML: &str = "text/html";
+pub const APPLICATION_JSON: &str = "application/json";
+
+// header map keeping every value of repeated keys, compared case-insensitively
+#[derive(Debug, Clone, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn append(&mut self, key: &str, value: &str) {
+        self.entries.push((key.to_owned(), value.to_owned()));
+    }
+
+    // replaces all existing values of the key
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        self.append(key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+// whether any of the comma-separated header values contains the token
+pub(crate) fn has_token<'a>(mut values: impl Iterator<Item = &'a str>, token: &str) -> bool {
+    values.any(|value| {
+        value
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub(crate) query: Option<String>,
+    pub version: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    // holds the body instead of `body` on paths in ParserConfig::stream_prefixes
+    pub(crate) body_file: Option<File>,
+    // captured by the router from ":name" segments of the matched route
+    pub(crate) params: Vec<(String, String)>,
+    // set on TLS connections where the client presented a verified certificate
+    pub client: Option<ClientIdentity>,
+    // the address of the other end of the connection, None for requests built by hand
+    pub peer: Option<IpAddr>,
+    // the client a trusted proxy forwarded the request for, see Config::trusted_proxies
+    pub(crate) forwarded_for: Option<IpAddr>,
+}
+
+// who a TLS client proved to be with its certificate
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity {
+    // e.g. "CN=alice, O=Example"
+    pub subject: String,
+    // DNS names, email addresses and URIs of the subject alternative names
+    pub sans: Vec<String>,
+}
+
+impl Request {
+    // value of a ":name" segment of the matched route
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // the body wherever it was read to, for handlers on streamed paths
+    pub fn body_reader(&mut self) -> Box<dyn Read + '_> {
+        match &mut self.body_file {
+            Some(file) => Box::new(file),
+            None => Box::new(self.body.as_slice()),
+        }
+    }
+
+    // body as text, with invalid UTF-8 replaced
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    // decoded key/value pairs of the query string, in order of appearance
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        match &self.query {
+            Some(query) => form::parse_urlencoded(query),
+            None => Vec::new(),
+        }
+    }
+
+    // host name from the Host header, without port or IPv6 brackets
+    pub fn host(&self) -> Option<&str> {
+        let (host, _) = split_host_port(self.headers.get(HOST)?)?;
+        Some(host)
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        split_host_port(self.headers.get(HOST)?)?.1
+    }
+
+    // a copy without the body, for looking at the request after it was handled
+    pub(crate) fn head(&self) -> Request {
+        Request {
+            method: self.method.clone(),
+            path: self.path.clone(),
+            query: self.query.clone(),
+            version: self.version.clone(),
+            headers: self.headers.clone(),
+            body: Vec::new(),
+            body_file: None,
+            params: self.params.clone(),
+            client: self.client.clone(),
+            peer: self.peer,
+            forwarded_for: self.forwarded_for,
+        }
+    }
+
+    // whether the client asked for the connection to be closed after this request
+    pub fn wants_close(&self) -> bool {
+        has_token(self.headers.get_all(CONNECTION), "close")
+    }
+
+    // the real client's address: the peer's, unless it's a trusted proxy that said who it forwarded
+    // the request for
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.forwarded_for.or(self.peer)
+    }
+
+    // set by the request_id middleware
+    pub fn request_id(&self) -> Option<&str> {
+        self.headers.get(X_REQUEST_ID)
+    }
+
+    // cookies from all `Cookie` headers
+    pub fn cookies(&self) -> HashMap<String, String> {
+        self.headers
+            .get_all(COOKIE)
+            .flat_map(parse_cookies)
+            .collect()
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut headers = String::new();
+        for (key, value) in self.headers.iter() {
+            headers.push_str(&format!("{}: {}\r\n", key, value));
+        }
+
+        let query = match &self.query {
+            Some(query) => format!("?{}", query),
+            None => String::new(),
+        };
+
+        write!(
+            f,
+            "{} {}{} {}\r\n{}\r\n{}",
+            self.method.as_str(),
+            self.path,
+            query,
+            self.version,
+            headers,
+            self.text()
+        )
+    }
+}
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    // streamed to the client in chunks, with the length taken from the file metadata
+    File(File, u64),
+    // produced while it's sent, of a length not known up front, so it goes out with chunked
+    // transfer encoding; an error while reading cuts the response short
+    Stream(Box<dyn Read + Send>),
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(bytes).finish(),
+            Body::File(file, len) => f.debug_tuple("File").field(file).field(len).finish(),
+            Body::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+impl Body {
+    // 0 for streams, whose length isn't known
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File(_, len) => *len,
+            Body::Stream(_) => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // the number of bytes of the body written, not counting chunk framing
+    pub fn write_to<W: Write>(self, stream: &mut W) -> io::Result<u64> {
+        match self {
+            Body::Bytes(bytes) => stream.write_all(&bytes).map(|()| bytes.len() as u64),
+            Body::File(file, len) => {
+                let copied = io::copy(&mut file.take(len), stream)?;
+                if copied < len {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "file truncated"));
+                }
+                Ok(len)
+            }
+            Body::Stream(mut reader) => {
+                let mut buf = vec![0; 64 * 1024];
+                let mut written = 0;
+                loop {
+                    let n = match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    write!(stream, "{:x}\r\n", n)?;
+                    stream.write_all(&buf[..n])?;
+                    stream.write_all(b"\r\n")?;
+                    written += n as u64;
+                }
+                stream.write_all(b"0\r\n\r\n")?;
+                Ok(written)
+            }
+        }
+    }
+
+    fn is_stream(&self) -> bool {
+        matches!(self, Body::Stream(_))
+    }
+}
+
+// the client's connection after a response took it over, reading what the client sent past the
+// request first
+pub struct Upgraded<'a> {
+    buffered: io::Cursor<Vec<u8>>,
+    stream: &'a mut dyn Connection,
+    // the socket underneath, for its timeouts; None for connections that aren't sockets
+    socket: Option<&'a TcpStream>,
+}
+
+pub(crate) trait Connection: Read + Write {}
+
+impl<S: Read + Write> Connection for S {}
+
+impl<'a> Upgraded<'a> {
+    pub(crate) fn new(
+        buffered: Vec<u8>,
+        stream: &'a mut dyn Connection,
+        socket: Option<&'a TcpStream>,
+    ) -> Self {
+        Self {
+            buffered: io::Cursor::new(buffered),
+            stream,
+            socket,
+        }
+    }
+
+    // reads fail with WouldBlock or TimedOut after waiting this long, as on a TcpStream
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.socket {
+            Some(socket) => socket.set_read_timeout(timeout),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.socket {
+            Some(socket) => socket.set_write_timeout(timeout),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Read for Upgraded<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.buffered.read(buf)? {
+            0 => self.stream.read(buf),
+            n => Ok(n),
+        }
+    }
+}
+
+impl Write for Upgraded<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl PartialEq<&str> for Body {
+    fn eq(&self, other: &&str) -> bool {
+        match self {
+            Body::Bytes(bytes) => bytes == other.as_bytes(),
+            Body::File(..) | Body::Stream(_) => false,
+        }
+    }
+}
+
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
+    pub(crate) suppressed_headers: Vec<String>,
+    // takes over the connection once the head is sent, see Response::upgrade
+    pub(crate) upgrade: Option<OnUpgrade>,
+    // answers HEAD: the head is written as it would be for GET, Content-Length included, the body
+    // isn't
+    pub(crate) omit_body: bool,
+}
+
+pub(crate) type OnUpgrade = Box<dyn FnOnce(Upgraded) + Send>;
+
+impl Response {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            headers: Headers::new(),
+            body: Body::Bytes(Vec::new()),
+            suppressed_headers: Vec::new(),
+            upgrade: None,
+            omit_body: false,
+        }
+    }
+
+    // hands the connection to `on_upgrade` right after the head is sent, without a body or any
+    // further requests, e.g. for a tunnel or a WebSocket; the connection is closed when it returns
+    pub fn upgrade<F>(mut self, on_upgrade: F) -> Self
+    where
+        F: FnOnce(Upgraded) + Send + 'static,
+    {
+        self.upgrade = Some(Box::new(on_upgrade));
+        self
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key, value);
+        self
+    }
+
+    // keeps a configured default header from being added to this response
+    pub fn without_default_header(mut self, key: &str) -> Self {
+        self.suppressed_headers.push(key.to_owned());
+        self
+    }
+
+    pub(crate) fn closes_connection(&self) -> bool {
+        has_token(self.headers.get_all(CONNECTION), "close")
+    }
+
+    // adds default headers the handler neither set nor suppressed
+    pub(crate) fn apply_default_headers(&mut self, defaults: &Headers) {
+        for (key, value) in defaults.iter() {
+            let suppressed = self
+                .suppressed_headers
+                .iter()
+                .any(|k| k.eq_ignore_ascii_case(key));
+            if !suppressed && self.headers.get(key).is_none() {
+                self.headers.append(key, value);
+            }
+        }
+    }
+
+    pub fn with_appended_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.append(key, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: &str) -> Self {
+        self.body = Body::Bytes(body.as_bytes().to_vec());
+        self
+    }
+
+    pub fn with_file(mut self, file: File, len: u64) -> Self {
+        self.body = Body::File(file, len);
+        self
+    }
+
+    pub fn with_cookie(self, cookie: &Cookie) -> Self {
+        self.with_appended_header(SET_COOKIE, &cookie.to_string())
+    }
+
+    pub fn with_stream(mut self, reader: impl Read + Send + 'static) -> Self {
+        self.body = Body::Stream(Box::new(reader));
+        self
+    }
+
+    // streams are sent chunked instead
+    pub fn with_content_type_and_current_length(self, content_type: &str) -> Self {
+        if self.body.is_stream() {
+            return self.with_header(CONTENT_TYPE, content_type);
+        }
+        let body_length = self.body.len().to_string();
+        self.with_header(CONTENT_TYPE, content_type)
+            .with_header(CONTENT_LENGTH, body_length.as_str())
+    }
+
+    // renders the representation the client's `Accept` header prefers, or 406 if none fits
+    pub fn negotiated(
+        request: &Request,
+        available: &[&str],
+        render: impl FnOnce(&str) -> String,
+    ) -> Self {
+        match accept::negotiate(request.headers.get(ACCEPT), available) {
+            Some(content_type) => Response::new(StatusCode::OK)
+                .with_body(&render(content_type))
+                .with_content_type_and_current_length(content_type)
+                .with_header(VARY, ACCEPT),
+            None => Response::new(StatusCode::NOT_ACCEPTABLE).with_header(VARY, ACCEPT),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Method {
+    Get,
+    // answered by GET routes unless a path has its own, without the body
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    // any other syntactically valid method, answered with 501
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Other(method) => method,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusCode(u16);
+
+impl StatusCode {
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const ACCEPTED: StatusCode = StatusCode(202);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const PARTIAL_CONTENT: StatusCode = StatusCode(206);
+    pub const MULTI_STATUS: StatusCode = StatusCode(207);
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const FOUND: StatusCode = StatusCode(302);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const PERMANENT_REDIRECT: StatusCode = StatusCode(308);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const UNAUTHORIZED: StatusCode = StatusCode(401);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const NOT_ACCEPTABLE: StatusCode = StatusCode(406);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const CONFLICT: StatusCode = StatusCode(409);
+    pub const PRECONDITION_FAILED: StatusCode = StatusCode(412);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const URI_TOO_LONG: StatusCode = StatusCode(414);
+    pub const UNSUPPORTED_MEDIA_TYPE: StatusCode = StatusCode(415);
+    pub const RANGE_NOT_SATISFIABLE: StatusCode = StatusCode(416);
+    pub const TOO_MANY_REQUESTS: StatusCode = StatusCode(429);
+    pub const UPGRADE_REQUIRED: StatusCode = StatusCode(426);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const BAD_GATEWAY: StatusCode = StatusCode(502);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const GATEWAY_TIMEOUT: StatusCode = StatusCode(504);
+}
+
+impl StatusCode {
+    // any three-digit code, e.g. one an upstream server answered
+    pub fn from_u16(code: u16) -> Option<Self> {
+        (100..1000).contains(&code).then_some(Self(code))
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.0 >= 400
+    }
+
+    // canonical reason phrase, empty for unknown codes
+    pub fn reason_phrase(&self) -> &'static str {
+        match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            207 => "Multi-Status",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            406 => "Not Acceptable",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            410 => "Gone",
+            411 => "Length Required",
+            412 => "Precondition Failed",
+            413 => "Payload Too Large",
+            414 => "URI Too Long",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            417 => "Expectation Failed",
+            422 => "Unprocessable Content",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+}
+
+impl From<u16> for StatusCode {
+    fn from(code: u16) -> Self {
+        StatusCode(code)
+    }
+}
+
+impl Display for StatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason_phrase())
+    }
+}
+
+// limits in bytes applied while reading a request
+#[derive(Debug, Clone)]
+pub struct ParserConfig {
+    pub max_request_line: usize,
+    pub max_header_line: usize,
+    pub max_headers_size: usize,
+    pub max_body_size: usize,
+    // body size limits for paths starting with a prefix, overriding `max_body_size`
+    pub route_max_body_sizes: Vec<(String, usize)>,
+    // bodies of requests under these prefixes are written to a temporary file in chunks instead
+    // of being held in memory; handlers read them with Request::body_reader
+    pub stream_prefixes: Vec<String>,
+    // accept bare LF line endings in addition to CRLF
+    pub lenient_line_endings: bool,
+}
+
+impl ParserConfig {
+    // the limit of the longest matching prefix applies
+    pub(crate) fn max_body_size_for(&self, path: &str) -> usize {
+        self.route_max_body_sizes
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.max_body_size, |(_, size)| *size)
+    }
+
+    pub(crate) fn streams(&self, path: &str) -> bool {
+        self.stream_prefixes
+            .iter()
+            .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_line: 8 * 1024,
+            max_headers_size: 64 * 1024,
+            max_body_size: 1024 * 1024,
+            route_max_body_sizes: Vec::new(),
+            stream_prefixes: Vec::new(),
+            lenient_line_endings: false,
+        }
+    }
+}
+
+// error carrying the status the client should receive instead of a plain 400
+#[derive(Debug)]
+pub struct StatusError(pub StatusCode);
+
+impl Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+// error a handler returns instead of panicking; anything convertible to anyhow::Error turns into
+// a 500, or into the status of a StatusError it wraps
+#[derive(Debug)]
+pub struct ServerError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ServerError {
+    pub fn new(status: StatusCode, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ServerError {
+    fn from(error: E) -> Self {
+        let error = error.into();
+        let status = match error.downcast_ref::<StatusError>() {
+            Some(StatusError(status)) => *status,
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self {
+            status,
+            message: error.to_string(),
+        }
+    }
+}
+
+// reads a line of at most `limit` bytes (line ending included), failing with `status` beyond that
+fn read_line_limited<R: BufRead>(
+    reader: &mut R,
+    limit: usize,
+    status: StatusCode,
+) -> Result<String> {
+    let mut line = Vec::new();
+    match reader.take(limit as u64 + 1).read_until(b'\n', &mut line) {
+        Ok(_) => {}
+        // the connection loop only starts parsing once data arrived, so the head is partial
+        Err(e) if is_timeout(&e) => bail!(StatusError(StatusCode::REQUEST_TIMEOUT)),
+        Err(e) => return Err(e.into()),
+    }
+    if line.len() > limit {
+        bail!(StatusError(status));
+    }
+    Ok(String::from_utf8(line)?)
+}
+
+// removes the CRLF (or, when lenient, LF) a line must end with
+fn strip_line_ending(line: &str, lenient: bool) -> Result<&str> {
+    if let Some(line) = line.strip_suffix("\r\n") {
+        return Ok(line);
+    }
+    match line.strip_suffix('\n') {
+        Some(line) if lenient => Ok(line),
+        _ => bail!("invalid line ending"),
+    }
+}
+
+pub fn parse_to_request<R: BufRead>(reader: &mut R, parser: &ParserConfig) -> Result<Request> {
+    let (request, content_length) = parse_head(reader, parser)?;
+    read_request_body(reader, request, content_length, parser)
+}
+
+// the body following a head parse_head returned, held in memory or streamed to a file
+pub(crate) fn read_request_body<R: BufRead>(
+    reader: &mut R,
+    mut request: Request,
+    content_length: usize,
+    parser: &ParserConfig,
+) -> Result<Request> {
+    if content_length > 0 && parser.streams(&request.path) {
+        request.body_file = Some(stream_body(reader, content_length)?);
+    } else {
+        request.body = read_body(reader, content_length)?;
+    }
+    Ok(request)
+}
+
+// the request line and headers, returning the request without its body and the body's length
+pub(crate) fn parse_head<R: BufRead>(
+    reader: &mut R,
+    parser: &ParserConfig,
+) -> Result<(Request, usize)> {
+    let line = read_line_limited(reader, parser.max_request_line, StatusCode::URI_TOO_LONG)?;
+    let line = strip_line_ending(&line, parser.lenient_line_endings)?;
+
+    let parts: Vec<_> = line.splitn(3, ' ').collect();
+    if parts.len() != 3 {
+        bail!("invalid request");
+    }
+
+    let method = match parts[0] {
+        "GET" => Method::Get,
+        "HEAD" => Method::Head,
+        "POST" => Method::Post,
+        "PUT" => Method::Put,
+        "DELETE" => Method::Delete,
+        "PATCH" => Method::Patch,
+        s if is_token(s) => Method::Other(s.to_owned()),
+        _ => bail!("invalid method"),
+    };
+
+    let (path, query) = split_target(parts[1]);
+    let Some(path) = decode_path(&path) else {
+        bail!("invalid path");
+    };
+
+    let version = match parts[2] {
+        s if s == "HTTP/1.1" => s.to_owned(),
+        _ => bail!("invalid version"),
+    };
+
+    let mut headers = Headers::new();
+    let mut headers_size = 0;
+
+    loop {
+        let line = read_line_limited(
+            reader,
+            parser.max_header_line,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        )?;
+        headers_size += line.len();
+        if headers_size > parser.max_headers_size {
+            bail!(StatusError(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE));
+        }
+        let line = strip_line_ending(&line, parser.lenient_line_endings)?;
+        if line.is_empty() {
+            break;
+        }
+        // obsolete line folding would let a proxy and us disagree on header values
+        if line.starts_with([' ', '\t']) {
+            bail!("obsolete header folding");
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            bail!("invalid header");
+        };
+        if !is_token(key) {
+            bail!("invalid header name");
+        }
+        headers.append(key, value.trim_matches([' ', '\t']));
+    }
+
+    // HTTP/1.1 requires exactly one valid Host header
+    let hosts: Vec<_> = headers.get_all(HOST).collect();
+    if hosts.len() != 1 || split_host_port(hosts[0]).is_none() {
+        bail!("invalid host");
+    }
+
+    let content_length = parse_content_length(&headers)?;
+
+    if content_length > parser.max_body_size_for(&path) {
+        bail!(StatusError(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    let request = Request {
+        method,
+        path,
+        query,
+        version,
+        headers,
+        body: Vec::new(),
+        body_file: None,
+        params: Vec::new(),
+        client: None,
+        peer: None,
+        forwarded_for: None,
+    };
+    Ok((request, content_length))
+}
+
+// reads exactly `content_length` bytes; a client that sends less runs into the read timeout
+pub(crate) fn read_body<R: BufRead>(reader: &mut R, content_length: usize) -> Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(content_length);
+    match reader.take(content_length as u64).read_to_end(&mut body) {
+        Ok(n) if n == content_length => Ok(body),
+        Ok(_) => bail!("body shorter than content-length"),
+        Err(e) if is_timeout(&e) => bail!(StatusError(StatusCode::REQUEST_TIMEOUT)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// like read_body, but into a temporary file
+fn stream_body<R: BufRead>(reader: &mut R, content_length: usize) -> Result<File> {
+    let mut file = temp_file()?;
+    match io::copy(&mut reader.take(content_length as u64), &mut file) {
+        Ok(n) if n == content_length as u64 => {}
+        Ok(_) => bail!("body shorter than content-length"),
+        Err(e) if is_timeout(&e) => bail!(StatusError(StatusCode::REQUEST_TIMEOUT)),
+        Err(e) => return Err(e.into()),
+    }
+    file.rewind()?;
+    Ok(file)
+}
+
+// an unnamed file in the system's temporary directory, gone once closed (on Unix the name is
+// removed right away, elsewhere removing it may fail and leave it behind)
+pub(crate) fn temp_file() -> io::Result<File> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = format!(
+        "http-server-rust-body-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let path = std::env::temp_dir().join(name);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    let _ = std::fs::remove_file(&path);
+    Ok(file)
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// body length, refusing anything a proxy in front of us could frame differently
+fn parse_content_length(headers: &Headers) -> Result<usize> {
+    if headers.get(TRANSFER_ENCODING).is_some() {
+        if headers.get(CONTENT_LENGTH).is_some() {
+            bail!("both content-length and transfer-encoding");
+        }
+        bail!(StatusError(StatusCode::NOT_IMPLEMENTED));
+    }
+
+    let mut content_length = None;
+    for value in headers.get_all(CONTENT_LENGTH).flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            bail!("invalid content-length");
+        }
+        let length = value.parse::<usize>()?;
+        if content_length.is_some_and(|l| l != length) {
+            bail!("conflicting content-length");
+        }
+        content_length = Some(length);
+    }
+    Ok(content_length.unwrap_or(0))
+}
+
+// RFC 9110 token, as used for methods and header names
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// splits a Host header value into host and optional port, e.g. "[::1]:4221"
+pub(crate) fn split_host_port(value: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = if let Some(rest) = value.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':')?)),
+        }
+    } else {
+        match value.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (value, None),
+        }
+    };
+
+    if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '/' || c == '@') {
+        return None;
+    }
+    let port = match port {
+        Some(port) => Some(port.parse::<u16>().ok()?),
+        None => None,
+    };
+    Some((host, port))
+}
+
+fn split_target(target: &str) -> (String, Option<String>) {
+    match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (target.to_owned(), None),
+    }
+}
+
+// escapes everything but unreserved characters and "/" so the path can be used in a URL
+pub(crate) fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+// decodes %XX escapes, returning None on malformed escapes or invalid UTF-8
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// decoded request path; dot segments are rejected so encoded "../" cannot escape a route
+pub(crate) fn decode_path(path: &str) -> Option<String> {
+    let decoded = percent_decode(path)?;
+    if decoded.contains('\0') || decoded.split('/').any(|segment| segment == "..") {
+        return None;
+    }
+    Some(decoded)
+}
+
+// answers the number of bytes of the body written
+pub fn write_response<W: Write>(response: Response, stream: &mut W) -> Result<u64> {
+    write_upgrading_response(response, stream).map(|(written, _)| written)
+}
+
+// like write_response, also answering what takes over the connection after the head, if anything
+pub(crate) fn write_upgrading_response<W: Write>(
+    mut response: Response,
+    stream: &mut W,
+) -> Result<(u64, Option<OnUpgrade>)> {
+    response.headers.insert(DATE, &date::now());
+    let upgrade = response.upgrade.take();
+    // the connection carries something else after the head, so there's no body to delimit
+    if upgrade.is_some() {
+        response.headers.remove(CONTENT_LENGTH);
+        response.headers.remove(TRANSFER_ENCODING);
+        response.body = Body::Bytes(Vec::new());
+    // without a length a persistent connection has no way to delimit the body
+    } else if response.body.is_stream() {
+        response.headers.remove(CONTENT_LENGTH);
+        response.headers.insert(TRANSFER_ENCODING, "chunked");
+    } else if response.headers.get(CONTENT_LENGTH).is_none() {
+        response
+            .headers
+            .insert(CONTENT_LENGTH, &response.body.len().to_string());
+    }
+
+    stream.write_all(format!("HTTP/1.1 {}\r\n", response.status).as_bytes())?;
+
+    for (key, value) in response.headers.iter() {
+        stream.write_all(format!("{}: {}\r\n", key, value).as_bytes())?;
+    }
+
+    stream.write_all(b"\r\n")?;
+    let written = match response.omit_body {
+        true => 0,
+        false => response.body.write_to(stream)?,
+    };
+    stream.flush()?;
+
+    Ok((written, upgrade))
+}
+
+// for building requests by hand, e.g. to test handlers without a connection
+impl Request {
+    pub fn new(method: Method, target: &str) -> Self {
+        let (path, query) = split_target(target);
+        Self {
+            method,
+            path,
+            query,
+            version: "HTTP/1.1".to_owned(),
+            headers: Headers::new(),
+            body: Vec::new(),
+            body_file: None,
+            params: Vec::new(),
+            client: None,
+            peer: None,
+            forwarded_for: None,
+        }
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.append(key, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: &str) -> Self {
+        self.body = body.as_bytes().to_vec();
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{handle_request, parse_args, Config, State};
+    use std::sync::Arc;
+
+    fn parse_error_status(raw: &str, parser: &ParserConfig) -> Option<StatusCode> {
+        let err = parse_to_request(&mut raw.as_bytes(), parser).err()?;
+        Some(
+            err.downcast_ref::<StatusError>()
+                .map_or(StatusCode::BAD_REQUEST, |e| e.0),
+        )
+    }
+
+    #[test]
+    fn test_cookies() {
+        let req = Request::new(Method::Get, "/")
+            .with_header(COOKIE, "a=1; b=2")
+            .with_header(COOKIE, "c=3");
+        let cookies = req.cookies();
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["c"], "3");
+
+        let res = Response::new(StatusCode::OK)
+            .with_cookie(&Cookie::new("a", "1").with_http_only())
+            .with_cookie(&Cookie::new("b", "2"));
+        assert_eq!(
+            res.headers.get_all(SET_COOKIE).collect::<Vec<_>>(),
+            vec!["a=1; HttpOnly", "b=2"]
+        );
+    }
+
+    #[test]
+    fn test_negotiated() {
+        let available = [TEXT_PLAIN, TEXT_HTML, APPLICATION_JSON];
+        let render = |content_type: &str| match content_type {
+            TEXT_HTML => "<p>hi</p>".to_owned(),
+            APPLICATION_JSON => "\"hi\"".to_owned(),
+            _ => "hi".to_owned(),
+        };
+
+        let req = Request::new(Method::Get, "/");
+        let res = Response::negotiated(&req, &available, render);
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "hi");
+        assert_eq!(res.headers.get(VARY), Some(ACCEPT));
+
+        let req = Request::new(Method::Get, "/").with_header(ACCEPT, "application/json");
+        let res = Response::negotiated(&req, &available, render);
+        assert_eq!(res.body, "\"hi\"");
+        assert_eq!(res.headers.get(CONTENT_TYPE), Some(APPLICATION_JSON));
+
+        let req = Request::new(Method::Get, "/").with_header(ACCEPT, "image/png");
+        let res = Response::negotiated(&req, &available, render);
+        assert_eq!(res.status, StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(res.headers.get(VARY), Some(ACCEPT));
+    }
+
+    #[test]
+    fn test_parser_limits() {
+        let parser = ParserConfig {
+            max_request_line: 32,
+            max_header_line: 32,
+            max_headers_size: 64,
+            ..ParserConfig::default()
+        };
+
+        let raw = "GET /echo/abc HTTP/1.1\r\nHost: a\r\nUser-Agent: curl\r\n\r\n";
+        assert_eq!(parse_error_status(raw, &parser), None);
+
+        let raw = "GET /echo/aaaaaaaaaaaaaaaaaaaaaaaaaaaa HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::URI_TOO_LONG)
+        );
+
+        let raw = "GET / HTTP/1.1\r\nUser-Agent: aaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+
+        let raw = "GET / HTTP/1.1\r\nA: aaaaaaaaaaaaaaaa\r\nB: bbbbbbbbbbbbbbbb\r\nC: cccccccccccccccc\r\n\r\n";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+
+        let raw = "GARBAGE\r\n\r\n";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn test_host() {
+        let parser = ParserConfig::default();
+        let raw = "GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        let raw = "GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        let raw = "GET / HTTP/1.1\r\nHost: a:port\r\n\r\n";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::BAD_REQUEST)
+        );
+
+        let mut raw = "GET / HTTP/1.1\r\nHost: localhost:4221\r\n\r\n".as_bytes();
+        let req = parse_to_request(&mut raw, &parser).unwrap();
+        assert_eq!(req.host(), Some("localhost"));
+        assert_eq!(req.port(), Some(4221));
+
+        let req = Request::new(Method::Get, "/").with_header(HOST, "[::1]:8080");
+        assert_eq!(req.host(), Some("::1"));
+        assert_eq!(req.port(), Some(8080));
+
+        let req = Request::new(Method::Get, "/").with_header(HOST, "example.com");
+        assert_eq!(req.host(), Some("example.com"));
+        assert_eq!(req.port(), None);
+    }
+
+    #[test]
+    fn test_unknown_method() {
+        let mut raw = "BREW /files/ HTTP/1.1\r\nHost: a\r\n\r\n".as_bytes();
+        let req = parse_to_request(&mut raw, &ParserConfig::default()).unwrap();
+        assert_eq!(req.method, Method::Other("BREW".to_owned()));
+
+        let state = Arc::new(State::new(Config::default()));
+        let res = handle_request(state, req);
+        assert_eq!(res.status, StatusCode::NOT_IMPLEMENTED);
+
+        let raw = "GE(T / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(
+            parse_error_status(raw, &ParserConfig::default()),
+            Some(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn test_smuggling() {
+        let parser = ParserConfig::default();
+        let bad_requests = [
+            "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 1, 2\r\n\r\nab",
+            "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: +1\r\n\r\na",
+            "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\na",
+            "GET / HTTP/1.1\r\nHost: a\r\nX-Folded: a\r\n b\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+        ];
+        for raw in bad_requests {
+            assert_eq!(
+                parse_error_status(raw, &parser),
+                Some(StatusCode::BAD_REQUEST),
+                "{}",
+                raw
+            );
+        }
+
+        let raw = "POST /echo HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::NOT_IMPLEMENTED)
+        );
+
+        let mut raw =
+            "POST /echo HTTP/1.1\r\nHost:a\r\nContent-Length: 2\r\nContent-Length: 2\r\n\r\nab"
+                .as_bytes();
+        let req = parse_to_request(&mut raw, &parser).unwrap();
+        assert_eq!(req.body, b"ab");
+        assert_eq!(req.headers.get(HOST), Some("a"));
+    }
+
+    #[test]
+    fn test_line_endings() {
+        let raw = "GET /echo/a HTTP/1.1\nHost: a\nUser-Agent: curl \n\n";
+        let strict = ParserConfig::default();
+        assert_eq!(
+            parse_error_status(raw, &strict),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        let raw_mixed = "GET /echo/a HTTP/1.1\r\nHost: a\n\r\n";
+        assert_eq!(
+            parse_error_status(raw_mixed, &strict),
+            Some(StatusCode::BAD_REQUEST)
+        );
+
+        let lenient = ParserConfig {
+            lenient_line_endings: true,
+            ..ParserConfig::default()
+        };
+        let req = parse_to_request(&mut raw.as_bytes(), &lenient).unwrap();
+        assert_eq!(req.path, "/echo/a");
+        assert_eq!(req.headers.get(USER_AGENT), Some("curl"));
+        assert!(parse_to_request(&mut raw_mixed.as_bytes(), &lenient).is_ok());
+
+        // a request cut off before the end of its head is rejected
+        let raw = "GET / HTTP/1.1\r\nHost: a\r\n";
+        assert_eq!(
+            parse_error_status(raw, &strict),
+            Some(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn test_max_body_size() {
+        let args: Vec<String> = ["--max-body-size", "4", "--max-body-size", "/files/=8"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let parser = parse_args(&args).unwrap().parser;
+
+        let raw = "POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+        let raw = "POST /files/a HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(parse_error_status(raw, &parser), None);
+        let raw = "POST /files/a HTTP/1.1\r\nHost: a\r\nContent-Length: 9\r\n\r\nhello";
+        assert_eq!(
+            parse_error_status(raw, &parser),
+            Some(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+    }
+
+    #[test]
+    fn test_streamed_bodies() {
+        let parser = ParserConfig {
+            stream_prefixes: vec!["/files/".to_owned()],
+            ..ParserConfig::default()
+        };
+        let raw = b"PUT /files/a HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        let mut request = parse_to_request(&mut &raw[..], &parser).unwrap();
+        assert!(request.body.is_empty());
+        let mut body = String::new();
+        request.body_reader().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+
+        let raw = b"PUT /files/a HTTP/1.1\r\nHost: a\r\nContent-Length: 9\r\n\r\nhello";
+        assert!(parse_to_request(&mut &raw[..], &parser).is_err());
+        let raw = b"PUT /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nhello";
+        let mut request = parse_to_request(&mut &raw[..], &parser).unwrap();
+        assert_eq!(request.body, b"hello");
+        let mut body = String::new();
+        request.body_reader().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+    }
+
+    #[test]
+    fn test_status_code() {
+        assert_eq!(StatusCode::from(204), StatusCode::NO_CONTENT);
+        assert_eq!(StatusCode::NOT_FOUND.as_u16(), 404);
+        assert_eq!(StatusCode::OK.to_string(), "200 OK");
+        assert_eq!(StatusCode::from(429).to_string(), "429 Too Many Requests");
+        assert_eq!(StatusCode::from(599).to_string(), "599 ");
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut headers = Headers::new();
+        headers.append("Accept", "text/plain");
+        headers.append("accept", "text/html");
+        assert_eq!(headers.get("ACCEPT"), Some("text/plain"));
+        assert_eq!(
+            headers.get_all("Accept").collect::<Vec<_>>(),
+            vec!["text/plain", "text/html"]
+        );
+
+        headers.insert("Accept", "application/json");
+        assert_eq!(
+            headers.get_all("Accept").collect::<Vec<_>>(),
+            vec!["application/json"]
+        );
+
+        let res = Response::new(StatusCode::OK)
+            .with_appended_header("Set-Cookie", "a=1")
+            .with_appended_header("Set-Cookie", "b=2");
+        assert_eq!(res.headers.get_all("set-cookie").count(), 2);
+    }
+
+    #[test]
+    fn test_chunked_responses() {
+        let res = Response::new(StatusCode::OK)
+            .with_stream(&b"hello"[..])
+            .with_content_type_and_current_length("text/plain");
+        let mut raw = Vec::new();
+        write_response(res, &mut raw).unwrap();
+        let raw = String::from_utf8(raw).unwrap();
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!raw.contains("Content-Length"));
+        assert!(raw.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+    }
+}
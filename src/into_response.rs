@@ -0,0 +1,96 @@
+use crate::http::{Response, ServerError, StatusCode, StatusError, TEXT_PLAIN};
+
+// anything a handler can return, converted to the response that is sent
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+// 200 with a text/plain body
+impl IntoResponse for &str {
+    fn into_response(self) -> Response {
+        Response::new(StatusCode::OK)
+            .with_body(self)
+            .with_content_type_and_current_length(TEXT_PLAIN)
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        self.as_str().into_response()
+    }
+}
+
+// an empty response with the status
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response {
+        Response::new(self)
+    }
+}
+
+impl IntoResponse for StatusError {
+    fn into_response(self) -> Response {
+        Response::new(self.0)
+    }
+}
+
+// client errors carry their message as the body, server errors are logged and left bare
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        if self.status.as_u16() >= 500 {
+            tracing::error!(status = self.status.as_u16(), "{}", self.message);
+            return Response::new(self.status);
+        }
+        (self.status, self.message).into_response()
+    }
+}
+
+// the inner response with its status replaced
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Response {
+        let mut response = self.1.into_response();
+        response.status = self.0;
+        response
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::CONTENT_TYPE;
+
+    #[test]
+    fn test_into_response() {
+        let res = "hi".into_response();
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "hi");
+        assert_eq!(res.headers.get(CONTENT_TYPE), Some(TEXT_PLAIN));
+
+        let res = (StatusCode::CREATED, "made".to_owned()).into_response();
+        assert_eq!(res.status, StatusCode::CREATED);
+        assert_eq!(res.body, "made");
+
+        let res = StatusCode::NO_CONTENT.into_response();
+        assert_eq!(res.status, StatusCode::NO_CONTENT);
+        assert_eq!(res.body.len(), 0);
+
+        let ok: Result<&str, StatusCode> = Ok("fine");
+        assert_eq!(ok.into_response().body, "fine");
+        let err: Result<&str, StatusError> = Err(StatusError(StatusCode::CONFLICT));
+        assert_eq!(err.into_response().status, StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,105 @@
+use crate::extract::FromRequest;
+use crate::http::{Request, Response, StatusCode, StatusError, APPLICATION_JSON, CONTENT_TYPE};
+use crate::into_response::IntoResponse;
+use crate::server::State;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Arc;
+
+// the JSON body as a handler argument, see Request::json
+#[derive(Debug, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(_state: &Arc<State>, request: &Request) -> Result<Self, StatusError> {
+        request.json().map(Json)
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        Response::json(&self.0)
+    }
+}
+
+impl Request {
+    // deserializes a JSON body, failing with 415 for other content types and 400 for bad JSON
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, StatusError> {
+        let is_json = self
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.split(';').next())
+            .is_some_and(|media_type| media_type.trim().eq_ignore_ascii_case(APPLICATION_JSON));
+        if !is_json {
+            return Err(StatusError(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        }
+        serde_json::from_slice(&self.body).map_err(|_| StatusError(StatusCode::BAD_REQUEST))
+    }
+}
+
+impl Response {
+    // 200 response with the value serialized as JSON
+    pub fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Response::new(StatusCode::OK)
+                .with_body(&body)
+                .with_content_type_and_current_length(APPLICATION_JSON),
+            Err(_) => Response::new(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::router::Router;
+    use crate::server::Config;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Greeting {
+        name: String,
+        count: u32,
+    }
+
+    #[test]
+    fn test_json() {
+        let req = Request::new(Method::Post, "/")
+            .with_header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .with_body(r#"{"name": "world", "count": 2}"#);
+        let greeting: Greeting = req.json().unwrap();
+        assert_eq!(
+            greeting,
+            Greeting {
+                name: "world".to_owned(),
+                count: 2
+            }
+        );
+
+        let res = Response::json(&greeting);
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers.get(CONTENT_TYPE), Some(APPLICATION_JSON));
+        assert_eq!(res.body, r#"{"name":"world","count":2}"#);
+
+        let req = Request::new(Method::Post, "/")
+            .with_header(CONTENT_TYPE, APPLICATION_JSON)
+            .with_body(r#"{"name": 1}"#);
+        let err = req.json::<Greeting>().unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+
+        let req = Request::new(Method::Post, "/").with_body(r#"{"name": "world", "count": 2}"#);
+        let err = req.json::<Greeting>().unwrap_err();
+        assert_eq!(err.0, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let router = Router::new().post("/", |Json(greeting): Json<Greeting>| Json(greeting.name));
+        let state = Arc::new(State::new(Config::default()));
+        let req = Request::new(Method::Post, "/")
+            .with_header(CONTENT_TYPE, APPLICATION_JSON)
+            .with_body(r#"{"name": "world", "count": 2}"#);
+        assert_eq!(router.handle(state.clone(), req).body, r#""world""#);
+        let req = Request::new(Method::Post, "/").with_body("{}");
+        let res = router.handle(state, req);
+        assert_eq!(res.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+}
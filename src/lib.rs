@@ -0,0 +1,53 @@
+mod accept;
+mod access_log;
+pub mod app;
+mod archive;
+#[cfg(feature = "async")]
+mod async_server;
+mod base64;
+pub mod cidr;
+mod cli;
+mod config_file;
+pub mod cookies;
+mod date;
+mod dav;
+pub mod extract;
+pub mod form;
+mod health;
+mod htpasswd;
+pub mod http;
+pub mod into_response;
+#[cfg(feature = "json")]
+pub mod json;
+mod listing;
+pub mod logging;
+mod metrics;
+pub mod middleware;
+mod mime;
+pub mod multipart;
+#[cfg(feature = "otel")]
+mod otel;
+mod pool;
+mod preload;
+pub mod proxy;
+mod proxy_protocol;
+mod rate_limit;
+pub mod reload;
+pub mod router;
+pub mod server;
+mod sha1;
+mod sha256;
+mod stats;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod websocket;
+
+pub use extract::{FromRequest, PathParams};
+pub use http::{
+    Headers, Method, Request, Response, ServerError, StatusCode, StatusError, Upgraded,
+};
+pub use into_response::IntoResponse;
+pub use router::{Handler, Middleware, Next, Router, TrailingSlash};
+pub use server::{Config, Server, State};
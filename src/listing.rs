@@ -0,0 +1,183 @@
+use crate::date::format_http_date;
+use crate::http::percent_encode_path;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+pub(crate) struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    // seconds since the unix epoch
+    pub modified: Option<u64>,
+}
+
+// the directory's entries, subdirectories first, then by name
+pub(crate) fn entries(directory: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        // names that aren't UTF-8 couldn't be requested anyway
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let metadata = entry.metadata()?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|elapsed| elapsed.as_secs());
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified,
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+// an index page for the directory served at `url_path`, which ends in "/" so the links can be
+// relative, with the entries `visible` accepts; subdirectories link back to their parent
+pub(crate) fn html(
+    directory: &Path,
+    url_path: &str,
+    is_root: bool,
+    visible: impl Fn(&Entry) -> bool,
+) -> io::Result<String> {
+    let title = format!("Index of {}", escape_html(url_path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+         <body>\n<h1>{0}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n",
+        title
+    );
+    if !is_root {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries(directory)?.into_iter().filter(visible) {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = match entry.is_dir {
+            true => "-".to_owned(),
+            false => entry.size.to_string(),
+        };
+        let modified = entry.modified.map(format_http_date).unwrap_or_default();
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            percent_encode_path(&entry.name),
+            suffix,
+            escape_html(&entry.name),
+            suffix,
+            size,
+            modified
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    Ok(html)
+}
+
+// the directory's entries as a JSON array of {"name", "type", "size", "mtime"} objects, with
+// "type" either "file" or "directory", "size" null for directories and "mtime" in seconds since
+// the unix epoch
+pub(crate) fn json(directory: &Path, visible: impl Fn(&Entry) -> bool) -> io::Result<String> {
+    let mut json = String::from("[");
+    for (i, entry) in entries(directory)?.into_iter().filter(visible).enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let (kind, size) = match entry.is_dir {
+            true => ("directory", "null".to_owned()),
+            false => ("file", entry.size.to_string()),
+        };
+        let mtime = entry
+            .modified
+            .map_or("null".to_owned(), |modified| modified.to_string());
+        let _ = write!(
+            json,
+            "{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"mtime\":{}}}",
+            escape_json(&entry.name),
+            kind,
+            size,
+            mtime
+        );
+    }
+    json.push(']');
+    Ok(json)
+}
+
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", u32::from(c));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_html_listing() {
+        let dir = TempDir::new("list");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("b <&>.txt"), "hello").unwrap();
+        fs::write(dir.join("a.txt"), "").unwrap();
+
+        let names: Vec<_> = entries(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["sub", "a.txt", "b <&>.txt"]);
+
+        let page = html(&dir, "/files/", true, |_| true).unwrap();
+        assert!(page.contains("<title>Index of /files/</title>"));
+        assert!(page.contains("<a href=\"sub/\">sub/</a></td><td>-</td>"));
+        assert!(
+            page.contains("<a href=\"b%20%3C%26%3E.txt\">b &lt;&amp;&gt;.txt</a></td><td>5</td>")
+        );
+        assert!(!page.contains("../"));
+        assert!(!html(&dir, "/files/", true, |entry| entry.is_dir)
+            .unwrap()
+            .contains("a.txt"));
+        assert!(html(&dir.join("sub"), "/files/sub/", false, |_| true)
+            .unwrap()
+            .contains("href=\"../\""));
+
+        fs::write(dir.join("q\"\\.txt"), "").unwrap();
+        let listing = json(&dir, |entry| entry.name != "a.txt").unwrap();
+        assert!(listing
+            .starts_with("[{\"name\":\"sub\",\"type\":\"directory\",\"size\":null,\"mtime\":"));
+        assert!(listing.contains("{\"name\":\"b <&>.txt\",\"type\":\"file\",\"size\":5,\"mtime\":"));
+        assert!(listing.contains("\"name\":\"q\\\"\\\\.txt\""));
+        assert!(!listing.contains("a.txt"));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::http::{Headers, AUTHORIZATION, COOKIE, SET_COOKIE};
+use std::io;
+use std::sync::OnceLock;
+use tracing::level_filters::LevelFilter;
+use tracing::{Level, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::{reload, Layer, Registry};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+type LevelHandle = reload::Handle<LevelFilter, Layered<Vec<BoxedLayer>, Registry>>;
+
+// the level of the subscriber init installed, for set_level
+static LEVEL: OnceLock<LevelHandle> = OnceLock::new();
+
+// from least to most verbose, what -q and -v step through
+const LEVELS: [Level; 5] = [
+    Level::ERROR,
+    Level::WARN,
+    Level::INFO,
+    Level::DEBUG,
+    Level::TRACE,
+];
+
+// credentials aren't written to the logs with the rest of the headers
+const REDACTED_HEADERS: [&str; 4] = [AUTHORIZATION, "Proxy-Authorization", COOKIE, SET_COOKIE];
+
+// how diagnostics are written to stderr: readable lines, or one JSON object per event for Loki or
+// ELK, carrying the fields of the connection and request spans it happened in
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// installs the tracing subscriber for the process, logging events up to the level and, with the
+// otel feature, exporting spans over OTLP; an embedding application can install its own instead,
+// in which case this does nothing
+pub fn init(format: LogFormat, level: Level) {
+    #[cfg(not(feature = "otel"))]
+    let layers = Vec::new();
+    #[cfg(feature = "otel")]
+    let (layers, otel_error) = match crate::otel::layer() {
+        Ok(layer) => (vec![layer], None),
+        Err(e) => (Vec::new(), Some(e)),
+    };
+    let (subscriber, handle) = subscriber(format, level, io::stderr, layers);
+    if tracing::subscriber::set_global_default(subscriber).is_ok() {
+        let _ = LEVEL.set(handle);
+    }
+    // only now that there's a subscriber to tell
+    #[cfg(feature = "otel")]
+    if let Some(e) = otel_error {
+        tracing::warn!(error = e, "OpenTelemetry export is disabled");
+    }
+}
+
+// changes the level of what init installed, e.g. when the config is reloaded
+pub fn set_level(level: Level) {
+    if let Some(handle) = LEVEL.get() {
+        let _ = handle.reload(LevelFilter::from_level(level));
+    }
+}
+
+// the level `steps` more verbose than the given one, or less for negative steps, within ERROR
+// and TRACE
+pub fn verbosity(level: Level, steps: isize) -> Level {
+    let current = LEVELS.iter().position(|known| *known == level).unwrap_or(2);
+    let index = current.saturating_add_signed(steps).min(LEVELS.len() - 1);
+    LEVELS[index]
+}
+
+// the headers as logged at debug level, with credentials replaced
+pub(crate) fn loggable_headers(headers: &Headers) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .map(|(key, value)| {
+            let redacted = REDACTED_HEADERS
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(key));
+            (key, if redacted { "[redacted]" } else { value })
+        })
+        .collect()
+}
+
+// the events formatted to the writer, along with any other layers such as the OTLP exporter, and
+// a handle to change the level with
+fn subscriber<W>(
+    format: LogFormat,
+    level: Level,
+    writer: W,
+    mut layers: Vec<BoxedLayer>,
+) -> (Box<dyn Subscriber + Send + Sync>, LevelHandle)
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    let layer: BoxedLayer = match format {
+        LogFormat::Text => Box::new(layer),
+        LogFormat::Json => Box::new(layer.json().flatten_event(true).with_span_list(true)),
+    };
+    layers.insert(0, layer);
+    let (filter, handle) = reload::Layer::new(LevelFilter::from_level(level));
+    let subscriber = tracing_subscriber::registry().with(layers).with(filter);
+    (Box::new(subscriber), handle)
+}
+
+// collects what a test subscriber writes
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct Captured(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Captured {
+    pub(crate) fn subscriber(&self, format: LogFormat, level: Level) -> impl Subscriber {
+        self.reloadable_subscriber(format, level).0
+    }
+
+    pub(crate) fn reloadable_subscriber(
+        &self,
+        format: LogFormat,
+        level: Level,
+    ) -> (impl Subscriber, LevelHandle) {
+        let captured = self.clone();
+        subscriber(format, level, move || captured.clone(), Vec::new())
+    }
+
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, Request, Response, ServerError, StatusCode};
+    use crate::middleware::request_id;
+    use crate::router::Router;
+    use crate::server::{handle_request, Config, State};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_json_logs() {
+        let router = Router::new()
+            .get("/fail", || -> Result<Response, ServerError> {
+                Err(ServerError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "disk on fire",
+                ))
+            })
+            .layer(request_id);
+        let state = Arc::new(State::with_router(Config::default(), router));
+        let captured = Captured::default();
+        let subscriber = captured.subscriber(LogFormat::Json, Level::INFO);
+        tracing::subscriber::with_default(subscriber, || {
+            let req = Request::new(Method::Get, "/fail").with_header("X-Request-Id", "abc-123");
+            handle_request(state.clone(), req);
+        });
+
+        let logs = captured.contents();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("disk on fire"))
+            .unwrap();
+        assert!(line.starts_with('{') && line.ends_with('}'));
+        assert!(line.contains(r#""level":"ERROR""#));
+        assert!(line.contains(r#""status":500"#));
+        assert!(line.contains(r#""name":"request""#));
+        assert!(line.contains(r#""method":"GET""#));
+        assert!(line.contains(r#""path":"/fail""#));
+        assert!(line.contains(r#""request_id":"abc-123""#));
+    }
+
+    #[test]
+    fn test_log_levels() {
+        let router = Router::new().get("/", || "ok");
+        let state = Arc::new(State::with_router(Config::default(), router));
+        let logs = |level| {
+            let captured = Captured::default();
+            let subscriber = captured.subscriber(LogFormat::Text, level);
+            tracing::subscriber::with_default(subscriber, || {
+                let req = Request::new(Method::Get, "/")
+                    .with_header("Authorization", "Basic c2VjcmV0")
+                    .with_header("Accept", "text/plain")
+                    .with_body("the body");
+                handle_request(state.clone(), req);
+            });
+            captured.contents()
+        };
+
+        assert_eq!(logs(Level::INFO), "");
+        let debug = logs(Level::DEBUG);
+        assert!(debug.contains(r#"("Accept", "text/plain")"#));
+        assert!(debug.contains(r#"("Authorization", "[redacted]")"#));
+        assert!(debug.contains("status=200"));
+        assert!(!debug.contains("c2VjcmV0"));
+        assert!(!debug.contains("the body"));
+
+        // a reload can change the level of a subscriber in use
+        let captured = Captured::default();
+        let (subscriber, handle) = captured.reloadable_subscriber(LogFormat::Text, Level::INFO);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug!("hidden");
+            handle.reload(LevelFilter::DEBUG).unwrap();
+            tracing::debug!("shown");
+        });
+        let contents = captured.contents();
+        assert!(!contents.contains("hidden") && contents.contains("shown"));
+
+        assert_eq!(verbosity(Level::INFO, 1), Level::DEBUG);
+        assert_eq!(verbosity(Level::INFO, 5), Level::TRACE);
+        assert_eq!(verbosity(Level::INFO, -1), Level::WARN);
+        assert_eq!(verbosity(Level::WARN, -5), Level::ERROR);
+    }
+}
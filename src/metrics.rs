@@ -0,0 +1,183 @@
+use crate::http::{Method, Response, StatusCode};
+use crate::server::State;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const METHODS: [&str; 6] = ["GET", "POST", "PUT", "DELETE", "PATCH", "other"];
+// every status from 100 to 599 has its own counter
+const STATUSES: usize = 500;
+// upper bounds in seconds of the latency histogram's buckets, as Prometheus clients default to
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// counters for the Prometheus endpoint, updated by the connection loops as requests are answered
+pub(crate) struct Metrics {
+    // by method, then status
+    requests: Vec<AtomicU64>,
+    in_flight: AtomicU64,
+    bytes: AtomicU64,
+    // from the first byte of the request to the last one of the response
+    durations: Histogram,
+    // until the response was ready to be sent, as in X-Response-Time
+    handling: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // per bucket, with one more for slower requests; cumulated when rendered
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| elapsed.as_secs_f64() <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let bound = match BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_owned(),
+            };
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+        }
+        let sum = self.micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "{}_sum {}", name, sum).unwrap();
+        writeln!(out, "{}_count {}", name, count).unwrap();
+    }
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Self {
+            requests: (0..METHODS.len() * STATUSES)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            in_flight: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            durations: Histogram::default(),
+            handling: Histogram::default(),
+        }
+    }
+
+    // a request started arriving; every call is paired with one to `answered`
+    pub(crate) fn started(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `method` is None for requests that couldn't be parsed
+    pub(crate) fn answered(
+        &self,
+        method: Option<&Method>,
+        status: StatusCode,
+        bytes: u64,
+        elapsed: Duration,
+        handled: Duration,
+    ) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        let method = match method {
+            Some(method) => METHODS
+                .iter()
+                .position(|known| *known == method.as_str())
+                .unwrap_or(METHODS.len() - 1),
+            None => METHODS.len() - 1,
+        };
+        let status = status.as_u16() as usize;
+        if (100..100 + STATUSES).contains(&status) {
+            self.requests[method * STATUSES + status - 100].fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.durations.observe(elapsed);
+        self.handling.observe(handled);
+    }
+
+    // the Prometheus text exposition format
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP http_requests_total Requests answered, by method and status.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for (i, count) in self.requests.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count > 0 {
+                let (method, status) = (METHODS[i / STATUSES], i % STATUSES + 100);
+                let labels = format!("method=\"{}\",status=\"{}\"", method, status);
+                writeln!(out, "http_requests_total{{{}}} {}", labels, count).unwrap();
+            }
+        }
+
+        out.push_str("# HELP http_requests_in_flight Requests being read, handled or answered.\n");
+        out.push_str("# TYPE http_requests_in_flight gauge\n");
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        writeln!(out, "http_requests_in_flight {}", in_flight).unwrap();
+
+        out.push_str("# HELP http_response_bytes_total Bytes of response bodies sent.\n");
+        out.push_str("# TYPE http_response_bytes_total counter\n");
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        writeln!(out, "http_response_bytes_total {}", bytes).unwrap();
+
+        self.durations.render(
+            &mut out,
+            "http_request_duration_seconds",
+            "Time from the first byte of a request to the last one of its response.",
+        );
+        self.handling.render(
+            &mut out,
+            "http_request_handling_seconds",
+            "Time from the first byte of a request until its response was ready to be sent.",
+        );
+        out
+    }
+}
+
+pub(crate) fn metrics_handler(state: Arc<State>) -> Response {
+    Response::new(StatusCode::OK)
+        .with_body(&state.metrics.render())
+        .with_content_type_and_current_length("text/plain; version=0.0.4; charset=utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        metrics.started();
+        metrics.started();
+        metrics.started();
+        let get = Method::Get;
+        let ms = Duration::from_millis;
+        metrics.answered(Some(&get), StatusCode::OK, 100, ms(3), ms(1));
+        metrics.answered(Some(&get), StatusCode::OK, 50, ms(30), ms(20));
+        metrics.answered(None, StatusCode::BAD_REQUEST, 0, ms(20000), ms(2));
+
+        let text = metrics.render();
+        assert!(text.contains("http_requests_total{method=\"GET\",status=\"200\"} 2\n"));
+        assert!(text.contains("http_requests_total{method=\"other\",status=\"400\"} 1\n"));
+        assert!(!text.contains("status=\"404\""));
+        assert!(text.contains("http_requests_in_flight 0\n"));
+        assert!(text.contains("http_response_bytes_total 150\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("http_request_duration_seconds_sum 20.033\n"));
+        assert!(text.contains("http_request_duration_seconds_count 3\n"));
+        assert!(text.contains("http_request_handling_seconds_bucket{le=\"0.005\"} 2\n"));
+        assert!(text.contains("http_request_handling_seconds_sum 0.023\n"));
+    }
+}
@@ -0,0 +1,797 @@
+use crate::base64;
+use crate::cookies::{Cookie, SameSite};
+use crate::form::Form;
+use crate::htpasswd::Htpasswd;
+use crate::http::{
+    Request, Response, StatusCode, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD,
+    AUTHORIZATION, CONTENT_SECURITY_POLICY, ORIGIN, REFERER, REFERRER_POLICY, VARY,
+    WWW_AUTHENTICATE, X_CONTENT_TYPE_OPTIONS, X_CSRF_TOKEN, X_FRAME_OPTIONS, X_REQUEST_ID,
+};
+use crate::router::{Middleware, Next};
+use crate::sha256::hex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, Span};
+
+// adopts the client's X-Request-Id or generates one, hands it to the handler as
+// Request::request_id() and echoes it in the response; it's recorded on the request span, so
+// everything logged while handling the request carries it
+pub fn request_id(mut request: Request, next: Next) -> Response {
+    let id = match request.headers.get(X_REQUEST_ID) {
+        Some(id) if is_valid_request_id(id) => id.to_owned(),
+        _ => generate_request_id(),
+    };
+    request.headers.insert(X_REQUEST_ID, &id);
+    Span::current().record("request_id", id.as_str());
+    let target = format!("{} {}", request.method.as_str(), request.path);
+
+    let response = next.run(request);
+    if response.status.as_u16() >= 500 {
+        error!(
+            status = response.status.as_u16(),
+            "{} answered {}", target, response.status
+        );
+    }
+    response.with_header(X_REQUEST_ID, &id)
+}
+
+// printable ASCII without spaces, so a client can't break up log lines with it
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// a counter behind a prefix from the start time and process id, so restarts don't repeat IDs
+fn generate_request_id() -> String {
+    static PREFIX: OnceLock<String> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let prefix = PREFIX.get_or_init(|| {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros());
+        format!("{:x}-{:x}", started, std::process::id())
+    });
+    format!("{}-{}", prefix, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+// answers 401 with a Basic challenge to requests under the protected path prefixes, or to all
+// requests if none are given, unless they carry one of the users' name and password
+pub struct BasicAuth {
+    realm: String,
+    prefixes: Vec<String>,
+    users: Vec<(String, String)>,
+    htpasswd: Option<Htpasswd>,
+}
+
+impl BasicAuth {
+    // the realm is shown by browsers when they ask for the credentials
+    pub fn new(realm: &str) -> Self {
+        Self {
+            realm: realm.to_owned(),
+            prefixes: Vec::new(),
+            users: Vec::new(),
+            htpasswd: None,
+        }
+    }
+
+    pub fn user(mut self, name: &str, password: &str) -> Self {
+        self.users.push((name.to_owned(), password.to_owned()));
+        self
+    }
+
+    // users from an Apache htpasswd file with bcrypt or {SHA} passwords, which is read again
+    // whenever it changes; a file that can't be read lets nobody in
+    pub fn htpasswd(mut self, path: &str) -> Self {
+        self.htpasswd = Some(Htpasswd::new(path));
+        self
+    }
+
+    // "/files/" protects /files and everything under it
+    pub fn protect(mut self, prefix: &str) -> Self {
+        self.prefixes.push(prefix.to_owned());
+        self
+    }
+
+    fn protects(&self, path: &str) -> bool {
+        self.prefixes.is_empty()
+            || self.prefixes.iter().any(|prefix| {
+                path.starts_with(prefix.as_str()) || path == prefix.trim_end_matches('/')
+            })
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some((name, password)) = basic_credentials(request) else {
+            return false;
+        };
+        // every user is compared, so the time taken doesn't tell which names exist
+        let found = self
+            .users
+            .iter()
+            .fold(false, |found, (known_name, known_password)| {
+                let matches = constant_time_eq(name.as_bytes(), known_name.as_bytes())
+                    & constant_time_eq(password.as_bytes(), known_password.as_bytes());
+                found | matches
+            });
+        found
+            || self
+                .htpasswd
+                .as_ref()
+                .is_some_and(|htpasswd| htpasswd.verify(&name, &password))
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn call(&self, request: Request, next: Next) -> Response {
+        if !self.protects(&request.path) || self.authorized(&request) {
+            return next.run(request);
+        }
+        let challenge = format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm);
+        Response::new(StatusCode::UNAUTHORIZED).with_header(WWW_AUTHENTICATE, &challenge)
+    }
+}
+
+// answers 401 with a Bearer challenge to requests without "Authorization: Bearer <token>" for one
+// of the tokens; with writes_only, requests that only read (GET, HEAD, OPTIONS, PROPFIND) don't need one
+#[derive(Default)]
+pub struct BearerAuth {
+    tokens: Vec<String>,
+    writes_only: bool,
+}
+
+impl BearerAuth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn token(mut self, token: &str) -> Self {
+        self.tokens.push(token.to_owned());
+        self
+    }
+
+    pub fn writes_only(mut self) -> Self {
+        self.writes_only = true;
+        self
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(given) = bearer_token(request) else {
+            return false;
+        };
+        self.tokens.iter().fold(false, |found, token| {
+            found | constant_time_eq(given.as_bytes(), token.as_bytes())
+        })
+    }
+}
+
+impl Middleware for BearerAuth {
+    fn call(&self, request: Request, next: Next) -> Response {
+        let reads = matches!(
+            request.method.as_str(),
+            "GET" | "HEAD" | "OPTIONS" | "PROPFIND"
+        );
+        if (self.writes_only && reads) || self.authorized(&request) {
+            return next.run(request);
+        }
+        Response::new(StatusCode::UNAUTHORIZED).with_header(WWW_AUTHENTICATE, "Bearer")
+    }
+}
+
+// lets browsers call the routes from pages of other origins: preflight OPTIONS requests are answered
+// with what may be sent, and responses to the origins allowed (or any origin if none are given)
+// carry Access-Control-Allow-Origin with the origin reflected; requests from other origins are
+// handled as usual, only without the headers, so browsers keep their responses from the page
+pub struct Cors {
+    origins: Vec<String>,
+    methods: Vec<String>,
+    // None allows whatever headers a preflight asks for
+    headers: Option<Vec<String>>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: u64,
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Self {
+            origins: Vec::new(),
+            methods: ["GET", "POST", "PUT", "PATCH", "DELETE"]
+                .map(str::to_owned)
+                .to_vec(),
+            headers: None,
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: 600,
+        }
+    }
+
+    // "https://example.com", exactly as browsers send it in Origin
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.origins.push(origin.to_owned());
+        self
+    }
+
+    // replaces the default GET, POST, PUT, PATCH and DELETE
+    pub fn allow_methods(mut self, methods: &[&str]) -> Self {
+        self.methods = methods.iter().map(|method| method.to_string()).collect();
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        self.headers = Some(headers.iter().map(|header| header.to_string()).collect());
+        self
+    }
+
+    // response headers besides the few simple ones that scripts may read
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.expose_headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    // lets pages send cookies and credentials along, and read the responses to them; only pages
+    // of the origins given with allow_origin, it's ignored while any origin is allowed as every
+    // site could then act with its visitors' cookies
+    pub fn allow_credentials(mut self) -> Self {
+        self.credentials = true;
+        self
+    }
+
+    // how long browsers may remember a preflight's answer
+    pub fn max_age(mut self, seconds: u64) -> Self {
+        self.max_age = seconds;
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|known| known == origin)
+    }
+
+    fn preflight(&self, origin: &str, request: &Request) -> Response {
+        let headers = match &self.headers {
+            Some(headers) => headers.join(", "),
+            None => request
+                .headers
+                .get(ACCESS_CONTROL_REQUEST_HEADERS)
+                .unwrap_or("")
+                .to_owned(),
+        };
+        let mut response = Response::new(StatusCode::NO_CONTENT)
+            .with_header(ACCESS_CONTROL_ALLOW_METHODS, &self.methods.join(", "))
+            .with_header(ACCESS_CONTROL_MAX_AGE, &self.max_age.to_string());
+        if !headers.is_empty() {
+            response
+                .headers
+                .insert(ACCESS_CONTROL_ALLOW_HEADERS, &headers);
+        }
+        self.allow(origin, &mut response);
+        add_vary(
+            &mut response,
+            "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+        );
+        response
+    }
+
+    fn allow(&self, origin: &str, response: &mut Response) {
+        response.headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if self.credentials && !self.origins.is_empty() {
+            response
+                .headers
+                .insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        if !self.expose_headers.is_empty() {
+            response.headers.insert(
+                ACCESS_CONTROL_EXPOSE_HEADERS,
+                &self.expose_headers.join(", "),
+            );
+        }
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Cors {
+    fn call(&self, request: Request, next: Next) -> Response {
+        let Some(origin) = request.headers.get(ORIGIN).map(str::to_owned) else {
+            return next.run(request);
+        };
+        // a response without the headers varies by origin as much as one with them
+        if !self.allows(&origin) {
+            let mut response = next.run(request);
+            add_vary(&mut response, "Origin");
+            return response;
+        }
+        let preflight = request.method.as_str() == "OPTIONS"
+            && request.headers.get(ACCESS_CONTROL_REQUEST_METHOD).is_some();
+        if preflight {
+            return self.preflight(&origin, &request);
+        }
+        let mut response = next.run(request);
+        self.allow(&origin, &mut response);
+        // the answer depends on the origin, so caches mustn't hand it to other ones
+        add_vary(&mut response, "Origin");
+        response
+    }
+}
+
+// adds the headers security scanners look for to responses that don't set them already:
+// X-Content-Type-Options: nosniff, X-Frame-Options (DENY), Referrer-Policy
+// (strict-origin-when-cross-origin) and, if one is given, Content-Security-Policy
+pub struct SecurityHeaders {
+    frame_options: String,
+    referrer_policy: String,
+    content_security_policy: Option<String>,
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        Self {
+            frame_options: "DENY".to_owned(),
+            referrer_policy: "strict-origin-when-cross-origin".to_owned(),
+            content_security_policy: None,
+        }
+    }
+
+    // "SAMEORIGIN" lets the site frame its own pages
+    pub fn frame_options(mut self, value: &str) -> Self {
+        self.frame_options = value.to_owned();
+        self
+    }
+
+    pub fn referrer_policy(mut self, value: &str) -> Self {
+        self.referrer_policy = value.to_owned();
+        self
+    }
+
+    // e.g. "default-src 'self'"
+    pub fn content_security_policy(mut self, policy: &str) -> Self {
+        self.content_security_policy = Some(policy.to_owned());
+        self
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn call(&self, request: Request, next: Next) -> Response {
+        let mut response = next.run(request);
+        let headers = [
+            (X_CONTENT_TYPE_OPTIONS, Some("nosniff")),
+            (X_FRAME_OPTIONS, Some(self.frame_options.as_str())),
+            (REFERRER_POLICY, Some(self.referrer_policy.as_str())),
+            (
+                CONTENT_SECURITY_POLICY,
+                self.content_security_policy.as_deref(),
+            ),
+        ];
+        for (key, value) in headers {
+            if let Some(value) = value {
+                if response.headers.get(key).is_none() {
+                    response.headers.insert(key, value);
+                }
+            }
+        }
+        response
+    }
+}
+
+const CSRF_COOKIE: &str = "csrf_token";
+const SEC_FETCH_SITE: &str = "Sec-Fetch-Site";
+
+// double-submit cookie protection for HTML UIs: responses hand browsers a random token in a
+// SameSite=Strict csrf_token cookie, which other sites' pages can neither read nor get sent along,
+// and requests from a browser that may change something (any method but GET, HEAD, OPTIONS and
+// PROPFIND) have to repeat it in an X-CSRF-Token header, a csrf_token form field or a csrf_token
+// query parameter, or get 403; requests with no Origin, Referer or Sec-Fetch-Site, like curl's,
+// can't have been sent by a page and pass
+#[derive(Default)]
+pub struct Csrf {
+    secure: bool,
+}
+
+impl Csrf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // marks the cookie Secure, for sites served over HTTPS
+    pub fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    fn passes(&self, request: &Request, cookie: Option<&str>) -> bool {
+        let reads = matches!(
+            request.method.as_str(),
+            "GET" | "HEAD" | "OPTIONS" | "PROPFIND"
+        );
+        let from_browser = [ORIGIN, REFERER, SEC_FETCH_SITE]
+            .iter()
+            .any(|key| request.headers.get(key).is_some());
+        if reads || !from_browser {
+            return true;
+        }
+        let Some(cookie) = cookie else {
+            return false;
+        };
+        let given = match request.headers.get(X_CSRF_TOKEN) {
+            Some(token) => Some(token.to_owned()),
+            None => request
+                .query_pairs()
+                .into_iter()
+                .find(|(key, _)| key == CSRF_COOKIE)
+                .map(|(_, token)| token)
+                .or_else(|| {
+                    Form::from_request(request)
+                        .and_then(|form| form.get(CSRF_COOKIE).map(str::to_owned))
+                }),
+        };
+        given.is_some_and(|given| constant_time_eq(given.as_bytes(), cookie.as_bytes()))
+    }
+}
+
+impl Middleware for Csrf {
+    fn call(&self, request: Request, next: Next) -> Response {
+        let cookie = request
+            .cookies()
+            .remove(CSRF_COOKIE)
+            .filter(|token| is_csrf_token(token));
+        if !self.passes(&request, cookie.as_deref()) {
+            return Response::new(StatusCode::FORBIDDEN);
+        }
+        let response = next.run(request);
+        if cookie.is_some() {
+            return response;
+        }
+        let mut token = [0; 16];
+        if let Err(e) = getrandom::getrandom(&mut token) {
+            error!(error = %e, "no random numbers for a CSRF token");
+            return response;
+        }
+        // not HttpOnly, the page's scripts have to read it
+        let mut cookie = Cookie::new(CSRF_COOKIE, &hex(&token))
+            .with_path("/")
+            .with_same_site(SameSite::Strict);
+        if self.secure {
+            cookie = cookie.with_secure();
+        }
+        response.with_cookie(&cookie)
+    }
+}
+
+fn is_csrf_token(token: &str) -> bool {
+    token.len() == 32 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn add_vary(response: &mut Response, value: &str) {
+    let vary = match response.headers.get(VARY) {
+        Some(vary) => format!("{}, {}", vary, value),
+        None => value.to_owned(),
+    };
+    response.headers.insert(VARY, &vary);
+}
+
+// the token of an "Authorization: Bearer <token>" header
+pub(crate) fn bearer_token(request: &Request) -> Option<&str> {
+    let value = request.headers.get(AUTHORIZATION)?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("Bearer").then(|| token.trim())
+}
+
+// the name and password of an "Authorization: Basic <base64 of name:password>" header
+fn basic_credentials(request: &Request) -> Option<(String, String)> {
+    let value = request.headers.get(AUTHORIZATION)?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64::decode(encoded.trim())?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    Some((name.to_owned(), password.to_owned()))
+}
+
+// so how long the comparison takes doesn't tell how much of a guessed secret was right
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, CONTENT_TYPE, COOKIE, SET_COOKIE};
+    use crate::router::Router;
+    use crate::server::{Config, State};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_request_id() {
+        let router = Router::new()
+            .get("/", |request: Request| {
+                request.request_id().unwrap().to_owned()
+            })
+            .layer(request_id);
+        let state = Arc::new(State::new(Config::default()));
+        let get = |request: Request| router.handle(state.clone(), request);
+
+        let res = get(Request::new(Method::Get, "/").with_header(X_REQUEST_ID, "abc-123"));
+        assert_eq!(res.body, "abc-123");
+        assert_eq!(res.headers.get(X_REQUEST_ID), Some("abc-123"));
+
+        let first = get(Request::new(Method::Get, "/"));
+        let second = get(Request::new(Method::Get, "/").with_header(X_REQUEST_ID, "a b\r\nc"));
+        let first = first.headers.get(X_REQUEST_ID).unwrap();
+        let second = second.headers.get(X_REQUEST_ID).unwrap();
+        assert!(is_valid_request_id(first));
+        assert!(is_valid_request_id(second));
+        assert_ne!(first, second);
+
+        let res = get(Request::new(Method::Get, "/nope"));
+        assert!(res.headers.get(X_REQUEST_ID).is_some());
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let router = Router::new()
+            .get("/", || "public")
+            .get("/admin/stats", || "secret")
+            .layer(
+                BasicAuth::new("admin area")
+                    .user("aladdin", "opensesame")
+                    .user("jasmine", "t:ger")
+                    .protect("/admin/"),
+            );
+        let state = Arc::new(State::new(Config::default()));
+        let get = |path: &str, authorization: Option<&str>| {
+            let mut request = Request::new(Method::Get, path);
+            if let Some(authorization) = authorization {
+                request = request.with_header(AUTHORIZATION, authorization);
+            }
+            router.handle(state.clone(), request)
+        };
+
+        assert_eq!(get("/", None).body, "public");
+        let res = get("/admin/stats", None);
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers.get(WWW_AUTHENTICATE),
+            Some("Basic realm=\"admin area\", charset=\"UTF-8\"")
+        );
+        // aladdin:opensesame, jasmine:t:ger
+        let res = get("/admin/stats", Some("Basic YWxhZGRpbjpvcGVuc2VzYW1l"));
+        assert_eq!(res.body, "secret");
+        let res = get("/admin/stats", Some("basic amFzbWluZTp0Omdlcg=="));
+        assert_eq!(res.body, "secret");
+        // aladdin:opensesam, a token and garbage
+        let res = get("/admin/stats", Some("Basic YWxhZGRpbjpvcGVuc2VzYW0="));
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        let res = get("/admin/stats", Some("Bearer YWxhZGRpbjpvcGVuc2VzYW1l"));
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        let res = get("/admin/stats", Some("Basic !!!"));
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(get("/admin", None).status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_bearer_auth() {
+        let router = Router::new()
+            .get("/files/*name", || "read")
+            .put("/files/*name", || "written")
+            .layer(
+                BearerAuth::new()
+                    .token("t0ken")
+                    .token("other")
+                    .writes_only(),
+            );
+        let state = Arc::new(State::new(Config::default()));
+        let send = |method: Method, authorization: Option<&str>| {
+            let mut request = Request::new(method, "/files/a.txt");
+            if let Some(authorization) = authorization {
+                request = request.with_header(AUTHORIZATION, authorization);
+            }
+            router.handle(state.clone(), request)
+        };
+
+        assert_eq!(send(Method::Get, None).body, "read");
+        assert_eq!(send(Method::Head, None).status, StatusCode::OK);
+        let res = send(Method::Put, None);
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers.get(WWW_AUTHENTICATE), Some("Bearer"));
+        assert_eq!(send(Method::Put, Some("Bearer t0ken")).body, "written");
+        assert_eq!(send(Method::Put, Some("bearer other")).body, "written");
+        let res = send(Method::Put, Some("Bearer t0ke"));
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        let res = send(Method::Put, Some("Basic t0ken"));
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn test_cors() {
+        let router = Router::new().get("/echo", || "hi").layer(
+            Cors::new()
+                .allow_origin("https://a.example")
+                .expose_headers(&[X_REQUEST_ID]),
+        );
+        let state = Arc::new(State::new(Config::default()));
+        let send = |request: Request| router.handle(state.clone(), request);
+
+        let res = send(Request::new(Method::Get, "/echo").with_header(ORIGIN, "https://a.example"));
+        assert_eq!(res.body, "hi");
+        assert_eq!(
+            res.headers.get(ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://a.example")
+        );
+        assert_eq!(
+            res.headers.get(ACCESS_CONTROL_EXPOSE_HEADERS),
+            Some(X_REQUEST_ID)
+        );
+        assert_eq!(res.headers.get(VARY), Some("Origin"));
+        assert_eq!(res.headers.get(ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+
+        // other origins and same-origin requests get no CORS headers
+        let res = send(Request::new(Method::Get, "/echo").with_header(ORIGIN, "https://b.example"));
+        assert_eq!(res.body, "hi");
+        assert_eq!(res.headers.get(ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        assert_eq!(res.headers.get(VARY), Some("Origin"));
+        let res = send(Request::new(Method::Get, "/echo"));
+        assert_eq!(res.headers.get(ACCESS_CONTROL_ALLOW_ORIGIN), None);
+        assert_eq!(res.headers.get(VARY), None);
+
+        let preflight = Request::new(Method::Other("OPTIONS".to_owned()), "/echo")
+            .with_header(ORIGIN, "https://a.example")
+            .with_header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .with_header(ACCESS_CONTROL_REQUEST_HEADERS, "content-type, x-custom");
+        let res = send(preflight);
+        assert_eq!(res.status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers.get(ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://a.example")
+        );
+        assert_eq!(
+            res.headers.get(ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET, POST, PUT, PATCH, DELETE")
+        );
+        assert_eq!(
+            res.headers.get(ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("content-type, x-custom")
+        );
+        assert_eq!(res.headers.get(ACCESS_CONTROL_MAX_AGE), Some("600"));
+        assert_eq!(
+            res.headers.get(VARY),
+            Some("Origin, Access-Control-Request-Method, Access-Control-Request-Headers")
+        );
+
+        // any origin is reflected when none are given, but never with credentials
+        let router = Router::new().get("/echo", || "hi").layer(
+            Cors::new()
+                .allow_headers(&["Content-Type"])
+                .allow_credentials(),
+        );
+        let preflight = Request::new(Method::Other("OPTIONS".to_owned()), "/echo")
+            .with_header(ORIGIN, "http://localhost:3000")
+            .with_header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .with_header(ACCESS_CONTROL_REQUEST_HEADERS, "x-custom");
+        let res = router.handle(state.clone(), preflight);
+        assert_eq!(
+            res.headers.get(ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("http://localhost:3000")
+        );
+        assert_eq!(
+            res.headers.get(ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("Content-Type")
+        );
+        assert_eq!(res.headers.get(ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+        let router = Router::new().get("/echo", || "hi").layer(
+            Cors::new()
+                .allow_credentials()
+                .allow_origin("http://localhost:3000"),
+        );
+        let request =
+            Request::new(Method::Get, "/echo").with_header(ORIGIN, "http://localhost:3000");
+        let res = router.handle(state.clone(), request);
+        assert_eq!(
+            res.headers.get(ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn test_security_headers() {
+        let router = Router::new()
+            .get("/", || "hi")
+            .get("/framed", || {
+                Response::new(StatusCode::OK).with_header(X_FRAME_OPTIONS, "SAMEORIGIN")
+            })
+            .layer(SecurityHeaders::new().content_security_policy("default-src 'self'"));
+        let state = Arc::new(State::new(Config::default()));
+
+        let res = router.handle(state.clone(), Request::new(Method::Get, "/"));
+        assert_eq!(res.headers.get(X_CONTENT_TYPE_OPTIONS), Some("nosniff"));
+        assert_eq!(res.headers.get(X_FRAME_OPTIONS), Some("DENY"));
+        assert_eq!(
+            res.headers.get(REFERRER_POLICY),
+            Some("strict-origin-when-cross-origin")
+        );
+        assert_eq!(
+            res.headers.get(CONTENT_SECURITY_POLICY),
+            Some("default-src 'self'")
+        );
+        // what handlers set wins
+        let res = router.handle(state.clone(), Request::new(Method::Get, "/framed"));
+        assert_eq!(res.headers.get(X_FRAME_OPTIONS), Some("SAMEORIGIN"));
+        let res = router.handle(state.clone(), Request::new(Method::Get, "/nope"));
+        assert_eq!(res.headers.get(X_CONTENT_TYPE_OPTIONS), Some("nosniff"));
+
+        let router = Router::new()
+            .get("/", || "hi")
+            .layer(SecurityHeaders::new());
+        let res = router.handle(state, Request::new(Method::Get, "/"));
+        assert_eq!(res.headers.get(CONTENT_SECURITY_POLICY), None);
+    }
+
+    #[test]
+    fn test_csrf() {
+        let router = Router::new()
+            .get("/", || "page")
+            .post("/files/*name", || "changed")
+            .layer(Csrf::new());
+        let state = Arc::new(State::new(Config::default()));
+        let send = |request: Request| router.handle(state.clone(), request);
+
+        let res = send(Request::new(Method::Get, "/"));
+        let cookie = res.headers.get(SET_COOKIE).unwrap().to_owned();
+        assert!(cookie.ends_with("; Path=/; SameSite=Strict"));
+        let token = cookie
+            .strip_prefix("csrf_token=")
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+        assert!(is_csrf_token(&token));
+        let with_cookie = || {
+            Request::new(Method::Post, "/files/a.txt")
+                .with_header(COOKIE, &format!("csrf_token={}", token))
+                .with_header(ORIGIN, "http://localhost:4221")
+        };
+
+        let res = send(with_cookie().with_header(X_CSRF_TOKEN, &token));
+        assert_eq!(res.body, "changed");
+        // a cookie that's kept isn't handed out again
+        assert_eq!(res.headers.get(SET_COOKIE), None);
+        let res = send(
+            with_cookie()
+                .with_header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .with_body(&format!("name=a&csrf_token={}", token)),
+        );
+        assert_eq!(res.body, "changed");
+        let mut request = with_cookie();
+        request.query = Some(format!("csrf_token={}", token));
+        assert_eq!(send(request).body, "changed");
+
+        // what a form on another site would send
+        assert_eq!(send(with_cookie()).status, StatusCode::FORBIDDEN);
+        let res = send(with_cookie().with_header(X_CSRF_TOKEN, "0123456789abcdef0123456789abcdef"));
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+        let res = send(
+            Request::new(Method::Post, "/files/a.txt")
+                .with_header(SEC_FETCH_SITE, "cross-site")
+                .with_header(X_CSRF_TOKEN, &token),
+        );
+        assert_eq!(res.status, StatusCode::FORBIDDEN);
+
+        // not from a browser
+        assert_eq!(
+            send(Request::new(Method::Post, "/files/a.txt")).body,
+            "changed"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::Path;
+
+pub(crate) const APPLICATION_OCTET_STREAM: &str = "application/octet-stream";
+
+// by lowercase file extension; text types are assumed to be UTF-8
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+// the Content-Type for a served file, from the configured overrides first and then the table;
+// unknown extensions are served as opaque bytes
+pub(crate) fn content_type<'a>(path: &Path, overrides: &'a [(String, String)]) -> &'a str {
+    let Some(extension) = path.extension().and_then(|extension| extension.to_str()) else {
+        return APPLICATION_OCTET_STREAM;
+    };
+    if let Some((_, mime)) = overrides
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+    {
+        return mime;
+    }
+    MIME_TYPES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map_or(APPLICATION_OCTET_STREAM, |(_, mime)| mime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type() {
+        let none = &[];
+        assert_eq!(
+            content_type(Path::new("index.html"), none),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type(Path::new("a/b/photo.JPG"), none), "image/jpeg");
+        assert_eq!(
+            content_type(Path::new("app.wasm"), none),
+            "application/wasm"
+        );
+        assert_eq!(
+            content_type(Path::new("data.bin"), none),
+            APPLICATION_OCTET_STREAM
+        );
+        assert_eq!(
+            content_type(Path::new("Makefile"), none),
+            APPLICATION_OCTET_STREAM
+        );
+
+        let overrides = [
+            ("bin".to_owned(), "application/x-custom".to_owned()),
+            ("txt".to_owned(), "text/plain; charset=latin1".to_owned()),
+        ];
+        assert_eq!(
+            content_type(Path::new("data.bin"), &overrides),
+            "application/x-custom"
+        );
+        assert_eq!(
+            content_type(Path::new("notes.TXT"), &overrides),
+            "text/plain; charset=latin1"
+        );
+        assert_eq!(
+            content_type(Path::new("a.css"), &overrides),
+            "text/css; charset=utf-8"
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use crate::http::Headers;
+use anyhow::{bail, Result};
+use std::io::{self, Read};
+
+// longest head of a single part
+const MAX_HEAD: usize = 16 * 1024;
+
+// a single part of a multipart/form-data body; from a Reader its data is left to be read
+#[derive(Debug)]
+pub struct Part {
+    pub headers: Headers,
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl Part {
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+}
+
+// boundary parameter of a `multipart/form-data` content type
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim().trim_matches('"');
+        (!value.is_empty()).then(|| value.to_owned())
+    })
+}
+
+pub fn parse(body: &[u8], boundary: &str) -> Result<Vec<Part>> {
+    let mut reader = Reader::new(body, boundary);
+    let mut parts = Vec::new();
+    while let Some(mut part) = reader.next_part()? {
+        reader.read_to_end(&mut part.data)?;
+        parts.push(part);
+    }
+    Ok(parts)
+}
+
+// reads a body a part at a time: next_part() gives the headers of the next one, then reading
+// from the Reader gives its data, so uploads of any size never have to be held in memory
+pub struct Reader<R> {
+    inner: R,
+    // read from inner but not handed out yet
+    buffer: Vec<u8>,
+    // "\r\n--boundary", which ends the data of every part
+    delimiter: Vec<u8>,
+    // reading the data of a part, or the preamble before the first
+    in_part: bool,
+    done: bool,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R, boundary: &str) -> Self {
+        Self {
+            inner,
+            // the first delimiter may come without a line break in front of it
+            buffer: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            in_part: true,
+            done: false,
+        }
+    }
+
+    // skips what's left of the current part's data; None after the last part
+    pub fn next_part(&mut self) -> Result<Option<Part>> {
+        if self.done {
+            return Ok(None);
+        }
+        io::copy(self, &mut io::sink())?;
+
+        // "--" after the last part, a line break before the next one's headers
+        while self.buffer.len() < 2 {
+            if !self.fill()? {
+                bail!("unterminated multipart body");
+            }
+        }
+        if self.buffer.starts_with(b"--") {
+            self.done = true;
+            return Ok(None);
+        }
+        if !self.buffer.starts_with(b"\r\n") {
+            bail!("invalid multipart boundary line");
+        }
+        self.buffer.drain(..2);
+
+        let head_end = loop {
+            if let Some(end) = find(&self.buffer, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buffer.len() > MAX_HEAD {
+                bail!("multipart headers too long");
+            }
+            if !self.fill()? {
+                bail!("unterminated multipart headers");
+            }
+        };
+        let headers = parse_headers(&self.buffer[..head_end])?;
+        self.buffer.drain(..head_end + 4);
+        self.in_part = true;
+
+        let disposition = headers.get("Content-Disposition").unwrap_or("");
+        Ok(Some(Part {
+            name: disposition_param(disposition, "name"),
+            filename: disposition_param(disposition, "filename"),
+            data: Vec::new(),
+            headers,
+        }))
+    }
+
+    // false at the end of the body
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 16 * 1024];
+        let n = self.inner.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    fn take(&mut self, n: usize, out: &mut [u8]) -> usize {
+        let n = n.min(out.len());
+        out[..n].copy_from_slice(&self.buffer[..n]);
+        self.buffer.drain(..n);
+        n
+    }
+}
+
+// the data of the current part, ending at the delimiter after it
+impl<R: Read> Read for Reader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if !self.in_part {
+            return Ok(0);
+        }
+        loop {
+            match find(&self.buffer, &self.delimiter) {
+                Some(0) => {
+                    self.buffer.drain(..self.delimiter.len());
+                    self.in_part = false;
+                    return Ok(0);
+                }
+                Some(end) => return Ok(self.take(end, out)),
+                None => {
+                    // all but what may be the start of a delimiter
+                    let safe = (self.buffer.len() + 1).saturating_sub(self.delimiter.len());
+                    if safe > 0 {
+                        return Ok(self.take(safe, out));
+                    }
+                }
+            }
+            if !self.fill()? {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unterminated multipart part",
+                ));
+            }
+        }
+    }
+}
+
+fn parse_headers(head: &[u8]) -> Result<Headers> {
+    let mut headers = Headers::new();
+    for line in String::from_utf8(head.to_vec())?.split("\r\n") {
+        let Some((key, value)) = line.split_once(':') else {
+            bail!("invalid multipart header");
+        };
+        headers.append(key.trim(), value.trim());
+    }
+    Ok(headers)
+}
+
+// parameter of a `Content-Disposition` value, e.g. `form-data; name="file"; filename="a.txt"`
+fn disposition_param(disposition: &str, name: &str) -> Option<String> {
+    disposition.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.trim().split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"').to_owned())
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boundary() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=abc"),
+            Some("abc".to_owned())
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b\""),
+            Some("a b".to_owned())
+        );
+        assert_eq!(boundary("text/plain; boundary=abc"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        let body = "preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            hello\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            line 1\r\nline 2\r\n--XyZ--\r\n";
+        let parts = parse(body.as_bytes(), "XyZ").unwrap();
+        assert_eq!(parts.len(), 2);
+
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].data, b"hello");
+
+        assert_eq!(parts[1].name.as_deref(), Some("file"));
+        assert_eq!(parts[1].filename.as_deref(), Some("a.txt"));
+        assert_eq!(parts[1].content_type(), Some("text/plain"));
+        assert_eq!(parts[1].data, b"line 1\r\nline 2");
+
+        assert!(parse(b"--XyZ\r\nno headers", "XyZ").is_err());
+        assert!(parse(b"nothing", "XyZ").is_err());
+
+        // read a byte at a time, a delimiter may be split anywhere
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+                let n = out.len().min(1);
+                self.0.read(&mut out[..n])
+            }
+        }
+        let mut reader = Reader::new(Trickle(body.as_bytes()), "XyZ");
+        reader.next_part().unwrap().unwrap();
+        let part = reader.next_part().unwrap().unwrap();
+        assert_eq!(part.filename.as_deref(), Some("a.txt"));
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"line 1\r\nline 2");
+        assert!(reader.next_part().unwrap().is_none());
+    }
+}
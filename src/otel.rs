@@ -0,0 +1,94 @@
+use crate::http::Headers;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::env;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{Layer, Registry};
+
+const SERVICE_NAME: &str = "http-server-rust";
+
+// exports spans over OTLP/HTTP to the collector the standard OTEL_EXPORTER_OTLP_ENDPOINT and
+// OTEL_EXPORTER_OTLP_TRACES_ENDPOINT variables name (http://localhost:4318 by default), batched on a
+// background thread; OTEL_SERVICE_NAME overrides the service name
+pub(crate) fn layer() -> Result<Box<dyn Layer<Registry> + Send + Sync>, String> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut resource = Resource::builder();
+    if env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(SERVICE_NAME);
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    Ok(Box::new(
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)),
+    ))
+}
+
+// continues the trace of the client's traceparent header, if it sent one, so the request shows up
+// under the service that called it
+pub(crate) fn set_parent(span: &Span, headers: &Headers) {
+    let context = TraceContextPropagator::new().extract(&HeaderExtractor(headers));
+    let _ = span.set_parent(context);
+}
+
+struct HeaderExtractor<'a>(&'a Headers);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|(key, _)| key).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, Request};
+    use crate::router::Router;
+    use crate::server::{handle_request, Config, State};
+    use opentelemetry::trace::TraceContextExt;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_trace_context() {
+        let seen = Arc::new(Mutex::new(None));
+        let router = Router::new().get("/", {
+            let seen = Arc::clone(&seen);
+            move || {
+                let context = Span::current().context();
+                let span = context.span();
+                *seen.lock().unwrap() = Some(span.span_context().trace_id().to_string());
+                "ok"
+            }
+        });
+        let state = Arc::new(State::with_router(Config::default(), router));
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let req = Request::new(Method::Get, "/").with_header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            );
+            handle_request(state.clone(), req);
+        });
+        assert_eq!(
+            seen.lock().unwrap().as_deref(),
+            Some("4bf92f3577b34da6a3ce929d0e0e4736")
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// fixed number of worker threads handling tasks from a bounded queue
+pub struct ThreadPool<T> {
+    sender: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> ThreadPool<T> {
+    pub fn new(
+        threads: usize,
+        queue_size: usize,
+        handle: impl Fn(T) + Send + Sync + 'static,
+    ) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handle = Arc::new(handle);
+
+        let workers = (0..threads)
+            .map(|i| {
+                let receiver = Arc::clone(&receiver);
+                let handle = Arc::clone(&handle);
+                thread::Builder::new()
+                    .name(format!("worker-{}", i))
+                    .spawn(move || work(&receiver, &*handle))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    // queues the task, handing it back if the queue is full
+    pub fn try_execute(&self, task: T) -> Result<(), T> {
+        let Some(sender) = &self.sender else {
+            return Err(task);
+        };
+        sender.try_send(task).map_err(|e| match e {
+            TrySendError::Full(task) | TrySendError::Disconnected(task) => task,
+        })
+    }
+}
+
+fn work<T>(receiver: &Mutex<Receiver<T>>, handle: &dyn Fn(T)) {
+    loop {
+        // the lock is released before handling so other workers can pick up tasks
+        let task = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        let Ok(task) = task else {
+            return;
+        };
+        // a panicking task must not take its worker down with it
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| handle(task)));
+    }
+}
+
+impl<T> Drop for ThreadPool<T> {
+    // finishes the queued tasks before returning
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_thread_pool() {
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let wait_release = Mutex::new(wait_release);
+        let handled = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&handled);
+        let pool = ThreadPool::new(1, 1, move |task: usize| {
+            if task == 0 {
+                started.send(()).unwrap();
+                wait_release.lock().unwrap().recv().unwrap();
+            }
+            if task == 2 {
+                panic!("task panicked");
+            }
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // the only worker is busy with 0 and the queue holds 1, so 2 is handed back
+        pool.try_execute(0).unwrap();
+        wait_started.recv().unwrap();
+        pool.try_execute(1).unwrap();
+        assert_eq!(pool.try_execute(2), Err(2));
+
+        release.send(()).unwrap();
+        for task in [2, 3] {
+            while pool.try_execute(task).is_err() {
+                thread::yield_now();
+            }
+        }
+        drop(pool);
+        assert_eq!(handled.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::app::{file_etag, modified_secs};
+use crate::server::{Mount, State};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+// how often the mounts are scanned for files changed by something other than the server
+const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+// a small file held in memory
+pub(crate) struct Preloaded {
+    pub bytes: Vec<u8>,
+    pub etag: String,
+    pub modified: Option<u64>,
+}
+
+// files under the mounts up to `max_size` bytes by their canonical path, so they're served
+// without opening them; symlinks aren't preloaded
+pub(crate) struct Preload {
+    max_size: u64,
+    files: RwLock<HashMap<PathBuf, Arc<Preloaded>>>,
+}
+
+impl Preload {
+    // nothing is preloaded with a max_size of 0
+    pub(crate) fn new(max_size: u64, mounts: &[Mount]) -> Self {
+        let preload = Self {
+            max_size,
+            files: RwLock::new(HashMap::new()),
+        };
+        preload.reload(mounts);
+        preload
+    }
+
+    pub(crate) fn get(&self, path: &Path) -> Option<Arc<Preloaded>> {
+        if self.max_size == 0 {
+            return None;
+        }
+        self.files.read().unwrap().get(path).cloned()
+    }
+
+    // scans the mounts again, only reading files whose ETag changed
+    pub(crate) fn reload(&self, mounts: &[Mount]) {
+        if self.max_size == 0 {
+            return;
+        }
+        let mut files = HashMap::new();
+        {
+            let old = self.files.read().unwrap();
+            for mount in mounts {
+                if let Ok(root) = Path::new(&mount.directory).canonicalize() {
+                    self.scan(&root, &old, &mut files);
+                }
+            }
+        }
+        *self.files.write().unwrap() = files;
+    }
+
+    // after the server changed the file or directory at the path
+    pub(crate) fn refresh(&self, path: &Path) {
+        if self.max_size == 0 {
+            return;
+        }
+        let mut files = self.files.write().unwrap();
+        files.retain(|known, _| !known.starts_with(path));
+        let mut found = HashMap::new();
+        self.scan(path, &HashMap::new(), &mut found);
+        files.extend(found);
+    }
+
+    // the file at the path, or every file below it
+    fn scan(
+        &self,
+        path: &Path,
+        old: &HashMap<PathBuf, Arc<Preloaded>>,
+        files: &mut HashMap<PathBuf, Arc<Preloaded>>,
+    ) {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return;
+        };
+        if metadata.is_dir() {
+            let Ok(entries) = fs::read_dir(path) else {
+                return;
+            };
+            for entry in entries.flatten() {
+                self.scan(&entry.path(), old, files);
+            }
+            return;
+        }
+        if !metadata.is_file() || metadata.len() > self.max_size {
+            return;
+        }
+        let etag = file_etag(&metadata);
+        let file = match old.get(path) {
+            Some(file) if file.etag == etag => Arc::clone(file),
+            _ => {
+                let Ok(bytes) = fs::read(path) else {
+                    return;
+                };
+                Arc::new(Preloaded {
+                    bytes,
+                    etag,
+                    modified: modified_secs(&metadata),
+                })
+            }
+        };
+        files.insert(path.to_owned(), file);
+    }
+}
+
+// rescans every RESCAN_INTERVAL for as long as the state is in use
+pub(crate) fn watch(state: &Arc<State>) {
+    if state.config.preload_max_size == 0 {
+        return;
+    }
+    let state = Arc::downgrade(state);
+    thread::spawn(move || loop {
+        thread::sleep(RESCAN_INTERVAL);
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        state.preload.reload(&state.config.mounts);
+    });
+}
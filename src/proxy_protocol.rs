@@ -0,0 +1,219 @@
+use anyhow::{bail, Result};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// what a version 2 header starts with, a version 1 header starts with "PROXY "
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// longest version 1 header, CRLF included
+const V1_MAX: usize = 107;
+// of the addresses and TLVs after a version 2 header's first 16 bytes; the spec has 536 bytes of
+// header fit in any segment, but load balancers may add more TLVs
+const V2_MAX: usize = 4096;
+
+// what's known of the header read so far
+enum Parsed {
+    // this many more bytes are needed, at least
+    More(usize),
+    // the client's address, None if the balancer connected on its own behalf (a health check) or
+    // didn't say
+    Done(Option<IpAddr>),
+}
+
+// reads the PROXY protocol header a TCP load balancer sends before anything of the client's,
+// without reading past it
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> Result<Option<IpAddr>> {
+    let mut header = Vec::new();
+    loop {
+        match parse(&header)? {
+            Parsed::More(n) => {
+                let start = header.len();
+                header.resize(start + n, 0);
+                reader.read_exact(&mut header[start..])?;
+            }
+            Parsed::Done(client) => return Ok(client),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub(crate) async fn read_header_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<IpAddr>> {
+    let mut header = Vec::new();
+    loop {
+        match parse(&header)? {
+            Parsed::More(n) => {
+                let start = header.len();
+                header.resize(start + n, 0);
+                reader.read_exact(&mut header[start..]).await?;
+            }
+            Parsed::Done(client) => return Ok(client),
+        }
+    }
+}
+
+fn parse(header: &[u8]) -> Result<Parsed> {
+    // as long as the shortest header, "PROXY UNKNOWN\r\n" of version 1
+    if header.len() < V2_SIGNATURE.len() {
+        return Ok(Parsed::More(V2_SIGNATURE.len() - header.len()));
+    }
+    if header.starts_with(V2_SIGNATURE) {
+        parse_v2(header)
+    } else if header.starts_with(b"PROXY ") {
+        parse_v1(header)
+    } else {
+        bail!("no PROXY protocol header");
+    }
+}
+
+// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n", read a byte at a time so nothing after it is
+fn parse_v1(header: &[u8]) -> Result<Parsed> {
+    if !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX {
+            bail!("PROXY protocol header too long");
+        }
+        return Ok(Parsed::More(1));
+    }
+    let line = std::str::from_utf8(&header[..header.len() - 2])?;
+    let parts: Vec<_> = line.split(' ').collect();
+    let client = match parts.get(1) {
+        Some(&"UNKNOWN") => return Ok(Parsed::Done(None)),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => parts[2].parse::<IpAddr>()?,
+        _ => bail!("invalid PROXY protocol header"),
+    };
+    parts[4].parse::<u16>()?;
+    if client.is_ipv4() != (parts[1] == "TCP4") {
+        bail!("invalid PROXY protocol header");
+    }
+    Ok(Parsed::Done(Some(client)))
+}
+
+// the signature, version and command, address family and protocol, length of the rest, then the
+// addresses
+fn parse_v2(header: &[u8]) -> Result<Parsed> {
+    if header.len() < 16 {
+        return Ok(Parsed::More(16 - header.len()));
+    }
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    if length > V2_MAX {
+        bail!("PROXY protocol header too long");
+    }
+    if header.len() < 16 + length {
+        return Ok(Parsed::More(16 + length - header.len()));
+    }
+    let addresses = &header[16..];
+    match (header[12], header[13] >> 4) {
+        // LOCAL, the balancer's own connection
+        (0x20, _) => Ok(Parsed::Done(None)),
+        (0x21, 1) if length >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into()?;
+            Ok(Parsed::Done(Some(Ipv4Addr::from(ip).into())))
+        }
+        (0x21, 2) if length >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into()?;
+            Ok(Parsed::Done(Some(Ipv6Addr::from(ip).into())))
+        }
+        // UNSPEC and UNIX sockets have no IP address to go by
+        (0x21, 0 | 3) => Ok(Parsed::Done(None)),
+        _ => bail!("invalid PROXY protocol header"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{serve, Config, Current, Listener, State};
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn test_proxy_protocol() {
+        // what follows the header is left to be read
+        let read = |header: &[u8]| {
+            let reader = [header, b"GET /"].concat();
+            let mut reader = reader.as_slice();
+            let client = read_header(&mut reader).map(|ip| ip.map(|ip| ip.to_string()));
+            (client.ok(), reader == b"GET /")
+        };
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        assert_eq!(read(v1), (Some(Some("192.0.2.1".into())), true));
+        let v1 = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(read(v1), (Some(Some("2001:db8::1".into())), true));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n"), (Some(None), true));
+        assert_eq!(read(b"PROXY TCP4 2001:db8::1 ::1 1 2\r\n").0, None);
+        assert_eq!(read(b"GET / HTTP/1.1\r\n").0, None);
+        assert_eq!(
+            read(&[b"PROXY TCP4 ".as_slice(), &[b'1'; 100]].concat()).0,
+            None
+        );
+
+        let v2 = |command: u8, family: u8, addresses: &[u8]| {
+            let length = (addresses.len() as u16).to_be_bytes();
+            [V2_SIGNATURE, &[command, family][..], &length, addresses].concat()
+        };
+        let tcp4 = [[192, 0, 2, 1], [198, 51, 100, 1], [0, 80, 1, 187]].concat();
+        assert_eq!(
+            read(&v2(0x21, 0x11, &tcp4)),
+            (Some(Some("192.0.2.1".into())), true)
+        );
+        let tcp6 = [&[0x20, 0x01, 0x0d, 0xb8][..], &[0; 11], &[1], &[0; 20]].concat();
+        let ip = Some(Some("2001:db8::1".into()));
+        assert_eq!(read(&v2(0x21, 0x21, &tcp6)), (ip, true));
+        // with TLVs after the addresses
+        let tlvs = [tcp4.as_slice(), &[0x04, 0, 2, 0, 0]].concat();
+        assert!(read(&v2(0x21, 0x11, &tlvs)).1);
+        assert_eq!(read(&v2(0x20, 0x00, &[])), (Some(None), true));
+        assert_eq!(read(&v2(0x21, 0x11, &tcp4[..8])).0, None);
+
+        // the client's address is the one requests come from
+        let current = Current::new(State::new(Config {
+            allowed_ips: vec!["192.0.2.0/24".parse().unwrap()],
+            max_connections_per_ip: Some(1),
+            ..Config::default()
+        }));
+        let listener = Listener {
+            socket: TcpListener::bind("127.0.0.1:0").unwrap(),
+            proxy_protocol: true,
+        };
+        let addr = listener.socket.local_addr().unwrap();
+        thread::spawn(move || serve(current, vec![listener]));
+        let get_ip = |header: &str| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            write!(
+                client,
+                "{}GET /ip HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+                header
+            )
+            .unwrap();
+            let mut response = String::new();
+            let _ = client.read_to_string(&mut response);
+            response
+        };
+        let response = get_ip("PROXY TCP4 192.0.2.7 127.0.0.1 40000 80\r\n");
+        assert!(response.ends_with("\r\n\r\n192.0.2.7"));
+        assert_eq!(get_ip("PROXY TCP4 10.0.0.7 127.0.0.1 40000 80\r\n"), "");
+        assert_eq!(get_ip(""), "");
+
+        // connections are counted by client, not by the balancer they all come from
+        let mut open = TcpStream::connect(addr).unwrap();
+        write!(
+            open,
+            "PROXY TCP4 192.0.2.7 127.0.0.1 40000 80\r\nGET /ip HTTP/1.1\r\nHost: a\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"192.0.2.7") {
+            let mut buf = [0; 1024];
+            let n = open.read(&mut buf).unwrap();
+            assert!(n > 0);
+            response.extend_from_slice(&buf[..n]);
+        }
+        let response = get_ip("PROXY TCP4 192.0.2.8 127.0.0.1 40000 80\r\n");
+        assert!(response.ends_with("\r\n\r\n192.0.2.8"));
+        let response = get_ip("PROXY TCP4 192.0.2.7 127.0.0.1 40000 80\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+}
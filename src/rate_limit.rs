@@ -0,0 +1,127 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// buckets of the clients are spread over this many separately locked maps, so connections from
+// different clients rarely wait for each other
+const SHARDS: usize = 16;
+// how often a shard drops the buckets of clients that have been quiet long enough to be full again
+const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+
+// a token bucket per client IP address: each request takes a token, and tokens come back at
+// `rate` per second up to `burst`
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+struct Shard {
+    buckets: HashMap<IpAddr, Bucket>,
+    evicted: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: f64, burst: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            hasher: RandomState::new(),
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        buckets: HashMap::new(),
+                        evicted: now,
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    // takes a token for the client, or tells how long until it'll have one
+    pub(crate) fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut shard = self.shard(client).lock().unwrap_or_else(|e| e.into_inner());
+        if now.saturating_duration_since(shard.evicted) >= EVICT_INTERVAL {
+            shard
+                .buckets
+                .retain(|_, bucket| self.refilled(bucket, now) < self.burst);
+            shard.evicted = now;
+        }
+
+        let bucket = shard.buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+
+    fn shard(&self, client: IpAddr) -> &Mutex<Shard> {
+        &self.shards[self.hasher.hash_one(client) as usize % SHARDS]
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let alice: IpAddr = "192.0.2.1".parse().unwrap();
+        let bob: IpAddr = "2001:db8::1".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(alice, start), Ok(()));
+        }
+        assert_eq!(limiter.check(alice, start), Err(Duration::from_millis(500)));
+        // other clients have their own bucket
+        assert_eq!(limiter.check(bob, start), Ok(()));
+
+        // a token back every half second
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check(alice, later), Ok(()));
+        assert!(limiter.check(alice, later).is_err());
+
+        // quiet clients are forgotten once their bucket would be full anyway
+        let carol = (2..=255)
+            .map(|n| IpAddr::from([192, 0, 2, n]))
+            .find(|&carol| std::ptr::eq(limiter.shard(carol), limiter.shard(alice)))
+            .unwrap();
+        assert_eq!(limiter.check(carol, start), Ok(()));
+        let known = |client| {
+            limiter
+                .shard(client)
+                .lock()
+                .unwrap()
+                .buckets
+                .contains_key(&client)
+        };
+        assert!(known(carol));
+        let much_later = start + EVICT_INTERVAL;
+        assert_eq!(limiter.check(alice, much_later), Ok(()));
+        assert!(!known(carol));
+        assert!(known(alice));
+    }
+}
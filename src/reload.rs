@@ -0,0 +1,214 @@
+use crate::app;
+use crate::logging;
+use crate::preload;
+use crate::router::Router;
+use crate::server::{check_config, panic_message, Config, Current};
+use anyhow::{bail, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+// replaces the config and router of a running server, see Server::reloader; connections already
+// open keep theirs until their current request is answered
+pub struct Reloader {
+    pub(crate) current: Arc<Current>,
+}
+
+impl Reloader {
+    // settings only read when the server starts keep their current values, and are returned by
+    // their option names so they can be reported as needing a restart
+    pub fn reload(&self, mut config: Config, router: Router) -> Result<Vec<&'static str>> {
+        check_config(&mut config)?;
+        let current = self.current.get();
+        let restart = keep_startup_settings(&current.config, &mut config);
+        let state = Arc::new(current.reloaded(config, router));
+        {
+            // nothing that could panic runs under the lock, which would poison it for every
+            // request after
+            let mut slot = self.current.0.write().unwrap();
+            if !Arc::ptr_eq(&slot, &current) {
+                bail!("Another reload happened at the same time!");
+            }
+            *slot = Arc::clone(&state);
+        }
+        logging::set_level(state.config.log_level);
+        preload::watch(&state);
+        app::watch_partial_uploads(&state);
+        Ok(restart)
+    }
+
+    // reloads with what `read` returns whenever the process gets SIGHUP, logging how it went;
+    // nothing changes if `read` or the reload fails
+    #[cfg(unix)]
+    pub fn reload_on_sighup<F>(self, read: F) -> Result<()>
+    where
+        F: Fn() -> Result<(Config, Router)> + Send + 'static,
+    {
+        let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                self.reload_logged(&read);
+            }
+        });
+        Ok(())
+    }
+
+    // a panic in `read` or the reload is logged like a failure, so later signals still reload
+    #[cfg(unix)]
+    fn reload_logged(&self, read: &impl Fn() -> Result<(Config, Router)>) {
+        let reloaded = panic::catch_unwind(AssertUnwindSafe(|| {
+            read().and_then(|(config, router)| self.reload(config, router))
+        }));
+        match reloaded {
+            Ok(Ok(restart)) if restart.is_empty() => info!("configuration reloaded"),
+            Ok(Ok(restart)) => warn!(
+                settings = restart.join(", "),
+                "configuration reloaded, except for settings that need a restart"
+            ),
+            Ok(Err(e)) => error!(
+                error = format!("{:#}", e),
+                "reloading the configuration failed, keeping the current one"
+            ),
+            Err(payload) => error!(
+                error = panic_message(&*payload),
+                "reloading the configuration panicked, keeping the current one"
+            ),
+        }
+    }
+}
+
+// the listeners, worker pool, TLS config and logs are set up once
+fn keep_startup_settings(current: &Config, config: &mut Config) -> Vec<&'static str> {
+    let mut restart = Vec::new();
+    macro_rules! keep {
+        ($($option:literal => $setting:ident),* $(,)?) => {
+            $(
+                if config.$setting != current.$setting {
+                    restart.push($option);
+                    config.$setting = current.$setting.clone();
+                }
+            )*
+        };
+    }
+    keep!(
+        "--bind" => bind,
+        "--proxy-protocol-bind" => proxy_protocol_bind,
+        "--port" => port,
+        "--threads" => threads,
+        "--queue-size" => queue_size,
+        "--acceptors" => acceptors,
+        "--access-log" => access_log,
+        "--access-log-max-size" => access_log_max_size,
+        "--access-log-rotate" => access_log_period,
+        "--access-log-keep" => access_log_keep,
+        "--log-format" => log_format,
+    );
+    #[cfg(feature = "async")]
+    keep!("--async" => async_io);
+    #[cfg(feature = "tls")]
+    keep!(
+        "--tls-cert" => tls_cert,
+        "--tls-key" => tls_key,
+        "--tls-client-ca" => tls_client_ca,
+        "--tls-client-optional" => tls_client_optional,
+    );
+    restart
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::app_router;
+    use crate::http::{Method, Request, StatusCode};
+    use crate::server::{handle_connection, handle_request, Mount, State};
+    use crate::test_util::TempDir;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn test_reload() {
+        let dir = TempDir::new("reload");
+        fs::create_dir_all(dir.join("one")).unwrap();
+        fs::create_dir_all(dir.join("two")).unwrap();
+        fs::write(dir.join("one/a.txt"), "one").unwrap();
+        fs::write(dir.join("two/b.txt"), "two").unwrap();
+        let config = |directory: &str, threads: usize| {
+            let mut config = Config::default();
+            config.add_mount(Mount::new("files", dir.join(directory).to_str().unwrap()));
+            config.threads = threads;
+            config
+        };
+        let current = Current::new(State::new(config("one", 4)));
+        let state = current.get();
+        let reloader = Reloader {
+            current: Arc::clone(&current),
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connection = thread::spawn({
+            let state = Arc::clone(&state);
+            move || handle_connection(state, listener.accept().unwrap().0, false)
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /files/a.txt HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"one") {
+            let mut buf = [0; 1024];
+            let n = client.read(&mut buf).unwrap();
+            assert!(n > 0);
+            response.extend_from_slice(&buf[..n]);
+        }
+
+        let mut reloaded = config("two", 8);
+        reloaded.rate_limit = Some(5.0);
+        let router = app_router(&reloaded);
+        assert_eq!(reloader.reload(reloaded, router).unwrap(), ["--threads"]);
+        let latest = state.latest();
+        assert_eq!(latest.config.threads, 4);
+        assert!(latest.rate_limiter.is_some());
+        assert!(Arc::ptr_eq(&latest.metrics, &state.metrics));
+        let get = |state: &Arc<State>, path: &str| {
+            handle_request(Arc::clone(state), Request::new(Method::Get, path)).status
+        };
+        assert_eq!(get(&latest, "/files/b.txt"), StatusCode::OK);
+        assert_eq!(get(&latest, "/files/a.txt"), StatusCode::NOT_FOUND);
+
+        // the open connection's next request is served with the new settings
+        client
+            .write_all(b"GET /files/a.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+        // the replaced state is dropped once its last connection closed
+        connection.join().unwrap();
+        let replaced = Arc::downgrade(&state);
+        drop(state);
+        assert!(replaced.upgrade().is_none());
+
+        // a config that can't be served is refused, keeping the current one
+        let missing = config("three", 4);
+        let router = app_router(&missing);
+        assert!(reloader.reload(missing, router).is_err());
+        assert!(Arc::ptr_eq(&current.get(), &latest));
+
+        // as is one that panics while being read, and the next signal reloads again
+        #[cfg(unix)]
+        {
+            reloader.reload_logged(&|| panic!("unreadable"));
+            assert!(Arc::ptr_eq(&current.get(), &latest));
+            reloader.reload_logged(&|| {
+                let config = config("one", 4);
+                let router = app_router(&config);
+                Ok((config, router))
+            });
+            assert_eq!(get(&current.get(), "/files/a.txt"), StatusCode::OK);
+        }
+    }
+}
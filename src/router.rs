@@ -0,0 +1,911 @@
+use crate::extract::FromRequest;
+use crate::http::{
+    percent_encode_path, Method, Request, Response, StatusCode, StatusError, ALLOW, LOCATION,
+};
+use crate::into_response::IntoResponse;
+use crate::server::{catch_panic, State};
+use regex::Regex;
+use std::sync::Arc;
+
+// anything that can answer a request: functions and closures taking extractors, optionally
+// followed by the request itself, and returning anything IntoResponse; Args only keeps the
+// impls apart
+pub trait Handler<Args>: Send + Sync + 'static {
+    fn call(&self, state: Arc<State>, request: Request) -> Response;
+}
+
+macro_rules! impl_handler {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg,)*> Handler<(R, $($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, state: Arc<State>, request: Request) -> Response {
+                $(
+                    let $arg = match $arg::from_request(&state, &request) {
+                        Ok(value) => value,
+                        Err(StatusError(status)) => return Response::new(status),
+                    };
+                )*
+                self($($arg),*).into_response()
+            }
+        }
+
+        impl<F, R, $($arg,)*> Handler<(R, $($arg,)* Request,)> for F
+        where
+            F: Fn($($arg,)* Request) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, state: Arc<State>, request: Request) -> Response {
+                $(
+                    let $arg = match $arg::from_request(&state, &request) {
+                        Ok(value) => value,
+                        Err(StatusError(status)) => return Response::new(status),
+                    };
+                )*
+                self($($arg,)* request).into_response()
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(T1);
+impl_handler!(T1, T2);
+impl_handler!(T1, T2, T3);
+impl_handler!(T1, T2, T3, T4);
+
+// a handler with its argument types erased so routes can be stored together
+type BoxedHandler = Box<dyn Fn(Arc<State>, Request) -> Response + Send + Sync>;
+
+fn boxed<Args>(handler: impl Handler<Args>) -> BoxedHandler {
+    Box::new(move |state, request| handler.call(state, request))
+}
+
+// renders the body of an error response, e.g. as a JSON envelope
+pub type ErrorHandler = fn(StatusCode) -> Response;
+
+// wraps request handling, e.g. for logging, auth or CORS: it can change the request before
+// passing it on with next.run(), change the response on the way back, or answer by itself
+pub trait Middleware: Send + Sync + 'static {
+    fn call(&self, request: Request, next: Next) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(Request, Next) -> Response + Send + Sync + 'static,
+{
+    fn call(&self, request: Request, next: Next) -> Response {
+        self(request, next)
+    }
+}
+
+// the rest of the chain: the middleware inside the current one, then the handler
+pub struct Next<'a> {
+    state: Arc<State>,
+    // outermost last
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a (dyn Fn(Arc<State>, Request) -> Response + Send + Sync),
+}
+
+impl Next<'_> {
+    pub fn state(&self) -> &Arc<State> {
+        &self.state
+    }
+
+    pub fn run(self, request: Request) -> Response {
+        match self.middleware.split_last() {
+            Some((outer, inner)) => outer.call(
+                request,
+                Next {
+                    middleware: inner,
+                    ..self
+                },
+            ),
+            None => {
+                let target = format!("{} {}", request.method.as_str(), request.path);
+                let state = Arc::clone(&self.state);
+                catch_panic(&state, &target, || (self.endpoint)(self.state, request))
+            }
+        }
+    }
+}
+
+// runs the handler inside the middleware of the router it was nested from
+fn with_middleware(handler: BoxedHandler, middleware: Arc<[Arc<dyn Middleware>]>) -> BoxedHandler {
+    Box::new(move |state, request| {
+        let next = Next {
+            state,
+            middleware: &middleware,
+            endpoint: &*handler,
+        };
+        next.run(request)
+    })
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    // ":name", capturing a single non-empty segment
+    Param(String),
+    // "{name:regex}" groups mixed with literal text, capturing every named group
+    Regex(Regex),
+    // trailing "*name", capturing all remaining segments joined by "/"
+    Rest(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: BoxedHandler,
+}
+
+impl Route {
+    // the captured params if the path matches
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut path_segments = path.split('/').skip(1);
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    if path_segments.next() != Some(literal.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => match path_segments.next() {
+                    Some(value) if !value.is_empty() => {
+                        params.push((name.clone(), value.to_owned()))
+                    }
+                    _ => return None,
+                },
+                Segment::Regex(regex) => {
+                    let captures = regex.captures(path_segments.next()?)?;
+                    for name in regex.capture_names().flatten() {
+                        if let Some(value) = captures.name(name) {
+                            params.push((name.to_owned(), value.as_str().to_owned()));
+                        }
+                    }
+                }
+                Segment::Rest(name) => {
+                    let rest = path_segments.collect::<Vec<_>>();
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    params.push((name.clone(), rest.join("/")));
+                    return Some(params);
+                }
+            }
+        }
+        path_segments.next().is_none().then_some(params)
+    }
+}
+
+// what to do with a path that only matches a route after adding or removing a trailing slash
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TrailingSlash {
+    // "/a/" and "/a" are different paths
+    #[default]
+    Strict,
+    // dispatch to the route as if the path had matched
+    Normalize,
+    // answer with the matching path as Location: 301 for GET and HEAD, 308 for other methods so
+    // clients don't turn them into a GET
+    Redirect,
+}
+
+enum RouteMatch<'a> {
+    Found(&'a Route, Vec<(String, String)>),
+    // methods of the routes whose path matched, in registration order
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+// dispatches requests to the first route matching both method and path
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    // handles requests no route matched, 404 if not set
+    fallback: Option<BoxedHandler>,
+    // renders error responses that came back without a body, left bare if not set
+    error_handler: Option<ErrorHandler>,
+    trailing_slash: TrailingSlash,
+    // wraps everything handle() does, in the order added with the last one outermost
+    middleware: Vec<Arc<dyn Middleware>>,
+    // methods implemented but not routed here, e.g. the writes of a read-only mount, so they get
+    // 405 with Allow rather than 501
+    recognized: Vec<Method>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<Args>(
+        mut self,
+        method: Method,
+        pattern: &str,
+        handler: impl Handler<Args>,
+    ) -> Self {
+        self.routes.push(Route {
+            method,
+            segments: parse_pattern(pattern),
+            handler: boxed(handler),
+        });
+        self
+    }
+
+    pub fn get<Args>(self, pattern: &str, handler: impl Handler<Args>) -> Self {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<Args>(self, pattern: &str, handler: impl Handler<Args>) -> Self {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<Args>(self, pattern: &str, handler: impl Handler<Args>) -> Self {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<Args>(self, pattern: &str, handler: impl Handler<Args>) -> Self {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    pub fn patch<Args>(self, pattern: &str, handler: impl Handler<Args>) -> Self {
+        self.route(Method::Patch, pattern, handler)
+    }
+
+    // mounts all routes of another router under a prefix, e.g. "/api" + "/users"; its
+    // middleware keeps wrapping just those routes, but its fallback, error handler and trailing
+    // slash setting are left behind, requests under the prefix get this router's
+    pub fn nest(mut self, prefix: &str, router: Router) -> Self {
+        let prefix = parse_pattern(prefix);
+        assert!(
+            !prefix
+                .iter()
+                .any(|segment| matches!(segment, Segment::Rest(_))),
+            "a nesting prefix can't contain \"*\""
+        );
+
+        self.recognized.extend(router.recognized);
+        let middleware: Arc<[Arc<dyn Middleware>]> = router.middleware.into();
+        for mut route in router.routes {
+            route.segments = prefix.iter().cloned().chain(route.segments).collect();
+            if !middleware.is_empty() {
+                route.handler = with_middleware(route.handler, Arc::clone(&middleware));
+            }
+            self.routes.push(route);
+        }
+        self
+    }
+
+    // routes sharing a path prefix, registered on a fresh router
+    pub fn group(self, prefix: &str, build: impl FnOnce(Router) -> Router) -> Self {
+        self.nest(prefix, build(Router::new()))
+    }
+
+    // a GET route answers HEAD too, unless the path has a HEAD route of its own
+    fn find(&self, method: &Method, path: &str) -> RouteMatch<'_> {
+        let found = self.find_exact(method, path);
+        if *method == Method::Head && !matches!(found, RouteMatch::Found(..)) {
+            let get = self.find_exact(&Method::Get, path);
+            if matches!(get, RouteMatch::Found(..)) {
+                return get;
+            }
+        }
+        found
+    }
+
+    fn find_exact(&self, method: &Method, path: &str) -> RouteMatch<'_> {
+        for route in self.routes.iter().filter(|route| route.method == *method) {
+            if let Some(params) = route.matches(path) {
+                return RouteMatch::Found(route, params);
+            }
+        }
+
+        let allowed = self.allowed(path);
+        if allowed.is_empty() {
+            RouteMatch::NotFound
+        } else {
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+    }
+
+    // the methods routed for the path in the order registered, as Allow lists them; HEAD comes
+    // with GET
+    pub(crate) fn allowed(&self, path: &str) -> Vec<Method> {
+        let mut allowed: Vec<Method> = Vec::new();
+        for route in &self.routes {
+            if route.matches(path).is_some() && !allowed.contains(&route.method) {
+                allowed.push(route.method.clone());
+            }
+        }
+
+        if let Some(get) = allowed.iter().position(|method| *method == Method::Get) {
+            if !allowed.contains(&Method::Head) {
+                allowed.insert(get + 1, Method::Head);
+            }
+        }
+        allowed
+    }
+
+    // answers the method with 405 where it isn't routed, instead of 501
+    pub(crate) fn recognize(mut self, method: Method) -> Self {
+        if !self.recognized.contains(&method) {
+            self.recognized.push(method);
+        }
+        self
+    }
+
+    // wraps every request this router handles, whether a route matched or not; inside a group
+    // only the group's routes
+    pub fn layer(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // like layer, but inside the middleware layered so far, so it only sees the requests that got
+    // through it
+    pub(crate) fn layer_inner(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.insert(0, Arc::new(middleware));
+        self
+    }
+
+    pub fn handle(&self, state: Arc<State>, request: Request) -> Response {
+        let next = Next {
+            state,
+            middleware: &self.middleware,
+            endpoint: &|state, request| self.dispatch(state, request),
+        };
+        next.run(request)
+    }
+
+    fn dispatch(&self, state: Arc<State>, mut request: Request) -> Response {
+        // a method no route was registered with isn't one we implement
+        if let Method::Other(_) = request.method {
+            let routed = self
+                .routes
+                .iter()
+                .any(|route| route.method == request.method);
+            if !routed && !self.recognized.contains(&request.method) {
+                return Response::new(StatusCode::NOT_IMPLEMENTED);
+            }
+        }
+
+        let mut found = self.find(&request.method, &request.path);
+
+        if matches!(found, RouteMatch::NotFound) && self.trailing_slash != TrailingSlash::Strict {
+            if let Some(alternate) = toggle_trailing_slash(&request.path) {
+                let alternate_found = self.find(&request.method, &alternate);
+                if matches!(alternate_found, RouteMatch::Found(..)) {
+                    if self.trailing_slash == TrailingSlash::Redirect {
+                        let location = match &request.query {
+                            Some(query) => format!("{}?{}", percent_encode_path(&alternate), query),
+                            None => percent_encode_path(&alternate),
+                        };
+                        let status = match request.method.as_str() {
+                            "GET" | "HEAD" => StatusCode::MOVED_PERMANENTLY,
+                            _ => StatusCode::PERMANENT_REDIRECT,
+                        };
+                        return Response::new(status).with_header(LOCATION, &location);
+                    }
+                    request.path = alternate;
+                    found = alternate_found;
+                }
+            }
+        }
+
+        match found {
+            RouteMatch::Found(route, params) => {
+                request.params = params;
+                // the GET handler answering a HEAD gets a GET, the body is dropped when written
+                if request.method == Method::Head && route.method == Method::Get {
+                    request.method = Method::Get;
+                }
+                (route.handler)(state, request)
+            }
+            RouteMatch::MethodNotAllowed(allowed) => {
+                let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+                Response::new(StatusCode::METHOD_NOT_ALLOWED).with_header(ALLOW, &allow.join(", "))
+            }
+            RouteMatch::NotFound => match &self.fallback {
+                Some(fallback) => fallback(state, request),
+                None => Response::new(StatusCode::NOT_FOUND),
+            },
+        }
+    }
+
+    pub fn trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    pub fn fallback<Args>(mut self, handler: impl Handler<Args>) -> Self {
+        self.fallback = Some(boxed(handler));
+        self
+    }
+
+    pub fn error_handler(mut self, handler: ErrorHandler) -> Self {
+        self.error_handler = Some(handler);
+        self
+    }
+
+    // replaces a bare error response with the error handler's, keeping headers like Allow
+    pub(crate) fn render_error(&self, response: Response) -> Response {
+        let Some(error_handler) = self.error_handler else {
+            return response;
+        };
+        if !response.status.is_error() || !response.body.is_empty() {
+            return response;
+        }
+
+        let mut rendered = error_handler(response.status);
+        for (key, value) in response.headers.iter() {
+            if rendered.headers.get(key).is_none() {
+                rendered.headers.append(key, value);
+            }
+        }
+        rendered
+            .suppressed_headers
+            .extend(response.suppressed_headers);
+        rendered
+    }
+}
+
+// "/a/" for "/a" and the other way around, None for the root
+fn toggle_trailing_slash(path: &str) -> Option<String> {
+    match path {
+        "/" => None,
+        _ => match path.strip_suffix('/') {
+            Some(trimmed) => Some(trimmed.to_owned()),
+            None => Some(format!("{}/", path)),
+        },
+    }
+}
+
+// patterns are "/"-separated literals or ":name" params, optionally ending in "*name"
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let segments = pattern
+        .split('/')
+        .skip(1)
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_owned())
+            } else if segment.contains('{') {
+                Segment::Regex(parse_regex_segment(segment, pattern))
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Rest(name.to_owned())
+            } else {
+                Segment::Literal(segment.to_owned())
+            }
+        })
+        .collect::<Vec<_>>();
+    let last = segments.len().saturating_sub(1);
+    assert!(
+        !segments[..last]
+            .iter()
+            .any(|segment| matches!(segment, Segment::Rest(_))),
+        "\"*\" must be the last segment of {}",
+        pattern
+    );
+    segments
+}
+
+// "{name:regex}" becomes a named group, "{name}" matches anything, the rest is literal text;
+// braces inside the regex must balance, and it can't contain "/" since patterns split on it
+fn parse_regex_segment(segment: &str, pattern: &str) -> Regex {
+    let mut source = String::from("^");
+    let mut rest = segment;
+    while let Some(start) = rest.find('{') {
+        source.push_str(&regex::escape(&rest[..start]));
+        let mut depth = 0;
+        let end = rest[start..]
+            .char_indices()
+            .find_map(|(i, c)| {
+                match c {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => {}
+                }
+                (depth == 0).then_some(start + i)
+            })
+            .unwrap_or_else(|| panic!("unclosed \"{{\" in {}", pattern));
+        let (name, group) = match rest[start + 1..end].split_once(':') {
+            Some((name, group)) => (name, group),
+            None => (&rest[start + 1..end], ".+"),
+        };
+        source.push_str(&format!("(?P<{}>{})", name, group));
+        rest = &rest[end + 1..];
+    }
+    source.push_str(&regex::escape(rest));
+    source.push('$');
+    Regex::new(&source).unwrap_or_else(|e| panic!("invalid regex in {}: {}", pattern, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::app_router;
+    use crate::http::{write_response, ServerError, APPLICATION_JSON, CONTENT_TYPE};
+    use crate::server::{handle_request, Config};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn dispatch(request: Request) -> Response {
+        handle_request(Arc::new(State::new(Config::default())), request)
+    }
+
+    #[test]
+    fn test_handlers() {
+        struct Greeting(&'static str);
+
+        impl Handler<Greeting> for Greeting {
+            fn call(&self, state: Arc<State>, _request: Request) -> Response {
+                let body = format!("{} from {}", self.0, state.config.mounts[0].directory);
+                Response::new(StatusCode::OK).with_body(&body)
+            }
+        }
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+        let router = Router::new()
+            .get("/", || Response::new(StatusCode::OK))
+            .get("/path", |request: Request| {
+                Response::new(StatusCode::OK).with_body(&request.path)
+            })
+            .get("/count", move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Response::new(StatusCode::NO_CONTENT)
+            })
+            .get("/dir", |state: Arc<State>, _request: Request| {
+                Response::new(StatusCode::OK).with_body(&state.config.mounts[0].directory)
+            })
+            .get("/greeting", Greeting("hi"));
+        let state = Arc::new(State::new(Config::default()));
+        let get = |path: &str| router.handle(state.clone(), Request::new(Method::Get, path));
+
+        assert_eq!(get("/").status, StatusCode::OK);
+        assert_eq!(get("/path").body, "/path");
+        get("/count");
+        get("/count");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(get("/dir").body, "lol");
+        assert_eq!(get("/greeting").body, "hi from lol");
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let router = Router::new()
+            .get("/items/:id", || "got")
+            .post("/items/:id", || "posted")
+            .delete("/items/:id", || "deleted")
+            .route(Method::Other("PROPFIND".to_owned()), "/items/:id", || {
+                "found"
+            })
+            .put("/items", || "put")
+            .patch("/*rest", || "patched")
+            .delete("/items/:id", || "deleted again");
+        let state = Arc::new(State::new(Config::default()));
+        let send =
+            |method: Method, path: &str| router.handle(state.clone(), Request::new(method, path));
+
+        // every route matching the path, in the order registered, each method once
+        let res = send(Method::Put, "/items/7");
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(
+            res.headers.get(ALLOW),
+            Some("GET, HEAD, POST, DELETE, PROPFIND, PATCH")
+        );
+        assert_eq!(res.body, "");
+        let res = send(Method::Get, "/items");
+        assert_eq!(res.headers.get(ALLOW), Some("PUT, PATCH"));
+
+        assert_eq!(send(Method::Delete, "/items/7").body, "deleted");
+        assert_eq!(send(Method::Patch, "/items/7").body, "patched");
+        let res = send(Method::Get, "/");
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers.get(ALLOW), Some("PATCH"));
+        let router = Router::new().get("/items/:id", || "got");
+        let res = router.handle(state.clone(), Request::new(Method::Post, "/other"));
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+        assert_eq!(res.headers.get(ALLOW), None);
+    }
+
+    #[test]
+    fn test_route_params() {
+        let route = Router::new()
+            .route(Method::Get, "/users/:id/posts/:post", || {
+                Response::new(StatusCode::OK)
+            })
+            .routes
+            .remove(0);
+        assert_eq!(
+            route.matches("/users/7/posts/hello"),
+            Some(vec![
+                ("id".to_owned(), "7".to_owned()),
+                ("post".to_owned(), "hello".to_owned())
+            ])
+        );
+        assert_eq!(route.matches("/users//posts/hello"), None);
+        assert_eq!(route.matches("/users/7/posts"), None);
+        assert_eq!(route.matches("/users/7/posts/hello/x"), None);
+
+        let res = dispatch(Request::new(Method::Get, "/echo/hello"));
+        assert_eq!(res.body, "hello");
+    }
+
+    #[test]
+    fn test_catch_all_routes() {
+        let route = Router::new()
+            .route(Method::Get, "/static/*path", || {
+                Response::new(StatusCode::OK)
+            })
+            .routes
+            .remove(0);
+        assert_eq!(
+            route.matches("/static/css/site.css"),
+            Some(vec![("path".to_owned(), "css/site.css".to_owned())])
+        );
+        assert_eq!(
+            route.matches("/static/"),
+            Some(vec![("path".to_owned(), "".to_owned())])
+        );
+        assert_eq!(route.matches("/static"), None);
+
+        let res = dispatch(Request::new(Method::Get, "/echo/a/b"));
+        assert_eq!(res.body, "a/b");
+
+        let router = Router::new()
+            .fallback(|request: Request| Response::new(StatusCode::OK).with_body(&request.path));
+        let state = Arc::new(State::new(Config::default()));
+        let res = router.handle(state, Request::new(Method::Get, "/anything"));
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "/anything");
+    }
+
+    #[test]
+    fn test_nested_routers() {
+        let api = Router::new()
+            .route(Method::Get, "/users/:id", |request: Request| {
+                Response::new(StatusCode::OK).with_body(request.param("id").unwrap())
+            })
+            .group("/admin", |admin| {
+                admin.route(Method::Get, "", || Response::new(StatusCode::OK))
+            });
+        let router = Router::new().nest("/api/v1", api);
+        let state = Arc::new(State::new(Config::default()));
+
+        let res = router.handle(state.clone(), Request::new(Method::Get, "/api/v1/users/7"));
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "7");
+
+        let res = router.handle(state.clone(), Request::new(Method::Get, "/api/v1/admin"));
+        assert_eq!(res.status, StatusCode::OK);
+
+        let res = router.handle(state.clone(), Request::new(Method::Get, "/users/7"));
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_regex_routes() {
+        let router = Router::new()
+            .route(
+                Method::Get,
+                r"/files/{name:[a-z0-9_-]+\.txt}",
+                |request: Request| {
+                    Response::new(StatusCode::OK).with_body(request.param("name").unwrap())
+                },
+            )
+            .route(
+                Method::Get,
+                r"/v{major:\d+}.{minor:\d{1,2}}/{slug}",
+                |request: Request| {
+                    let body = format!("{:?}", request.params);
+                    Response::new(StatusCode::OK).with_body(&body)
+                },
+            );
+        let state = Arc::new(State::new(Config::default()));
+
+        let res = router.handle(state.clone(), Request::new(Method::Get, "/files/a_1.txt"));
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.body, "a_1.txt");
+        for path in [
+            "/files/A.txt",
+            "/files/a.txt.bak",
+            "/files/atxt",
+            "/files/a.txt/b",
+        ] {
+            let res = router.handle(state.clone(), Request::new(Method::Get, path));
+            assert_eq!(res.status, StatusCode::NOT_FOUND, "{}", path);
+        }
+
+        let res = router.handle(state.clone(), Request::new(Method::Get, "/v1.12/x"));
+        assert_eq!(
+            res.body,
+            r#"[("major", "1"), ("minor", "12"), ("slug", "x")]"#
+        );
+        let res = router.handle(state.clone(), Request::new(Method::Get, "/v1x12/x"));
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_error_handler() {
+        let router = Router::new()
+            .route(Method::Get, "/bare", || {
+                Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+            })
+            .route(Method::Get, "/body", || {
+                Response::new(StatusCode::BAD_REQUEST).with_body("custom")
+            })
+            .error_handler(|status| {
+                let body = format!(r#"{{"error":"{}"}}"#, status.reason_phrase());
+                Response::new(status)
+                    .with_body(&body)
+                    .with_content_type_and_current_length(APPLICATION_JSON)
+            });
+        let state = Arc::new(State::with_router(Config::default(), router));
+        let get = |path: &str| handle_request(state.clone(), Request::new(Method::Get, path));
+
+        let res = get("/nope");
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+        assert_eq!(res.body, r#"{"error":"Not Found"}"#);
+        assert_eq!(res.headers.get(CONTENT_TYPE), Some(APPLICATION_JSON));
+
+        let res = get("/bare");
+        assert_eq!(res.body, r#"{"error":"Internal Server Error"}"#);
+        assert_eq!(get("/body").body, "custom");
+
+        let res = handle_request(state.clone(), Request::new(Method::Post, "/bare"));
+        assert_eq!(res.body, r#"{"error":"Method Not Allowed"}"#);
+        assert_eq!(res.headers.get(ALLOW), Some("GET, HEAD"));
+    }
+
+    #[test]
+    fn test_middleware() {
+        fn log(request: Request, next: Next) -> Response {
+            let path = request.path.clone();
+            let response = next.run(request);
+            let line = format!("{} {}", path, response.status);
+            response.with_appended_header("X-Log", &line)
+        }
+
+        fn require_token(request: Request, next: Next) -> Response {
+            match request.headers.get("Authorization") {
+                Some("token") => next.run(request),
+                _ => Response::new(StatusCode::UNAUTHORIZED),
+            }
+        }
+
+        let router = Router::new()
+            .get("/", || "home")
+            .group("/admin", |admin| {
+                admin.get("", || "admin").layer(require_token)
+            })
+            .layer(log)
+            .layer(|mut request: Request, next: Next| {
+                // outermost, so log sees the rewritten path
+                request.path = request.path.replace("/old", "");
+                next.run(request).with_header("X-Outer", "1")
+            });
+        let state = Arc::new(State::new(Config::default()));
+        let handle = |request: Request| router.handle(state.clone(), request);
+
+        let res = handle(Request::new(Method::Get, "/old/"));
+        assert_eq!(res.body, "home");
+        assert_eq!(res.headers.get("X-Log"), Some("/ 200 OK"));
+        assert_eq!(res.headers.get("X-Outer"), Some("1"));
+
+        let res = handle(Request::new(Method::Get, "/admin"));
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers.get("X-Log"), Some("/admin 401 Unauthorized"));
+        let res = handle(Request::new(Method::Get, "/admin").with_header("Authorization", "token"));
+        assert_eq!(res.body, "admin");
+
+        // unmatched requests pass through the router's middleware too, but not a group's
+        let res = handle(Request::new(Method::Get, "/nope"));
+        assert_eq!(res.headers.get("X-Log"), Some("/nope 404 Not Found"));
+        let res = handle(Request::new(Method::Other("BREW".to_owned()), "/"));
+        assert_eq!(res.headers.get("X-Log"), Some("/ 501 Not Implemented"));
+    }
+
+    #[test]
+    fn test_fallible_handlers() {
+        let router = Router::new()
+            .get("/missing", || -> Result<String, ServerError> {
+                Ok(std::fs::read_to_string("lol/does-not-exist")?)
+            })
+            .get("/status", || -> Result<&'static str, ServerError> {
+                Err(StatusError(StatusCode::CONFLICT).into())
+            })
+            .get("/invalid", || -> Result<&'static str, ServerError> {
+                Err(ServerError::new(StatusCode::BAD_REQUEST, "invalid id"))
+            });
+        let state = Arc::new(State::new(Config::default()));
+        let get = |path: &str| router.handle(state.clone(), Request::new(Method::Get, path));
+
+        let res = get("/missing");
+        assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.body, "");
+        assert_eq!(get("/status").status, StatusCode::CONFLICT);
+        let res = get("/invalid");
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert_eq!(res.body, "invalid id");
+    }
+
+    #[test]
+    fn test_trailing_slash() {
+        let build = |trailing_slash| {
+            Router::new()
+                .route(Method::Get, "/a", || Response::new(StatusCode::OK))
+                .route(Method::Post, "/a", || Response::new(StatusCode::OK))
+                .route(Method::Get, "/b/", |request: Request| {
+                    Response::new(StatusCode::OK).with_body(&request.path)
+                })
+                .trailing_slash(trailing_slash)
+        };
+        let state = Arc::new(State::new(Config::default()));
+        let status = |router: &Router, path: &str| {
+            router
+                .handle(state.clone(), Request::new(Method::Get, path))
+                .status
+        };
+
+        let strict = build(TrailingSlash::Strict);
+        assert_eq!(status(&strict, "/a"), StatusCode::OK);
+        assert_eq!(status(&strict, "/a/"), StatusCode::NOT_FOUND);
+        assert_eq!(status(&strict, "/b"), StatusCode::NOT_FOUND);
+
+        let normalize = build(TrailingSlash::Normalize);
+        assert_eq!(status(&normalize, "/a/"), StatusCode::OK);
+        let res = normalize.handle(state.clone(), Request::new(Method::Get, "/b"));
+        assert_eq!(res.body, "/b/");
+
+        let redirect = build(TrailingSlash::Redirect);
+        let res = redirect.handle(state.clone(), Request::new(Method::Get, "/a/?x=1"));
+        assert_eq!(res.status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(res.headers.get(LOCATION), Some("/a?x=1"));
+        let res = redirect.handle(state.clone(), Request::new(Method::Post, "/a/"));
+        assert_eq!(res.status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(status(&redirect, "/c/"), StatusCode::NOT_FOUND);
+
+        assert_eq!(percent_encode_path("/a b/ü"), "/a%20b/%C3%BC");
+        assert_eq!(
+            status(&app_router(&Config::default()), "/user-agent/"),
+            StatusCode::MOVED_PERMANENTLY
+        );
+    }
+
+    #[test]
+    fn test_head() {
+        let router = Router::new()
+            .get("/", |request: Request| {
+                Response::new(StatusCode::OK).with_body(request.method.as_str())
+            })
+            .post("/", || "posted")
+            .route(Method::Head, "/own", || {
+                Response::new(StatusCode::NO_CONTENT)
+            })
+            .get("/own", || "got");
+        let state = Arc::new(State::with_router(Config::default(), router));
+        let head = |path: &str| handle_request(state.clone(), Request::new(Method::Head, path));
+
+        // the GET route's head, with the length of the body that isn't sent
+        let mut written = Vec::new();
+        assert_eq!(write_response(head("/"), &mut written).unwrap(), 0);
+        let written = String::from_utf8(written).unwrap();
+        assert!(written.starts_with("HTTP/1.1 200 OK\r\n"), "{}", written);
+        assert!(written.contains("Content-Length: 3\r\n"), "{}", written);
+        assert!(written.ends_with("\r\n\r\n"), "{}", written);
+
+        assert_eq!(head("/own").status, StatusCode::NO_CONTENT);
+        assert_eq!(head("/nope").status, StatusCode::NOT_FOUND);
+
+        let res = handle_request(state.clone(), Request::new(Method::Put, "/"));
+        assert_eq!(res.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers.get(ALLOW), Some("GET, HEAD, POST"));
+    }
+}
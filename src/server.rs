@@ -0,0 +1,2562 @@
+use crate::access_log::{self, AccessLog, Rotation};
+use crate::app::{self, app_router};
+#[cfg(feature = "async")]
+use crate::async_server;
+use crate::cidr::Cidr;
+use crate::cli::{self, Cli};
+use crate::config_file;
+use crate::http::{
+    parse_head, read_request_body, write_response, write_upgrading_response, ClientIdentity,
+    Headers, Method, ParserConfig, Request, Response, ServerError, StatusCode, StatusError,
+    Upgraded, CONNECTION, RETRY_AFTER, SERVER, USER_AGENT, X_RESPONSE_TIME,
+};
+use crate::logging::{self, LogFormat};
+use crate::metrics::Metrics;
+use crate::pool::ThreadPool;
+use crate::preload::{self, Preload};
+use crate::proxy::{self, Destination, ForwardedHeader, Proxy};
+use crate::proxy_protocol;
+use crate::rate_limit::RateLimiter;
+use crate::reload::Reloader;
+use crate::router::Router;
+use crate::stats::Stats;
+#[cfg(feature = "tls")]
+use crate::tls;
+use anyhow::{bail, Context, Result};
+use std::any::Any;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, field, info, info_span, warn, Level};
+
+// served under /files/ unless --directory or HTTP_SERVER_DIRECTORY says otherwise
+pub(crate) const DEFAULT_DIRECTORY: &str = "lol";
+
+// environment variables read when the matching option isn't given
+const BIND_VAR: &str = "HTTP_SERVER_BIND";
+const PORT_VAR: &str = "HTTP_SERVER_PORT";
+const ADMIN_TOKEN_VAR: &str = "HTTP_SERVER_ADMIN_TOKEN";
+const WRITE_TOKENS_VAR: &str = "HTTP_SERVER_WRITE_TOKENS";
+const DIRECTORY_VAR: &str = "HTTP_SERVER_DIRECTORY";
+const THREADS_VAR: &str = "HTTP_SERVER_THREADS";
+const MAX_BODY_SIZE_VAR: &str = "HTTP_SERVER_MAX_BODY_SIZE";
+const LOG_LEVEL_VAR: &str = "HTTP_SERVER_LOG_LEVEL";
+const LOG_FORMAT_VAR: &str = "HTTP_SERVER_LOG_FORMAT";
+#[cfg(feature = "tls")]
+const TLS_CERT_VAR: &str = "HTTP_SERVER_TLS_CERT";
+#[cfg(feature = "tls")]
+const TLS_KEY_VAR: &str = "HTTP_SERVER_TLS_KEY";
+
+// uploads to a mount go to disk, so they can be much larger than other bodies
+const MOUNT_MAX_BODY_SIZE: usize = 1024 * 1024 * 1024;
+
+// a directory served under "/<name>/"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mount {
+    pub name: String,
+    pub directory: String,
+    // every request that would change a file under it gets 403
+    pub read_only: bool,
+    // extensions of the only files served, all when empty
+    pub allowed_extensions: Vec<String>,
+    pub denied_extensions: Vec<String>,
+    // whether names starting with "." such as .env or .git are served
+    pub dotfiles: bool,
+}
+
+impl Mount {
+    pub fn new(name: &str, directory: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            directory: directory.to_owned(),
+            read_only: false,
+            allowed_extensions: Vec::new(),
+            denied_extensions: Vec::new(),
+            dotfiles: false,
+        }
+    }
+
+    // whether a file or directory with the name may be read or changed; anything else gets 403
+    pub(crate) fn allows(&self, name: &str, is_dir: bool) -> bool {
+        if name.starts_with('.') && !self.dotfiles {
+            return false;
+        }
+        if is_dir {
+            return true;
+        }
+        let extension = Path::new(name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("");
+        let listed = |extensions: &[String]| {
+            extensions
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(extension))
+        };
+        (self.allowed_extensions.is_empty() || listed(&self.allowed_extensions))
+            && !listed(&self.denied_extensions)
+    }
+}
+
+// everything the command line can set, see parse_args
+pub struct Config {
+    // the served directories by URL prefix, "files" being the one --directory sets
+    pub mounts: Vec<Mount>,
+    // hosts or IP addresses to listen on, optionally with their own port
+    pub bind: Vec<String>,
+    // more of them, where connections start with a PROXY protocol header from a TCP load balancer
+    // saying who the client is
+    pub proxy_protocol_bind: Vec<String>,
+    pub port: u16,
+    pub default_headers: Headers,
+    pub parser: ParserConfig,
+    pub read_timeout: Duration,
+    // the whole head of a request has to arrive within this once its first byte did, however
+    // steadily it trickles in
+    pub head_timeout: Duration,
+    // a client that stops reading our response is dropped after this
+    pub write_timeout: Duration,
+    // worker threads, and accepted connections waiting for one before new ones get 503
+    pub threads: usize,
+    pub queue_size: usize,
+    // open connections beyond which new ones get 503, altogether and from a single IP address
+    pub max_connections: usize,
+    pub max_connections_per_ip: Option<usize>,
+    // sockets bound to each address with SO_REUSEPORT, each with its own accept loop
+    pub acceptors: usize,
+    // extension and Content-Type pairs for served files, taking precedence over the built-in table
+    pub mime_types: Vec<(String, String)>,
+    // Cache-Control values for served files by "*.ext" or URL path prefix, the first match wins
+    pub cache_control: Vec<(String, String)>,
+    // files sent with Content-Disposition: attachment, by "*.ext" or URL path prefix
+    pub downloads: Vec<String>,
+    // whether symlinks under the directory are served, as long as they point inside it
+    pub follow_symlinks: bool,
+    // file names served for GET on a directory, the first one that exists wins
+    pub index_files: Vec<String>,
+    // GET on a missing file under /files/ serves the root index file, for single-page apps that
+    // route on the client
+    pub spa: bool,
+    // GET on a directory under /files/ without an index file answers an HTML index instead of 404;
+    // off unless asked for, so the trees under the mounts can't be enumerated by anyone
+    pub directory_listing: bool,
+    // files under the mounts up to this many bytes are held in memory, 0 for none
+    pub preload_max_size: u64,
+    // a resumable upload nothing was added to for this long is deleted by the next sweep of the
+    // mounts, every ten minutes
+    pub partial_upload_max_age: Duration,
+    // where a line per request goes in the Common Log Format, "-" for stdout
+    pub access_log: Option<String>,
+    // a file access log is moved to <name>.1 once writing another line would take it beyond the
+    // size, or at the end of every period (3600 or 86400 seconds for hourly or daily); the
+    // `access_log_keep` newest moved files are kept
+    pub access_log_max_size: Option<u64>,
+    pub access_log_period: Option<Duration>,
+    pub access_log_keep: usize,
+    // of everything else logged, to stderr; an embedding application that builds its own Server
+    // calls logging::init itself, or installs another tracing subscriber
+    pub log_format: LogFormat,
+    // the most verbose events logged; DEBUG adds every request's and response's headers
+    pub log_level: Level,
+    // GET /metrics answers the request counters in the Prometheus format; off unless asked for,
+    // as anyone who can reach the port could read them
+    pub metrics: bool,
+    // adds X-Response-Time with the time until the response was ready to every response
+    pub response_time_header: bool,
+    // GET /_stats answers runtime statistics to requests with "Authorization: Bearer <token>"
+    pub admin_token: Option<String>,
+    // with any users, requests under the paths (or all requests if there are none) need one's
+    // name and password with Basic auth
+    pub basic_auth_users: Vec<(String, String)>,
+    pub basic_auth_paths: Vec<String>,
+    // more users from an Apache htpasswd file, see BasicAuth::htpasswd
+    pub basic_auth_file: Option<String>,
+    // with any, changing files under the mounts needs "Authorization: Bearer <token>" with one of
+    // them, while reading them doesn't
+    pub write_tokens: Vec<String>,
+    // requests taking at least this long to answer are logged as warnings with their timings
+    pub slow_request: Option<Duration>,
+    // requests from browsers that may change something need the token of the csrf_token cookie,
+    // see middleware::Csrf
+    pub csrf: bool,
+    // no mount's files can be changed, and methods that would are answered 405
+    pub read_only: bool,
+    // with any allowed networks only clients in one of them are served, and never those in a denied
+    // one; the others get 403 right after being accepted
+    pub allowed_ips: Vec<Cidr>,
+    pub denied_ips: Vec<Cidr>,
+    // load balancers and proxies in these networks are believed about who they forwarded requests
+    // for, see Request::client_ip; rate limits and the access log go by that address
+    pub trusted_proxies: Vec<Cidr>,
+    // the header they add the client's address to, the other one is ignored
+    pub forwarded_header: ForwardedHeader,
+    // requests per second each client IP address may make on average, and how many it may make at
+    // once after being quiet (the rate rounded up if None); more get 429 before being routed
+    pub rate_limit: Option<f64>,
+    pub rate_limit_burst: Option<usize>,
+    // answers CORS preflights and lets pages of other origins read the responses: of the origins
+    // given, or of any origin if there are none
+    pub cors: bool,
+    pub cors_origins: Vec<String>,
+    // adds nosniff, frame and referrer headers to responses, see middleware::SecurityHeaders, and
+    // the Content-Security-Policy if one is given
+    pub security_headers: bool,
+    pub content_security_policy: Option<String>,
+    // requests under these prefixes are forwarded to upstream servers instead of being routed
+    pub proxies: Vec<Proxy>,
+    // CONNECT requests may open tunnels to these, see proxy::Tunnel; without any CONNECT isn't
+    // supported
+    pub connect_allowed: Vec<Destination>,
+    // serve connections as tokio tasks instead of on the worker pool
+    #[cfg(feature = "async")]
+    pub async_io: bool,
+    // PEM files with the certificate chain and private key to serve HTTPS with
+    #[cfg(feature = "tls")]
+    pub tls_cert: Option<String>,
+    #[cfg(feature = "tls")]
+    pub tls_key: Option<String>,
+    // PEM bundle of the CAs client certificates must be signed by; with it set clients need a
+    // certificate, or with tls_client_optional only to change files
+    #[cfg(feature = "tls")]
+    pub tls_client_ca: Option<String>,
+    #[cfg(feature = "tls")]
+    pub tls_client_optional: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut default_headers = Headers::new();
+        default_headers.append(
+            SERVER,
+            concat!("http-server-rust/", env!("CARGO_PKG_VERSION")),
+        );
+
+        let mut config = Self {
+            mounts: Vec::new(),
+            bind: vec!["127.0.0.1".to_owned()],
+            proxy_protocol_bind: Vec::new(),
+            port: 4221,
+            default_headers,
+            parser: ParserConfig::default(),
+            read_timeout: Duration::from_secs(30),
+            head_timeout: Duration::from_secs(10),
+            write_timeout: Duration::from_secs(30),
+            threads: thread::available_parallelism().map_or(4, |n| n.get() * 4),
+            queue_size: 128,
+            max_connections: 1024,
+            max_connections_per_ip: None,
+            acceptors: 1,
+            mime_types: Vec::new(),
+            cache_control: Vec::new(),
+            downloads: Vec::new(),
+            follow_symlinks: true,
+            index_files: vec!["index.html".to_owned()],
+            spa: false,
+            directory_listing: false,
+            preload_max_size: 0,
+            partial_upload_max_age: Duration::from_secs(24 * 60 * 60),
+            access_log: Some("-".to_owned()),
+            access_log_max_size: None,
+            access_log_period: None,
+            access_log_keep: 5,
+            log_format: LogFormat::Text,
+            log_level: Level::INFO,
+            metrics: false,
+            response_time_header: false,
+            admin_token: None,
+            basic_auth_users: Vec::new(),
+            basic_auth_paths: Vec::new(),
+            basic_auth_file: None,
+            write_tokens: Vec::new(),
+            slow_request: None,
+            csrf: false,
+            read_only: false,
+            allowed_ips: Vec::new(),
+            denied_ips: Vec::new(),
+            trusted_proxies: Vec::new(),
+            forwarded_header: ForwardedHeader::XForwardedFor,
+            rate_limit: None,
+            rate_limit_burst: None,
+            cors: false,
+            cors_origins: Vec::new(),
+            security_headers: false,
+            content_security_policy: None,
+            proxies: Vec::new(),
+            connect_allowed: Vec::new(),
+            #[cfg(feature = "async")]
+            async_io: false,
+            #[cfg(feature = "tls")]
+            tls_cert: None,
+            #[cfg(feature = "tls")]
+            tls_key: None,
+            #[cfg(feature = "tls")]
+            tls_client_ca: None,
+            #[cfg(feature = "tls")]
+            tls_client_optional: false,
+        };
+        config.add_mount(Mount::new("files", DEFAULT_DIRECTORY));
+        config
+    }
+}
+
+impl Config {
+    // replaces the mount with the same name, if any; bodies sent to it are streamed to disk
+    pub fn add_mount(&mut self, mount: Mount) {
+        let prefix = format!("/{}/", mount.name);
+        self.mounts.retain(|known| known.name != mount.name);
+        self.mounts.push(mount);
+        if !self.parser.stream_prefixes.contains(&prefix) {
+            self.parser.stream_prefixes.push(prefix.clone());
+            self.parser
+                .route_max_body_sizes
+                .push((prefix, MOUNT_MAX_BODY_SIZE));
+        }
+    }
+
+    // replaces the proxy for the same prefix, if any; bodies sent to it are streamed from disk
+    pub fn add_proxy(&mut self, proxy: Proxy) {
+        let prefix = format!("{}/", proxy.prefix());
+        self.proxies
+            .retain(|known| known.prefix() != proxy.prefix());
+        self.proxies.push(proxy);
+        if !self.parser.stream_prefixes.contains(&prefix) {
+            self.parser.stream_prefixes.push(prefix);
+        }
+    }
+
+    pub(crate) fn ip_allowed(&self, ip: IpAddr) -> bool {
+        (self.allowed_ips.is_empty() || self.allowed_ips.iter().any(|net| net.contains(ip)))
+            && !self.denied_ips.iter().any(|net| net.contains(ip))
+    }
+
+    // the mount serving the path
+    pub(crate) fn mount_for(&self, path: &str) -> Option<&Mount> {
+        let name = path.strip_prefix('/')?.split('/').next()?;
+        self.mounts.iter().find(|mount| mount.name == name)
+    }
+}
+
+// shared by every connection, and handed to handlers that ask for it
+pub struct State {
+    pub config: Config,
+    pub(crate) router: Router,
+    // the server's current state, which a reload replaces this one with, see State::latest;
+    // everything behind an Arc below is shared with it. Only the server holds it, so a state that
+    // was replaced is dropped once its last connection closes
+    pub(crate) current: Weak<Current>,
+    // connections accepted and not yet closed, see ConnectionGuard
+    pub(crate) active_connections: Arc<AtomicUsize>,
+    // connections waiting in the worker pool's queue for a free worker
+    pub(crate) queued_connections: Arc<AtomicUsize>,
+    // open connections by client, kept only with max_connections_per_ip
+    pub(crate) connections_per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    // accepted connections are wrapped in TLS sessions when set
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<Arc<rustls::ServerConfig>>,
+    pub(crate) hooks: Arc<Hooks>,
+    pub(crate) preload: Preload,
+    // opened by ServerBuilder::build, so only a running server logs requests
+    pub(crate) access_log: Option<Arc<AccessLog>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+// the state a running server hands to new connections, replaced by a reload
+pub(crate) struct Current(pub(crate) RwLock<Arc<State>>);
+
+impl Current {
+    pub(crate) fn new(state: State) -> Arc<Current> {
+        Arc::new_cyclic(|current| {
+            Current(RwLock::new(Arc::new(State {
+                current: Weak::clone(current),
+                ..state
+            })))
+        })
+    }
+
+    pub(crate) fn get(&self) -> Arc<State> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+}
+
+type RequestHook = Box<dyn Fn(&mut Request) + Send + Sync>;
+type ResponseHook = Box<dyn Fn(&Request, &mut Response) + Send + Sync>;
+type ErrorHook = Box<dyn Fn(&ServerError) + Send + Sync>;
+
+// callbacks set with ServerBuilder::on_request, on_response and on_error, run in the order added
+#[derive(Default)]
+pub(crate) struct Hooks {
+    on_request: Vec<RequestHook>,
+    on_response: Vec<ResponseHook>,
+    on_error: Vec<ErrorHook>,
+}
+
+impl Hooks {
+    pub(crate) fn error(&self, status: StatusCode, message: String) {
+        if self.on_error.is_empty() {
+            return;
+        }
+        let error = ServerError { status, message };
+        for hook in &self.on_error {
+            hook(&error);
+        }
+    }
+}
+
+impl State {
+    // serving the binary's routes, see app::app_router
+    pub fn new(config: Config) -> Self {
+        let router = app_router(&config);
+        Self::with_router(config, router)
+    }
+
+    pub fn with_router(config: Config, router: Router) -> Self {
+        Self {
+            preload: Preload::new(config.preload_max_size, &config.mounts),
+            rate_limiter: rate_limiter(&config).map(Arc::new),
+            config,
+            router,
+            current: Weak::new(),
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            queued_connections: Arc::new(AtomicUsize::new(0)),
+            connections_per_ip: Arc::new(Mutex::new(HashMap::new())),
+            #[cfg(feature = "tls")]
+            tls: None,
+            hooks: Arc::new(Hooks::default()),
+            access_log: None,
+            metrics: Arc::new(Metrics::new()),
+            stats: Arc::new(Stats::new()),
+        }
+    }
+
+    // the state with the config and router of a reload, continuing the connection counts, hooks,
+    // logs and statistics of this one; its rate limiter too, unless the limits changed
+    pub(crate) fn reloaded(&self, config: Config, router: Router) -> Self {
+        let same_limits = (config.rate_limit, config.rate_limit_burst)
+            == (self.config.rate_limit, self.config.rate_limit_burst);
+        Self {
+            preload: Preload::new(config.preload_max_size, &config.mounts),
+            rate_limiter: match same_limits {
+                true => self.rate_limiter.clone(),
+                false => rate_limiter(&config).map(Arc::new),
+            },
+            config,
+            router,
+            current: Weak::clone(&self.current),
+            active_connections: Arc::clone(&self.active_connections),
+            queued_connections: Arc::clone(&self.queued_connections),
+            connections_per_ip: Arc::clone(&self.connections_per_ip),
+            #[cfg(feature = "tls")]
+            tls: self.tls.clone(),
+            hooks: Arc::clone(&self.hooks),
+            access_log: self.access_log.clone(),
+            metrics: Arc::clone(&self.metrics),
+            stats: Arc::clone(&self.stats),
+        }
+    }
+
+    // the state of the last reload, which new connections and the next request on an open one are
+    // served with; this one for a state no server runs with
+    pub(crate) fn latest(self: &Arc<Self>) -> Arc<State> {
+        match self.current.upgrade() {
+            Some(current) => current.get(),
+            None => Arc::clone(self),
+        }
+    }
+
+    // whether /files/ writes are refused to clients without a verified certificate
+    pub(crate) fn writes_need_client_cert(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some() && self.config.tls_client_ca.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+
+    // the connection loops call this once a request starts arriving, and request_answered when
+    // it's been answered or the connection failed
+    pub(crate) fn request_started(&self) -> Timing {
+        self.metrics.started();
+        Timing {
+            started: Instant::now(),
+            parsed: Duration::ZERO,
+            handled: Duration::ZERO,
+        }
+    }
+
+    // `request` is None for requests that couldn't be parsed; `bytes` counts the body only
+    pub(crate) fn request_answered(
+        &self,
+        client: Option<IpAddr>,
+        request: Option<&RequestSummary>,
+        status: StatusCode,
+        bytes: u64,
+        timing: &Timing,
+    ) {
+        let elapsed = timing.started.elapsed();
+        let method = request.map(|request| &request.method);
+        self.metrics
+            .answered(method, status, bytes, elapsed, timing.handled);
+        self.stats.answered(status);
+        if let Some(access_log) = &self.access_log {
+            let request_line = request.map(|request| request.line.as_str());
+            access_log.log(client, request_line, status, bytes, elapsed, timing.handled);
+        }
+        if self.config.slow_request.is_some_and(|slow| elapsed >= slow) {
+            let millis = |duration: Duration| duration.as_millis() as u64;
+            warn!(
+                request = request.map_or("-", |request| request.line.as_str()),
+                user_agent = request.and_then(|request| request.user_agent.as_deref()),
+                status = status.as_u16(),
+                bytes,
+                parse_ms = millis(timing.parsed),
+                handler_ms = millis(timing.handled - timing.parsed),
+                write_ms = millis(elapsed - timing.handled),
+                total_ms = millis(elapsed),
+                "slow request"
+            );
+        }
+    }
+
+    // and the client's address behind it if the peer is a trusted proxy
+    pub(crate) fn set_peer(&self, request: &mut Request, peer: Option<IpAddr>) {
+        request.peer = peer;
+        let (trusted, header) = (&self.config.trusted_proxies, self.config.forwarded_header);
+        request.forwarded_for = proxy::forwarded_for(trusted, header, request);
+    }
+
+    // the 429 answered instead of routing the request when the client is over the rate limit
+    pub(crate) fn rate_limited(&self, client: Option<IpAddr>) -> Option<Response> {
+        let limiter = self.rate_limiter.as_ref()?;
+        let wait = limiter.check(client?, Instant::now()).err()?;
+        let seconds = wait.as_secs_f64().ceil().max(1.0);
+        let response = Response::new(StatusCode::TOO_MANY_REQUESTS)
+            .with_header(RETRY_AFTER, &seconds.to_string());
+        Some(self.router.render_error(response))
+    }
+
+    // counts a new connection, or None if max_connections are already open, or
+    // max_connections_per_ip from the client
+    pub(crate) fn try_connect(self: &Arc<Self>, peer: Option<IpAddr>) -> Option<ConnectionGuard> {
+        let max = self.config.max_connections;
+        self.active_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()?;
+        // from here on the guard gives back what was taken
+        let mut guard = ConnectionGuard(Arc::clone(self), None);
+        guard.1 = Some(self.client_slot(peer)?);
+        self.stats.connected();
+        Some(guard)
+    }
+
+    // counts a connection of the client's, or None if it has max_connections_per_ip open already
+    pub(crate) fn client_slot(&self, peer: Option<IpAddr>) -> Option<ClientSlot> {
+        let connections = Arc::clone(&self.connections_per_ip);
+        let (Some(max), Some(peer)) = (self.config.max_connections_per_ip, peer) else {
+            return Some(ClientSlot(connections, None));
+        };
+        {
+            let mut connections = connections.lock().unwrap();
+            let count = connections.entry(peer).or_insert(0);
+            if *count >= max {
+                debug!(%peer, "too many connections from the client");
+                return None;
+            }
+            *count += 1;
+        }
+        Some(ClientSlot(connections, Some(peer)))
+    }
+}
+
+// when a request started arriving, and how long after that it had been read and parsed and its
+// response was ready; the rest of the time until it's answered went into writing the response
+pub(crate) struct Timing {
+    started: Instant,
+    parsed: Duration,
+    handled: Duration,
+}
+
+impl Timing {
+    pub(crate) fn parsed(&mut self) {
+        self.parsed = self.started.elapsed();
+    }
+
+    // what X-Response-Time tells
+    pub(crate) fn handled(&mut self) -> Duration {
+        self.handled = self.started.elapsed();
+        self.handled
+    }
+}
+
+// what's kept of a request for the logs and metrics once it's handed to the router
+pub(crate) struct RequestSummary {
+    method: Method,
+    line: String,
+    user_agent: Option<String>,
+}
+
+impl RequestSummary {
+    pub(crate) fn of(request: &Request) -> Self {
+        Self {
+            method: request.method.clone(),
+            line: access_log::request_line(request),
+            user_agent: request.headers.get(USER_AGENT).map(str::to_owned),
+        }
+    }
+}
+
+// releases its connection slot when dropped, and the client's along with it
+pub(crate) struct ConnectionGuard(pub(crate) Arc<State>, Option<ClientSlot>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// releases the client's slot when dropped, if it's counted
+pub(crate) struct ClientSlot(Arc<Mutex<HashMap<IpAddr, usize>>>, Option<IpAddr>);
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        if let Some(peer) = self.1 {
+            let mut connections = self.0.lock().unwrap();
+            if let Some(count) = connections.get_mut(&peer) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.remove(&peer);
+                }
+            }
+        }
+    }
+}
+
+fn rate_limiter(config: &Config) -> Option<RateLimiter> {
+    let rate = config.rate_limit?;
+    let burst = config.rate_limit_burst.unwrap_or(rate.ceil() as usize);
+    Some(RateLimiter::new(rate, burst))
+}
+
+// command line options, falling back to the settings of a --config file and then the environment
+pub fn parse_args(args: &[String]) -> Result<Config> {
+    let cli = Cli::parse(args)?;
+    let config = apply_env(Config::default(), |key| env::var(key).ok())?;
+    let config = match &cli.config {
+        Some(path) => Cli::parse(&config_file::read(path)?)
+            .map_err(cli::message)
+            .and_then(|file| file.apply(config))
+            .with_context(|| format!("Invalid setting in {}", path))?,
+        None => config,
+    };
+    cli.apply(config)
+}
+
+// environment fallbacks for options the command line doesn't set
+fn apply_env(mut config: Config, var: impl Fn(&str) -> Option<String>) -> Result<Config> {
+    // comma-separated, like repeating --bind
+    if let Some(bind) = var(BIND_VAR) {
+        config.bind = bind.split(',').map(|addr| addr.trim().to_owned()).collect();
+    }
+    if let Some(port) = var(PORT_VAR) {
+        config.port = env_value(PORT_VAR, &port, |port| Ok(port.parse()?))?;
+    }
+    // better kept out of the command line, where other users can see it
+    if let Some(token) = var(ADMIN_TOKEN_VAR) {
+        config.admin_token = Some(token);
+    }
+    // comma-separated, like repeating --write-token
+    if let Some(tokens) = var(WRITE_TOKENS_VAR) {
+        config.write_tokens = tokens
+            .split(',')
+            .map(|token| token.trim().to_owned())
+            .filter(|token| !token.is_empty())
+            .collect();
+    }
+    if let Some(directory) = var(DIRECTORY_VAR) {
+        config.add_mount(Mount::new("files", &directory));
+    }
+    if let Some(threads) = var(THREADS_VAR) {
+        config.threads = env_value(THREADS_VAR, &threads, cli::positive)?;
+    }
+    if let Some(size) = var(MAX_BODY_SIZE_VAR) {
+        config.parser.max_body_size = env_value(MAX_BODY_SIZE_VAR, &size, cli::positive)?;
+    }
+    if let Some(level) = var(LOG_LEVEL_VAR) {
+        config.log_level = env_value(LOG_LEVEL_VAR, &level, cli::log_level)?;
+    }
+    if let Some(format) = var(LOG_FORMAT_VAR) {
+        config.log_format = env_value(LOG_FORMAT_VAR, &format, cli::log_format)?;
+    }
+    #[cfg(feature = "tls")]
+    if let Some(cert) = var(TLS_CERT_VAR) {
+        config.tls_cert = Some(cert);
+    }
+    #[cfg(feature = "tls")]
+    if let Some(key) = var(TLS_KEY_VAR) {
+        config.tls_key = Some(key);
+    }
+    Ok(config)
+}
+
+// parsed like the option the variable stands for
+fn env_value<T>(var: &str, value: &str, parse: impl Fn(&str) -> Result<T>) -> Result<T> {
+    parse(value).with_context(|| format!("Invalid value for {}", var))
+}
+
+// a mount added before, by name
+pub(crate) fn find_mount<'a>(config: &'a mut Config, name: &str) -> Result<&'a mut Mount> {
+    match config.mounts.iter_mut().find(|mount| mount.name == name) {
+        Some(mount) => Ok(mount),
+        None => bail!("Unknown mount {}!", name),
+    }
+}
+
+// names become a literal URL path segment
+pub(crate) fn check_mount_name(name: &str) -> Result<()> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(allowed) {
+        bail!("Invalid mount name {:?}!", name);
+    }
+    Ok(())
+}
+
+// with a leading dot on the extension ignored
+pub(crate) fn mime_type(extension: &str, mime: &str) -> (String, String) {
+    let extension = extension.trim().trim_start_matches('.');
+    (extension.to_owned(), mime.trim().to_owned())
+}
+
+// the addresses to listen on, each bind address using --port unless it has its own
+pub fn listen_addrs(config: &Config) -> Result<Vec<SocketAddr>> {
+    let addrs = proxied_listen_addrs(config)?;
+    Ok(addrs.into_iter().map(|(addr, _)| addr).collect())
+}
+
+// and whether connections to each start with a PROXY protocol header
+pub(crate) fn proxied_listen_addrs(config: &Config) -> Result<Vec<(SocketAddr, bool)>> {
+    let binds = config.bind.iter().map(|bind| (bind, false));
+    let proxied = config.proxy_protocol_bind.iter().map(|bind| (bind, true));
+    binds
+        .chain(proxied)
+        .map(
+            |(bind, proxy_protocol)| match resolve_bind_addr(bind, config.port) {
+                Some(addr) => Ok((addr, proxy_protocol)),
+                None => bail!("Invalid bind address {}!", bind),
+            },
+        )
+        .collect()
+}
+
+// a bound socket, and whether its connections start with a PROXY protocol header
+pub(crate) struct Listener {
+    pub(crate) socket: TcpListener,
+    pub(crate) proxy_protocol: bool,
+}
+
+impl From<TcpListener> for Listener {
+    fn from(socket: TcpListener) -> Self {
+        Self {
+            socket,
+            proxy_protocol: false,
+        }
+    }
+}
+
+// "::1" and "0.0.0.0" take the default port, "[::1]:8080" and "localhost:8080" have their own
+fn resolve_bind_addr(bind: &str, port: u16) -> Option<SocketAddr> {
+    if let Ok(ip) = bind.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, port));
+    }
+    let mut addrs = if bind.contains(':') {
+        bind.to_socket_addrs().ok()?
+    } else {
+        (bind, port).to_socket_addrs().ok()?
+    };
+    addrs.next()
+}
+
+// more than one acceptor share the address through SO_REUSEPORT, so the kernel spreads
+// incoming connections over their accept loops
+fn bind_listeners(addr: SocketAddr, acceptors: usize) -> io::Result<Vec<TcpListener>> {
+    if acceptors == 1 {
+        return Ok(vec![TcpListener::bind(addr)?]);
+    }
+    let first = bind_reuse_port(addr)?;
+    // port 0 gets a random port on the first bind, the others have to join that one
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..acceptors {
+        listeners.push(bind_reuse_port(addr)?);
+    }
+    Ok(listeners)
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // what TcpListener::bind sets as well
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn bind_reuse_port(_addr: SocketAddr) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--acceptors needs SO_REUSEPORT, which this platform doesn't have",
+    ))
+}
+
+pub fn handle_request(state: Arc<State>, mut request: Request) -> Response {
+    let span = info_span!(
+        "request",
+        method = request.method.as_str(),
+        path = request.path,
+        client = field::Empty,
+        status = field::Empty,
+        request_id = field::Empty
+    );
+    // the connection's span has the proxy's address
+    if let Some(client) = request.forwarded_for {
+        span.record("client", field::display(client));
+    }
+    #[cfg(feature = "otel")]
+    crate::otel::set_parent(&span, &request.headers);
+    let _entered = span.enter();
+    // bodies are never logged, they may be large or private
+    debug!(headers = ?logging::loggable_headers(&request.headers), "request");
+    for hook in &state.hooks.on_request {
+        hook(&mut request);
+    }
+    // the body has been consumed by then, on_response only gets the head
+    let head = match state.hooks.on_response.is_empty() {
+        true => None,
+        false => Some(request.head()),
+    };
+
+    // handler panics are caught closer to the handler so middleware sees the 500, this one is for
+    // panicking middleware
+    let target = format!("{} {}", request.method.as_str(), request.path);
+    let omit_body = request.method == Method::Head;
+    let response = catch_panic(&state, &target, || {
+        state.router.handle(Arc::clone(&state), request)
+    });
+    let mut response = state.router.render_error(response);
+    response.omit_body = omit_body;
+
+    if let Some(head) = head {
+        for hook in &state.hooks.on_response {
+            hook(&head, &mut response);
+        }
+    }
+    span.record("status", response.status.as_u16());
+    debug!(
+        headers = ?logging::loggable_headers(&response.headers),
+        "response"
+    );
+    response
+}
+
+// a panic becomes a logged 500, so the client still gets an answer and the connection stays usable
+pub(crate) fn catch_panic(
+    state: &State,
+    target: &str,
+    handle: impl FnOnce() -> Response,
+) -> Response {
+    panic::catch_unwind(AssertUnwindSafe(handle)).unwrap_or_else(|payload| {
+        let message = format!("panicked on {}: {}", target, panic_message(&*payload));
+        error!("{}", message);
+        let status = StatusCode::INTERNAL_SERVER_ERROR;
+        state.hooks.error(status, message);
+        Response::new(status)
+    })
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+// serves an accepted connection, over TLS if configured
+pub(crate) fn handle_connection(state: Arc<State>, stream: TcpStream, proxy_protocol: bool) {
+    if set_timeouts(&state, &stream).is_err() {
+        return;
+    }
+    let mut peer = stream.peer_addr().ok().map(|addr| addr.ip());
+    // the client's slot under max_connections_per_ip, taken here rather than when accepted
+    let mut _client = None;
+    if proxy_protocol {
+        match proxy_protocol::read_header(&mut &stream) {
+            Ok(client) => peer = client.or(peer),
+            Err(e) => {
+                debug!(peer = ?peer, error = %e, "connection without a valid PROXY protocol header");
+                return;
+            }
+        }
+        // not when it was accepted, it's the client that has to be allowed and not the balancer
+        if !connection_allowed(&state, peer) {
+            return;
+        }
+        match state.client_slot(peer) {
+            Some(slot) => _client = Some(slot),
+            None => return reject_connection(&state, stream, StatusCode::SERVICE_UNAVAILABLE),
+        }
+    }
+    let span = info_span!("connection", peer = field::Empty);
+    if let Some(peer) = peer {
+        span.record("peer", field::display(peer));
+    }
+    let _entered = span.enter();
+    // to shorten the read timeout as the head's deadline nears, whatever the stream is wrapped in
+    let socket = stream.try_clone().ok();
+
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &state.tls {
+        let Some((stream, client)) = tls::accept(Arc::clone(tls), stream) else {
+            return;
+        };
+        let stream = serve_connection(&state, stream, socket.as_ref(), peer, client);
+        tls::close(stream);
+        return;
+    }
+
+    serve_connection(&state, stream, socket.as_ref(), peer, None);
+}
+
+// serves requests until the client closes or asks to close the connection, then hands the
+// stream back
+fn serve_connection<S: Read + Write>(
+    state: &Arc<State>,
+    stream: S,
+    socket: Option<&TcpStream>,
+    peer: Option<IpAddr>,
+    client: Option<ClientIdentity>,
+) -> S {
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        // the client closed the connection between requests
+        match reader.fill_buf() {
+            Ok(buf) if !buf.is_empty() => {}
+            _ => break,
+        }
+
+        // settings reloaded since the previous request apply to this one
+        let latest = state.latest();
+        let state = &latest;
+        let mut timing = state.request_started();
+        let parsed = read_request(state, &mut reader, socket);
+        timing.parsed();
+        let mut client_ip = peer;
+        let (response, close, summary) = match parsed {
+            Ok(mut request) => {
+                let summary = RequestSummary::of(&request);
+                let close = request.wants_close();
+                request.client = client.clone();
+                state.set_peer(&mut request, peer);
+                client_ip = request.client_ip();
+                let response = match state.rate_limited(client_ip) {
+                    Some(response) => response,
+                    None => handle_request(state.clone(), request),
+                };
+                (response, close, Some(summary))
+            }
+            // the rest of the stream can't be trusted after a malformed request
+            Err(e) => (parse_error_response(state, &e), true, None),
+        };
+
+        let handled = timing.handled();
+        let (response, close) = finish_response(state, response, close, handled);
+        let status = response.status;
+        let mut writer = BufWriter::new(reader.get_mut());
+        let written = write_upgrading_response(response, &mut writer);
+        drop(writer);
+        let bytes = written.as_ref().map_or(0, |(bytes, _)| *bytes);
+        state.request_answered(client_ip, summary.as_ref(), status, bytes, &timing);
+        match written {
+            Ok((_, Some(upgrade))) => {
+                let buffered = reader.buffer().to_vec();
+                reader.consume(buffered.len());
+                upgrade(Upgraded::new(buffered, reader.get_mut(), socket));
+                break;
+            }
+            Ok(_) if !close => {}
+            _ => break,
+        }
+    }
+    reader.into_inner()
+}
+
+// the next request, its head read within head_timeout altogether
+fn read_request<R: BufRead>(
+    state: &State,
+    reader: &mut R,
+    socket: Option<&TcpStream>,
+) -> Result<Request> {
+    let config = &state.config;
+    let mut head_reader = HeadReader {
+        reader: &mut *reader,
+        socket,
+        deadline: Instant::now() + config.head_timeout,
+        read_timeout: config.read_timeout,
+    };
+    let head = parse_head(&mut head_reader, &config.parser);
+    if let Some(socket) = socket {
+        let _ = socket.set_read_timeout(Some(config.read_timeout));
+    }
+    let (request, content_length) = head?;
+    read_request_body(reader, request, content_length, &config.parser)
+}
+
+// reads until the deadline, after which reading fails as if it timed out; once the deadline is
+// nearer than the read timeout, the socket's timeout is shortened so no read outlasts it
+struct HeadReader<'a, R> {
+    reader: &'a mut R,
+    socket: Option<&'a TcpStream>,
+    deadline: Instant,
+    read_timeout: Duration,
+}
+
+impl<R: BufRead> Read for HeadReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for HeadReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        if let Some(socket) = self.socket.filter(|_| remaining < self.read_timeout) {
+            socket.set_read_timeout(Some(remaining))?;
+        }
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+    }
+}
+
+// the status a parse error is answered with, rendered by the router's error handler
+pub(crate) fn parse_error_response(state: &State, error: &anyhow::Error) -> Response {
+    let status = match error.downcast_ref::<StatusError>() {
+        Some(StatusError(status)) => *status,
+        None => StatusCode::BAD_REQUEST,
+    };
+    state.hooks.error(status, error.to_string());
+    state.router.render_error(Response::new(status))
+}
+
+// adds Connection: close if either side wants to close, X-Response-Time if enabled and the
+// default headers
+pub(crate) fn finish_response(
+    state: &State,
+    mut response: Response,
+    close: bool,
+    handled: Duration,
+) -> (Response, bool) {
+    let close = close || response.closes_connection();
+    if close {
+        response.headers.insert(CONNECTION, "close");
+    }
+    if state.config.response_time_header {
+        let millis = handled.as_secs_f64() * 1000.0;
+        response
+            .headers
+            .insert(X_RESPONSE_TIME, &format!("{:.3}ms", millis));
+    }
+    response.apply_default_headers(&state.config.default_headers);
+    (response, close)
+}
+
+// so a client that never sends or never reads can't hold a worker forever
+fn set_timeouts(state: &State, stream: &TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(state.config.read_timeout))?;
+    stream.set_write_timeout(Some(state.config.write_timeout))
+}
+
+// whether the client may connect at all, see Config::allowed_ips; unknown peers aren't let in while
+// any lists are set
+pub(crate) fn connection_allowed(state: &State, peer: Option<IpAddr>) -> bool {
+    let config = &state.config;
+    if config.allowed_ips.is_empty() && config.denied_ips.is_empty() {
+        return true;
+    }
+    let allowed = peer.is_some_and(|peer| config.ip_allowed(peer));
+    if !allowed {
+        debug!(peer = ?peer, "connection from a disallowed address refused");
+    }
+    allowed
+}
+
+// answers 503 and closes when too many connections are open or the worker queue is full, or 403
+// when the client isn't allowed
+fn reject_connection(state: &State, stream: TcpStream, status: StatusCode) {
+    // a plain text response would be garbage to a TLS client, just close
+    #[cfg(feature = "tls")]
+    if state.tls.is_some() {
+        return;
+    }
+    if set_timeouts(state, &stream).is_err() {
+        return;
+    }
+    let _ = write_response(rejection_response(state, status), &mut &stream);
+}
+
+pub(crate) fn rejection_response(state: &State, status: StatusCode) -> Response {
+    let mut response = Response::new(status);
+    response.headers.insert(CONNECTION, "close");
+    response.apply_default_headers(&state.config.default_headers);
+    response
+}
+
+// serves the router with the config
+pub fn run(config: Config, router: Router) -> Result<()> {
+    logging::init(config.log_format, config.log_level);
+    Server::builder()
+        .config(config)
+        .router(router)
+        .build()?
+        .run()
+}
+
+// as the binary does: like run, and on SIGHUP swaps in the config and router `reload` returns
+pub fn run_reloadable<F>(config: Config, router: Router, reload: F) -> Result<()>
+where
+    F: Fn() -> Result<(Config, Router)> + Send + 'static,
+{
+    logging::init(config.log_format, config.log_level);
+    let server = Server::builder().config(config).router(router).build()?;
+    #[cfg(unix)]
+    server.reloader().reload_on_sighup(reload)?;
+    #[cfg(not(unix))]
+    drop(reload);
+    server.run()
+}
+
+// a server with its listeners bound, ready to run
+pub struct Server {
+    pub(crate) current: Arc<Current>,
+    listeners: Vec<Listener>,
+}
+
+// marks a builder that has no router yet; build() only exists once one is set
+pub struct NoRouter;
+
+// everything the command line can set, starting from Config::default() except that files are
+// served from the working directory
+pub struct ServerBuilder<R> {
+    config: Config,
+    // the first bind() replaces the default address, like the first --bind
+    bind_given: bool,
+    router: R,
+    hooks: Hooks,
+    // the first setter given something invalid, e.g. an unknown mount, which build() returns
+    error: Option<anyhow::Error>,
+    // what proxy() and connect_allow() added, layered onto the router by build(); those of a
+    // config() are left to its router, as app_router layers them
+    proxies: Vec<Proxy>,
+    connect_allowed: Vec<Destination>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder<NoRouter> {
+        let mut config = Config::default();
+        config.add_mount(Mount::new("files", "."));
+        ServerBuilder {
+            config,
+            bind_given: false,
+            router: NoRouter,
+            hooks: Hooks::default(),
+            error: None,
+            proxies: Vec::new(),
+            connect_allowed: Vec::new(),
+        }
+    }
+
+    // the bound addresses, with the actual port where port 0 was asked for
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.socket.local_addr().ok())
+            .collect()
+    }
+
+    // swaps in another config and router while the server runs
+    pub fn reloader(&self) -> Reloader {
+        Reloader {
+            current: Arc::clone(&self.current),
+        }
+    }
+
+    // serves until the process is killed
+    pub fn run(self) -> Result<()> {
+        #[cfg(feature = "async")]
+        if self.current.get().config.async_io {
+            return async_server::run(self.current, self.listeners);
+        }
+        serve(self.current, self.listeners)
+    }
+}
+
+impl<R> ServerBuilder<R> {
+    // replaces everything set so far, e.g. with the result of parse_args
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self.bind_given = true;
+        self.proxies.clear();
+        self.connect_allowed.clear();
+        self
+    }
+
+    pub fn router(self, router: Router) -> ServerBuilder<Router> {
+        ServerBuilder {
+            config: self.config,
+            bind_given: self.bind_given,
+            router,
+            hooks: self.hooks,
+            error: self.error,
+            proxies: self.proxies,
+            connect_allowed: self.connect_allowed,
+        }
+    }
+
+    // a host or IP address, optionally with its own port; repeatable
+    pub fn bind(mut self, addr: &str) -> Self {
+        if !self.bind_given {
+            self.config.bind.clear();
+            self.bind_given = true;
+        }
+        self.config.bind.push(addr.to_owned());
+        self
+    }
+
+    // another address to listen on, for connections from a TCP load balancer that starts them
+    // with a PROXY protocol header; repeatable
+    pub fn proxy_protocol_bind(mut self, addr: &str) -> Self {
+        self.config.proxy_protocol_bind.push(addr.to_owned());
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    // served under /files/, relative to the working directory
+    pub fn directory(mut self, directory: &str) -> Self {
+        self.config.add_mount(Mount::new("files", directory));
+        self
+    }
+
+    // another directory served under "/<name>/"; a mount with the same name is replaced
+    pub fn mount(mut self, name: &str, directory: &str, read_only: bool) -> Self {
+        self.config.add_mount(Mount {
+            read_only,
+            ..Mount::new(name, directory)
+        });
+        self
+    }
+
+    // only files with these extensions are served from the mount, which must exist
+    pub fn allow_extensions(self, mount: &str, extensions: &[&str]) -> Self {
+        self.with_mount(mount, |mount| {
+            mount
+                .allowed_extensions
+                .extend(extensions.iter().map(|extension| extension.to_string()))
+        })
+    }
+
+    pub fn deny_extensions(self, mount: &str, extensions: &[&str]) -> Self {
+        self.with_mount(mount, |mount| {
+            mount
+                .denied_extensions
+                .extend(extensions.iter().map(|extension| extension.to_string()))
+        })
+    }
+
+    pub fn dotfiles(self, mount: &str, served: bool) -> Self {
+        self.with_mount(mount, |mount| mount.dotfiles = served)
+    }
+
+    // changes the mount with the name, or has build() fail if there's none
+    fn with_mount(mut self, name: &str, change: impl FnOnce(&mut Mount)) -> Self {
+        match find_mount(&mut self.config, name) {
+            Ok(mount) => change(mount),
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    // adds or overrides a default response header
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.config.default_headers.insert(key, value);
+        self
+    }
+
+    // removes a default response header such as Server
+    pub fn without_header(mut self, key: &str) -> Self {
+        self.config.default_headers.remove(key);
+        self
+    }
+
+    pub fn max_request_line(mut self, size: usize) -> Self {
+        self.config.parser.max_request_line = size;
+        self
+    }
+
+    pub fn max_header_size(mut self, size: usize) -> Self {
+        self.config.parser.max_header_line = size;
+        self
+    }
+
+    pub fn max_headers_size(mut self, size: usize) -> Self {
+        self.config.parser.max_headers_size = size;
+        self
+    }
+
+    pub fn max_body_size(mut self, size: usize) -> Self {
+        self.config.parser.max_body_size = size;
+        self
+    }
+
+    // the body size limit for paths under the prefix
+    pub fn route_max_body_size(mut self, prefix: &str, size: usize) -> Self {
+        self.config
+            .parser
+            .route_max_body_sizes
+            .push((prefix.to_owned(), size));
+        self
+    }
+
+    pub fn lenient_line_endings(mut self, lenient: bool) -> Self {
+        self.config.parser.lenient_line_endings = lenient;
+        self
+    }
+
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    pub fn head_timeout(mut self, timeout: Duration) -> Self {
+        self.config.head_timeout = timeout;
+        self
+    }
+
+    pub fn max_connections_per_ip(mut self, max: usize) -> Self {
+        self.config.max_connections_per_ip = Some(max);
+        self
+    }
+
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.config.threads = threads;
+        self
+    }
+
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.config.queue_size = queue_size;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = max_connections;
+        self
+    }
+
+    pub fn acceptors(mut self, acceptors: usize) -> Self {
+        self.config.acceptors = acceptors;
+        self
+    }
+
+    // the Content-Type for files with the extension, e.g. ("log", "text/plain"); repeatable
+    pub fn mime_type(mut self, extension: &str, mime: &str) -> Self {
+        self.config.mime_types.push(mime_type(extension, mime));
+        self
+    }
+
+    // e.g. ("*.html", "no-cache") or ("/files/assets/", "max-age=31536000, immutable")
+    pub fn cache_control(mut self, pattern: &str, value: &str) -> Self {
+        self.config
+            .cache_control
+            .push((pattern.to_owned(), value.to_owned()));
+        self
+    }
+
+    // files to send as downloads, by "*.ext" or URL path prefix
+    pub fn download(mut self, pattern: &str) -> Self {
+        self.config.downloads.push(pattern.to_owned());
+        self
+    }
+
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.config.follow_symlinks = follow;
+        self
+    }
+
+    // replaces the default index.html
+    pub fn index_files(mut self, names: &[&str]) -> Self {
+        self.config.index_files = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    pub fn spa(mut self, enabled: bool) -> Self {
+        self.config.spa = enabled;
+        self
+    }
+
+    pub fn directory_listing(mut self, enabled: bool) -> Self {
+        self.config.directory_listing = enabled;
+        self
+    }
+
+    // files up to the size are served from memory
+    pub fn preload(mut self, max_size: u64) -> Self {
+        self.config.preload_max_size = max_size;
+        self
+    }
+
+    pub fn partial_upload_max_age(mut self, max_age: Duration) -> Self {
+        self.config.partial_upload_max_age = max_age;
+        self
+    }
+
+    // a file to log requests to, "-" for stdout or None for no access log
+    pub fn access_log(mut self, target: Option<&str>) -> Self {
+        self.config.access_log = target.map(str::to_owned);
+        self
+    }
+
+    // when a file access log is moved aside, and how many of the moved files are kept
+    pub fn access_log_rotation(
+        mut self,
+        max_size: Option<u64>,
+        period: Option<Duration>,
+        keep: usize,
+    ) -> Self {
+        self.config.access_log_max_size = max_size;
+        self.config.access_log_period = period;
+        self.config.access_log_keep = keep;
+        self
+    }
+
+    pub fn metrics(mut self, enabled: bool) -> Self {
+        self.config.metrics = enabled;
+        self
+    }
+
+    pub fn response_time_header(mut self, enabled: bool) -> Self {
+        self.config.response_time_header = enabled;
+        self
+    }
+
+    pub fn admin_token(mut self, token: &str) -> Self {
+        self.config.admin_token = Some(token.to_owned());
+        self
+    }
+
+    pub fn basic_auth_user(mut self, name: &str, password: &str) -> Self {
+        let user = (name.to_owned(), password.to_owned());
+        self.config.basic_auth_users.push(user);
+        self
+    }
+
+    pub fn basic_auth_file(mut self, path: &str) -> Self {
+        self.config.basic_auth_file = Some(path.to_owned());
+        self
+    }
+
+    pub fn basic_auth_path(mut self, prefix: &str) -> Self {
+        self.config.basic_auth_paths.push(prefix.to_owned());
+        self
+    }
+
+    pub fn write_token(mut self, token: &str) -> Self {
+        self.config.write_tokens.push(token.to_owned());
+        self
+    }
+
+    pub fn slow_request(mut self, threshold: Duration) -> Self {
+        self.config.slow_request = Some(threshold);
+        self
+    }
+
+    pub fn cors(mut self, cors: bool) -> Self {
+        self.config.cors = cors;
+        self
+    }
+
+    pub fn cors_origin(mut self, origin: &str) -> Self {
+        self.config.cors = true;
+        self.config.cors_origins.push(origin.to_owned());
+        self
+    }
+
+    pub fn security_headers(mut self, security_headers: bool) -> Self {
+        self.config.security_headers = security_headers;
+        self
+    }
+
+    pub fn content_security_policy(mut self, policy: &str) -> Self {
+        self.config.security_headers = true;
+        self.config.content_security_policy = Some(policy.to_owned());
+        self
+    }
+
+    // forwards requests under the proxy's prefix to its upstream server, ahead of the router's
+    // routes but inside its middleware, so authentication layered on the router applies too
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxies
+            .retain(|known| known.prefix() != proxy.prefix());
+        self.proxies.push(proxy.clone());
+        self.config.add_proxy(proxy);
+        self
+    }
+
+    // lets CONNECT requests open tunnels to the destination, ahead of the router's routes but
+    // inside its middleware, so authentication layered on the router applies too
+    pub fn connect_allow(mut self, destination: Destination) -> Self {
+        self.connect_allowed.push(destination.clone());
+        self.config.connect_allowed.push(destination);
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    pub fn csrf(mut self, csrf: bool) -> Self {
+        self.config.csrf = csrf;
+        self
+    }
+
+    pub fn allow_ip(mut self, network: Cidr) -> Self {
+        self.config.allowed_ips.push(network);
+        self
+    }
+
+    pub fn deny_ip(mut self, network: Cidr) -> Self {
+        self.config.denied_ips.push(network);
+        self
+    }
+
+    pub fn trusted_proxy(mut self, network: Cidr) -> Self {
+        self.config.trusted_proxies.push(network);
+        self
+    }
+
+    pub fn forwarded_header(mut self, header: ForwardedHeader) -> Self {
+        self.config.forwarded_header = header;
+        self
+    }
+
+    pub fn rate_limit(mut self, per_second: f64, burst: usize) -> Self {
+        self.config.rate_limit = Some(per_second);
+        self.config.rate_limit_burst = Some(burst);
+        self
+    }
+
+    #[cfg(feature = "async")]
+    pub fn async_io(mut self, async_io: bool) -> Self {
+        self.config.async_io = async_io;
+        self
+    }
+
+    // PEM files with the certificate chain and private key
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, cert: &str, key: &str) -> Self {
+        self.config.tls_cert = Some(cert.to_owned());
+        self.config.tls_key = Some(key.to_owned());
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls_client_ca(mut self, ca: &str) -> Self {
+        self.config.tls_client_ca = Some(ca.to_owned());
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls_client_optional(mut self, optional: bool) -> Self {
+        self.config.tls_client_optional = optional;
+        self
+    }
+
+    // runs before routing, with the parsed request
+    pub fn on_request(mut self, hook: impl Fn(&mut Request) + Send + Sync + 'static) -> Self {
+        self.hooks.on_request.push(Box::new(hook));
+        self
+    }
+
+    // runs with the final response before the default headers are added; the request comes
+    // without its body
+    pub fn on_response(
+        mut self,
+        hook: impl Fn(&Request, &mut Response) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.on_response.push(Box::new(hook));
+        self
+    }
+
+    // runs for requests the server answered itself because they couldn't be parsed or their
+    // handler panicked
+    pub fn on_error(mut self, hook: impl Fn(&ServerError) + Send + Sync + 'static) -> Self {
+        self.hooks.on_error.push(Box::new(hook));
+        self
+    }
+}
+
+impl ServerBuilder<Router> {
+    // checks the config and binds the listeners
+    pub fn build(self) -> Result<Server> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let mut config = self.config;
+        let addrs = proxied_listen_addrs(&config)?;
+        check_config(&mut config)?;
+
+        #[cfg(feature = "tls")]
+        let tls = tls::server_config(&config)?;
+        #[cfg(all(feature = "tls", feature = "async"))]
+        if tls.is_some() && config.async_io {
+            bail!("TLS isn't supported with --async!");
+        }
+        let rotation = Rotation {
+            max_size: config.access_log_max_size,
+            period: config.access_log_period,
+            keep: config.access_log_keep,
+        };
+        let access_log = match &config.access_log {
+            Some(target) => match AccessLog::open(target, rotation) {
+                Ok(access_log) => Some(access_log),
+                Err(e) => bail!("Could not open the access log {}: {}", target, e),
+            },
+            None => None,
+        };
+        // layered from the inside out: the proxies within the tunnel, as in app_router, and the
+        // first proxy outermost, as with layer
+        let router = match self.connect_allowed.is_empty() {
+            true => self.router,
+            false => self
+                .router
+                .layer_inner(proxy::Tunnel::new(self.connect_allowed)),
+        };
+        let router = self
+            .proxies
+            .into_iter()
+            .rev()
+            .fold(router, |router, proxy| router.layer_inner(proxy));
+        let current = Current::new(State {
+            #[cfg(feature = "tls")]
+            tls,
+            hooks: Arc::new(self.hooks),
+            access_log: access_log.map(Arc::new),
+            ..State::with_router(config, router)
+        });
+        let state = current.get();
+        preload::watch(&state);
+        app::watch_partial_uploads(&state);
+
+        let mut listeners = Vec::new();
+        for (addr, proxy_protocol) in addrs {
+            match bind_listeners(addr, state.config.acceptors) {
+                Ok(bound) => listeners.extend(bound.into_iter().map(|socket| Listener {
+                    socket,
+                    proxy_protocol,
+                })),
+                Err(e) => bail!("Could not listen on {}: {}", addr, e),
+            }
+            info!(%addr, proxy_protocol, "listening started, ready to accept");
+        }
+        for mount in &state.config.mounts {
+            let access = if mount.read_only || state.config.read_only {
+                "read-only"
+            } else {
+                "read-write"
+            };
+            info!(
+                mount = format!("/{}/", mount.name),
+                directory = mount.directory,
+                access,
+                "serving"
+            );
+        }
+        Ok(Server { current, listeners })
+    }
+}
+
+// what build checks of the config, also before a reload; relative mount directories are made
+// absolute
+pub(crate) fn check_config(config: &mut Config) -> Result<()> {
+    for (name, value) in [
+        ("threads", config.threads),
+        ("queue size", config.queue_size),
+        ("max connections", config.max_connections),
+        ("acceptors", config.acceptors),
+    ] {
+        if value == 0 {
+            bail!("The {} must be a positive number!", name);
+        }
+    }
+    // NaN isn't above 0 either
+    if config
+        .rate_limit
+        .is_some_and(|rate| !(rate > 0.0 && rate.is_finite()))
+    {
+        bail!("The rate limit must be a positive number!");
+    }
+    if config.rate_limit_burst == Some(0) {
+        bail!("The rate limit burst must be a positive number!");
+    }
+
+    for mount in &mut config.mounts {
+        check_mount_name(&mount.name)?;
+        // canonical like the paths the preload keeps, so a mount's files are found in it
+        let Ok(path) = Path::new(&mount.directory).canonicalize() else {
+            bail!("Directory {} does not exist!", mount.directory);
+        };
+        // the working directory the relative path is resolved against needn't be UTF-8
+        mount.directory = match path.into_os_string().into_string() {
+            Ok(directory) => directory,
+            Err(path) => bail!("Directory {} isn't valid UTF-8!", path.to_string_lossy()),
+        };
+    }
+
+    // it's read again when it changes, but should be there to begin with
+    if let Some(path) = &config.basic_auth_file {
+        if let Err(e) = fs::metadata(path) {
+            bail!("Could not read the htpasswd file {}: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+// hands accepted connections to the worker pool
+pub(crate) fn serve(current: Arc<Current>, listeners: Vec<Listener>) -> Result<()> {
+    // not holding on to the state, which a reload may replace
+    let (threads, queue_size) = {
+        let state = current.get();
+        (state.config.threads, state.config.queue_size)
+    };
+    let pool = ThreadPool::new(
+        threads,
+        queue_size,
+        move |(stream, guard, proxy_protocol): Accepted| {
+            guard.0.queued_connections.fetch_sub(1, Ordering::SeqCst);
+            handle_connection(Arc::clone(&guard.0), stream, proxy_protocol)
+        },
+    );
+
+    // one accept loop per listener, all feeding the same pool
+    thread::scope(|scope| {
+        for listener in &listeners {
+            scope.spawn(|| accept_loop(&current, &pool, listener));
+        }
+    });
+    Ok(())
+}
+
+// a connection waiting for a worker, and whether it starts with a PROXY protocol header
+type Accepted = (TcpStream, ConnectionGuard, bool);
+
+fn accept_loop(current: &Current, pool: &ThreadPool<Accepted>, listener: &Listener) {
+    for stream in listener.socket.incoming() {
+        match stream {
+            Ok(stream) => {
+                let state = &current.get();
+                let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+                if !listener.proxy_protocol && !connection_allowed(state, peer) {
+                    reject_connection(state, stream, StatusCode::FORBIDDEN);
+                    continue;
+                }
+                // behind a load balancer the client is only known once the header is read
+                let client = peer.filter(|_| !listener.proxy_protocol);
+                let Some(guard) = state.try_connect(client) else {
+                    reject_connection(state, stream, StatusCode::SERVICE_UNAVAILABLE);
+                    continue;
+                };
+                state.queued_connections.fetch_add(1, Ordering::SeqCst);
+                if let Err((stream, _guard, _)) =
+                    pool.try_execute((stream, guard, listener.proxy_protocol))
+                {
+                    state.queued_connections.fetch_sub(1, Ordering::SeqCst);
+                    reject_connection(state, stream, StatusCode::SERVICE_UNAVAILABLE);
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "accepting a connection failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::read_body;
+    use crate::logging::Captured;
+    use crate::test_util::TempDir;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_default_headers() {
+        let args: Vec<String> = ["--header", "X-Frame-Options: DENY", "--header", "Server:"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let config = parse_args(&args).unwrap();
+        assert_eq!(config.default_headers.get(SERVER), None);
+        assert_eq!(config.default_headers.get("X-Frame-Options"), Some("DENY"));
+
+        let config = Config::default();
+        let mut res = Response::new(StatusCode::OK);
+        res.apply_default_headers(&config.default_headers);
+        assert!(res
+            .headers
+            .get(SERVER)
+            .unwrap()
+            .starts_with("http-server-rust/"));
+
+        let mut res = Response::new(StatusCode::OK).with_header(SERVER, "custom");
+        res.apply_default_headers(&config.default_headers);
+        assert_eq!(
+            res.headers.get_all(SERVER).collect::<Vec<_>>(),
+            vec!["custom"]
+        );
+
+        let mut res = Response::new(StatusCode::OK).without_default_header("server");
+        res.apply_default_headers(&config.default_headers);
+        assert_eq!(res.headers.get(SERVER), None);
+
+        assert!(parse_args(&["--directory".to_owned()]).is_err());
+        assert!(parse_args(&["--nope".to_owned(), "x".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(State::new(Config::default()));
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(state, stream, false);
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET /echo/a HTTP/1.1\r\nHost: a\r\n\r\nGET /echo/b HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        let responses: Vec<_> = response.split("HTTP/1.1 200 OK").skip(1).collect();
+        assert_eq!(responses.len(), 2);
+        assert!(!responses[0].contains("Connection: close"));
+        assert!(responses[0].ends_with("\r\n\r\na"));
+        assert!(responses[1].contains("Connection: close"));
+        assert!(responses[1].ends_with("\r\n\r\nb"));
+
+        let req = Request::new(Method::Get, "/").with_header(CONNECTION, "keep-alive, Close");
+        assert!(req.wants_close());
+    }
+
+    #[test]
+    fn test_body_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(State::new(Config {
+            read_timeout: Duration::from_millis(100),
+            ..Config::default()
+        }));
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(state, stream, false);
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"POST /echo HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\n\r\nab")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+        assert!(response.contains("Connection: close"));
+
+        let mut raw = "abcdef".as_bytes();
+        assert_eq!(read_body(&mut raw, 4).unwrap(), b"abcd");
+        assert_eq!(raw, b"ef");
+        assert!(read_body(&mut "ab".as_bytes(), 4).is_err());
+    }
+
+    #[test]
+    fn test_bind_address() {
+        let env = |key: &str| match key {
+            BIND_VAR => Some("0.0.0.0".to_owned()),
+            PORT_VAR => Some("8080".to_owned()),
+            THREADS_VAR => Some("8".to_owned()),
+            _ => None,
+        };
+        let addrs = |config: &Config| -> Vec<String> {
+            let addrs = listen_addrs(config).unwrap();
+            addrs.iter().map(|addr| addr.to_string()).collect()
+        };
+        let config = apply_env(Config::default(), env).unwrap();
+        assert_eq!(addrs(&config), ["0.0.0.0:8080"]);
+
+        // flags take precedence over the environment
+        let args: Vec<String> = [
+            "--bind",
+            "::1",
+            "--port",
+            "9000",
+            "--bind",
+            "127.0.0.1:4221",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        let config = Cli::parse(&args).unwrap().apply(config).unwrap();
+        assert_eq!(addrs(&config), ["[::1]:9000", "127.0.0.1:4221"]);
+        assert_eq!(config.threads, 8);
+        let args = ["--threads".to_owned(), "2".to_owned()];
+        let threads = Cli::parse(&args).unwrap().apply(config).unwrap();
+        assert_eq!(threads.threads, 2);
+        assert_eq!(addrs(&Config::default()), ["127.0.0.1:4221"]);
+
+        let config = parse_args(&["--bind".to_owned(), "not an address".to_owned()]).unwrap();
+        assert!(listen_addrs(&config).is_err());
+        assert!(parse_args(&["--port".to_owned(), "70000".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let apply = |vars: &[(&str, &str)]| {
+            apply_env(Config::default(), |key| {
+                let value = vars.iter().find(|(name, _)| *name == key);
+                value.map(|(_, value)| value.to_string())
+            })
+        };
+        let dir = TempDir::new("env");
+        let directory = dir.to_str().unwrap();
+
+        let config = apply(&[
+            (BIND_VAR, "0.0.0.0, ::1"),
+            (PORT_VAR, "8080"),
+            (ADMIN_TOKEN_VAR, "s3cret"),
+            (WRITE_TOKENS_VAR, "one, two,"),
+            (DIRECTORY_VAR, directory),
+            (THREADS_VAR, "8"),
+            (MAX_BODY_SIZE_VAR, "4096"),
+            (LOG_LEVEL_VAR, "debug"),
+            (LOG_FORMAT_VAR, "json"),
+            #[cfg(feature = "tls")]
+            (TLS_CERT_VAR, "cert.pem"),
+            #[cfg(feature = "tls")]
+            (TLS_KEY_VAR, "key.pem"),
+        ])
+        .unwrap();
+        assert_eq!(config.bind, ["0.0.0.0", "::1"]);
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.admin_token.as_deref(), Some("s3cret"));
+        assert_eq!(config.write_tokens, ["one", "two"]);
+        assert_eq!(config.mounts.len(), 1);
+        assert_eq!(config.mounts[0].directory, directory);
+        assert_eq!(config.threads, 8);
+        assert_eq!(config.parser.max_body_size, 4096);
+        assert_eq!(config.log_level, Level::DEBUG);
+        assert_eq!(config.log_format, LogFormat::Json);
+        #[cfg(feature = "tls")]
+        assert_eq!(config.tls_cert.as_deref(), Some("cert.pem"));
+        #[cfg(feature = "tls")]
+        assert_eq!(config.tls_key.as_deref(), Some("key.pem"));
+
+        // values parsed like their options are rejected naming the variable
+        for (var, value) in [
+            (PORT_VAR, "70000"),
+            (PORT_VAR, "http"),
+            (THREADS_VAR, "0"),
+            (THREADS_VAR, "-1"),
+            (MAX_BODY_SIZE_VAR, "0"),
+            (MAX_BODY_SIZE_VAR, "4k"),
+            (LOG_LEVEL_VAR, "loud"),
+            (LOG_FORMAT_VAR, "xml"),
+        ] {
+            let Err(e) = apply(&[(var, value)]) else {
+                panic!("{}={} was accepted", var, value);
+            };
+            assert!(e.to_string().contains(var), "{}", e);
+        }
+        // the others are taken as they are, and rejected where the option's value would be
+        let config = apply(&[(BIND_VAR, "not an address")]).unwrap();
+        assert!(listen_addrs(&config).is_err());
+        let mut config = apply(&[(DIRECTORY_VAR, "does-not-exist")]).unwrap();
+        assert!(check_config(&mut config).is_err());
+        let config = apply(&[(WRITE_TOKENS_VAR, " , ")]).unwrap();
+        assert!(config.write_tokens.is_empty());
+        #[cfg(feature = "tls")]
+        {
+            let config = apply(&[(TLS_CERT_VAR, "cert.pem")]).unwrap();
+            assert!(tls::server_config(&config).is_err());
+            let config = apply(&[(TLS_CERT_VAR, "missing.pem"), (TLS_KEY_VAR, "missing.pem")]);
+            assert!(tls::server_config(&config.unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_config_file_precedence() {
+        let addrs = |config: &Config| -> Vec<String> {
+            let addrs = listen_addrs(config).unwrap();
+            addrs.iter().map(|addr| addr.to_string()).collect()
+        };
+        let dir = TempDir::new("config-precedence");
+        let path = dir.join("config.toml");
+        fs::write(&path, "bind = [\"0.0.0.0\"]\nport = 8000\nthreads = 3\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        // a config file comes between the environment and the flags
+        let config = parse_args(&["--config", path].map(str::to_owned)).unwrap();
+        assert_eq!(addrs(&config), ["0.0.0.0:8000"]);
+        assert_eq!(config.threads, 3);
+        let args = ["--bind", "::1", "--config", path, "--threads", "5"].map(str::to_owned);
+        let config = parse_args(&args).unwrap();
+        assert_eq!(addrs(&config), ["[::1]:8000"]);
+        assert_eq!(config.threads, 5);
+        fs::write(path, "threads = 0\n").unwrap();
+        let Err(e) = parse_args(&["--config", path].map(str::to_owned)) else {
+            panic!("threads = 0 was accepted");
+        };
+        assert!(format!("{:#}", e).contains("--threads"));
+    }
+
+    #[test]
+    fn test_multiple_listeners() {
+        let listeners: Vec<Listener> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap().into())
+            .collect();
+        let addrs: Vec<_> = listeners
+            .iter()
+            .map(|l| l.socket.local_addr().unwrap())
+            .collect();
+        let current = Current::new(State::new(Config::default()));
+        thread::spawn(move || serve(current, listeners));
+
+        for addr in addrs {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+        }
+    }
+
+    #[test]
+    fn test_acceptors() {
+        let listeners = bind_listeners("127.0.0.1:0".parse().unwrap(), 3).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_eq!(listeners.len(), 3);
+        assert!(listeners.iter().all(|l| l.local_addr().unwrap() == addr));
+        let current = Current::new(State::new(Config::default()));
+        let listeners = listeners.into_iter().map(Listener::from).collect();
+        thread::spawn(move || serve(current, listeners));
+
+        for _ in 0..6 {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK"));
+        }
+
+        let config = parse_args(&["--acceptors".to_owned(), "4".to_owned()]).unwrap();
+        assert_eq!(config.acceptors, 4);
+    }
+
+    #[test]
+    fn test_server_builder() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .directory("lol")
+            .router(Router::new().get("/", || "built"))
+            .threads(2)
+            .header("X-Built", "yes")
+            .mime_type(".log", "text/plain")
+            .build()
+            .unwrap();
+        let state = server.current.get();
+        assert_eq!(state.config.threads, 2);
+        let mime_types = [("log".to_owned(), "text/plain".to_owned())];
+        assert_eq!(state.config.mime_types, mime_types);
+        assert!(state.config.mounts[0].directory.ends_with("lol"));
+        let addrs = server.local_addrs();
+        assert_eq!(addrs.len(), 1);
+        thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addrs[0]).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("X-Built: yes"));
+        assert!(response.ends_with("built"));
+
+        let build = |builder: ServerBuilder<NoRouter>| builder.router(Router::new()).build();
+        assert!(build(Server::builder().port(0).directory("does-not-exist")).is_err());
+        assert!(build(Server::builder().port(0).threads(0)).is_err());
+        assert!(build(Server::builder().bind("not an address")).is_err());
+        assert!(build(Server::builder().port(0).dotfiles("nope", true)).is_err());
+        assert!(build(Server::builder().port(0).rate_limit(0.0, 1)).is_err());
+        assert!(build(Server::builder().port(0).rate_limit(f64::NAN, 1)).is_err());
+        assert!(build(Server::builder().port(0).rate_limit(1.0, 0)).is_err());
+    }
+
+    #[test]
+    fn test_access_log() {
+        let path = env::temp_dir().join(format!(
+            "http-server-rust-access-{}.log",
+            std::process::id()
+        ));
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .access_log(Some(path.to_str().unwrap()))
+            .router(Router::new().get("/", || "logged"))
+            .build()
+            .unwrap();
+        let addr = server.local_addrs()[0];
+        thread::spawn(move || server.run());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /?a=\"b\" HTTP/1.1\r\nHost: a\r\n\r\nBAD\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("127.0.0.1 - - ["));
+        assert!(lines[0].contains("] \"GET /?a=\\x22b\\x22 HTTP/1.1\" 200 6 "));
+        assert!(lines[1].contains("] \"-\" 400 "));
+        std::fs::remove_file(path).unwrap();
+
+        let args = ["--access-log", "/tmp/a.log"].map(str::to_owned);
+        assert_eq!(
+            parse_args(&args).unwrap().access_log.as_deref(),
+            Some("/tmp/a.log")
+        );
+        let args = ["--no-access-log".to_owned()];
+        assert_eq!(parse_args(&args).unwrap().access_log, None);
+        assert_eq!(Config::default().access_log.as_deref(), Some("-"));
+        let args = [
+            "--access-log-rotate",
+            "daily",
+            "--access-log-max-size",
+            "1048576",
+        ];
+        let config = parse_args(&args.map(str::to_owned)).unwrap();
+        assert_eq!(config.access_log_period, Some(Duration::from_secs(86400)));
+        assert_eq!(config.access_log_max_size, Some(1048576));
+        let args = ["--access-log-rotate", "weekly"].map(str::to_owned);
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(Config::default().log_format, LogFormat::Text);
+        let args = ["--log-format", "json"].map(str::to_owned);
+        assert_eq!(parse_args(&args).unwrap().log_format, LogFormat::Json);
+        let args = ["--log-format", "json", "--log-format", "text"].map(str::to_owned);
+        assert_eq!(parse_args(&args).unwrap().log_format, LogFormat::Text);
+        let args = ["--log-format", "xml"].map(str::to_owned);
+        assert!(parse_args(&args).is_err());
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(Config::default().log_level, Level::INFO);
+        let args = ["--log-level", "warn", "-vv"].map(str::to_owned);
+        assert_eq!(parse_args(&args).unwrap().log_level, Level::DEBUG);
+        let args = ["-q".to_owned()];
+        assert_eq!(parse_args(&args).unwrap().log_level, Level::WARN);
+        let args = ["--log-level", "loud"].map(str::to_owned);
+        assert!(parse_args(&args).is_err());
+        assert_eq!(
+            parse_args(&["-vq".to_owned()]).unwrap().log_level,
+            Level::INFO
+        );
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = parse_args(&["--metrics".to_owned()]).unwrap();
+        let state = Arc::new(State::new(config));
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(state, stream, false);
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET /echo/abc HTTP/1.1\r\nHost: a\r\n\r\nGET /nope HTTP/1.1\r\nHost: a\r\n\r\nGET /metrics HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        let metrics = response.rsplit("\r\n\r\n").next().unwrap();
+        assert!(metrics.contains("http_requests_total{method=\"GET\",status=\"200\"} 1\n"));
+        assert!(metrics.contains("http_requests_total{method=\"GET\",status=\"404\"} 1\n"));
+        // the /metrics request itself is still being answered
+        assert!(metrics.contains("http_requests_in_flight 1\n"));
+        assert!(metrics.contains("http_response_bytes_total 3\n"));
+        assert!(metrics.contains("http_request_duration_seconds_count 2\n"));
+        assert!(metrics.contains("http_request_handling_seconds_count 2\n"));
+        assert!(!response.contains(X_RESPONSE_TIME));
+
+        let state = Arc::new(State::new(Config::default()));
+        let res = handle_request(state, Request::new(Method::Get, "/metrics"));
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_handler_panics() {
+        let router = Router::new()
+            .get("/panic", || -> &'static str { panic!("boom") })
+            .get("/ok", || "ok");
+        let state = Arc::new(State::with_router(Config::default(), router));
+        let res = handle_request(state.clone(), Request::new(Method::Get, "/panic"));
+        assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        // the connection survives to serve the next request
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(state, stream, false);
+        });
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET /panic HTTP/1.1\r\nHost: a\r\n\r\nGET /ok HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error"));
+        assert!(response.ends_with("\r\n\r\nok"));
+        assert_eq!(panic_message(&String::from("boom")), "boom");
+    }
+
+    #[test]
+    fn test_hooks() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&errors);
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .router(
+                Router::new()
+                    .get("/", |request: Request| request.path)
+                    .get("/panic", || -> &'static str { panic!("boom") }),
+            )
+            .on_request(|request| {
+                if request.path == "/old" {
+                    request.path = "/".to_owned();
+                }
+            })
+            .on_response(|request, response| {
+                let target = format!("{} {}", request.method.as_str(), request.path);
+                response.headers.insert("X-Target", &target);
+            })
+            .on_error(move |error| seen.lock().unwrap().push(error.status))
+            .build()
+            .unwrap();
+        let state = server.current.get();
+
+        let res = handle_request(state.clone(), Request::new(Method::Get, "/old"));
+        assert_eq!(res.body, "/");
+        assert_eq!(res.headers.get("X-Target"), Some("GET /"));
+        assert!(errors.lock().unwrap().is_empty());
+
+        let res = handle_request(state.clone(), Request::new(Method::Get, "/panic"));
+        assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(res.headers.get("X-Target"), Some("GET /panic"));
+        let parse_error = anyhow!(StatusError(StatusCode::URI_TOO_LONG));
+        parse_error_response(&state, &parse_error);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            [StatusCode::INTERNAL_SERVER_ERROR, StatusCode::URI_TOO_LONG]
+        );
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let state = Arc::new(State::new(Config {
+            max_connections: 2,
+            ..Config::default()
+        }));
+        let first = state.try_connect(None).unwrap();
+        let second = state.try_connect(None).unwrap();
+        assert!(state.try_connect(None).is_none());
+        drop(first);
+        let third = state.try_connect(None).unwrap();
+        assert_eq!(state.active_connections.load(Ordering::SeqCst), 2);
+        drop((second, third));
+        assert_eq!(state.active_connections.load(Ordering::SeqCst), 0);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        reject_connection(&state, stream, StatusCode::SERVICE_UNAVAILABLE);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+        assert!(response.contains("Connection: close"));
+    }
+
+    #[test]
+    fn test_head_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(State::new(Config {
+            read_timeout: Duration::from_millis(100),
+            ..Config::default()
+        }));
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                handle_connection(Arc::clone(&state), stream, false);
+            }
+        });
+
+        // a client that never sends anything is dropped without a response
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "");
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+
+        let args: Vec<String> = ["--write-timeout", "5"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let config = parse_args(&args).unwrap();
+        assert_eq!(config.write_timeout, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_response_time_header() {
+        let config = parse_args(&["--response-time".to_owned()]).unwrap();
+        let state = Arc::new(State::new(config));
+        let (response, _) = finish_response(
+            &state,
+            Response::new(StatusCode::OK),
+            false,
+            Duration::from_micros(12345),
+        );
+        assert_eq!(response.headers.get(X_RESPONSE_TIME), Some("12.345ms"));
+
+        let state = Arc::new(State::new(Config::default()));
+        let (response, _) =
+            finish_response(&state, Response::new(StatusCode::OK), false, Duration::ZERO);
+        assert_eq!(response.headers.get(X_RESPONSE_TIME), None);
+    }
+
+    #[test]
+    fn test_slow_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(State::new(Config {
+            slow_request: Some(Duration::ZERO),
+            ..Config::default()
+        }));
+        let captured = Captured::default();
+        let server = thread::spawn({
+            let captured = captured.clone();
+            move || {
+                let subscriber = captured.subscriber(LogFormat::Text, Level::INFO);
+                tracing::subscriber::with_default(subscriber, || {
+                    let (stream, _) = listener.accept().unwrap();
+                    handle_connection(state, stream, false);
+                });
+            }
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET /echo/abc HTTP/1.1\r\nHost: a\r\nUser-Agent: curl/8.0\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        client.read_to_string(&mut String::new()).unwrap();
+        server.join().unwrap();
+
+        let logs = captured.contents();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("slow request"))
+            .unwrap();
+        assert!(line.contains("WARN"));
+        assert!(line.contains("request=\"GET /echo/abc HTTP/1.1\""));
+        assert!(line.contains("user_agent=\"curl/8.0\""));
+        assert!(line.contains("status=200 bytes=3 parse_ms="));
+        assert!(line.contains("handler_ms="));
+        assert!(line.contains("write_ms="));
+        assert!(line.contains("total_ms="));
+
+        let args = ["--slow-request-ms", "500"].map(str::to_owned);
+        let config = parse_args(&args).unwrap();
+        assert_eq!(config.slow_request, Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let args = ["--rate-limit", "1/m", "--rate-limit-burst", "2"].map(str::to_owned);
+        let config = parse_args(&args).unwrap();
+        assert_eq!(config.rate_limit, Some(1.0 / 60.0));
+        assert_eq!(config.rate_limit_burst, Some(2));
+        let state = Arc::new(State::new(config));
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(state, stream, false);
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: a\r\n\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\n\
+                  GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(response.contains("HTTP/1.1 429 Too Many Requests\r\n"));
+        assert!(response.contains("Retry-After: 60\r\n"));
+
+        assert!(parse_args(&["--rate-limit".to_owned(), "5/h".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let args = ["--allow-ip", "10.0.0.0/8", "--deny-ip", "10.0.0.5"].map(str::to_owned);
+        let config = parse_args(&args).unwrap();
+        assert!(config.ip_allowed("10.1.2.3".parse().unwrap()));
+        assert!(!config.ip_allowed("10.0.0.5".parse().unwrap()));
+        assert!(!config.ip_allowed("192.168.0.1".parse().unwrap()));
+        assert!(parse_args(&["--deny-ip".to_owned(), "10.0.0.0/40".to_owned()]).is_err());
+
+        // loopback clients are turned away before anything is read
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let current = Current::new(State::new(config));
+        thread::spawn(move || serve(current, vec![listener.into()]));
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+        assert!(response.contains("Connection: close"));
+
+        let state = State::new(Config {
+            denied_ips: vec!["::1".parse().unwrap()],
+            ..Config::default()
+        });
+        assert!(connection_allowed(&state, "127.0.0.1".parse().ok()));
+        assert!(!connection_allowed(&state, None));
+    }
+
+    #[test]
+    fn test_slow_heads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let args = ["--head-timeout", "1", "--max-connections-per-ip", "1"].map(str::to_owned);
+        let config = parse_args(&args).unwrap();
+        assert_eq!(config.head_timeout, Duration::from_secs(1));
+        let state = Arc::new(State::new(config));
+        thread::spawn({
+            let state = Arc::clone(&state);
+            move || {
+                for stream in listener.incoming().flatten() {
+                    handle_connection(Arc::clone(&state), stream, false);
+                }
+            }
+        });
+
+        // every byte arrives well within the read timeout, but the head never ends
+        let mut client = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+        for byte in b"GET / HTTP/1.1\r\nHost: a\r\nX-Drip: 1234567890" {
+            if client.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // one connection per client
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = state.try_connect(Some(client)).unwrap();
+        assert!(state.try_connect(Some(client)).is_none());
+        let other = state.try_connect("192.0.2.2".parse().ok()).unwrap();
+        drop(first);
+        let again = state.try_connect(Some(client)).unwrap();
+        drop((other, again));
+        assert!(state.connections_per_ip.lock().unwrap().is_empty());
+        assert_eq!(state.active_connections.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::http::{Request, Response, StatusCode, APPLICATION_JSON, WWW_AUTHENTICATE};
+use crate::middleware::{bearer_token, constant_time_eq};
+use crate::server::State;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// counters behind GET /_stats, updated by the connection loops
+pub(crate) struct Stats {
+    started: Instant,
+    connections: AtomicU64,
+    requests: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+}
+
+impl Stats {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            client_errors: AtomicU64::new(0),
+            server_errors: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn answered(&self, status: StatusCode) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let errors = match status.as_u16() {
+            400..=499 => &self.client_errors,
+            500..=599 => &self.server_errors,
+            _ => return,
+        };
+        errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, uptime: Duration, active_connections: usize, queued: usize) -> String {
+        format!(
+            "{{\"uptime_seconds\":{},\"connections\":{{\"total\":{},\"active\":{}}},\
+             \"requests\":{},\"errors\":{{\"client\":{},\"server\":{}}},\"queue_depth\":{}}}",
+            uptime.as_secs(),
+            self.connections.load(Ordering::Relaxed),
+            active_connections,
+            self.requests.load(Ordering::Relaxed),
+            self.client_errors.load(Ordering::Relaxed),
+            self.server_errors.load(Ordering::Relaxed),
+            queued
+        )
+    }
+}
+
+// only routed when an admin token is configured, and answered only to requests bearing it
+pub(crate) fn stats_handler(state: Arc<State>, request: Request) -> Response {
+    let Some(token) = &state.config.admin_token else {
+        return Response::new(StatusCode::NOT_FOUND);
+    };
+    let given = bearer_token(&request);
+    if !given.is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())) {
+        return Response::new(StatusCode::UNAUTHORIZED).with_header(WWW_AUTHENTICATE, "Bearer");
+    }
+
+    let stats = &state.stats;
+    let body = stats.render(
+        stats.started.elapsed(),
+        state.active_connections.load(Ordering::SeqCst),
+        state.queued_connections.load(Ordering::SeqCst),
+    );
+    Response::new(StatusCode::OK)
+        .with_body(&body)
+        .with_content_type_and_current_length(APPLICATION_JSON)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::{Method, AUTHORIZATION};
+    use crate::server::{handle_request, Config};
+
+    #[test]
+    fn test_stats() {
+        let stats = Stats::new();
+        stats.connected();
+        stats.answered(StatusCode::OK);
+        stats.answered(StatusCode::NOT_FOUND);
+        stats.answered(StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            stats.render(Duration::from_secs(90), 1, 2),
+            "{\"uptime_seconds\":90,\"connections\":{\"total\":1,\"active\":1},\"requests\":3,\
+             \"errors\":{\"client\":1,\"server\":1},\"queue_depth\":2}"
+        );
+
+        let state = Arc::new(State::new(Config {
+            admin_token: Some("s3cret".to_owned()),
+            ..Config::default()
+        }));
+        let get = |authorization: Option<&str>| {
+            let mut request = Request::new(Method::Get, "/_stats");
+            if let Some(authorization) = authorization {
+                request = request.with_header(AUTHORIZATION, authorization);
+            }
+            handle_request(state.clone(), request).status
+        };
+        assert_eq!(get(Some("Bearer s3cret")), StatusCode::OK);
+        assert_eq!(get(Some("Bearer s3cre")), StatusCode::UNAUTHORIZED);
+        assert_eq!(get(None), StatusCode::UNAUTHORIZED);
+
+        let state = Arc::new(State::new(Config::default()));
+        let res = handle_request(state, Request::new(Method::Get, "/_stats"));
+        assert_eq!(res.status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::http::{Method, Request, Response};
+use crate::server::{handle_request, parse_args, Config, Mount, State};
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+// a test's own directory under the temp directory, empty to begin with and removed when dropped,
+// so a failed assertion doesn't leave files behind for the next run
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("http-server-rust-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// the binary's routes with the directory mounted under /files/ and the rest of the default config
+// changed by `configure`
+pub(crate) fn files_state(dir: &Path, configure: impl FnOnce(&mut Config)) -> Arc<State> {
+    let mut config = Config {
+        mounts: vec![Mount::new("files", dir.to_str().unwrap())],
+        ..Config::default()
+    };
+    configure(&mut config);
+    Arc::new(State::new(config))
+}
+
+// the binary's routes with the config of the command line options
+pub(crate) fn args_state(args: &[&str]) -> Arc<State> {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    Arc::new(State::new(parse_args(&args).unwrap()))
+}
+
+pub(crate) fn send(state: &Arc<State>, request: Request) -> Response {
+    handle_request(Arc::clone(state), request)
+}
+
+pub(crate) fn get(state: &Arc<State>, target: &str) -> Response {
+    send(state, Request::new(Method::Get, target))
+}
@@ -0,0 +1,277 @@
+use crate::http::ClientIdentity;
+use crate::server::Config;
+use anyhow::{anyhow, bail, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use x509_parser::extensions::GeneralName;
+
+// the server config for --tls-cert and --tls-key, None when serving plain HTTP
+pub fn server_config(config: &Config) -> Result<Option<Arc<ServerConfig>>> {
+    let (cert_path, key_path) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => return Ok(None),
+        _ => bail!("Both --tls-cert and --tls-key are needed for TLS!"),
+    };
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.tls_client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match config.tls_client_optional {
+                true => verifier.allow_unauthenticated().build()?,
+                false => verifier.build()?,
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None if config.tls_client_optional => bail!("--tls-client-optional needs --tls-client-ca!"),
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config =
+        builder.with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Some(Arc::new(server_config)))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).map_err(|e| anyhow!("Could not read {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}!", path);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).map_err(|e| anyhow!("Could not read {}: {}", path, e))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file))? {
+        Some(key) => Ok(key),
+        None => bail!("No private key found in {}!", path),
+    }
+}
+
+// finishes the handshake so the client certificate, if any, is known before the first request
+pub fn accept(
+    config: Arc<ServerConfig>,
+    mut sock: TcpStream,
+) -> Option<(
+    StreamOwned<ServerConnection, TcpStream>,
+    Option<ClientIdentity>,
+)> {
+    let mut conn = ServerConnection::new(config).ok()?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock).ok()?;
+    }
+    let client = conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(client_identity);
+    Some((StreamOwned::new(conn, sock), client))
+}
+
+// subject and subject alternative names of a verified client certificate
+fn client_identity(cert: &CertificateDer) -> Option<ClientIdentity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let sans = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Some(ClientIdentity {
+        subject: cert.subject().to_string(),
+        sans,
+    })
+}
+
+// tells the client we're done so it can tell a finished response from a truncated one
+pub fn close(mut stream: StreamOwned<ServerConnection, TcpStream>) {
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::app_router;
+    use crate::http::Request;
+    use crate::server::{handle_connection, Mount, State};
+    use crate::test_util::TempDir;
+    use rcgen::{
+        BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose,
+        IsCa, KeyPair,
+    };
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection};
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    // sends raw requests over a fresh TLS connection and reads until the server closes it
+    fn tls_request(addr: SocketAddr, client_config: &Arc<ClientConfig>, request: &[u8]) -> String {
+        let session = ClientConnection::new(
+            Arc::clone(client_config),
+            ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let mut client = StreamOwned::new(session, TcpStream::connect(addr).unwrap());
+        client.write_all(request).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_tls() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = TempDir::new("tls");
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        let config = Config {
+            tls_cert: Some(cert_path.to_str().unwrap().to_owned()),
+            tls_key: Some(key_path.to_str().unwrap().to_owned()),
+            ..Config::default()
+        };
+        let tls = server_config(&config).unwrap();
+        assert!(tls.is_some());
+        let state = Arc::new(State {
+            tls,
+            ..State::new(config)
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(state, stream, false);
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let response = tls_request(
+            addr,
+            &Arc::new(client_config),
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("Hello World"));
+
+        let config = Config {
+            tls_cert: Some(cert_path.to_str().unwrap().to_owned()),
+            ..Config::default()
+        };
+        assert!(server_config(&config).is_err());
+        assert!(server_config(&Config::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_client_certificates() {
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(vec!["client.example".to_owned()]).unwrap();
+        client_params.distinguished_name = DistinguishedName::new();
+        client_params
+            .distinguished_name
+            .push(DnType::CommonName, "client");
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        let dir = TempDir::new("mtls");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        std::fs::write(path("cert.pem"), server.cert.pem()).unwrap();
+        std::fs::write(path("key.pem"), server.key_pair.serialize_pem()).unwrap();
+        std::fs::write(path("ca.pem"), ca.pem()).unwrap();
+
+        // anonymous clients may read, only certificate holders may change files
+        let config = Config {
+            mounts: vec![Mount::new("files", dir.to_str().unwrap())],
+            tls_cert: Some(path("cert.pem")),
+            tls_key: Some(path("key.pem")),
+            tls_client_ca: Some(path("ca.pem")),
+            tls_client_optional: true,
+            ..Config::default()
+        };
+        let router = app_router(&config).get("/whoami", |request: Request| match request.client {
+            Some(client) => format!("{} {}", client.subject, client.sans.join(",")),
+            None => "anonymous".to_owned(),
+        });
+        let state = Arc::new(State {
+            tls: server_config(&config).unwrap(),
+            ..State::with_router(config, router)
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                handle_connection(Arc::clone(&state), stream.unwrap(), false);
+            }
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(server.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let anonymous = Arc::new(builder.clone().with_no_client_auth());
+        let authenticated = Arc::new(
+            builder
+                .with_client_auth_cert(
+                    vec![client_cert.der().clone()],
+                    PrivatePkcs8KeyDer::from(client_key.serialize_der()).into(),
+                )
+                .unwrap(),
+        );
+
+        let requests = b"GET /whoami HTTP/1.1\r\nHost: localhost\r\n\r\n\
+            POST /files/upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\
+            Connection: close\r\n\r\nhi";
+        let response = tls_request(addr, &anonymous, requests);
+        assert!(response.contains("\r\n\r\nanonymous"));
+        assert!(response.contains("HTTP/1.1 403 Forbidden"));
+        let response = tls_request(addr, &authenticated, requests);
+        assert!(response.contains("\r\n\r\nCN=client client.example"));
+        assert!(response.contains("HTTP/1.1 201 Created"));
+        assert_eq!(std::fs::read(dir.join("upload")).unwrap(), b"hi");
+
+        let config = Config {
+            tls_cert: Some(path("cert.pem")),
+            tls_key: Some(path("key.pem")),
+            tls_client_optional: true,
+            ..Config::default()
+        };
+        assert!(server_config(&config).is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use crate::base64;
+use crate::http::{has_token, Request, Response, StatusCode, Upgraded, CONNECTION, UPGRADE};
+use crate::sha1::sha1;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+const SEC_WEBSOCKET_ACCEPT: &str = "Sec-WebSocket-Accept";
+const SEC_WEBSOCKET_KEY: &str = "Sec-WebSocket-Key";
+const SEC_WEBSOCKET_VERSION: &str = "Sec-WebSocket-Version";
+// appended to the client's key before hashing it for Sec-WebSocket-Accept
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// largest message put together from frames; a larger one closes the connection
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// status codes of close frames
+const NORMAL: u16 = 1000;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_owned())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(bytes: Vec<u8>) -> Self {
+        Message::Binary(bytes)
+    }
+}
+
+// answers a WebSocket handshake with 101 and hands the connection to `on_open`, which has it until
+// it returns; requests that aren't a handshake get 426, or 400 without a valid key
+pub fn upgrade<F>(request: &Request, on_open: F) -> Response
+where
+    F: FnOnce(WebSocket) + Send + 'static,
+{
+    let headers = &request.headers;
+    let upgrading = has_token(headers.get_all(UPGRADE), "websocket")
+        && has_token(headers.get_all(CONNECTION), "upgrade");
+    if !upgrading || headers.get(SEC_WEBSOCKET_VERSION) != Some("13") {
+        return Response::new(StatusCode::UPGRADE_REQUIRED)
+            .with_header(UPGRADE, "websocket")
+            .with_header(SEC_WEBSOCKET_VERSION, "13");
+    }
+    let Some(key) = headers.get(SEC_WEBSOCKET_KEY) else {
+        return Response::new(StatusCode::BAD_REQUEST);
+    };
+    if base64::decode(key).is_none_or(|nonce| nonce.len() != 16) {
+        return Response::new(StatusCode::BAD_REQUEST);
+    }
+    Response::new(StatusCode::SWITCHING_PROTOCOLS)
+        .with_header(UPGRADE, "websocket")
+        .with_header(CONNECTION, "Upgrade")
+        .with_header(SEC_WEBSOCKET_ACCEPT, &accept_key(key))
+        .upgrade(move |connection| on_open(WebSocket::new(connection)))
+}
+
+fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+// the server's end of a WebSocket connection; pings are answered while receiving, and a close
+// frame is sent when it's dropped unless one was already
+pub struct WebSocket<'a> {
+    connection: Upgraded<'a>,
+    // read but not yet taken apart into frames
+    buffer: Vec<u8>,
+    // the opcode and payload so far of a message sent in fragments
+    fragmented: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl<'a> WebSocket<'a> {
+    pub(crate) fn new(connection: Upgraded<'a>) -> Self {
+        Self {
+            connection,
+            buffer: Vec::new(),
+            fragmented: None,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    // the next message, or None once the client closed the connection; with a read timeout set
+    // this fails with WouldBlock or TimedOut when it runs out, and can be called again
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            if self.close_received {
+                return Ok(None);
+            }
+            let frame = match parse_frame(&self.buffer) {
+                Ok(Some((frame, length))) => {
+                    self.buffer.drain(..length);
+                    frame
+                }
+                Ok(None) => {
+                    let mut chunk = [0; 16 * 1024];
+                    match self.connection.read(&mut chunk)? {
+                        // gone without a close frame
+                        0 => self.close_received = true,
+                        n => self.buffer.extend_from_slice(&chunk[..n]),
+                    }
+                    continue;
+                }
+                Err(code) => return Err(self.fail(code)),
+            };
+            match (frame.opcode, self.fragmented.as_mut()) {
+                (PING, _) => self.write_frame(PONG, &frame.payload)?,
+                (PONG, _) => {}
+                (CLOSE, _) => {
+                    self.close_received = true;
+                    // with the client's status code, if it gave one
+                    let code = frame.payload.get(..2).unwrap_or_default();
+                    if !self.close_sent {
+                        self.close_sent = true;
+                        self.write_frame(CLOSE, code)?;
+                    }
+                }
+                (TEXT | BINARY, None) if frame.fin => {
+                    return self.message(frame.opcode, frame.payload).map(Some);
+                }
+                (TEXT | BINARY, None) => self.fragmented = Some((frame.opcode, frame.payload)),
+                (CONTINUATION, Some((_, payload))) => {
+                    if payload.len() + frame.payload.len() > MAX_MESSAGE {
+                        return Err(self.fail(TOO_BIG));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let (opcode, payload) = self.fragmented.take().unwrap();
+                        return self.message(opcode, payload).map(Some);
+                    }
+                }
+                _ => return Err(self.fail(PROTOCOL_ERROR)),
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: impl Into<Message>) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "WebSocket closed",
+            ));
+        }
+        match message.into() {
+            Message::Text(text) => self.write_frame(TEXT, text.as_bytes()),
+            Message::Binary(bytes) => self.write_frame(BINARY, &bytes),
+        }
+    }
+
+    // e.g. to keep the connection from looking idle to something in between; at most 125 bytes
+    pub fn ping(&mut self, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > 125 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "ping too long"));
+        }
+        self.write_frame(PING, payload)
+    }
+
+    // sends a close frame and waits for the client's, skipping messages still on their way
+    pub fn close(&mut self) -> io::Result<()> {
+        if !self.close_sent {
+            self.close_sent = true;
+            self.write_frame(CLOSE, &NORMAL.to_be_bytes())?;
+        }
+        while self.recv()?.is_some() {}
+        Ok(())
+    }
+
+    // how long recv waits for the client, the server's read timeout at first
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.connection.set_read_timeout(timeout)
+    }
+
+    fn message(&mut self, opcode: u8, payload: Vec<u8>) -> io::Result<Message> {
+        match opcode {
+            TEXT => match String::from_utf8(payload) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => Err(self.fail(INVALID_DATA)),
+            },
+            _ => Ok(Message::Binary(payload)),
+        }
+    }
+
+    // server frames go unmasked
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            n if n < 126 => frame.push(n as u8),
+            n if n <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.connection.write_all(&frame)?;
+        self.connection.flush()
+    }
+
+    // closes the connection with the status code after the client broke the protocol
+    fn fail(&mut self, code: u16) -> io::Error {
+        if !self.close_sent {
+            self.close_sent = true;
+            let _ = self.write_frame(CLOSE, &code.to_be_bytes());
+        }
+        self.close_received = true;
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("WebSocket closed with {}", code),
+        )
+    }
+}
+
+impl Drop for WebSocket<'_> {
+    fn drop(&mut self) {
+        if !self.close_sent {
+            let _ = self.write_frame(CLOSE, &NORMAL.to_be_bytes());
+        }
+    }
+}
+
+// the first frame in the buffer and its length, None if it's not all there yet, or the status
+// code to close the connection with
+fn parse_frame(buffer: &[u8]) -> Result<Option<(Frame, usize)>, u16> {
+    let [first, second, ..] = *buffer else {
+        return Ok(None);
+    };
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0f;
+    // no extensions are negotiated, and clients have to mask what they send
+    if first & 0x70 != 0 || second & 0x80 == 0 {
+        return Err(PROTOCOL_ERROR);
+    }
+    let (length, start) = match second & 0x7f {
+        126 => match buffer.get(2..4) {
+            Some(length) => (u16::from_be_bytes(length.try_into().unwrap()) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buffer.get(2..10) {
+            Some(length) => (u64::from_be_bytes(length.try_into().unwrap()), 10),
+            None => return Ok(None),
+        },
+        length => (length as u64, 2),
+    };
+    let control = opcode & 0x8 != 0;
+    if control && (!fin || length > 125) {
+        return Err(PROTOCOL_ERROR);
+    }
+    if length > MAX_MESSAGE as u64 {
+        return Err(TOO_BIG);
+    }
+    let end = start + 4 + length as usize;
+    if buffer.len() < end {
+        return Ok(None);
+    }
+    let mask = &buffer[start..start + 4];
+    let payload = buffer[start + 4..end]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        end,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::server::{handle_connection, handle_request, Config, State};
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    // a masked frame, as clients send them
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![(fin as u8) << 7 | opcode];
+        match payload.len() {
+            n if n < 126 => frame.push(0x80 | n as u8),
+            n => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // the opcode and payload of the next server frame
+    fn server_frame<R: Read>(reader: &mut R) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head).unwrap();
+        assert_eq!(head[0] & 0x80, 0x80);
+        assert_eq!(head[1] & 0x80, 0, "server frames aren't masked");
+        let length = match head[1] {
+            126 => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            n => n as usize,
+        };
+        let mut payload = vec![0; length];
+        reader.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    #[test]
+    fn test_websocket() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let state = Arc::new(State::new(Config::default()));
+        let response = handle_request(Arc::clone(&state), Request::new(Method::Get, "/ws"));
+        assert_eq!(response.status, StatusCode::UPGRADE_REQUIRED);
+
+        // the demo route echoes messages
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || handle_connection(state, listener.accept().unwrap().0, false));
+        let mut client = TcpStream::connect(addr).unwrap();
+        // the first frame arrives along with the handshake
+        let handshake = "GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let hello = client_frame(true, TEXT, b"hello");
+        client
+            .write_all(&[handshake.as_bytes(), &hello].concat())
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(server_frame(&mut reader), (TEXT, b"hello".to_vec()));
+
+        // pings are answered in between the fragments of a message
+        let long = vec![7; 300];
+        client
+            .write_all(&client_frame(false, BINARY, &long[..100]))
+            .unwrap();
+        client.write_all(&client_frame(true, PING, b"hi")).unwrap();
+        client
+            .write_all(&client_frame(true, CONTINUATION, &long[100..]))
+            .unwrap();
+        assert_eq!(server_frame(&mut reader), (PONG, b"hi".to_vec()));
+        assert_eq!(server_frame(&mut reader), (BINARY, long));
+
+        // invalid UTF-8 closes the connection
+        client
+            .write_all(&client_frame(true, TEXT, &[0xff]))
+            .unwrap();
+        let code = INVALID_DATA.to_be_bytes().to_vec();
+        assert_eq!(server_frame(&mut reader), (CLOSE, code));
+        assert_eq!(reader.read(&mut [0; 1]).unwrap(), 0);
+    }
+}